@@ -162,7 +162,6 @@
             extraArgs = {
               TEST_SYSTEMD = pkgs.systemd;
               nativeCheckInputs = with pkgs; [
-                binutils-unwrapped
                 sbsigntool
               ];
             };
@@ -177,10 +176,10 @@
             } ''
             mkdir -p $out/bin
 
-            # Clean PATH to only contain what we need to do objcopy. Also
+            # Clean PATH to only contain what we need to sign. Also
             # tell lanzatool where to find our UEFI binaries.
             makeWrapper ${tool}/bin/lzbt-systemd $out/bin/lzbt \
-              --set PATH ${lib.makeBinPath [ pkgs.binutils-unwrapped pkgs.sbsigntool ]} \
+              --set PATH ${lib.makeBinPath [ pkgs.sbsigntool ]} \
               --set LANZABOOTE_STUB ${stub}/bin/lanzaboote_stub.efi
           '';
         in
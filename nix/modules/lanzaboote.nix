@@ -42,6 +42,19 @@ in
       '';
     };
 
+    maxGenerationAge = mkOption {
+      default = null;
+      example = 30;
+      type = types.nullOr types.ints.positive;
+      description = ''
+        Maximum age in days of the generations in the boot menu, in addition
+        to `configurationLimit`. The newest generation and the currently
+        booted generation are always kept.
+
+        `null` means no limit.
+      '';
+    };
+
     pkiBundle = mkOption {
       type = types.nullOr types.path;
       description = "PKI bundle containing db, PK, KEK";
@@ -116,6 +129,57 @@ in
         https://uapi-group.org/specifications/specs/boot_loader_specification/#sorting
       '';
     };
+
+    bootMessage = mkOption {
+      default = null;
+      type = lib.types.nullOr lib.types.str;
+      example = "Property of ACME Corp - authorized use only";
+      description = ''
+        A message that the stub shows on the console before booting, e.g. a
+        legal notice.
+      '';
+    };
+
+    fallbackCmdline = mkOption {
+      default = null;
+      type = lib.types.nullOr lib.types.str;
+      example = "systemd.unit=rescue.target";
+      description = ''
+        Kernel command line parameters that the stub appends on the last boot
+        attempt of a generation, as counted by systemd-boot's boot counting.
+        This turns a boot loop into a rescue shell.
+      '';
+    };
+
+    latestImage = mkEnableOption "a copy of the newest generation's image at `EFI/nixos/latest.efi` for firmware boot entries";
+
+    fallbackImage = mkEnableOption "booting the image of the previous generation if the kernel or initrd of a generation does not match its hash";
+
+    buildCache = mkEnableOption "a cache in `/var/lib/lanzaboote` that skips rebuilding generations whose inputs did not change";
+
+    verifyImages = mkEnableOption "checking every built image against the kernel and initrds on the ESP before installing it";
+
+    pcrPrivateKeyFile = mkOption {
+      default = null;
+      type = lib.types.nullOr lib.types.path;
+      description = ''
+        RSA private key in PEM format with which to sign the values of PCR 11
+        that every image produces. The signatures are embedded in the `.pcrsig`
+        section, so that disks can be bound to a signed PCR policy, like with
+        `systemd-measure sign --pcr-private-key`.
+      '';
+    };
+
+    pcrPublicKeyFile = mkOption {
+      default = null;
+      type = lib.types.nullOr lib.types.path;
+      description = ''
+        RSA public key in PEM format to embed in the `.pcrpkey` section of
+        every image. systemd in the initrd checks the signatures of
+        `pcrPrivateKeyFile` against it, so it must be the public key of that
+        private key.
+      '';
+    };
   };
 
   config = mkIf cfg.enable {
@@ -145,6 +209,15 @@ in
             --public-key ${cfg.publicKeyFile} \
             --private-key ${cfg.privateKeyFile} \
             --configuration-limit ${toString configurationLimit} \
+            ${optionalString (cfg.maxGenerationAge != null) "--max-generation-age ${toString cfg.maxGenerationAge}"} \
+            ${optionalString (cfg.bootMessage != null) "--boot-message ${lib.escapeShellArg cfg.bootMessage}"} \
+            ${optionalString (cfg.fallbackCmdline != null) "--cmdline-fallback ${lib.escapeShellArg cfg.fallbackCmdline}"} \
+            ${optionalString cfg.latestImage "--latest-image"} \
+            ${optionalString cfg.fallbackImage "--fallback-image"} \
+            ${optionalString cfg.buildCache "--build-cache /var/lib/lanzaboote/build-cache"} \
+            ${optionalString cfg.verifyImages "--verify-images"} \
+            ${optionalString (cfg.pcrPrivateKeyFile != null) "--pcr-private-key ${cfg.pcrPrivateKeyFile}"} \
+            ${optionalString (cfg.pcrPublicKeyFile != null) "--pcr-public-key ${cfg.pcrPublicKeyFile}"} \
             ${mountPoint} \
             /nix/var/nix/profiles/system-*-link
           '')
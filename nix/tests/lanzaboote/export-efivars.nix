@@ -40,12 +40,11 @@ in
 
     # This is the sd-boot EFI variable indicator, we should not have it at this point.
     print(machine.execute("bootctl")[1]) # Check if there's incorrect value in the output.
-    machine.succeed(
-        "test -e /sys/firmware/efi/efivars/LoaderEntrySelected-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f && false || true"
-    )
+    machine.fail(f"test -e /sys/firmware/efi/efivars/LoaderInfo-{SD_LOADER_GUID}")
 
     expected_variables = ["LoaderDevicePartUUID",
       "LoaderImageIdentifier",
+      "LoaderEntrySelected",
       "LoaderFirmwareInfo",
       "LoaderFirmwareType",
       "StubInfo",
@@ -62,6 +61,12 @@ in
         assert "lanzastub" in read_string_variable("StubInfo"), "Unexpected stub information, provenance is not lanzaboote project!"
 
     assert_variable_string("LoaderImageIdentifier", "\\EFI\\BOOT\\BOOT${efiArchUppercased}.EFI")
+    # Without systemd-boot, the stub reports itself as the selected entry.
+    assert_variable_string("LoaderEntrySelected", "BOOT${efiArchUppercased}.EFI")
+    with subtest("Is `LoaderEntrySelected` volatile"):
+        attributes = machine.succeed(f"cat /sys/firmware/efi/efivars/LoaderEntrySelected-{SD_LOADER_GUID}").encode('raw_unicode_escape')[:4]
+        # EFI_VARIABLE_BOOTSERVICE_ACCESS | EFI_VARIABLE_RUNTIME_ACCESS, but not EFI_VARIABLE_NON_VOLATILE.
+        assert struct.unpack('<I', attributes)[0] == 0x6, f"Unexpected attributes of `LoaderEntrySelected`: {attributes!r}"
     # TODO: exploit QEMU test infrastructure to pass the good value all the time.
     assert_variable_string("LoaderDevicePartUUID", "1c06f03b-704e-4657-b9cd-681a087a2fdc")
     # OVMF tests are using EDK II tree.
@@ -1,6 +1,8 @@
+use std::fmt;
 use std::path::PathBuf;
 
 use anyhow::{bail, Result};
+use goblin::{elf::header as elf_header, pe::header as pe_header, Object};
 
 /// Supported system
 #[non_exhaustive]
@@ -33,3 +35,50 @@ impl Architecture {
         })
     }
 }
+
+impl Architecture {
+    /// Detect the architecture of a PE or ELF binary, e.g. of the stub or of a kernel.
+    ///
+    /// Returns `None` if the format or the machine type is not known.
+    pub fn from_binary(data: &[u8]) -> Option<Self> {
+        match Object::parse(data).ok()? {
+            Object::PE(pe) => match pe.header.coff_header.machine {
+                pe_header::COFF_MACHINE_X86_64 => Some(Self::X86),
+                pe_header::COFF_MACHINE_ARM64 => Some(Self::AArch64),
+                _ => None,
+            },
+            Object::Elf(elf) => match elf.header.e_machine {
+                elf_header::EM_X86_64 => Some(Self::X86),
+                elf_header::EM_AARCH64 => Some(Self::AArch64),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Architecture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::X86 => "x86_64",
+            Self::AArch64 => "aarch64",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn detect_architecture_of_elf_binary() {
+        let binary = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        assert_eq!(Architecture::from_binary(&binary), Some(Architecture::X86));
+    }
+
+    #[test]
+    fn unknown_binary_has_no_architecture() {
+        assert_eq!(Architecture::from_binary(b"not a binary"), None);
+    }
+}
@@ -0,0 +1,95 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// The zstd level with which data is compressed: the highest one that does not need a larger
+/// window, and thus more memory, to decompress.
+const ZSTD_LEVEL: i32 = 19;
+
+/// The formats in which data can be embedded compressed.
+///
+/// The stub detects either by its magic bytes, so images do not record which one was chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    Gzip,
+    /// Compresses better than gzip and decompresses faster, at the cost of a slower build.
+    Zstd,
+}
+
+impl Compression {
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Gzip => Ok(gzip(data)),
+            Self::Zstd => zstd(data),
+        }
+    }
+}
+
+/// Compress data into a single gzip member, see RFC 1952.
+///
+/// The stub detects gzip by its magic bytes and decompresses it before use, see the `compression`
+/// module of the stub.
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate, no flags, no modification time, no extra flags, unknown OS.
+    const HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+
+    let mut member = HEADER.to_vec();
+    member.extend(miniz_oxide::deflate::compress_to_vec(data, 9));
+    member.extend(crc32(data).to_le_bytes());
+    // The size of the uncompressed data modulo 2^32.
+    member.extend((data.len() as u32).to_le_bytes());
+    member
+}
+
+/// Compress data into a single zstd frame, see RFC 8878.
+pub fn zstd(data: &[u8]) -> Result<Vec<u8>> {
+    ::zstd::bulk::compress(data, ZSTD_LEVEL).context("Failed to compress with zstd")
+}
+
+/// Compute the CRC-32 of data as used by gzip.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compute_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let data = b"lanzaboote ".repeat(100);
+        let member = gzip(&data);
+
+        assert_eq!(&member[..2], [0x1f, 0x8b]);
+        let trailer = &member[member.len() - 8..];
+        assert_eq!(trailer[..4], crc32(&data).to_le_bytes());
+        assert_eq!(
+            miniz_oxide::inflate::decompress_to_vec(&member[10..member.len() - 8]).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn zstd_round_trip() -> Result<()> {
+        use ruzstd::io::Read;
+
+        let kernel = crate::pe::minimal_pe().repeat(16);
+        let frame = Compression::Zstd.compress(&kernel)?;
+
+        assert_eq!(&frame[..4], [0x28, 0xb5, 0x2f, 0xfd]);
+        assert!(frame.len() < kernel.len());
+        // The stub decompresses with ruzstd, not with the zstd library that compressed it.
+        let mut decompressed = Vec::new();
+        ruzstd::StreamingDecoder::new(&frame[..])?.read_to_end(&mut decompressed)?;
+        assert_eq!(decompressed, kernel);
+        Ok(())
+    }
+}
@@ -1,6 +1,12 @@
+use std::fs::{self, File};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
+use anyhow::{Context, Result};
+use base32ct::{Base32Unpadded, Encoding};
+
 use crate::architecture::Architecture;
+use crate::utils::{file_hash, TempFileGuard};
 
 /// Generic ESP paths which can be specific to a bootloader
 pub trait EspPaths<const N: usize> {
@@ -16,3 +22,97 @@ pub trait EspPaths<const N: usize> {
     /// Returns the path containing Linux EFI binaries
     fn linux_path(&self) -> &Path;
 }
+
+/// Install a file to a content-addressed path in `directory`, i.e. `<label>-<hash>.efi`.
+///
+/// Because the file name depends on the contents, files of different generations never collide
+/// and an existing file is never overwritten with different contents. The full path to the target
+/// file is returned.
+pub fn install_content_addressed(from: &Path, directory: &Path, label: &str) -> Result<PathBuf> {
+    let to = content_addressed_path(from, directory, label)?;
+    install(from, &to)?;
+    Ok(to)
+}
+
+/// Compute the path in `directory` that [`install_content_addressed`] installs a file to, without
+/// installing it.
+pub fn content_addressed_path(from: &Path, directory: &Path, label: &str) -> Result<PathBuf> {
+    let hash = file_hash(from).context("Failed to read the source file.")?;
+    Ok(directory.join(format!(
+        "{}-{}.efi",
+        label,
+        Base32Unpadded::encode_string(&hash)
+    )))
+}
+
+/// Install an arbitrary file.
+///
+/// The file is only copied if
+///     (1) it doesn't exist at the destination or,
+///     (2) the hash of the file at the destination does not match the hash of the source file.
+pub fn install(from: &Path, to: &Path) -> Result<()> {
+    if !to.exists() || file_hash(from)? != file_hash(to)? {
+        force_install(from, to)?;
+    }
+    Ok(())
+}
+
+/// Forcibly install an arbitrary file.
+///
+/// If the file already exists at the destination, it is overwritten.
+///
+/// This function is only designed to copy files to the ESP. It sets the permission bits of the
+/// file at the destination to 0o755, the expected permissions for a vfat ESP. This is useful for
+/// producing file systems trees which can then be converted to a file system image.
+pub fn force_install(from: &Path, to: &Path) -> Result<()> {
+    log::debug!("Installing {to:?}...");
+    ensure_parent_dir(to);
+    atomic_copy(from, to)?;
+    set_permission_bits(to, 0o755)
+        .with_context(|| format!("Failed to set permission bits to 0o755 on file: {to:?}"))?;
+    Ok(())
+}
+
+/// Atomically copy a file.
+///
+/// First, the content is written to a temporary file (with a `.tmp` extension).
+/// Then, this file is synced, to ensure its data and metadata are fully on disk before continuing.
+/// In the last step, the temporary file is renamed to the final destination.
+///
+/// Due to the deficiencies of FAT32, it is possible for the filesystem to become corrupted after power loss.
+/// It is not possible to fully defend against this situation, so this operation is not actually fully atomic.
+/// However, in all other cases, the target file is either present with its correct content or not present at all.
+fn atomic_copy(from: &Path, to: &Path) -> Result<()> {
+    let tmp_guard = TempFileGuard::new(to.with_extension(".tmp"));
+    let tmp = tmp_guard.path();
+    {
+        let mut from_file =
+            File::open(from).with_context(|| format!("Failed to read the source file {from:?}"))?;
+        let mut tmp_file = File::create(tmp)
+            .with_context(|| format!("Failed to create the temporary file {tmp:?}"))?;
+        std::io::copy(&mut from_file, &mut tmp_file).with_context(|| {
+            format!("Failed to copy from {from:?} to the temporary file {tmp:?}")
+        })?;
+        tmp_file
+            .sync_all()
+            .with_context(|| format!("Failed to sync the temporary file {tmp:?}"))?;
+    }
+    tmp_guard.persist(to)
+}
+
+/// Set the octal permission bits of the specified file.
+fn set_permission_bits(path: &Path, permission_bits: u32) -> Result<()> {
+    let mut perms = fs::metadata(path)
+        .with_context(|| format!("File {path:?} doesn't have any metadata"))?
+        .permissions();
+    perms.set_mode(permission_bits);
+    fs::set_permissions(path, perms)
+        .with_context(|| format!("Failed to set permissions on {path:?}"))
+}
+
+// Ensures the parent directory of an arbitrary path exists
+pub fn ensure_parent_dir(path: &Path) {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).ok();
+    }
+}
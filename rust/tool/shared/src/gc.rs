@@ -34,7 +34,8 @@ impl Roots {
         }
     }
 
-    pub fn collect_garbage(&self, directory: impl AsRef<Path>) -> Result<()> {
+    /// Delete all unused paths in a directory and return them.
+    pub fn collect_garbage(&self, directory: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
         self.collect_garbage_with_filter(directory, |_| true)
     }
 
@@ -43,11 +44,13 @@ impl Roots {
     /// The filter function takes a &Path and returns a bool. The paths for which the filter
     /// function returns true are considered for garbage collection. This means that _only_ files
     /// that are unused AND for which the filter function returns true are deleted.
+    ///
+    /// The deleted paths are returned, so that they can be logged.
     pub fn collect_garbage_with_filter<P>(
         &self,
         directory: impl AsRef<Path>,
         mut predicate: P,
-    ) -> Result<()>
+    ) -> Result<Vec<PathBuf>>
     where
         P: FnMut(&Path) -> bool,
     {
@@ -61,6 +64,7 @@ impl Roots {
             });
 
         // Remove all entries not in use.
+        let mut removed = Vec::new();
         for e in entries_not_in_use {
             let entry = e?;
             let path = entry.path();
@@ -70,13 +74,14 @@ impl Roots {
                 // If a directory is marked as unused all its children can be deleted too.
                 fs::remove_dir_all(path)
                     .with_context(|| format!("Failed to remove directory: {:?}", path))?;
-            } else {
+            } else if fs::remove_file(path).is_err() {
                 // Ignore failing to remove path because the parent directory might have been removed before.
-                fs::remove_file(path).ok();
+                continue;
             };
+            removed.push(path.to_path_buf());
         }
 
-        Ok(())
+        Ok(removed)
     }
 }
 
@@ -115,9 +120,10 @@ mod tests {
 
         let mut roots = Roots::new();
         roots.extend(vec![&rootdir]);
-        roots.collect_garbage(&rootdir)?;
+        let removed = roots.collect_garbage(&rootdir)?;
 
         assert!(!unused_file.exists());
+        assert_eq!(removed, [unused_file]);
         Ok(())
     }
 
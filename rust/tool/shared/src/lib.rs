@@ -1,8 +1,14 @@
 pub mod architecture;
+pub mod compression;
 pub mod esp;
 pub mod gc;
 pub mod generation;
 pub mod os_release;
+pub mod pcrs;
 pub mod pe;
+pub mod predict;
+pub mod sbat;
+pub mod sign;
 pub mod signature;
+pub mod uname;
 pub mod utils;
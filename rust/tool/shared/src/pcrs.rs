@@ -0,0 +1,141 @@
+use std::fmt;
+
+use anyhow::{Context, Result};
+use goblin::pe::PE;
+
+use crate::pe::CMDLINE_CONTINUATION_SECTIONS;
+
+/// The PCR into which the stub measures the unified sections of its image.
+pub const PCR_KERNEL_IMAGE: u32 = 11;
+/// The PCR into which the stub measures the kernel configuration, i.e. credentials and command
+/// lines that are not fully covered by PCR 11.
+pub const PCR_KERNEL_CONFIG: u32 = 12;
+/// The PCR into which the stub measures system extensions.
+pub const PCR_SYSEXTS: u32 = 13;
+
+/// The unified sections that the stub measures, if they are present in the image, in the order in
+/// which it measures them. This mirrors `MEASURED_SECTIONS` of the stub.
+///
+/// `.pcrsig` is deliberately missing: it contains signatures over the expected value of PCR 11 and
+/// can thus not be part of it.
+pub(crate) const MEASURED_SECTIONS: &[&str] = &[
+    ".linux", ".osrel", ".cmdline", ".initrd", ".splash", ".dtb", ".uname", ".pcrpkey", ".dtbauto",
+];
+
+/// A kind of measurement that the stub makes into a PCR.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PcrMeasurement {
+    pub pcr: u32,
+    pub description: String,
+}
+
+impl fmt::Display for PcrMeasurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PCR {}: {}", self.pcr, self.description)
+    }
+}
+
+/// List the measurements that the stub makes when booting a lanzaboote image.
+///
+/// Measurements of companion files only happen if those files are present on the ESP at boot.
+pub fn stub_measurements(image: &[u8]) -> Result<Vec<PcrMeasurement>> {
+    let pe = PE::parse(image).context("Failed to parse lanzaboote image")?;
+    let section_names = pe
+        .sections
+        .iter()
+        .map(|section| section.name())
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to read section names of lanzaboote image")?;
+
+    Ok(measurements_for_sections(&section_names))
+}
+
+/// List the measurements of the stub for an image with the given sections.
+fn measurements_for_sections(section_names: &[&str]) -> Vec<PcrMeasurement> {
+    let mut measurements = Vec::new();
+
+    // The stub measures the unified sections in its own order, not in the order in which they
+    // appear in the image.
+    let measured_sections: Vec<&str> = MEASURED_SECTIONS
+        .iter()
+        .copied()
+        .filter(|name| section_names.contains(name))
+        .collect();
+    if !measured_sections.is_empty() {
+        measurements.push(PcrMeasurement {
+            pcr: PCR_KERNEL_IMAGE,
+            description: format!("unified sections {}", measured_sections.join(", ")),
+        });
+    }
+
+    if section_names
+        .iter()
+        .any(|name| CMDLINE_CONTINUATION_SECTIONS.contains(name))
+    {
+        measurements.push(PcrMeasurement {
+            pcr: PCR_KERNEL_CONFIG,
+            description: "complete kernel command line, including its continuation sections"
+                .to_string(),
+        });
+    }
+
+    if section_names.contains(&".cmdovrh") {
+        measurements.push(PcrMeasurement {
+            pcr: PCR_KERNEL_CONFIG,
+            description: "kernel command line override, if one is used".to_string(),
+        });
+    }
+
+    if section_names.contains(&".cmdfb") {
+        measurements.push(PcrMeasurement {
+            pcr: PCR_KERNEL_CONFIG,
+            description: "fallback kernel command line, on the last boot attempt".to_string(),
+        });
+    }
+
+    measurements.push(PcrMeasurement {
+        pcr: PCR_KERNEL_CONFIG,
+        description:
+            "device path of the boot device, if the measure-boot-device setting is enabled"
+                .to_string(),
+    });
+    measurements.push(PcrMeasurement {
+        pcr: PCR_KERNEL_CONFIG,
+        description: "credentials, if there are any".to_string(),
+    });
+    measurements.push(PcrMeasurement {
+        pcr: PCR_SYSEXTS,
+        description: "system extensions, if there are any".to_string(),
+    });
+
+    measurements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_measurements_in_measurement_order() {
+        let measurements = measurements_for_sections(&[
+            ".text", ".osrel", ".linux", ".cmdline", ".initrd", ".linuxh", ".pcrsig", ".cmdovrh",
+            ".cmdl2", ".cmdfb",
+        ]);
+
+        assert_eq!(
+            measurements
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "PCR 11: unified sections .linux, .osrel, .cmdline, .initrd",
+                "PCR 12: complete kernel command line, including its continuation sections",
+                "PCR 12: kernel command line override, if one is used",
+                "PCR 12: fallback kernel command line, on the last boot attempt",
+                "PCR 12: device path of the boot device, if the measure-boot-device setting is enabled",
+                "PCR 12: credentials, if there are any",
+                "PCR 13: system extensions, if there are any",
+            ]
+        );
+    }
+}
@@ -1,20 +1,32 @@
-use std::ffi::OsString;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
-use std::os::unix::fs::MetadataExt;
-use std::path::{Path, PathBuf};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use goblin::pe::optional_header::OptionalHeader;
+use goblin::pe::section_table::{
+    IMAGE_SCN_CNT_CODE, IMAGE_SCN_CNT_INITIALIZED_DATA, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_READ,
+};
 use goblin::pe::PE;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 
-use crate::utils::{file_hash, tmpname, SecureTempDirExt};
+use crate::architecture::Architecture;
+use crate::compression::Compression;
+use crate::esp::install_content_addressed;
+use crate::predict::{pcr_sign, predict_kernel_image, read_pcr_public_key, DEFAULT_PHASES};
+use crate::uname::kernel_release;
+use crate::utils::{file_hash, tmpname};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StubParameters {
     pub lanzaboote_store_path: PathBuf,
     pub kernel_cmdline: Vec<String>,
+    /// Parts appended to `kernel_cmdline`, each embedded in its own continuation section.
+    pub kernel_cmdline_continuations: Vec<Vec<String>>,
     pub os_release_contents: Vec<u8>,
     pub kernel_store_path: PathBuf,
     pub initrd_store_path: PathBuf,
@@ -25,6 +37,53 @@ pub struct StubParameters {
     pub kernel_path_at_esp: String,
     /// Same as kernel.
     pub initrd_path_at_esp: String,
+    /// Initrds that the stub appends to the initrd, in order: their store paths and their paths
+    /// rooted at the ESP, like `initrd_path_at_esp`.
+    pub initrd_continuations: Vec<(PathBuf, String)>,
+    /// Directory rooted at the ESP that contains the kernel and initrd of this generation, if they
+    /// are laid out in per-generation directories.
+    pub generation_directory_at_esp: Option<String>,
+    /// DER-encoded public key (SubjectPublicKeyInfo) that must have signed the kernel itself.
+    pub kernel_signing_key: Option<Vec<u8>>,
+    /// DER-encoded public key (SubjectPublicKeyInfo) that may sign overrides of the command line
+    /// and the initrd on the ESP, for recovery.
+    pub recovery_key: Option<Vec<u8>>,
+    /// Flattened device tree that the stub installs instead of the one of the firmware.
+    pub devicetree: Option<Vec<u8>>,
+    /// Flattened device trees of several boards, of which the stub installs the one that matches
+    /// the board.
+    pub auto_devicetrees: Vec<Vec<u8>>,
+    /// Kernel command lines that the stub accepts as per-generation overrides from the ESP.
+    pub trusted_cmdline_overrides: Vec<String>,
+    /// A message that the stub shows before booting the kernel.
+    pub boot_message: Option<String>,
+    /// Kernel command line parameters that the stub appends on the last boot attempt.
+    pub cmdline_fallback: Option<String>,
+    /// Whether to embed the initrd compressed in the image instead of referencing it on the ESP.
+    pub embed_initrd: Option<Compression>,
+    /// Runtime settings of the stub, embedded as `key=value` lines in the `.conf` section.
+    pub stub_config: BTreeMap<String, String>,
+    /// SHA-256 hashes of the credentials that the stub passes to the system, by credential name.
+    /// If there are any, the stub checks all credentials against them.
+    pub trusted_credentials: BTreeMap<String, [u8; 32]>,
+    /// SBAT data in CSV format that identifies the image for revocations, see
+    /// [`crate::sbat`].
+    pub sbat: Option<String>,
+    /// ESP on which to check the kernel and initrds that the image references after building it,
+    /// see [`verify_image`].
+    pub verify_esp: Option<PathBuf>,
+    /// RSA private key in PEM format with which to sign the predicted values of PCR 11, see
+    /// [`crate::predict::pcr_sign`].
+    pub pcr_signing_key: Option<PathBuf>,
+    /// RSA public key in PEM format that is embedded in the `.pcrpkey` section, see
+    /// [`crate::predict::read_pcr_public_key`].
+    pub pcr_public_key: Option<PathBuf>,
+    /// The release of the kernel for the `.uname` section. If it is not set, it is read from the
+    /// kernel, see [`crate::uname::kernel_release`].
+    pub uname: Option<String>,
+    /// A known-good image on the ESP that the stub boots instead if the kernel or an initrd does
+    /// not match its hash: its path rooted at the ESP, like `kernel_path_at_esp`, and its hash.
+    pub fallback_image: Option<(String, [u8; 32])>,
 }
 
 impl StubParameters {
@@ -44,13 +103,59 @@ impl StubParameters {
             lanzaboote_store_path: lanzaboote_stub.to_path_buf(),
             kernel_store_path: kernel_path.to_path_buf(),
             initrd_store_path: initrd_path.to_path_buf(),
-            kernel_path_at_esp: esp_relative_uefi_path(esp, kernel_target)?,
-            initrd_path_at_esp: esp_relative_uefi_path(esp, initrd_target)?,
+            kernel_path_at_esp: esp_relative_path(esp, kernel_target)?,
+            initrd_path_at_esp: esp_relative_path(esp, initrd_target)?,
+            initrd_continuations: Vec::new(),
             kernel_cmdline: Vec::new(),
+            kernel_cmdline_continuations: Vec::new(),
             os_release_contents: Vec::new(),
+            generation_directory_at_esp: None,
+            kernel_signing_key: None,
+            recovery_key: None,
+            devicetree: None,
+            auto_devicetrees: Vec::new(),
+            trusted_cmdline_overrides: Vec::new(),
+            boot_message: None,
+            cmdline_fallback: None,
+            embed_initrd: None,
+            stub_config: BTreeMap::new(),
+            trusted_credentials: BTreeMap::new(),
+            sbat: None,
+            verify_esp: None,
+            pcr_signing_key: None,
+            pcr_public_key: None,
+            uname: None,
+            fallback_image: None,
         })
     }
 
+    /// Like [`StubParameters::new`], but also place the kernel and initrd on the ESP.
+    ///
+    /// They are copied from the Nix store to content-addressed paths in `directory` on the ESP,
+    /// e.g. `EFI/nixos/kernel-<hash>.efi`, whose ESP-relative paths are then embedded. This way,
+    /// the caller does not need to install them first.
+    pub fn from_store_paths(
+        lanzaboote_stub: &Path,
+        kernel_path: &Path,
+        initrd_path: &Path,
+        esp: &Path,
+        directory: &Path,
+    ) -> Result<Self> {
+        let kernel_target = install_content_addressed(kernel_path, directory, "kernel")
+            .context("Failed to install the kernel.")?;
+        let initrd_target = install_content_addressed(initrd_path, directory, "initrd")
+            .context("Failed to install the initrd.")?;
+
+        Self::new(
+            lanzaboote_stub,
+            kernel_path,
+            initrd_path,
+            &kernel_target,
+            &initrd_target,
+            esp,
+        )
+    }
+
     pub fn with_os_release_contents(mut self, os_release_contents: &[u8]) -> Self {
         self.os_release_contents = os_release_contents.to_vec();
         self
@@ -60,6 +165,174 @@ impl StubParameters {
         self.kernel_cmdline = cmdline.to_vec();
         self
     }
+
+    /// Append a part to the kernel command line.
+    ///
+    /// The stub concatenates the parts in order, separated by spaces. This allows composing a
+    /// base command line with appended parts at build time.
+    pub fn with_appended_cmdline(mut self, cmdline: &[String]) -> Self {
+        self.kernel_cmdline_continuations.push(cmdline.to_vec());
+        self
+    }
+
+    /// Append an initrd to the initrd, e.g. to layer CPU microcode, the main initrd and further
+    /// initrds.
+    ///
+    /// The stub checks the hash of every initrd on its own and passes their concatenation to the
+    /// kernel. Like the initrd, the appended initrd is read from `initrd_target` on the ESP, unless
+    /// initrds are embedded.
+    pub fn with_appended_initrd(
+        mut self,
+        initrd_path: &Path,
+        initrd_target: &Path,
+        esp: &Path,
+    ) -> Result<Self> {
+        self.initrd_continuations.push((
+            initrd_path.to_path_buf(),
+            esp_relative_path(esp, initrd_target)?,
+        ));
+        Ok(self)
+    }
+
+    /// Record the per-generation directory on the ESP, so that the stub can check that the kernel
+    /// and initrd are read from it.
+    pub fn with_generation_directory(mut self, esp: &Path, directory: &Path) -> Result<Self> {
+        self.generation_directory_at_esp = Some(esp_relative_path(esp, directory)?);
+        Ok(self)
+    }
+
+    /// Require the kernel to carry an Authenticode signature made by this key, on top of matching
+    /// its hash.
+    pub fn with_kernel_signing_key(mut self, kernel_signing_key: &[u8]) -> Self {
+        self.kernel_signing_key = Some(kernel_signing_key.to_vec());
+        self
+    }
+
+    /// Allow the stub to use a command line or initrd override from `\loader\overrides` on the ESP
+    /// if it is signed by this key, even if it is not trusted otherwise.
+    ///
+    /// This allows recovering a machine without disabling Secure Boot. The key should be distinct
+    /// from the Secure Boot signing key, so that it can be kept offline.
+    pub fn with_recovery_key(mut self, recovery_key: &[u8]) -> Self {
+        self.recovery_key = Some(recovery_key.to_vec());
+        self
+    }
+
+    /// Embed a flattened device tree, e.g. for an ARM board whose firmware provides none or an
+    /// outdated one.
+    ///
+    /// The stub ignores it on x86_64.
+    pub fn with_devicetree(mut self, devicetree: &[u8]) -> Self {
+        self.devicetree = Some(devicetree.to_vec());
+        self
+    }
+
+    /// Embed the flattened device tree of one of several boards that the image boots on.
+    ///
+    /// The stub installs the first of them whose `compatible` property contains the model of the
+    /// board, according to the device tree of the firmware. If none matches, it falls back to the
+    /// one of [`Self::with_devicetree`]. The stub ignores them on x86_64.
+    pub fn with_auto_devicetree(mut self, devicetree: &[u8]) -> Self {
+        self.auto_devicetrees.push(devicetree.to_vec());
+        self
+    }
+
+    /// Allow the stub to replace the embedded command line with one of these command lines when
+    /// it is found in `\loader\overrides\<image>.cmdline` on the ESP.
+    pub fn with_trusted_cmdline_overrides(mut self, cmdlines: &[String]) -> Self {
+        self.trusted_cmdline_overrides = cmdlines.to_vec();
+        self
+    }
+
+    /// Show a message, e.g. a legal notice, before booting the kernel.
+    pub fn with_boot_message(mut self, boot_message: &str) -> Self {
+        self.boot_message = Some(boot_message.to_string());
+        self
+    }
+
+    /// Append these parameters, e.g. `systemd.unit=rescue.target`, to the kernel command line when
+    /// the boot counter of the image says that this is the last boot attempt.
+    pub fn with_cmdline_fallback(mut self, cmdline_fallback: &str) -> Self {
+        self.cmdline_fallback = Some(cmdline_fallback.to_string());
+        self
+    }
+
+    /// Embed the initrd compressed in the `.initrd` section, while the kernel stays on the ESP.
+    ///
+    /// The initrd is then covered by the signature of the image. The stub detects the compression
+    /// by its magic bytes, decompresses the initrd and checks the hash of the decompressed initrd,
+    /// like for an initrd on the ESP.
+    pub fn with_embedded_initrd(mut self, compression: Compression) -> Self {
+        self.embed_initrd = Some(compression);
+        self
+    }
+
+    /// Pin the contents of a credential, e.g. `\loader\credentials\<name>.cred` on the ESP.
+    ///
+    /// As soon as one credential is pinned, the stub skips or refuses all credentials that do not
+    /// match, depending on the `credential-mismatch` setting.
+    pub fn with_trusted_credential(mut self, name: &str, contents: &[u8]) -> Self {
+        self.trusted_credentials
+            .insert(name.to_string(), Sha256::digest(contents).into());
+        self
+    }
+
+    /// Set a runtime setting of the stub, e.g. `verify-only`.
+    pub fn with_stub_config(mut self, key: &str, value: &str) -> Self {
+        self.stub_config.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Embed SBAT data in CSV format in the `.sbat` section, so that the image can be revoked
+    /// selectively, e.g. `sbat,1,...` followed by a line for lanzaboote and one for the
+    /// distribution.
+    ///
+    /// The stub must not have a `.sbat` section of its own.
+    pub fn with_sbat(mut self, sbat_csv: &str) -> Self {
+        self.sbat = Some(sbat_csv.to_string());
+        self
+    }
+
+    /// Embed this kernel release, e.g. `6.6.1`, in the `.uname` section instead of the one read
+    /// from the kernel.
+    pub fn with_uname(mut self, uname: &str) -> Self {
+        self.uname = Some(uname.to_string());
+        self
+    }
+
+    /// Boot this image on the ESP instead if the kernel or an initrd does not match its hash.
+    ///
+    /// The image must already be installed, as it is, because the stub checks it against its
+    /// hash before booting it.
+    pub fn with_fallback_image(mut self, esp: &Path, image: &Path) -> Result<Self> {
+        self.fallback_image = Some((esp_relative_path(esp, image)?, file_hash(image)?.into()));
+        Ok(self)
+    }
+
+    /// Check the built image against the kernel and initrds on this ESP, see [`verify_image`].
+    pub fn with_verification(mut self, esp: &Path) -> Self {
+        self.verify_esp = Some(esp.to_path_buf());
+        self
+    }
+
+    /// Sign the values of PCR 11 that the image produces in the boot phases of systemd-pcrphase
+    /// with this RSA private key in PEM format, and embed the signatures in the `.pcrsig` section.
+    ///
+    /// This allows binding secrets to the key instead of to the PCR values of a single image.
+    pub fn with_pcr_signing_key(mut self, key: &Path) -> Self {
+        self.pcr_signing_key = Some(key.to_path_buf());
+        self
+    }
+
+    /// Embed this RSA public key in PEM format in the `.pcrpkey` section, against which systemd in
+    /// the initrd checks the signatures of the `.pcrsig` section.
+    ///
+    /// The section is measured into PCR 11 like the other unified sections. If a PCR signing key is
+    /// set as well, the public key must belong to it.
+    pub fn with_pcr_public_key(mut self, key: &Path) -> Self {
+        self.pcr_public_key = Some(key.to_path_buf());
+        self
+    }
 }
 
 /// Performs the evil operation
@@ -92,110 +365,742 @@ pub fn lanzaboote_image(
     tempdir: &TempDir,
     stub_parameters: &StubParameters,
 ) -> Result<PathBuf> {
-    // objcopy can only copy files into the PE binary. That's why we
-    // have to write the contents of some bootspec properties to disk.
-    let kernel_cmdline_file =
-        tempdir.write_secure_file(stub_parameters.kernel_cmdline.join(" "))?;
-
-    let kernel_path_file = tempdir.write_secure_file(&stub_parameters.kernel_path_at_esp)?;
-    let kernel_hash_file =
-        tempdir.write_secure_file(file_hash(&stub_parameters.kernel_store_path)?.as_slice())?;
-
-    let initrd_path_file = tempdir.write_secure_file(&stub_parameters.initrd_path_at_esp)?;
-    let initrd_hash_file =
-        tempdir.write_secure_file(file_hash(&stub_parameters.initrd_store_path)?.as_slice())?;
-
-    let os_release = tempdir.write_secure_file(&stub_parameters.os_release_contents)?;
-    let os_release_offs = stub_offset(&stub_parameters.lanzaboote_store_path)?;
-    let kernel_cmdline_offs = os_release_offs + file_size(&os_release)?;
-    let initrd_path_offs = kernel_cmdline_offs + file_size(&kernel_cmdline_file)?;
-    let kernel_path_offs = initrd_path_offs + file_size(&initrd_path_file)?;
-    let initrd_hash_offs = kernel_path_offs + file_size(&kernel_path_file)?;
-    let kernel_hash_offs = initrd_hash_offs + file_size(&initrd_hash_file)?;
-
-    let sections = vec![
-        s(".osrel", os_release, os_release_offs),
-        s(".cmdline", kernel_cmdline_file, kernel_cmdline_offs),
-        s(".initrd", initrd_path_file, initrd_path_offs),
-        s(".linux", kernel_path_file, kernel_path_offs),
-        s(".initrdh", initrd_hash_file, initrd_hash_offs),
-        s(".linuxh", kernel_hash_file, kernel_hash_offs),
+    check_architecture(
+        &stub_parameters.lanzaboote_store_path,
+        &stub_parameters.kernel_store_path,
+    )?;
+
+    let mut sections = vec![
+        (".osrel", stub_parameters.os_release_contents.clone()),
+        (
+            ".osrelh",
+            Sha256::digest(&stub_parameters.os_release_contents).to_vec(),
+        ),
+        (
+            ".cmdline",
+            stub_parameters.kernel_cmdline.join(" ").into_bytes(),
+        ),
+        (
+            ".initrd",
+            initrd_section_contents(
+                stub_parameters,
+                &stub_parameters.initrd_store_path,
+                &stub_parameters.initrd_path_at_esp,
+            )?,
+        ),
+        (
+            ".linux",
+            stub_parameters.kernel_path_at_esp.clone().into_bytes(),
+        ),
+        (
+            ".initrdh",
+            file_hash(&stub_parameters.initrd_store_path)?.to_vec(),
+        ),
+        (
+            ".linuxh",
+            file_hash(&stub_parameters.kernel_store_path)?.to_vec(),
+        ),
     ];
 
+    if stub_parameters.kernel_cmdline_continuations.len() > CMDLINE_CONTINUATION_SECTIONS.len() {
+        bail!(
+            "The kernel command line can have at most {} appended parts",
+            CMDLINE_CONTINUATION_SECTIONS.len()
+        );
+    }
+    for (section_name, cmdline) in CMDLINE_CONTINUATION_SECTIONS
+        .iter()
+        .zip(&stub_parameters.kernel_cmdline_continuations)
+    {
+        sections.push((section_name, cmdline.join(" ").into_bytes()));
+    }
+
+    if stub_parameters.initrd_continuations.len() > INITRD_CONTINUATION_SECTIONS.len() {
+        bail!(
+            "At most {} initrds can be appended to the initrd",
+            INITRD_CONTINUATION_SECTIONS.len()
+        );
+    }
+    for ((section_name, hash_section_name), (initrd_store_path, initrd_path_at_esp)) in
+        INITRD_CONTINUATION_SECTIONS
+            .iter()
+            .zip(&stub_parameters.initrd_continuations)
+    {
+        sections.push((
+            section_name,
+            initrd_section_contents(stub_parameters, initrd_store_path, initrd_path_at_esp)?,
+        ));
+        sections.push((hash_section_name, file_hash(initrd_store_path)?.to_vec()));
+    }
+
+    if let Some(generation_directory) = &stub_parameters.generation_directory_at_esp {
+        sections.push((".gendir", generation_directory.clone().into_bytes()));
+    }
+
+    if let Some(kernel_signing_key) = &stub_parameters.kernel_signing_key {
+        sections.push((".linuxpk", kernel_signing_key.clone()));
+    }
+
+    if let Some(recovery_key) = &stub_parameters.recovery_key {
+        sections.push((".recpk", recovery_key.clone()));
+    }
+
+    if let Some(devicetree) = &stub_parameters.devicetree {
+        sections.push((".dtb", devicetree.clone()));
+    }
+
+    // Unlike other sections, `.dtbauto` may occur more than once.
+    for devicetree in &stub_parameters.auto_devicetrees {
+        sections.push((".dtbauto", devicetree.clone()));
+    }
+
+    if !stub_parameters.trusted_cmdline_overrides.is_empty() {
+        let hashes: Vec<u8> = stub_parameters
+            .trusted_cmdline_overrides
+            .iter()
+            .flat_map(Sha256::digest)
+            .collect();
+        sections.push((".cmdovrh", hashes));
+    }
+
+    if let Some(boot_message) = &stub_parameters.boot_message {
+        sections.push((".bootmsg", boot_message.clone().into_bytes()));
+    }
+
+    if let Some(cmdline_fallback) = &stub_parameters.cmdline_fallback {
+        sections.push((".cmdfb", cmdline_fallback.clone().into_bytes()));
+    }
+
+    if !stub_parameters.trusted_credentials.is_empty() {
+        sections.push((
+            ".credh",
+            credential_manifest_contents(&stub_parameters.trusted_credentials).into_bytes(),
+        ));
+    }
+
+    if let Some(sbat) = &stub_parameters.sbat {
+        let stub = &stub_parameters.lanzaboote_store_path;
+        let stub_data = fs::read(stub).with_context(|| format!("Failed to read stub: {stub:?}"))?;
+        if read_section_data(&stub_data, ".sbat").is_some() {
+            bail!("The stub already has a .sbat section, SBAT data cannot be embedded");
+        }
+        sections.push((".sbat", sbat.clone().into_bytes()));
+    }
+
+    if let Some((fallback_image_path, fallback_image_hash)) = &stub_parameters.fallback_image {
+        sections.push((".fallbkp", fallback_image_path.clone().into_bytes()));
+        sections.push((".fallbkh", fallback_image_hash.to_vec()));
+    }
+
+    if !stub_parameters.stub_config.is_empty() {
+        sections.push((
+            ".conf",
+            stub_config_contents(&stub_parameters.stub_config).into_bytes(),
+        ));
+    }
+
+    // systemd-boot shows the release of the kernel in its menu. Compressed kernels do not reveal
+    // it, so the section is left out then.
+    let uname = match &stub_parameters.uname {
+        Some(uname) => Some(uname.clone()),
+        None => {
+            let kernel = &stub_parameters.kernel_store_path;
+            kernel_release(
+                &fs::read(kernel).with_context(|| format!("Failed to read kernel: {kernel:?}"))?,
+            )
+        }
+    };
+    if let Some(uname) = uname {
+        sections.push((".uname", uname.into_bytes()));
+    }
+
+    if let Some(pcr_public_key) = &stub_parameters.pcr_public_key {
+        let pcr_public_key =
+            read_pcr_public_key(pcr_public_key, stub_parameters.pcr_signing_key.as_deref())?;
+        sections.push((".pcrpkey", pcr_public_key));
+    }
+
+    // This must come after all measured sections, so that the signed values of PCR 11 cover them.
+    if let Some(pcr_signing_key) = &stub_parameters.pcr_signing_key {
+        let predictions = predict_kernel_image(&sections, DEFAULT_PHASES);
+        let signatures = pcr_sign(&predictions, pcr_signing_key)
+            .context("Failed to sign the predicted values of PCR 11")?;
+        sections.push((".pcrsig", signatures.into_bytes()));
+    }
+
+    // The hashes are taken from the stub as it was built. Adding sections moves the contents of
+    // its sections in the file at most, but never changes them.
+    let code_checksums = code_checksums_contents(&stub_parameters.lanzaboote_store_path)?;
+    if !code_checksums.is_empty() {
+        sections.push((".selfsum", code_checksums.into_bytes()));
+    }
+
+    // This must come last, so that it covers all other sections.
+    let section_checksums = section_checksums_contents(&sections);
+    sections.push((".sectsum", section_checksums.into_bytes()));
+
+    let (stub_end, section_alignment) = stub_layout(&stub_parameters.lanzaboote_store_path)?;
+    let sections = layout_sections(stub_end, section_alignment, sections);
+
     let image_path = tempdir.path().join(tmpname());
     wrap_in_pe(
         &stub_parameters.lanzaboote_store_path,
         sections,
         &image_path,
     )?;
+
+    if let Some(esp) = &stub_parameters.verify_esp {
+        verify_image(&image_path, esp)?;
+    }
+
     Ok(image_path)
 }
 
+/// Check that the kernel and initrds that an image references on the ESP match the hashes that
+/// are embedded in the image, like the stub checks them at boot.
+///
+/// This catches an image that would not boot with Secure Boot before rebooting into it, e.g.
+/// because a file was corrupted while it was written to the ESP. Embedded initrds are covered by
+/// the `.sectsum` section instead.
+pub fn verify_image(image: &Path, esp: &Path) -> Result<()> {
+    let data = fs::read(image).with_context(|| format!("Failed to read image {image:?}"))?;
+
+    let payload_sections = [(".linux", ".linuxh"), (".initrd", ".initrdh")]
+        .into_iter()
+        .chain(INITRD_CONTINUATION_SECTIONS.iter().copied());
+    for (section, hash_section) in payload_sections {
+        let Some(payload) = read_section_data(&data, section) else {
+            continue;
+        };
+        if !payload.starts_with(b"\\") {
+            continue;
+        }
+        let expected_hash = read_section_data(&data, hash_section).with_context(|| {
+            format!("Image {image:?} has no {hash_section} section for its {section} section")
+        })?;
+        let payload = esp_path(esp, &String::from_utf8_lossy(payload));
+        let actual_hash = file_hash(&payload)?;
+        if actual_hash.as_slice() != expected_hash {
+            bail!(
+                "{payload:?} does not match the hash in the {hash_section} section of image {image:?}"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The contents of the section of an initrd: its path at the ESP, or the compressed initrd if
+/// initrds are embedded.
+fn initrd_section_contents(
+    stub_parameters: &StubParameters,
+    initrd_store_path: &Path,
+    initrd_path_at_esp: &str,
+) -> Result<Vec<u8>> {
+    if let Some(compression) = stub_parameters.embed_initrd {
+        let initrd = fs::read(initrd_store_path)
+            .with_context(|| format!("Failed to read initrd: {initrd_store_path:?}"))?;
+        compression.compress(&initrd)
+    } else {
+        Ok(initrd_path_at_esp.as_bytes().to_vec())
+    }
+}
+
+/// Check that the stub and the kernel are built for the same architecture.
+///
+/// Mixing them up, e.g. when cross-compiling, results in an image that fails cryptically at boot.
+/// Binaries of unknown architecture are not rejected.
+fn check_architecture(stub: &Path, kernel: &Path) -> Result<()> {
+    let stub_architecture = Architecture::from_binary(
+        &fs::read(stub).with_context(|| format!("Failed to read stub: {stub:?}"))?,
+    );
+    let kernel_architecture = Architecture::from_binary(
+        &fs::read(kernel).with_context(|| format!("Failed to read kernel: {kernel:?}"))?,
+    );
+
+    if let (Some(stub_architecture), Some(kernel_architecture)) =
+        (stub_architecture, kernel_architecture)
+    {
+        if stub_architecture != kernel_architecture {
+            bail!("The stub is {stub_architecture}, but the kernel is {kernel_architecture}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Assemble a lanzaboote image and write it to `output` instead of a file.
+///
+/// Combined with [`crate::utils::open_output`], this allows writing the image to stdout.
+pub fn write_lanzaboote_image(
+    tempdir: &TempDir,
+    stub_parameters: &StubParameters,
+    output: &mut dyn Write,
+) -> Result<()> {
+    let image_path = lanzaboote_image(tempdir, stub_parameters)?;
+    let mut image = fs::File::open(&image_path)
+        .with_context(|| format!("Failed to open lanzaboote image: {image_path:?}"))?;
+    io::copy(&mut image, output).context("Failed to write lanzaboote image")?;
+    output.flush().context("Failed to write lanzaboote image")?;
+    Ok(())
+}
+
+/// The canonical order of the sections added to a lanzaboote image.
+///
+/// This is the file layout order only; it is unrelated to the measurement order, which the stub and
+/// `systemd-measure` derive from their own tables. The unified sections come first, followed by
+/// the lanzaboote-specific sections. Sections that are not listed here come last, sorted by name.
+///
+/// Changing this order changes the layout of every image, so that rebuilding an unchanged
+/// generation no longer yields the same image. Only ever append to it.
+const SECTION_ORDER: &[&str] = &[
+    ".linux", ".osrel", ".cmdline", ".initrd", ".linuxh", ".initrdh", ".gendir", ".linuxpk",
+    ".conf", ".cmdovrh", ".osrelh", ".cmdl2", ".cmdl3", ".cmdl4", ".cmdl5", ".cmdl6", ".cmdl7",
+    ".cmdl8", ".cmdl9", ".bootmsg", ".credh", ".cmdfb", ".sectsum", ".recpk", ".initrd2",
+    ".initrh2", ".initrd3", ".initrh3", ".initrd4", ".initrh4", ".initrd5", ".initrh5", ".initrd6",
+    ".initrh6", ".initrd7", ".initrh7", ".initrd8", ".initrh8", ".initrd9", ".initrh9", ".dtb",
+    ".selfsum", ".dtbauto", ".sbat", ".pcrsig", ".pcrpkey", ".uname", ".fallbkp", ".fallbkh",
+];
+
+/// The sections that continue `.cmdline`, in order.
+///
+/// `.cmdline2` and so on would exceed the 8 characters that PE section names are limited to.
+pub const CMDLINE_CONTINUATION_SECTIONS: &[&str] = &[
+    ".cmdl2", ".cmdl3", ".cmdl4", ".cmdl5", ".cmdl6", ".cmdl7", ".cmdl8", ".cmdl9",
+];
+
+/// The sections that hold the initrds appended to `.initrd` and their hashes, in order.
+///
+/// `.initrd2h` and so on would exceed the 8 characters that PE section names are limited to.
+pub const INITRD_CONTINUATION_SECTIONS: &[(&str, &str)] = &[
+    (".initrd2", ".initrh2"),
+    (".initrd3", ".initrh3"),
+    (".initrd4", ".initrh4"),
+    (".initrd5", ".initrh5"),
+    (".initrd6", ".initrh6"),
+    (".initrd7", ".initrh7"),
+    (".initrd8", ".initrh8"),
+    (".initrd9", ".initrh9"),
+];
+
+/// Render the runtime settings of the stub in the format of the `.conf` section.
+fn stub_config_contents(stub_config: &BTreeMap<String, String>) -> String {
+    stub_config
+        .iter()
+        .map(|(key, value)| format!("{key}={value}\n"))
+        .collect()
+}
+
+/// Render the hashes of trusted credentials in the format of the `.credh` section, i.e. the
+/// format of `sha256sum`.
+fn credential_manifest_contents(trusted_credentials: &BTreeMap<String, [u8; 32]>) -> String {
+    trusted_credentials
+        .iter()
+        .map(|(name, hash)| sha256sum_line(hash, name))
+        .collect()
+}
+
+/// Render the hashes of the sections that lzbt adds to the stub in the format of the `.sectsum`
+/// section, i.e. the format of `sha256sum` with section names instead of file names.
+///
+/// The stub checks its sections against these hashes before it uses any of them, which detects
+/// corruption even without Secure Boot. The sections of the stub itself are not covered, because
+/// relocations and global variables change them in memory.
+fn section_checksums_contents(sections: &[(&str, Vec<u8>)]) -> String {
+    sections
+        .iter()
+        .map(|(name, contents)| sha256sum_line(&Sha256::digest(contents), name))
+        .collect()
+}
+
+/// Render the hashes of the code sections of the stub in the format of the `.selfsum` section,
+/// i.e. the format of `.sectsum`.
+///
+/// The stub checks its code sections in its file on the ESP against these hashes at startup, which
+/// detects a stub that was modified in place even without Secure Boot.
+fn code_checksums_contents(stub: &Path) -> Result<String> {
+    let stub_data = fs::read(stub).with_context(|| format!("Failed to read stub: {stub:?}"))?;
+    let pe = PE::parse(&stub_data).context("Failed to parse stub")?;
+
+    pe.sections
+        .iter()
+        .filter(|section| {
+            section.characteristics & (IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE) != 0
+        })
+        .map(|section| {
+            let name = section
+                .name()
+                .context("Stub has a section with an invalid name")?;
+            let data = read_section_data(&stub_data, name)
+                .with_context(|| format!("Failed to read section {name} of the stub"))?;
+            Ok(sha256sum_line(&Sha256::digest(data), name))
+        })
+        .collect()
+}
+
+/// Render a line of `sha256sum` output.
+fn sha256sum_line(hash: &[u8], name: &str) -> String {
+    let hash: String = hash.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("{hash}  {name}\n")
+}
+
+/// Sort sections into their canonical order and lay them out one after another, starting at
+/// `offset`. Every section starts at a multiple of `section_alignment`.
+fn layout_sections(
+    mut offset: u64,
+    section_alignment: u64,
+    mut contents: Vec<(&'static str, Vec<u8>)>,
+) -> Vec<Section> {
+    contents.sort_by_key(|(name, _)| {
+        (
+            SECTION_ORDER
+                .iter()
+                .position(|canonical| canonical == name)
+                .unwrap_or(SECTION_ORDER.len()),
+            *name,
+        )
+    });
+
+    let mut sections = Vec::with_capacity(contents.len());
+    for (name, contents) in contents {
+        offset = align_up(offset, section_alignment);
+        let size = contents.len() as u64;
+        sections.push(s(name, contents, offset));
+        offset += size;
+    }
+
+    sections
+}
+
 /// Take a PE binary stub and attach sections to it.
 ///
 /// The resulting binary is then written to a newly created file at the provided output path.
 fn wrap_in_pe(stub: &Path, sections: Vec<Section>, output: &Path) -> Result<()> {
-    let mut args: Vec<OsString> = sections.iter().flat_map(Section::to_objcopy).collect();
+    // The stub would only find one of several sections with the same name, except for
+    // `.dtbauto`, of which it considers all.
+    let mut names = BTreeSet::new();
+    if let Some(duplicate) = sections
+        .iter()
+        .find(|section| section.name != ".dtbauto" && !names.insert(section.name))
+    {
+        bail!("Section {} would be added more than once", duplicate.name);
+    }
 
-    [stub.as_os_str(), output.as_os_str()]
+    let stub_data = fs::read(stub).with_context(|| format!("Failed to read stub: {stub:?}"))?;
+    let sections: Vec<_> = sections
+        .into_iter()
+        .map(|section| (section.name, section.offset, section.contents))
+        .collect();
+
+    let image = add_sections(&stub_data, &sections)?;
+    fs::write(output, image).with_context(|| format!("Failed to write image: {output:?}"))
+}
+
+/// Offsets into the headers of a PE binary, see the PE format specification.
+const COFF_NUMBER_OF_SECTIONS: usize = 2;
+const COFF_TIME_DATE_STAMP: usize = 4;
+const COFF_POINTER_TO_SYMBOL_TABLE: usize = 8;
+const OPTIONAL_SIZE_OF_INITIALIZED_DATA: usize = 8;
+const OPTIONAL_SIZE_OF_IMAGE: usize = 56;
+const OPTIONAL_SIZE_OF_HEADERS: usize = 60;
+pub(crate) const OPTIONAL_CHECKSUM: usize = 64;
+const SECTION_HEADER_SIZE: usize = 40;
+const SECTION_POINTER_TO_RAW_DATA: usize = 20;
+const DEBUG_DIRECTORY_ENTRY_SIZE: usize = 28;
+const DEBUG_POINTER_TO_RAW_DATA: usize = 24;
+
+/// Offset of the optional header of a PE binary, which follows the PE signature and the COFF
+/// header.
+pub(crate) fn optional_header_offset(pe: &PE) -> usize {
+    pe.header.dos_header.pe_pointer as usize + 4 + 20
+}
+
+/// Offset of the data directories of a PE binary, at the end of the optional header.
+fn data_directories_offset(pe: &PE, optional_header: &OptionalHeader) -> usize {
+    optional_header_offset(pe)
+        + if optional_header.standard_fields.magic == goblin::pe::optional_header::MAGIC_64 {
+            112
+        } else {
+            96
+        }
+}
+
+/// Offset of the entry of the certificate table in the data directories of a PE binary, i.e. the
+/// location and size of its Authenticode signature.
+pub(crate) fn certificate_table_entry_offset(pe: &PE, optional_header: &OptionalHeader) -> usize {
+    // The certificate table is the fifth data directory, each of which is 8 bytes long.
+    data_directories_offset(pe, optional_header) + 4 * 8
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.next_multiple_of(alignment)
+}
+
+/// Append sections to a PE binary, given as name, virtual memory address (including the image
+/// base) and contents.
+///
+/// The sections must be aligned to the section alignment of the binary and follow its sections
+/// in memory, without overlapping them or each other. Their contents are appended to the file,
+/// aligned to the file alignment. If the headers have no room for the additional section headers,
+/// they are grown and the contents of the existing sections are moved back.
+///
+/// The size of the image and of its initialized data, and the checksum are updated.
+pub(crate) fn add_sections(stub: &[u8], sections: &[(&str, u64, Vec<u8>)]) -> Result<Vec<u8>> {
+    let pe = PE::parse(stub).context("Failed to parse stub")?;
+    let optional_header = pe
+        .header
+        .optional_header
+        .context("Stub has no optional header")?;
+    if optional_header
+        .data_directories
+        .get_certificate_table()
+        .is_some_and(|certificate_table| certificate_table.virtual_address != 0)
+    {
+        bail!("Stub is signed, adding sections to it would invalidate its signature");
+    }
+
+    let image_base = optional_header.windows_fields.image_base;
+    let section_alignment = u64::from(optional_header.windows_fields.section_alignment);
+    let file_alignment = u64::from(optional_header.windows_fields.file_alignment);
+    if section_alignment == 0 || file_alignment == 0 {
+        bail!("Stub has no valid section or file alignment");
+    }
+
+    let coff_header_offset = pe.header.dos_header.pe_pointer as usize + 4;
+    let optional_header_offset = optional_header_offset(&pe);
+    let section_table_offset =
+        optional_header_offset + usize::from(pe.header.coff_header.size_of_optional_header);
+
+    // Check the new sections before anything is written.
+    let mut memory_end = 0;
+    let mut previous = "the headers";
+    for stub_section in &pe.sections {
+        let end = u64::from(stub_section.virtual_address) + u64::from(stub_section.virtual_size);
+        if end > memory_end {
+            memory_end = end;
+            previous = stub_section.name().unwrap_or("<invalid>");
+        }
+    }
+    for (name, vma, data) in sections {
+        if name.len() > 8 {
+            bail!("Section name {name} is longer than 8 characters");
+        }
+        let address = vma
+            .checked_sub(image_base)
+            .with_context(|| format!("Section {name} at {vma:#x} is below the image base"))?;
+        if address % section_alignment != 0 {
+            bail!("Section {name} at {vma:#x} is not aligned to {section_alignment:#x}");
+        }
+        if address < align_up(memory_end, section_alignment) {
+            bail!("Section {name} at {vma:#x} overlaps {previous}");
+        }
+        memory_end = address + data.len() as u64;
+        previous = name;
+    }
+    let size_of_image = u32::try_from(align_up(memory_end, section_alignment))
+        .context("Image would be larger than 4 GiB")?;
+
+    // Grow the headers if the new section headers do not fit, and move the contents of all
+    // sections back by as much.
+    let size_of_headers = read_u32(stub, optional_header_offset + OPTIONAL_SIZE_OF_HEADERS) as u64;
+    let headers_end =
+        (section_table_offset + (pe.sections.len() + sections.len()) * SECTION_HEADER_SIZE) as u64;
+    let new_size_of_headers = align_up(headers_end, file_alignment).max(size_of_headers);
+    let first_section_address = pe
+        .sections
+        .iter()
+        .map(|section| u64::from(section.virtual_address))
+        .min()
+        .unwrap_or(u64::MAX);
+    if new_size_of_headers > first_section_address {
+        bail!(
+            "Stub has no room for {} more section headers",
+            sections.len()
+        );
+    }
+    let contents_start = pe
+        .sections
         .iter()
-        .for_each(|a| args.push(a.into()));
+        .map(|section| section.pointer_to_raw_data as usize)
+        .filter(|&pointer| pointer != 0)
+        .min()
+        .unwrap_or(stub.len());
+    let shift = align_up(
+        new_size_of_headers.saturating_sub(contents_start as u64),
+        file_alignment,
+    ) as usize;
 
-    let status = Command::new("objcopy")
-        .args(&args)
-        .status()
-        .context("Failed to run objcopy. Most likely, the binary is not on PATH.")?;
-    if !status.success() {
-        return Err(anyhow::anyhow!(
-            "Failed to wrap in pe with args `{:?}`",
-            &args
-        ));
+    let mut image = stub[..contents_start].to_vec();
+    image.resize(contents_start + shift, 0);
+    image.extend_from_slice(&stub[contents_start..]);
+
+    if shift > 0 {
+        let shift_pointer = |image: &mut Vec<u8>, offset: usize| {
+            let pointer = read_u32(image, offset);
+            if pointer != 0 {
+                write_u32(image, offset, pointer + shift as u32);
+            }
+        };
+
+        for index in 0..pe.sections.len() {
+            shift_pointer(
+                &mut image,
+                section_table_offset + index * SECTION_HEADER_SIZE + SECTION_POINTER_TO_RAW_DATA,
+            );
+        }
+        shift_pointer(
+            &mut image,
+            coff_header_offset + COFF_POINTER_TO_SYMBOL_TABLE,
+        );
+
+        // The debug directory refers to its data by file offset as well.
+        if let Some(debug_table) = optional_header.data_directories.get_debug_table() {
+            let debug_table_offset = pe.sections.iter().find_map(|section| {
+                debug_table
+                    .virtual_address
+                    .checked_sub(section.virtual_address)
+                    .filter(|&offset| offset < section.size_of_raw_data)
+                    .map(|offset| (section.pointer_to_raw_data + offset) as usize + shift)
+            });
+            if let Some(debug_table_offset) = debug_table_offset {
+                for index in 0..debug_table.size as usize / DEBUG_DIRECTORY_ENTRY_SIZE {
+                    shift_pointer(
+                        &mut image,
+                        debug_table_offset
+                            + index * DEBUG_DIRECTORY_ENTRY_SIZE
+                            + DEBUG_POINTER_TO_RAW_DATA,
+                    );
+                }
+            }
+        }
     }
 
-    Ok(())
+    let mut size_of_initialized_data = read_u32(
+        &image,
+        optional_header_offset + OPTIONAL_SIZE_OF_INITIALIZED_DATA,
+    );
+    for (index, (name, vma, data)) in sections.iter().enumerate() {
+        image.resize(align_up(image.len() as u64, file_alignment) as usize, 0);
+        let size_of_raw_data = align_up(data.len() as u64, file_alignment) as usize;
+        let pointer_to_raw_data = if data.is_empty() { 0 } else { image.len() };
+        image.extend_from_slice(data);
+        image.resize(image.len() + size_of_raw_data - data.len(), 0);
+
+        let mut header = [0u8; SECTION_HEADER_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        write_u32(&mut header, 8, data.len() as u32);
+        write_u32(&mut header, 12, (vma - image_base) as u32);
+        write_u32(&mut header, 16, size_of_raw_data as u32);
+        write_u32(&mut header, 20, pointer_to_raw_data as u32);
+        write_u32(
+            &mut header,
+            36,
+            IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+        );
+        let header_offset =
+            section_table_offset + (pe.sections.len() + index) * SECTION_HEADER_SIZE;
+        image[header_offset..header_offset + SECTION_HEADER_SIZE].copy_from_slice(&header);
+
+        size_of_initialized_data += size_of_raw_data as u32;
+    }
+
+    let number_of_sections = u16::try_from(pe.sections.len() + sections.len())
+        .context("Image would have too many sections")?;
+    image[coff_header_offset + COFF_NUMBER_OF_SECTIONS
+        ..coff_header_offset + COFF_NUMBER_OF_SECTIONS + 2]
+        .copy_from_slice(&number_of_sections.to_le_bytes());
+    // The link time of the stub says nothing about the image, and would make images of the same
+    // generation differ between builds of the stub from the same source.
+    write_u32(&mut image, coff_header_offset + COFF_TIME_DATE_STAMP, 0);
+    write_u32(
+        &mut image,
+        optional_header_offset + OPTIONAL_SIZE_OF_INITIALIZED_DATA,
+        size_of_initialized_data,
+    );
+    write_u32(
+        &mut image,
+        optional_header_offset + OPTIONAL_SIZE_OF_IMAGE,
+        size_of_image,
+    );
+    write_u32(
+        &mut image,
+        optional_header_offset + OPTIONAL_SIZE_OF_HEADERS,
+        new_size_of_headers as u32,
+    );
+    write_u32(&mut image, optional_header_offset + OPTIONAL_CHECKSUM, 0);
+    let checksum = pe_checksum(&image);
+    write_u32(
+        &mut image,
+        optional_header_offset + OPTIONAL_CHECKSUM,
+        checksum,
+    );
+
+    Ok(image)
+}
+
+/// Compute the checksum of a PE binary, whose checksum field must be zero.
+///
+/// This is the 16-bit ones' complement sum of the file, plus its length.
+pub(crate) fn pe_checksum(image: &[u8]) -> u32 {
+    let sum = image.chunks(2).fold(0u32, |sum, word| {
+        let sum = sum + u32::from(word[0]) + (u32::from(*word.get(1).unwrap_or(&0)) << 8);
+        (sum & 0xffff) + (sum >> 16)
+    });
+    sum.wrapping_add(image.len() as u32)
 }
 
+/// A section to add to the stub, with its contents in memory.
 struct Section {
     name: &'static str,
-    file_path: PathBuf,
+    contents: Vec<u8>,
     offset: u64,
 }
 
-impl Section {
-    /// Create objcopy `-add-section` command line parameters that
-    /// attach the section to a PE file.
-    fn to_objcopy(&self) -> Vec<OsString> {
-        // There is unfortunately no format! for OsString, so we cannot
-        // just format a path.
-        let mut map_str: OsString = format!("{}=", self.name).into();
-        map_str.push(&self.file_path);
-
-        vec![
-            OsString::from("--add-section"),
-            map_str,
-            OsString::from("--change-section-vma"),
-            format!("{}={:#x}", self.name, self.offset).into(),
-        ]
-    }
-}
-
-fn s(name: &'static str, file_path: impl AsRef<Path>, offset: u64) -> Section {
+fn s(name: &'static str, contents: impl Into<Vec<u8>>, offset: u64) -> Section {
     Section {
         name,
-        file_path: file_path.as_ref().into(),
+        contents: contents.into(),
         offset,
     }
 }
 
 /// Convert a path to an UEFI path relative to the specified ESP.
-fn esp_relative_uefi_path(esp: &Path, path: &Path) -> Result<String> {
+///
+/// E.g. `/boot/EFI/Linux/nixos.efi` on the ESP `/boot` becomes `\EFI\Linux\nixos.efi`.
+///
+/// The path has to be inside of the ESP. Because the check is purely lexical, paths that contain
+/// `..` are rejected, as they could point outside of it.
+pub fn esp_relative_path(esp: &Path, path: &Path) -> Result<String> {
     let relative_path = path
         .strip_prefix(esp)
         .with_context(|| format!("Failed to strip esp prefix: {:?} from: {:?}", esp, path))?;
+    if relative_path
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        bail!("{path:?} is not a plain path inside of the ESP {esp:?}");
+    }
     let uefi_path = uefi_path(relative_path)?;
     Ok(format!("\\{}", &uefi_path))
 }
 
+/// Convert an ESP-relative path as embedded in an image, e.g. `\EFI\nixos\kernel.efi`, to a path
+/// below the ESP mountpoint.
+pub fn esp_path(esp: &Path, esp_relative_path: &str) -> PathBuf {
+    esp_relative_path
+        .split('\\')
+        .filter(|component| !component.is_empty())
+        .fold(esp.to_path_buf(), |path, component| path.join(component))
+}
+
 /// Convert a path to a UEFI string representation.
 ///
 /// This might not _necessarily_ produce a valid UEFI path, since some UEFI implementations might
@@ -207,20 +1112,26 @@ fn uefi_path(path: &Path) -> Result<String> {
         .with_context(|| format!("Failed to convert {:?} to an UEFI path", path))
 }
 
-fn stub_offset(binary: &Path) -> Result<u64> {
+/// Find where the sections of a stub end in memory and their alignment.
+fn stub_layout(binary: &Path) -> Result<(u64, u64)> {
     let pe_binary = fs::read(binary).context("Failed to read PE binary file")?;
     let pe = PE::parse(&pe_binary).context("Failed to parse PE binary file")?;
 
     let image_base = image_base(&pe);
+    let section_alignment = pe
+        .header
+        .optional_header
+        .map(|optional_header| u64::from(optional_header.windows_fields.section_alignment))
+        .context("Failed to find optional header")?;
 
     // The Virtual Memory Address (VMA) is relative to the image base, aka the image base
     // needs to be added to the virtual address to get the actual (but still virtual address)
-    Ok(u64::from(
-        pe.sections
-            .last()
-            .map(|s| s.virtual_size + s.virtual_address)
-            .expect("Failed to calculate offset"),
-    ) + image_base)
+    let end = pe
+        .sections
+        .last()
+        .map(|s| s.virtual_size + s.virtual_address)
+        .expect("Failed to calculate offset");
+    Ok((u64::from(end) + image_base, section_alignment))
 }
 
 fn image_base(pe: &PE) -> u64 {
@@ -231,48 +1142,669 @@ fn image_base(pe: &PE) -> u64 {
         .image_base
 }
 
-fn file_size(path: impl AsRef<Path>) -> Result<u64> {
-    Ok(fs::metadata(&path)
-        .with_context(|| {
-            format!(
-                "Failed to read file metadata to calculate its size: {:?}",
-                path.as_ref()
-            )
-        })?
-        .size())
+/// Whether a binary is an image that lzbt built, as told by the sections that only lzbt adds: the
+/// hashes of the kernel and the initrd.
+pub fn is_lanzaboote_image(file_data: &[u8]) -> bool {
+    read_section_data(file_data, ".linuxh").is_some()
+        && read_section_data(file_data, ".initrdh").is_some()
 }
 
 /// Read the data from a section of a PE binary.
 ///
 /// The binary is supplied as a `u8` slice.
 pub fn read_section_data<'a>(file_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
-    let pe_binary = goblin::pe::PE::parse(file_data).ok()?;
+    read_sections_data(file_data, section_name)
+        .into_iter()
+        .next()
+}
+
+/// Read the data of all sections of a PE binary with this name, in the order of the section table.
+///
+/// Some sections, e.g. `.dtbauto`, can occur more than once.
+pub fn read_sections_data<'a>(file_data: &'a [u8], section_name: &str) -> Vec<&'a [u8]> {
+    let Ok(pe_binary) = goblin::pe::PE::parse(file_data) else {
+        return Vec::new();
+    };
 
     pe_binary
         .sections
         .iter()
-        .find(|s| s.name().unwrap() == section_name)
-        .and_then(|s| {
+        .filter(|s| s.name().is_ok_and(|name| name == section_name))
+        .filter_map(|s| {
             let section_start: usize = s.pointer_to_raw_data.try_into().ok()?;
-            assert!(s.virtual_size <= s.size_of_raw_data);
-            let section_end: usize = section_start + usize::try_from(s.virtual_size).ok()?;
-            Some(&file_data[section_start..section_end])
+            // Sections can be larger in memory than in the file, the rest is zero-filled.
+            let section_size = usize::try_from(s.virtual_size.min(s.size_of_raw_data)).ok()?;
+            file_data.get(section_start..section_start.checked_add(section_size)?)
+        })
+        .collect()
+}
+
+/// Read the kernel command line of an image, i.e. the `.cmdline` section followed by its
+/// continuation sections, separated by spaces, as the stub composes it.
+///
+/// Returns `None` if the image has no `.cmdline` section.
+pub fn read_cmdline(file_data: &[u8]) -> Option<String> {
+    let mut cmdline =
+        String::from_utf8_lossy(read_section_data(file_data, ".cmdline")?).into_owned();
+
+    for section_name in CMDLINE_CONTINUATION_SECTIONS {
+        if let Some(continuation) = read_section_data(file_data, section_name) {
+            cmdline.push(' ');
+            cmdline.push_str(&String::from_utf8_lossy(continuation));
+        }
+    }
+
+    Some(cmdline)
+}
+
+/// Remove the Authenticode signature of an image, so that it can be compared to an unsigned build
+/// of it.
+///
+/// Signing sets the checksum and the certificate table entry in the optional header and appends
+/// the certificate table. The former are zeroed and the latter is cut off. Trailing zeros are
+/// removed as well, because the padding in front of the certificate table cannot be told apart
+/// from zeros at the end of the last section.
+pub fn strip_signature(image: &[u8]) -> Result<Vec<u8>> {
+    let pe = PE::parse(image).context("Failed to parse image")?;
+    let optional_header = pe
+        .header
+        .optional_header
+        .context("Image has no optional header")?;
+
+    let checksum_offset = optional_header_offset(&pe) + OPTIONAL_CHECKSUM;
+    let certificate_table_entry_offset = certificate_table_entry_offset(&pe, &optional_header);
+
+    let mut stripped = image.to_vec();
+    if let Some(certificate_table) = optional_header
+        .data_directories
+        .get_certificate_table()
+        .as_ref()
+        .filter(|certificate_table| certificate_table.virtual_address != 0)
+    {
+        stripped.truncate(certificate_table.virtual_address as usize);
+        stripped
+            .get_mut(certificate_table_entry_offset..certificate_table_entry_offset + 8)
+            .context("Image is truncated")?
+            .fill(0);
+    }
+    stripped
+        .get_mut(checksum_offset..checksum_offset + 4)
+        .context("Image is truncated")?
+        .fill(0);
+
+    let end = stripped
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |i| i + 1);
+    stripped.truncate(end);
+    Ok(stripped)
+}
+
+/// Find the name of the section that contains an offset into a PE binary, if any.
+pub fn section_at_offset(file_data: &[u8], offset: usize) -> Option<String> {
+    let pe_binary = goblin::pe::PE::parse(file_data).ok()?;
+
+    pe_binary
+        .sections
+        .iter()
+        .find(|s| {
+            let start = s.pointer_to_raw_data as usize;
+            (start..start + s.size_of_raw_data as usize).contains(&offset)
         })
+        .and_then(|s| s.name().ok().map(str::to_string))
+}
+
+/// Build a minimal PE32+ binary with a single `.text` section at 0x1000.
+#[cfg(test)]
+pub(crate) fn minimal_pe() -> Vec<u8> {
+    let mut pe = vec![0u8; 0x400];
+    pe[..2].copy_from_slice(b"MZ");
+    write_u32(&mut pe, 0x3c, 0x40);
+    pe[0x40..0x44].copy_from_slice(b"PE\0\0");
+    // COFF header: x86_64, one section, optional header of 240 bytes, executable.
+    pe[0x44..0x46].copy_from_slice(&0x8664u16.to_le_bytes());
+    pe[0x46..0x48].copy_from_slice(&1u16.to_le_bytes());
+    pe[0x54..0x56].copy_from_slice(&240u16.to_le_bytes());
+    pe[0x56..0x58].copy_from_slice(&0x22u16.to_le_bytes());
+    // Optional header.
+    let optional_header = 0x58;
+    pe[optional_header..optional_header + 2].copy_from_slice(&0x20bu16.to_le_bytes());
+    pe[optional_header + 24..optional_header + 32].copy_from_slice(&0x1_4000_0000u64.to_le_bytes());
+    write_u32(&mut pe, optional_header + 32, 0x1000);
+    write_u32(&mut pe, optional_header + 36, 0x200);
+    write_u32(&mut pe, optional_header + OPTIONAL_SIZE_OF_IMAGE, 0x2000);
+    write_u32(&mut pe, optional_header + OPTIONAL_SIZE_OF_HEADERS, 0x200);
+    pe[optional_header + 68..optional_header + 70].copy_from_slice(&10u16.to_le_bytes());
+    write_u32(&mut pe, optional_header + 108, 16);
+    // Section table.
+    let text = optional_header + 240;
+    pe[text..text + 5].copy_from_slice(b".text");
+    write_u32(&mut pe, text + 8, 0x10);
+    write_u32(&mut pe, text + 12, 0x1000);
+    write_u32(&mut pe, text + 16, 0x200);
+    write_u32(&mut pe, text + 20, 0x200);
+    write_u32(&mut pe, text + 36, 0x6000_0020);
+    pe[0x200..0x210].copy_from_slice(b"lanzaboote stub!");
+    pe
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::SecureTempDirExt;
 
     #[test]
     fn convert_to_valid_uefi_path_relative_to_esp() {
         let esp = Path::new("esp");
         let path = Path::new("esp/lanzaboote/is/great.txt");
-        let converted_path = esp_relative_uefi_path(esp, path).unwrap();
+        let converted_path = esp_relative_path(esp, path).unwrap();
         let expected_path = String::from("\\lanzaboote\\is\\great.txt");
         assert_eq!(converted_path, expected_path);
     }
 
+    #[test]
+    fn reject_paths_outside_of_esp() {
+        let esp = Path::new("esp");
+        assert!(esp_relative_path(esp, Path::new("other/great.txt")).is_err());
+        assert!(esp_relative_path(esp, Path::new("esp/../great.txt")).is_err());
+        assert!(esp_relative_path(esp, Path::new("esp/lanzaboote/../../great.txt")).is_err());
+    }
+
+    #[test]
+    fn convert_esp_relative_path() {
+        assert_eq!(
+            esp_path(Path::new("/boot"), "\\EFI\\nixos\\kernel.efi"),
+            Path::new("/boot/EFI/nixos/kernel.efi")
+        );
+    }
+
+    #[test]
+    fn reject_duplicate_section_names() {
+        let sections = vec![
+            s(".osrel", "os-release", 0x20000),
+            s(".cmdline", "cmdline", 0x30000),
+            s(".osrel", "other-os-release", 0x40000),
+        ];
+        let err = wrap_in_pe(Path::new("stub"), sections, Path::new("image")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Section .osrel would be added more than once"
+        );
+    }
+
+    #[test]
+    fn add_sections_to_pe() -> Result<()> {
+        let image = add_sections(
+            &minimal_pe(),
+            &[
+                (".osrel", 0x1_4000_2000, b"ID=nixos".to_vec()),
+                (".cmdline", 0x1_4000_3000, b"quiet".to_vec()),
+            ],
+        )?;
+
+        assert_eq!(
+            read_section_data(&image, ".text"),
+            Some(&b"lanzaboote stub!"[..])
+        );
+        assert_eq!(read_section_data(&image, ".osrel"), Some(&b"ID=nixos"[..]));
+        assert_eq!(read_section_data(&image, ".cmdline"), Some(&b"quiet"[..]));
+
+        let pe = PE::parse(&image)?;
+        let windows_fields = pe.header.optional_header.unwrap().windows_fields;
+        assert_eq!(windows_fields.size_of_image, 0x4000);
+        assert_eq!(image.len() % 0x200, 0);
+
+        let mut unchecksummed = image.clone();
+        write_u32(&mut unchecksummed, 0x58 + OPTIONAL_CHECKSUM, 0);
+        assert_eq!(windows_fields.check_sum, pe_checksum(&unchecksummed));
+        Ok(())
+    }
+
+    #[test]
+    fn read_composed_cmdline() -> Result<()> {
+        let image = add_sections(
+            &minimal_pe(),
+            &[
+                (".cmdline", 0x1_4000_2000, b"init=/nix/store/init".to_vec()),
+                (".cmdl3", 0x1_4000_3000, b"quiet".to_vec()),
+                (".cmdl2", 0x1_4000_4000, b"console=ttyS0".to_vec()),
+            ],
+        )?;
+        assert_eq!(
+            read_cmdline(&image).as_deref(),
+            Some("init=/nix/store/init console=ttyS0 quiet")
+        );
+        assert_eq!(read_cmdline(&minimal_pe()), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn grow_headers_for_many_sections() -> Result<()> {
+        let sections: Vec<(&str, u64, Vec<u8>)> = CMDLINE_CONTINUATION_SECTIONS
+            .iter()
+            .chain(&[".linux", ".initrd", ".osrel", ".cmdline"])
+            .enumerate()
+            .map(|(index, name)| {
+                (
+                    *name,
+                    0x1_4000_2000 + index as u64 * 0x1000,
+                    name.as_bytes().to_vec(),
+                )
+            })
+            .collect();
+        let image = add_sections(&minimal_pe(), &sections)?;
+
+        let pe = PE::parse(&image)?;
+        assert_eq!(
+            pe.header
+                .optional_header
+                .unwrap()
+                .windows_fields
+                .size_of_headers,
+            0x400
+        );
+        assert_eq!(
+            read_section_data(&image, ".text"),
+            Some(&b"lanzaboote stub!"[..])
+        );
+        for (name, _, data) in &sections {
+            assert_eq!(read_section_data(&image, name), Some(&data[..]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reject_misplaced_sections() {
+        let stub = minimal_pe();
+        let overlapping = add_sections(&stub, &[(".osrel", 0x1_4000_1000, b"ID=nixos".to_vec())]);
+        assert_eq!(
+            overlapping.unwrap_err().to_string(),
+            "Section .osrel at 0x140001000 overlaps .text"
+        );
+        let unaligned = add_sections(&stub, &[(".osrel", 0x1_4000_2010, b"ID=nixos".to_vec())]);
+        assert_eq!(
+            unaligned.unwrap_err().to_string(),
+            "Section .osrel at 0x140002010 is not aligned to 0x1000"
+        );
+    }
+
+    #[test]
+    fn install_store_paths_to_esp() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let esp = tempdir.path().join("esp");
+        let kernel = tempdir.write_secure_file("kernel")?;
+        let initrd = tempdir.write_secure_file("initrd")?;
+
+        let parameters = StubParameters::from_store_paths(
+            Path::new("stub"),
+            &kernel,
+            &initrd,
+            &esp,
+            &esp.join("EFI/nixos"),
+        )?;
+
+        assert!(parameters
+            .kernel_path_at_esp
+            .starts_with("\\EFI\\nixos\\kernel-"));
+        assert!(parameters
+            .initrd_path_at_esp
+            .starts_with("\\EFI\\nixos\\initrd-"));
+        assert_eq!(fs::read_dir(esp.join("EFI/nixos"))?.count(), 2);
+        Ok(())
+    }
+
+    /// Parameters of an image of [`minimal_pe`] with the kernel `kernel` and the initrd `initrd`,
+    /// which are installed to an ESP in `tempdir`.
+    fn test_parameters(tempdir: &TempDir) -> Result<StubParameters> {
+        let esp = tempdir.path().join("esp");
+        StubParameters::new(
+            &tempdir.write_secure_file(minimal_pe())?,
+            &tempdir.write_secure_file("kernel")?,
+            &tempdir.write_secure_file("initrd")?,
+            &esp.join("EFI/nixos/kernel.efi"),
+            &esp.join("EFI/nixos/initrd.efi"),
+            &esp,
+        )
+    }
+
+    /// Build an image in `tempdir` and read it.
+    fn build(tempdir: &TempDir, parameters: &StubParameters) -> Result<Vec<u8>> {
+        Ok(fs::read(lanzaboote_image(tempdir, parameters)?)?)
+    }
+
+    #[test]
+    fn embed_appended_initrds() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let esp = tempdir.path().join("esp");
+        let microcode = tempdir.write_secure_file("microcode")?;
+
+        let parameters = test_parameters(&tempdir)?.with_appended_initrd(
+            &microcode,
+            &esp.join("EFI/nixos/microcode.efi"),
+            &esp,
+        )?;
+        let image = build(&tempdir, &parameters)?;
+
+        assert_eq!(
+            read_section_data(&image, ".initrd"),
+            Some(&b"\\EFI\\nixos\\initrd.efi"[..])
+        );
+        assert_eq!(
+            read_section_data(&image, ".initrd2"),
+            Some(&b"\\EFI\\nixos\\microcode.efi"[..])
+        );
+        assert_eq!(
+            read_section_data(&image, ".initrh2"),
+            Some(Sha256::digest("microcode").as_slice())
+        );
+        assert_eq!(read_section_data(&image, ".initrd3"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn embed_auto_devicetrees() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let parameters = test_parameters(&tempdir)?
+            .with_auto_devicetree(b"board a")
+            .with_auto_devicetree(b"board b");
+        let image = build(&tempdir, &parameters)?;
+
+        assert_eq!(
+            read_sections_data(&image, ".dtbauto"),
+            [&b"board a"[..], &b"board b"[..]]
+        );
+        // Both are covered by `.sectsum`, in order.
+        let checksums = String::from_utf8(read_section_data(&image, ".sectsum").unwrap().to_vec())?;
+        let expected = sha256sum_line(&Sha256::digest("board a"), ".dtbauto")
+            + &sha256sum_line(&Sha256::digest("board b"), ".dtbauto");
+        assert!(checksums.contains(&expected));
+        Ok(())
+    }
+
+    #[test]
+    fn embed_code_checksums() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let image = build(&tempdir, &test_parameters(&tempdir)?)?;
+
+        let expected = sha256sum_line(&Sha256::digest("lanzaboote stub!"), ".text");
+        assert_eq!(
+            read_section_data(&image, ".selfsum"),
+            Some(expected.as_bytes())
+        );
+        // The code sections are unchanged in the image, so that the stub can check them.
+        assert_eq!(
+            read_section_data(&image, ".text"),
+            Some(&b"lanzaboote stub!"[..])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn embed_devicetree() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let devicetree = [0xd0, 0x0d, 0xfe, 0xed, 0, 0, 0, 8];
+        let parameters = test_parameters(&tempdir)?.with_devicetree(&devicetree);
+        let image = build(&tempdir, &parameters)?;
+
+        assert_eq!(read_section_data(&image, ".dtb"), Some(&devicetree[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn embed_sbat() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let sbat = "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n";
+        let parameters = test_parameters(&tempdir)?.with_sbat(sbat);
+        let image_path = lanzaboote_image(&tempdir, &parameters)?;
+        let image = fs::read(&image_path)?;
+        assert_eq!(read_section_data(&image, ".sbat"), Some(sbat.as_bytes()));
+
+        // The SBAT data of an image cannot be extended, it would need to be merged instead.
+        let parameters = StubParameters {
+            lanzaboote_store_path: image_path,
+            ..parameters
+        };
+        assert!(lanzaboote_image(&tempdir, &parameters).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn embed_pcr_signature() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let key = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../systemd/tests/fixtures/uefi-keys/db.key");
+        let parameters = test_parameters(&tempdir)?
+            .with_cmdline(&[String::from("quiet")])
+            .with_pcr_signing_key(&key);
+        let image = build(&tempdir, &parameters)?;
+
+        // The signatures are made over the predictions for the image as it was built.
+        let predictions = crate::predict::predict_pcrs(&image, DEFAULT_PHASES)?;
+        assert_eq!(
+            read_section_data(&image, ".pcrsig"),
+            Some(pcr_sign(&predictions.sha256, &key)?.as_bytes())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn embed_pcr_public_key() -> Result<()> {
+        use rsa::pkcs8::{DecodePublicKey, EncodePublicKey, LineEnding};
+        use rsa::RsaPublicKey;
+
+        let tempdir = tempfile::tempdir()?;
+        let signing_key = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../systemd/tests/fixtures/uefi-keys/db.key");
+        let public_key = RsaPublicKey::from(&crate::sign::read_private_key(&signing_key)?);
+        let public_key_pem = public_key.to_public_key_pem(LineEnding::LF)?;
+        let public_key_path = tempdir.write_secure_file(&public_key_pem)?;
+
+        let parameters = test_parameters(&tempdir)?
+            .with_pcr_public_key(&public_key_path)
+            .with_pcr_signing_key(&signing_key);
+        let image = build(&tempdir, &parameters)?;
+
+        let embedded = read_section_data(&image, ".pcrpkey").context("No .pcrpkey section")?;
+        assert_eq!(embedded, public_key_pem.as_bytes());
+        assert_eq!(
+            RsaPublicKey::from_public_key_pem(std::str::from_utf8(embedded)?)?,
+            public_key
+        );
+        // The public key is measured, so the signatures must cover it.
+        let predictions = crate::predict::predict_pcrs(&image, DEFAULT_PHASES)?;
+        assert_eq!(
+            read_section_data(&image, ".pcrsig"),
+            Some(pcr_sign(&predictions.sha256, &signing_key)?.as_bytes())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn embed_zstd_compressed_initrd() -> Result<()> {
+        use ruzstd::io::Read;
+
+        let tempdir = tempfile::tempdir()?;
+        let initrd_contents = b"initrd ".repeat(1000);
+        let parameters = StubParameters {
+            initrd_store_path: tempdir.write_secure_file(&initrd_contents)?,
+            ..test_parameters(&tempdir)?
+        }
+        .with_embedded_initrd(Compression::Zstd);
+        let image = build(&tempdir, &parameters)?;
+
+        let section = read_section_data(&image, ".initrd").context("No .initrd section")?;
+        let mut decompressed = Vec::new();
+        ruzstd::StreamingDecoder::new(section)?.read_to_end(&mut decompressed)?;
+        assert_eq!(decompressed, initrd_contents);
+        // Like the stub, the hash covers the decompressed initrd.
+        assert_eq!(
+            read_section_data(&image, ".initrdh"),
+            Some(&Sha256::digest(&initrd_contents)[..])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn build_images_reproducibly() -> Result<()> {
+        let build_in_new_tempdir = || -> Result<Vec<u8>> {
+            // Everything lives in a different temporary directory for every build.
+            let tempdir = tempfile::tempdir()?;
+            let mut stub = minimal_pe();
+            write_u32(&mut stub, 0x44 + COFF_TIME_DATE_STAMP, 0x6543_2100);
+            let parameters = StubParameters {
+                lanzaboote_store_path: tempdir.write_secure_file(stub)?,
+                ..test_parameters(&tempdir)?
+            }
+            .with_cmdline(&[String::from("quiet"), String::from("splash")])
+            .with_os_release_contents(b"ID=nixos\n")
+            .with_trusted_credential("secret", b"contents")
+            .with_stub_config("verify-only", "true")
+            .with_embedded_initrd(Compression::Zstd);
+            build(&tempdir, &parameters)
+        };
+
+        let image = build_in_new_tempdir()?;
+        assert_eq!(image, build_in_new_tempdir()?);
+        assert_eq!(read_u32(&image, 0x44 + COFF_TIME_DATE_STAMP), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn embed_uname() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let parameters = StubParameters {
+            kernel_store_path: tempdir
+                .write_secure_file("...Linux version 6.6.1 (nixbld@localhost) #1-NixOS")?,
+            ..test_parameters(&tempdir)?
+        };
+        let image = build(&tempdir, &parameters)?;
+        let uname = read_section_data(&image, ".uname").context("No .uname section")?;
+        assert_eq!(std::str::from_utf8(uname)?, "6.6.1");
+
+        let parameters = parameters.with_uname("6.6.1-custom");
+        let image = build(&tempdir, &parameters)?;
+        assert_eq!(
+            read_section_data(&image, ".uname"),
+            Some(&b"6.6.1-custom"[..])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn embed_fallback_image() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let esp = tempdir.path().join("esp");
+        let fallback_image = esp.join("EFI/Linux/nixos-generation-1.efi");
+        fs::create_dir_all(fallback_image.parent().unwrap())?;
+        fs::write(&fallback_image, "known-good image")?;
+
+        let parameters = test_parameters(&tempdir)?.with_fallback_image(&esp, &fallback_image)?;
+        let image = build(&tempdir, &parameters)?;
+        assert_eq!(
+            read_section_data(&image, ".fallbkp"),
+            Some(&b"\\EFI\\Linux\\nixos-generation-1.efi"[..])
+        );
+        assert_eq!(
+            read_section_data(&image, ".fallbkh"),
+            Some(&Sha256::digest("known-good image")[..])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn embed_payload_hashes() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let image = build(&tempdir, &test_parameters(&tempdir)?)?;
+
+        for (hash_section, payload) in [(".linuxh", "kernel"), (".initrdh", "initrd")] {
+            let hash = read_section_data(&image, hash_section).unwrap();
+            assert_eq!(hash.len(), 32);
+            assert_eq!(hash, Sha256::digest(payload).as_slice());
+        }
+        assert!(is_lanzaboote_image(&image));
+        assert!(!is_lanzaboote_image(&minimal_pe()));
+        Ok(())
+    }
+
+    #[test]
+    fn verify_referenced_files() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let esp = tempdir.path().join("esp");
+        let stub = tempdir.write_secure_file(minimal_pe())?;
+        let kernel = tempdir.write_secure_file("kernel")?;
+        let initrd = tempdir.write_secure_file("initrd")?;
+
+        let parameters = StubParameters::from_store_paths(
+            &stub,
+            &kernel,
+            &initrd,
+            &esp,
+            &esp.join("EFI/nixos"),
+        )?
+        .with_verification(&esp);
+        let image = lanzaboote_image(&tempdir, &parameters)?;
+
+        let installed_kernel = esp_path(&esp, &parameters.kernel_path_at_esp);
+        fs::write(installed_kernel, "corrupt kernel")?;
+        let err = verify_image(&image, &esp).unwrap_err();
+        assert!(err.to_string().contains("the .linuxh section"));
+        Ok(())
+    }
+
+    #[test]
+    fn lay_out_sections_in_canonical_order() {
+        let contents = [
+            ".linuxh", ".zzz", ".cmdline", ".linux", ".aaa", ".osrel", ".initrd",
+        ]
+        .into_iter()
+        .map(|name| (name, name.as_bytes().to_vec()))
+        .collect();
+
+        let sections = layout_sections(0x1000, 0x10, contents);
+
+        let names: Vec<&str> = sections.iter().map(|s| s.name).collect();
+        assert_eq!(
+            names,
+            [".linux", ".osrel", ".cmdline", ".initrd", ".linuxh", ".aaa", ".zzz"]
+        );
+        let offsets: Vec<u64> = sections.iter().map(|s| s.offset).collect();
+        assert_eq!(
+            offsets,
+            [0x1000, 0x1010, 0x1020, 0x1030, 0x1040, 0x1050, 0x1060]
+        );
+    }
+
+    #[test]
+    fn render_stub_config() {
+        let stub_config = BTreeMap::from([
+            ("verify-only".to_string(), "yes".to_string()),
+            ("a-setting".to_string(), "1".to_string()),
+        ]);
+        assert_eq!(
+            stub_config_contents(&stub_config),
+            "a-setting=1\nverify-only=yes\n"
+        );
+    }
+
+    #[test]
+    fn render_credential_manifest() {
+        let trusted_credentials = BTreeMap::from([("secret".to_string(), [0xab; 32])]);
+        assert_eq!(
+            credential_manifest_contents(&trusted_credentials),
+            format!("{}  secret\n", "ab".repeat(32))
+        );
+    }
+
+    #[test]
+    fn render_section_checksums() {
+        let sections = [(".cmdline", b"quiet".to_vec())];
+        assert_eq!(
+            section_checksums_contents(&sections),
+            format!("{:x}  .cmdline\n", Sha256::digest("quiet"))
+        );
+    }
+
     #[test]
     fn convert_to_valid_uefi_path() {
         let path = Path::new("lanzaboote/is/great.txt");
@@ -0,0 +1,345 @@
+//! Predicting the values of the PCRs that the stub extends, so that secrets can be sealed against
+//! them when an image is built rather than after it was booted.
+//!
+//! The stub extends PCR 11 with the SHA-256 hash of every unified section that it measures, in the
+//! order of [`crate::pcrs`], and PCR 12 with the complete kernel command line if it has
+//! continuation sections. Unlike systemd-stub, it does not measure the names of the sections, so
+//! the values differ from those that `systemd-measure` predicts for the same image. After the
+//! stub, systemd-pcrphase extends PCR 11 with the phases of the boot, e.g. `enter-initrd`.
+//!
+//! The predictions of PCR 11 can be signed and embedded in the `.pcrsig` section, like
+//! `systemd-measure sign` does, so that systemd-cryptsetup can unlock volumes that are bound to a
+//! signed PCR policy instead of fixed PCR values. It checks the signatures against the public key
+//! in the `.pcrpkey` section, which the stub passes to the initrd along with them.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use base64ct::{Base64, Encoding};
+use goblin::pe::PE;
+use rsa::pkcs8::{DecodePublicKey, EncodePublicKey};
+use rsa::{Pkcs1v15Sign, RsaPublicKey};
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use crate::pcrs::{MEASURED_SECTIONS, PCR_KERNEL_CONFIG, PCR_KERNEL_IMAGE};
+use crate::pe::{
+    read_cmdline, read_section_data, read_sections_data, CMDLINE_CONTINUATION_SECTIONS,
+};
+use crate::sign::read_private_key;
+
+/// The phases for which `systemd-measure` predicts PCR 11 by default, i.e. the initrd, the switch
+/// to the root file system, early boot and the booted system.
+///
+/// A phase is a `:`-separated list of the words that systemd-pcrphase measured, the empty phase
+/// stands for the value right after the stub.
+pub const DEFAULT_PHASES: &[&str] = &[
+    "enter-initrd",
+    "enter-initrd:leave-initrd",
+    "enter-initrd:leave-initrd:sysinit",
+    "enter-initrd:leave-initrd:sysinit:ready",
+];
+
+/// The predicted value of a PCR, in a phase of the boot for PCR 11.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct PcrPrediction {
+    pub pcr: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phase: Option<String>,
+    #[serde(serialize_with = "serialize_hex")]
+    pub hash: Vec<u8>,
+}
+
+fn serialize_hex<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex(bytes))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The predicted values of the PCRs per bank, like the output of `systemd-measure calculate
+/// --json`.
+///
+/// Only the SHA-256 bank is predicted.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct PcrPredictions {
+    pub sha256: Vec<PcrPrediction>,
+}
+
+type PcrValue = sha2::digest::Output<Sha256>;
+
+/// Extend a PCR with the hash of `data`, as the TPM does.
+fn extend(pcr: &mut PcrValue, data: &[u8]) {
+    *pcr = Sha256::new()
+        .chain_update(*pcr)
+        .chain_update(Sha256::digest(data))
+        .finalize();
+}
+
+/// Predict the values of PCR 11 in the given phases and of PCR 12 when booting an image.
+///
+/// The prediction of PCR 12 only holds if nothing else is measured into it, e.g. credentials, a
+/// command line override or the boot device path.
+pub fn predict_pcrs(image: &[u8], phases: &[&str]) -> Result<PcrPredictions> {
+    PE::parse(image).context("Failed to parse lanzaboote image")?;
+
+    let sections: Vec<(&str, &[u8])> = MEASURED_SECTIONS
+        .iter()
+        .flat_map(|section_name| {
+            read_sections_data(image, section_name)
+                .into_iter()
+                .map(move |data| (*section_name, data))
+        })
+        .collect();
+    let mut predictions = predict_kernel_image(&sections, phases);
+
+    let mut kernel_config = PcrValue::default();
+    if CMDLINE_CONTINUATION_SECTIONS
+        .iter()
+        .any(|section_name| read_section_data(image, section_name).is_some())
+    {
+        if let Some(cmdline) = read_cmdline(image) {
+            extend(&mut kernel_config, cmdline.as_bytes());
+        }
+    }
+    predictions.push(PcrPrediction {
+        pcr: PCR_KERNEL_CONFIG,
+        phase: None,
+        hash: kernel_config.to_vec(),
+    });
+
+    Ok(PcrPredictions {
+        sha256: predictions,
+    })
+}
+
+/// Predict the values of PCR 11 in the given phases for an image with these sections, given by
+/// name and contents.
+///
+/// Sections that the stub does not measure are skipped. Sections that occur more than once, like
+/// `.dtbauto`, are measured in the given order.
+pub fn predict_kernel_image(
+    sections: &[(&str, impl AsRef<[u8]>)],
+    phases: &[&str],
+) -> Vec<PcrPrediction> {
+    let mut kernel_image = PcrValue::default();
+    for section_name in MEASURED_SECTIONS {
+        for (_, data) in sections.iter().filter(|(name, _)| name == section_name) {
+            extend(&mut kernel_image, data.as_ref());
+        }
+    }
+
+    phases
+        .iter()
+        .map(|phase| {
+            let mut pcr = kernel_image;
+            for word in phase.split(':').filter(|word| !word.is_empty()) {
+                extend(&mut pcr, word.as_bytes());
+            }
+            PcrPrediction {
+                pcr: PCR_KERNEL_IMAGE,
+                phase: Some(phase.to_string()),
+                hash: pcr.to_vec(),
+            }
+        })
+        .collect()
+}
+
+/// The TPM command code of `TPM2_PolicyPCR`.
+const TPM_CC_POLICY_PCR: u32 = 0x0000_017f;
+/// The TPM algorithm ID of SHA-256.
+const TPM_ALG_SHA256: u16 = 0x000b;
+
+/// Compute the digest of a TPM2 policy that only allows PCR 11 to have the given value, i.e. a
+/// single `TPM2_PolicyPCR` on the SHA-256 bank.
+fn pcr_policy_digest(pcr_value: &[u8]) -> PcrValue {
+    // TPML_PCR_SELECTION with a single selection of 3 bytes, in which PCR 11 is bit 3 of byte 1.
+    let mut pcr_selection = Vec::new();
+    pcr_selection.extend(1u32.to_be_bytes());
+    pcr_selection.extend(TPM_ALG_SHA256.to_be_bytes());
+    pcr_selection.push(3);
+    pcr_selection.extend([0x00, 0x08, 0x00]);
+
+    Sha256::new()
+        .chain_update(PcrValue::default())
+        .chain_update(TPM_CC_POLICY_PCR.to_be_bytes())
+        .chain_update(pcr_selection)
+        .chain_update(Sha256::digest(pcr_value))
+        .finalize()
+}
+
+/// A signed PCR policy in the format of the `.pcrsig` section.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct PcrSignature {
+    pcrs: Vec<u32>,
+    /// The SHA-256 fingerprint of the public key, i.e. of its DER-encoded SubjectPublicKeyInfo.
+    pkfp: String,
+    /// The policy digest.
+    pol: String,
+    /// The PKCS#1 v1.5 signature of the policy digest, base64-encoded.
+    sig: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct PcrSignatures {
+    sha256: Vec<PcrSignature>,
+}
+
+/// Sign the predicted values of PCR 11 with an RSA key in PEM format and render the signatures in
+/// the JSON format of the `.pcrsig` section, like `systemd-measure sign --pcr-private-key`.
+///
+/// Every phase gets its own signature. Predictions of other PCRs are not signed, because the stub
+/// cannot tell their values at build time.
+pub fn pcr_sign(predictions: &[PcrPrediction], key: &Path) -> Result<String> {
+    let key = read_private_key(key)?;
+    let public_key = RsaPublicKey::from(&key)
+        .to_public_key_der()
+        .context("Failed to encode the public key of the PCR signing key")?;
+    let fingerprint = hex(&Sha256::digest(public_key.as_bytes()));
+
+    let signatures = predictions
+        .iter()
+        .filter(|prediction| prediction.pcr == PCR_KERNEL_IMAGE)
+        .map(|prediction| {
+            let policy = pcr_policy_digest(&prediction.hash);
+            // The policy digest is signed as is, like TPM2_VerifySignature expects it.
+            let signature = key
+                .sign(Pkcs1v15Sign::new::<Sha256>(), &policy)
+                .context("Failed to sign the PCR policy")?;
+            Ok(PcrSignature {
+                pcrs: vec![PCR_KERNEL_IMAGE],
+                pkfp: fingerprint.clone(),
+                pol: hex(&policy),
+                sig: Base64::encode_string(&signature),
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(serde_json::to_string(&PcrSignatures {
+        sha256: signatures,
+    })?)
+}
+
+/// Read the RSA public key in PEM format against which systemd checks the signatures of the
+/// `.pcrsig` section, for the `.pcrpkey` section.
+///
+/// The key is returned as it is in the file. If the signing key is given, the public key must
+/// belong to it, so that an image never carries signatures that cannot be checked.
+pub fn read_pcr_public_key(path: &Path, signing_key: Option<&Path>) -> Result<Vec<u8>> {
+    let pem = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read PCR public key: {path:?}"))?;
+    let public_key = RsaPublicKey::from_public_key_pem(&pem).with_context(|| {
+        format!("Failed to parse PCR public key, expected an RSA key in PEM format: {path:?}")
+    })?;
+
+    if let Some(signing_key) = signing_key {
+        if RsaPublicKey::from(&read_private_key(signing_key)?) != public_key {
+            bail!("The PCR public key {path:?} does not belong to the PCR signing key {signing_key:?}");
+        }
+    }
+
+    Ok(pem.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe::{add_sections, minimal_pe};
+
+    fn extended(data: &[&[u8]]) -> Vec<u8> {
+        let mut pcr = PcrValue::default();
+        for data in data {
+            extend(&mut pcr, data);
+        }
+        pcr.to_vec()
+    }
+
+    #[test]
+    fn predict_pcrs_in_measurement_order() -> Result<()> {
+        let image = add_sections(
+            &minimal_pe(),
+            &[
+                (".osrel", 0x1_4000_2000, b"ID=nixos".to_vec()),
+                (".cmdline", 0x1_4000_3000, b"quiet".to_vec()),
+                (".cmdl2", 0x1_4000_4000, b"splash".to_vec()),
+                (".linux", 0x1_4000_5000, b"kernel".to_vec()),
+                (".pcrsig", 0x1_4000_6000, b"{}".to_vec()),
+            ],
+        )?;
+
+        let predictions = predict_pcrs(&image, &["", "enter-initrd:leave-initrd"])?;
+
+        let sections: [&[u8]; 3] = [b"kernel", b"ID=nixos", b"quiet"];
+        assert_eq!(
+            predictions,
+            PcrPredictions {
+                sha256: vec![
+                    PcrPrediction {
+                        pcr: 11,
+                        phase: Some(String::new()),
+                        hash: extended(&sections),
+                    },
+                    PcrPrediction {
+                        pcr: 11,
+                        phase: Some(String::from("enter-initrd:leave-initrd")),
+                        hash: extended(
+                            &[&sections[..], &[b"enter-initrd", b"leave-initrd"]].concat()
+                        ),
+                    },
+                    PcrPrediction {
+                        pcr: 12,
+                        phase: None,
+                        hash: extended(&[b"quiet splash"]),
+                    },
+                ],
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&predictions.sha256[2])?,
+            format!(
+                r#"{{"pcr":12,"hash":"{}"}}"#,
+                hex(&extended(&[b"quiet splash"]))
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn sign_pcr_policies() -> Result<()> {
+        let key = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../systemd/tests/fixtures/uefi-keys/db.key");
+        let predictions = vec![
+            PcrPrediction {
+                pcr: 11,
+                phase: Some(String::from("enter-initrd")),
+                hash: extended(&[b"kernel", b"enter-initrd"]),
+            },
+            PcrPrediction {
+                pcr: 12,
+                phase: None,
+                hash: extended(&[]),
+            },
+        ];
+
+        let signatures: serde_json::Value = serde_json::from_str(&pcr_sign(&predictions, &key)?)?;
+        let signatures = signatures["sha256"].as_array().context("No SHA-256 bank")?;
+        assert_eq!(signatures.len(), 1);
+        assert_eq!(signatures[0]["pcrs"], serde_json::json!([11]));
+        assert_eq!(
+            signatures[0]["pkfp"],
+            "7d9e9f8884e92de8a378037e94cf2ce9f4a5d584dd147a46a1041efbcf3b8b36"
+        );
+        let policy = pcr_policy_digest(&predictions[0].hash);
+        assert_eq!(signatures[0]["pol"], hex(&policy));
+
+        let signature = Base64::decode_vec(signatures[0]["sig"].as_str().context("No signature")?)?;
+        RsaPublicKey::from(&read_private_key(&key)?).verify(
+            Pkcs1v15Sign::new::<Sha256>(),
+            &policy,
+            &signature,
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::pe::read_section_data;
+
+/// The `SbatLevel` EFI variable of shim in efivarfs, i.e. the SBAT revocations that the firmware
+/// enforces on this machine.
+pub const SBAT_LEVEL_PATH: &str =
+    "/sys/firmware/efi/efivars/SbatLevel-605dab50-e046-4300-abb6-3dd810dd8b23";
+
+/// A component of a binary that is revoked by the SBAT policy of the machine.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SbatRevocation {
+    pub component: String,
+    /// The generation of the component in the binary.
+    pub generation: u32,
+    /// The minimum generation of the component that the SBAT policy accepts.
+    pub minimum_generation: u32,
+}
+
+impl fmt::Display for SbatRevocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "SBAT component {} has generation {}, but at least generation {} is required",
+            self.component, self.generation, self.minimum_generation
+        )
+    }
+}
+
+/// Parse SBAT CSV data into its components and their generations.
+///
+/// Only the first two fields of each line are relevant for revocations, the rest is ignored, so
+/// that this works for both the `.sbat` section of binaries and for `SbatLevel`. Lines that cannot
+/// be parsed are skipped.
+fn parse_sbat(csv: &str) -> Vec<(&str, u32)> {
+    csv.lines()
+        .filter_map(|line| {
+            let mut fields = line.trim_matches(char::from(0)).split(',');
+            let component = fields.next()?.trim();
+            let generation = fields.next()?.trim().parse().ok()?;
+            (!component.is_empty()).then_some((component, generation))
+        })
+        .collect()
+}
+
+/// Find the components of a binary's `.sbat` section that an `SbatLevel` policy revokes.
+///
+/// The first line of `SbatLevel` is the version of the SBAT format itself, which is compared like
+/// any other component.
+pub fn sbat_revocations(binary_sbat: &str, sbat_level: &str) -> Vec<SbatRevocation> {
+    let sbat_level = parse_sbat(sbat_level);
+
+    parse_sbat(binary_sbat)
+        .into_iter()
+        .filter_map(|(component, generation)| {
+            let minimum_generation = sbat_level
+                .iter()
+                .find(|(revoked, _)| *revoked == component)
+                .map(|(_, minimum_generation)| *minimum_generation)?;
+            (generation < minimum_generation).then(|| SbatRevocation {
+                component: component.to_string(),
+                generation,
+                minimum_generation,
+            })
+        })
+        .collect()
+}
+
+/// Find the components of several binaries that a revocation baseline revokes, e.g. an `SbatLevel`
+/// that is about to be rolled out.
+///
+/// `sbat` is the SBAT data of the binaries, e.g. of the stub and of the kernel that an image boots.
+/// A component that occurs in more than one of them is checked in each.
+pub fn baseline_revocations(sbat: &[&str], baseline: &str) -> Vec<SbatRevocation> {
+    sbat.iter()
+        .flat_map(|sbat| sbat_revocations(sbat, baseline))
+        .collect()
+}
+
+/// Read the `.sbat` section of a binary, e.g. of the stub or of a kernel, if it has one.
+pub fn read_sbat(binary: &Path) -> Result<Option<String>> {
+    let data = fs::read(binary).with_context(|| format!("Failed to read binary: {binary:?}"))?;
+    Ok(read_section_data(&data, ".sbat").map(|sbat| String::from_utf8_lossy(sbat).into_owned()))
+}
+
+/// Check the `.sbat` section of the stub against the SBAT policy installed on this machine.
+///
+/// Firmware refuses to boot a stub that is revoked by this policy, so it should not be installed.
+/// Nothing can be checked if the stub has no `.sbat` section or the machine has no `SbatLevel`
+/// variable, e.g. because it does not boot via shim.
+pub fn installed_sbat_revocations(stub: &Path) -> Result<Vec<SbatRevocation>> {
+    let sbat_level = match fs::read(SBAT_LEVEL_PATH) {
+        // efivarfs prefixes the contents of a variable with its 4 bytes of attributes.
+        Ok(variable) => String::from_utf8_lossy(variable.get(4..).unwrap_or_default()).into_owned(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).context("Failed to read SbatLevel from efivarfs"),
+    };
+
+    let Some(stub_sbat) = read_sbat(stub)? else {
+        return Ok(Vec::new());
+    };
+
+    Ok(sbat_revocations(&stub_sbat, &sbat_level))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STUB_SBAT: &str = "\
+sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md
+lanzaboote,2,nix-community,lanzaboote,0.4.1,https://github.com/nix-community/lanzaboote
+";
+
+    #[test]
+    fn report_revoked_components() {
+        let sbat_level = "sbat,1,2024010900\nlanzaboote,3\ngrub,4\n\0";
+        assert_eq!(
+            sbat_revocations(STUB_SBAT, sbat_level),
+            [SbatRevocation {
+                component: "lanzaboote".to_string(),
+                generation: 2,
+                minimum_generation: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_known_good_sbat() {
+        let kernel_sbat = "\
+sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md
+linux,1,The Linux Developers,linux,6.6.1,https://linux.org
+linux.nixos,1,NixOS,linux,6.6.1,https://nixos.org
+";
+        assert_eq!(
+            parse_sbat(kernel_sbat),
+            [("sbat", 1), ("linux", 1), ("linux.nixos", 1)]
+        );
+    }
+
+    #[test]
+    fn report_revocations_of_stub_and_kernel() {
+        let kernel_sbat = "sbat,1,SBAT Version\nlinux,1,The Linux Developers\n";
+        let baseline = "sbat,1,2024010900\nlanzaboote,2\nlinux,2\n";
+        assert_eq!(
+            baseline_revocations(&[STUB_SBAT, kernel_sbat], baseline),
+            [SbatRevocation {
+                component: "linux".to_string(),
+                generation: 1,
+                minimum_generation: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn accept_components_that_are_not_revoked() {
+        let sbat_level = "sbat,1,2024010900\nlanzaboote,2\ngrub,4\n";
+        assert!(sbat_revocations(STUB_SBAT, sbat_level).is_empty());
+    }
+}
@@ -0,0 +1,444 @@
+//! Signing images for Secure Boot with Authenticode, without `sbsign`.
+//!
+//! An Authenticode signature is a PKCS#7 `SignedData` in the certificate table of a PE binary. It
+//! signs a digest of the binary that leaves out the checksum, the certificate table entry in the
+//! optional header and the certificate table itself, so that adding the signature does not change
+//! the digest. The firmware recomputes the digest before it starts an image and compares it to the
+//! signed one.
+
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use cms::cert::{CertificateChoices, IssuerAndSerialNumber};
+use cms::content_info::{CmsVersion, ContentInfo};
+use cms::signed_data::{
+    CertificateSet, EncapsulatedContentInfo, SignedData, SignerIdentifier, SignerInfo, SignerInfos,
+};
+use der::asn1::{Any, ObjectIdentifier, OctetString, SetOfVec};
+use der::{Decode, DecodePem, Encode, Sequence};
+use goblin::pe::PE;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::{Signature, SigningKey, VerifyingKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::signature::{SignatureEncoding, Signer, Verifier};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+use x509_cert::attr::Attribute;
+use x509_cert::spki::AlgorithmIdentifierOwned;
+use x509_cert::Certificate;
+
+use crate::pe::{
+    certificate_table_entry_offset, optional_header_offset, pe_checksum, write_u32,
+    OPTIONAL_CHECKSUM,
+};
+
+const ID_SIGNED_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.2");
+const ID_CONTENT_TYPE: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.3");
+const ID_MESSAGE_DIGEST: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+const ID_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.1");
+const RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+const SPC_INDIRECT_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.2.1.4");
+const SPC_PE_IMAGE_DATA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.311.2.1.15");
+
+/// The DER encoding of the `SpcPeImageData` that is signed along with the digest. Like the one of
+/// signtool and sbsign, it has no flags and links to the file `<<<Obsolete>>>`.
+const PE_IMAGE_DATA: &[u8] =
+    b"\x30\x25\x03\x01\x00\xa0\x20\xa2\x1e\x80\x1c\0<\0<\0<\0O\0b\0s\0o\0l\0e\0t\0e\0>\0>\0>";
+
+/// The header of an entry of the certificate table, see `WIN_CERTIFICATE` in the PE format
+/// specification.
+const WIN_CERTIFICATE_HEADER_SIZE: usize = 8;
+const WIN_CERT_REVISION_2_0: u16 = 0x0200;
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+
+/// The content that an Authenticode signature signs, i.e. the digest of the image.
+#[derive(Sequence)]
+struct SpcIndirectDataContent {
+    data: SpcAttributeTypeAndOptionalValue,
+    message_digest: DigestInfo,
+}
+
+#[derive(Sequence)]
+struct SpcAttributeTypeAndOptionalValue {
+    value_type: ObjectIdentifier,
+    value: Any,
+}
+
+#[derive(Sequence)]
+struct DigestInfo {
+    digest_algorithm: AlgorithmIdentifierOwned,
+    digest: OctetString,
+}
+
+fn sha256_algorithm() -> AlgorithmIdentifierOwned {
+    AlgorithmIdentifierOwned {
+        oid: ID_SHA256,
+        parameters: Some(Any::null()),
+    }
+}
+
+/// Compute the Authenticode digest of a PE binary, i.e. the SHA-256 hash of everything but the
+/// checksum, the certificate table entry and the certificate table.
+///
+/// Like the firmware, this hashes the headers, then the data of the sections in the order in which
+/// it appears in the file, then whatever follows up to the certificate table.
+pub fn authenticode_digest(image: &[u8]) -> Result<Vec<u8>> {
+    let pe = PE::parse(image).context("Failed to parse image")?;
+    let optional_header = pe
+        .header
+        .optional_header
+        .context("Image has no optional header")?;
+    if optional_header.windows_fields.number_of_rva_and_sizes < 5 {
+        bail!("Image has no certificate table entry");
+    }
+
+    let checksum_offset = optional_header_offset(&pe) + OPTIONAL_CHECKSUM;
+    let certificate_table_entry_offset = certificate_table_entry_offset(&pe, &optional_header);
+    let size_of_headers = optional_header.windows_fields.size_of_headers as usize;
+    let certificate_table_size = optional_header
+        .data_directories
+        .get_certificate_table()
+        .as_ref()
+        .map_or(0, |certificate_table| certificate_table.size as usize);
+
+    let part = |range: Range<usize>| image.get(range).context("Image is truncated");
+    let mut hasher = Sha256::new();
+    hasher.update(part(0..checksum_offset)?);
+    hasher.update(part(checksum_offset + 4..certificate_table_entry_offset)?);
+    hasher.update(part(certificate_table_entry_offset + 8..size_of_headers)?);
+
+    let mut sections: Vec<_> = pe
+        .sections
+        .iter()
+        .filter(|section| section.size_of_raw_data > 0)
+        .collect();
+    sections.sort_by_key(|section| section.pointer_to_raw_data);
+    let mut hashed = size_of_headers;
+    for section in sections {
+        let start = section.pointer_to_raw_data as usize;
+        let size = section.size_of_raw_data as usize;
+        hasher.update(part(start..start + size)?);
+        hashed += size;
+    }
+
+    let end = image
+        .len()
+        .checked_sub(certificate_table_size)
+        .context("Image is truncated")?;
+    if end > hashed {
+        hasher.update(part(hashed..end)?);
+    }
+
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Read an RSA private key in PEM format, either PKCS#8 or PKCS#1.
+pub(crate) fn read_private_key(path: &Path) -> Result<RsaPrivateKey> {
+    let pem = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read private key: {path:?}"))?;
+    RsaPrivateKey::from_pkcs8_pem(&pem)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+        .with_context(|| format!("Failed to parse private key, expected an RSA key: {path:?}"))
+}
+
+/// Read an X.509 certificate in PEM format.
+fn read_certificate(path: &Path) -> Result<Certificate> {
+    let pem = fs::read(path).with_context(|| format!("Failed to read certificate: {path:?}"))?;
+    Certificate::from_pem(pem).with_context(|| format!("Failed to parse certificate: {path:?}"))
+}
+
+fn attribute(oid: ObjectIdentifier, value: Any) -> Result<Attribute> {
+    Ok(Attribute {
+        oid,
+        values: SetOfVec::try_from(vec![value])?,
+    })
+}
+
+/// Build the PKCS#7 `SignedData` of an Authenticode signature of an image with the given digest.
+///
+/// PKCS#1 v1.5 signatures are deterministic, so this always returns the same signature for the
+/// same digest, key and certificate.
+fn signed_data(digest: &[u8], key: RsaPrivateKey, cert: Certificate) -> Result<Vec<u8>> {
+    let content = Any::encode_from(&SpcIndirectDataContent {
+        data: SpcAttributeTypeAndOptionalValue {
+            value_type: SPC_PE_IMAGE_DATA,
+            value: Any::from_der(PE_IMAGE_DATA)?,
+        },
+        message_digest: DigestInfo {
+            digest_algorithm: sha256_algorithm(),
+            digest: OctetString::new(digest)?,
+        },
+    })?;
+
+    // Unlike in CMS, the content is not wrapped in an octet string. Its message digest covers the
+    // value of the `SpcIndirectDataContent`, without its tag and length.
+    let message_digest = Sha256::digest(content.value());
+    let signed_attributes = SetOfVec::try_from(vec![
+        attribute(ID_CONTENT_TYPE, Any::encode_from(&SPC_INDIRECT_DATA)?)?,
+        attribute(
+            ID_MESSAGE_DIGEST,
+            Any::encode_from(&OctetString::new(message_digest.to_vec())?)?,
+        )?,
+    ])?;
+    let signature = SigningKey::<Sha256>::new(key)
+        .try_sign(&signed_attributes.to_der()?)
+        .context("Failed to sign image")?;
+
+    let signer_info = SignerInfo {
+        version: CmsVersion::V1,
+        sid: SignerIdentifier::IssuerAndSerialNumber(IssuerAndSerialNumber {
+            issuer: cert.tbs_certificate.issuer.clone(),
+            serial_number: cert.tbs_certificate.serial_number.clone(),
+        }),
+        digest_alg: sha256_algorithm(),
+        signed_attrs: Some(signed_attributes),
+        signature_algorithm: AlgorithmIdentifierOwned {
+            oid: RSA_ENCRYPTION,
+            parameters: Some(Any::null()),
+        },
+        signature: OctetString::new(signature.to_vec())?,
+        unsigned_attrs: None,
+    };
+    let signed_data = SignedData {
+        version: CmsVersion::V1,
+        digest_algorithms: SetOfVec::try_from(vec![sha256_algorithm()])?,
+        encap_content_info: EncapsulatedContentInfo {
+            econtent_type: SPC_INDIRECT_DATA,
+            econtent: Some(content),
+        },
+        certificates: Some(CertificateSet(SetOfVec::try_from(vec![
+            CertificateChoices::Certificate(cert),
+        ])?)),
+        crls: None,
+        signer_infos: SignerInfos(SetOfVec::try_from(vec![signer_info])?),
+    };
+
+    Ok(ContentInfo {
+        content_type: ID_SIGNED_DATA,
+        content: Any::encode_from(&signed_data)?,
+    }
+    .to_der()?)
+}
+
+/// Sign a PE binary with an RSA key and its certificate in PEM format, e.g. the db key, and return
+/// the signed binary.
+///
+/// The binary is padded to a multiple of 8 bytes and the signature is appended as its certificate
+/// table. The checksum is updated. Signing the same binary with the same key always results in the
+/// same signed binary.
+pub fn sign(image: &[u8], key: &Path, cert: &Path) -> Result<Vec<u8>> {
+    let pe = PE::parse(image).context("Failed to parse image")?;
+    let optional_header = pe
+        .header
+        .optional_header
+        .context("Image has no optional header")?;
+    if optional_header
+        .data_directories
+        .get_certificate_table()
+        .is_some_and(|certificate_table| certificate_table.virtual_address != 0)
+    {
+        bail!("Image is already signed");
+    }
+    let checksum_offset = optional_header_offset(&pe) + OPTIONAL_CHECKSUM;
+    let certificate_table_entry_offset = certificate_table_entry_offset(&pe, &optional_header);
+
+    let mut signed = image.to_vec();
+    signed.resize(signed.len().next_multiple_of(8), 0);
+    let digest = authenticode_digest(&signed)?;
+    let signed_data = signed_data(&digest, read_private_key(key)?, read_certificate(cert)?)?;
+
+    let certificate_table_offset = signed.len();
+    let length = WIN_CERTIFICATE_HEADER_SIZE + signed_data.len();
+    signed.extend((length as u32).to_le_bytes());
+    signed.extend(WIN_CERT_REVISION_2_0.to_le_bytes());
+    signed.extend(WIN_CERT_TYPE_PKCS_SIGNED_DATA.to_le_bytes());
+    signed.extend(signed_data);
+    signed.resize(signed.len().next_multiple_of(8), 0);
+
+    let certificate_table_size = signed.len() - certificate_table_offset;
+    write_u32(
+        &mut signed,
+        certificate_table_entry_offset,
+        certificate_table_offset as u32,
+    );
+    write_u32(
+        &mut signed,
+        certificate_table_entry_offset + 4,
+        certificate_table_size as u32,
+    );
+    write_u32(&mut signed, checksum_offset, 0);
+    let checksum = pe_checksum(&signed);
+    write_u32(&mut signed, checksum_offset, checksum);
+
+    Ok(signed)
+}
+
+/// Sign the image at `image`, see [`sign`], and write the signed image next to it.
+///
+/// The signature is verified against the certificate before the path of the signed image is
+/// returned.
+pub fn sign_image(image: &Path, key: &Path, cert: &Path) -> Result<PathBuf> {
+    let data = fs::read(image).with_context(|| format!("Failed to read image: {image:?}"))?;
+    let signed = sign(&data, key, cert).with_context(|| format!("Failed to sign {image:?}"))?;
+    ensure!(
+        verify(&signed, cert)?,
+        "The signature of {image:?} does not verify against {cert:?}"
+    );
+
+    let file_name = image
+        .file_name()
+        .with_context(|| format!("Image has no file name: {image:?}"))?;
+    let signed_image = image.with_file_name(format!("signed-{}", file_name.to_string_lossy()));
+    fs::write(&signed_image, signed)
+        .with_context(|| format!("Failed to write signed image: {signed_image:?}"))?;
+    Ok(signed_image)
+}
+
+/// Read the PKCS#7 `SignedData` of the Authenticode signature of an image, if it is signed.
+fn read_signed_data(image: &[u8]) -> Result<Option<SignedData>> {
+    let pe = PE::parse(image).context("Failed to parse image")?;
+    let Some(certificate_table) = pe
+        .header
+        .optional_header
+        .and_then(|optional_header| *optional_header.data_directories.get_certificate_table())
+        .filter(|certificate_table| certificate_table.virtual_address != 0)
+    else {
+        return Ok(None);
+    };
+
+    // Unlike those of the other data directories, the address of the certificate table is an
+    // offset into the file.
+    let start = certificate_table.virtual_address as usize;
+    let certificate = image
+        .get(start..start + WIN_CERTIFICATE_HEADER_SIZE)
+        .context("Certificate table is truncated")?;
+    let length = u32::from_le_bytes(certificate[..4].try_into()?) as usize;
+    let certificate_type = u16::from_le_bytes(certificate[6..8].try_into()?);
+    if certificate_type != WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+        bail!("Unsupported certificate type {certificate_type:#x}");
+    }
+    let signed_data = image
+        .get(start + WIN_CERTIFICATE_HEADER_SIZE..start + length)
+        .context("Certificate table is truncated")?;
+
+    let content_info = ContentInfo::from_der(signed_data).context("Failed to parse signature")?;
+    if content_info.content_type != ID_SIGNED_DATA {
+        bail!("Signature is not PKCS#7 signed data");
+    }
+    Ok(Some(
+        content_info
+            .content
+            .decode_as()
+            .context("Failed to parse signature")?,
+    ))
+}
+
+/// Verify the Authenticode signature of an image against a certificate in PEM format.
+///
+/// The signature verifies if it was made with the key of the certificate over the digest of the
+/// image. Unlike the firmware or sbverify, this does not verify chains of certificates, it is meant
+/// to check signatures made by [`sign`].
+pub fn verify(image: &[u8], cert: &Path) -> Result<bool> {
+    let Some(signed_data) = read_signed_data(image)? else {
+        return Ok(false);
+    };
+    let cert = read_certificate(cert)?;
+
+    let Some(content) = signed_data
+        .encap_content_info
+        .econtent
+        .filter(|_| signed_data.encap_content_info.econtent_type == SPC_INDIRECT_DATA)
+    else {
+        return Ok(false);
+    };
+    let indirect_data: SpcIndirectDataContent = content
+        .decode_as()
+        .context("Failed to parse signed digest")?;
+    if indirect_data.message_digest.digest.as_bytes() != authenticode_digest(image)? {
+        return Ok(false);
+    }
+
+    let public_key =
+        RsaPublicKey::from_public_key_der(&cert.tbs_certificate.subject_public_key_info.to_der()?)
+            .context("Certificate has no RSA public key")?;
+    let message_digest = Sha256::digest(content.value());
+    for signer_info in signed_data.signer_infos.0.iter() {
+        let Some(signed_attributes) = &signer_info.signed_attrs else {
+            continue;
+        };
+        let signs_content = signed_attributes.iter().any(|attribute| {
+            attribute.oid == ID_MESSAGE_DIGEST
+                && attribute.values.iter().any(|value| {
+                    value
+                        .decode_as::<OctetString>()
+                        .is_ok_and(|digest| digest.as_bytes() == message_digest.as_slice())
+                })
+        });
+        let Ok(signature) = Signature::try_from(signer_info.signature.as_bytes()) else {
+            continue;
+        };
+        if signs_content
+            && VerifyingKey::<Sha256>::new(public_key.clone())
+                .verify(&signed_attributes.to_der()?, &signature)
+                .is_ok()
+        {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pe::{minimal_pe, read_section_data, strip_signature};
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../systemd/tests/fixtures/uefi-keys")
+            .join(name)
+    }
+
+    #[test]
+    fn sign_and_verify_image() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let image = tempdir.path().join("image.efi");
+        fs::write(&image, minimal_pe())?;
+
+        let signed_image = sign_image(&image, &fixture("db.key"), &fixture("db.pem"))?;
+        let signed = fs::read(&signed_image)?;
+
+        assert_eq!(
+            signed,
+            sign(&minimal_pe(), &fixture("db.key"), &fixture("db.pem"))?
+        );
+        assert_eq!(signed.len() % 8, 0);
+        assert_eq!(
+            read_section_data(&signed, ".text"),
+            Some(&b"lanzaboote stub!"[..])
+        );
+        assert_eq!(
+            authenticode_digest(&signed)?,
+            authenticode_digest(&minimal_pe())?
+        );
+        assert_eq!(strip_signature(&signed)?, strip_signature(&minimal_pe())?);
+
+        let signed_data = read_signed_data(&signed)?.context("Image is not signed")?;
+        assert_eq!(signed_data.version, CmsVersion::V1);
+        assert_eq!(
+            signed_data.encap_content_info.econtent_type,
+            SPC_INDIRECT_DATA
+        );
+        assert!(verify(&signed, &fixture("db.pem"))?);
+
+        let mut tampered = signed.clone();
+        tampered[0x200] ^= 1;
+        assert!(!verify(&tampered, &fixture("db.pem"))?);
+        assert!(!verify(&minimal_pe(), &fixture("db.pem"))?);
+        assert!(sign(&signed, &fixture("db.key"), &fixture("db.pem")).is_err());
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 use crate::pe::lanzaboote_image;
+use crate::sign::{sign, sign_image};
 use crate::utils::SecureTempDirExt;
 use std::ffi::OsString;
 use std::io::Write;
@@ -15,11 +16,8 @@ use super::Signer;
 ///
 /// The security of the private key is the responsibility of the user.
 ///
-/// Currently, signature happens via `sbsign` where the input is temporarily
-/// copied in a secure directory and signed over there.
-///
-/// In the future, `sbsign` may be removed to perform signature in-memory
-/// without any temporary directory.
+/// Signing happens in-memory, see [`crate::sign`]. Verification still
+/// happens via `sbverify`, which also verifies chains of certificates.
 #[derive(Debug, Clone)]
 pub struct LocalKeyPair {
     pub private_key: PathBuf,
@@ -40,49 +38,20 @@ impl Signer for LocalKeyPair {
         Ok(std::fs::read(&self.public_key)?)
     }
 
-    fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
-        let args: Vec<OsString> = vec![
-            OsString::from("--key"),
-            self.private_key.clone().into(),
-            OsString::from("--cert"),
-            self.public_key.clone().into(),
-            from.as_os_str().to_owned(),
-            OsString::from("--output"),
-            to.as_os_str().to_owned(),
-        ];
-
-        let output = Command::new("sbsign")
-            .args(&args)
-            .output()
-            .context("Failed to run sbsign. Most likely, the binary is not on PATH.")?;
-
-        if !output.status.success() {
-            std::io::stderr()
-                .write_all(&output.stderr)
-                .context("Failed to write output of sbsign to stderr.")?;
-            log::debug!("sbsign failed with args: `{args:?}`.");
-            return Err(anyhow::anyhow!("Failed to sign {to:?}."));
-        }
-
-        Ok(())
-    }
-
     fn sign_store_path(&self, store_path: &Path) -> Result<Vec<u8>> {
-        let working_tree = tempdir()?;
-        let to = &working_tree.path().join("signed.efi");
-        self.sign_and_copy(store_path, to)?;
-
-        Ok(std::fs::read(to)?)
+        let image =
+            std::fs::read(store_path).with_context(|| format!("Failed to read {store_path:?}"))?;
+        sign(&image, &self.private_key, &self.public_key)
+            .with_context(|| format!("Failed to sign {store_path:?}"))
     }
 
     fn build_and_sign_stub(&self, stub: &crate::pe::StubParameters) -> Result<Vec<u8>> {
         let working_tree = tempdir()?;
         let lzbt_image_path =
             lanzaboote_image(&working_tree, stub).context("Failed to build a lanzaboote image")?;
-        let to = working_tree.path().join("signed-stub.efi");
-        self.sign_and_copy(&lzbt_image_path, &to)?;
+        let signed_image_path = sign_image(&lzbt_image_path, &self.private_key, &self.public_key)?;
 
-        std::fs::read(&to).context("Failed to read a lanzaboote image")
+        std::fs::read(&signed_image_path).context("Failed to read a lanzaboote image")
     }
 
     fn verify(&self, pe_binary: &[u8]) -> Result<bool> {
@@ -0,0 +1,76 @@
+//! Reading the release of a kernel, i.e. what `uname -r` shows once it booted, from its image.
+//!
+//! systemd-boot and `bootctl` show the release of a UKI from its `.uname` section. Kernels embed
+//! it in two ways: x86 kernels point to a version string in the setup header of the boot
+//! protocol, and all kernels contain the banner `Linux version <release> ...` that they print on
+//! boot, unless the image is compressed.
+
+/// Offset of the magic `HdrS` of the setup header in an x86 kernel.
+const SETUP_HEADER_MAGIC_OFFSET: usize = 0x202;
+/// Offset of the `kernel_version` field of the setup header, a pointer to the version string
+/// relative to the end of the boot sector.
+const KERNEL_VERSION_OFFSET: usize = 0x20e;
+const BOOT_SECTOR_SIZE: usize = 0x200;
+
+const BANNER: &[u8] = b"Linux version ";
+
+/// Read the release of a kernel from its image, e.g. `6.6.1`.
+///
+/// Nothing is returned if the image contains neither a setup header with a version string nor
+/// the banner, e.g. because it is compressed.
+pub fn kernel_release(kernel: &[u8]) -> Option<String> {
+    setup_header_release(kernel).or_else(|| banner_release(kernel))
+}
+
+/// Read the release from the version string that the setup header of an x86 kernel points to.
+fn setup_header_release(kernel: &[u8]) -> Option<String> {
+    if kernel.get(SETUP_HEADER_MAGIC_OFFSET..SETUP_HEADER_MAGIC_OFFSET + 4)? != b"HdrS" {
+        return None;
+    }
+    let pointer = kernel.get(KERNEL_VERSION_OFFSET..KERNEL_VERSION_OFFSET + 2)?;
+    let pointer = u16::from_le_bytes([pointer[0], pointer[1]]);
+    if pointer == 0 {
+        return None;
+    }
+    release(kernel.get(BOOT_SECTOR_SIZE + usize::from(pointer)..)?)
+}
+
+/// Read the release from the banner that the kernel prints on boot.
+fn banner_release(kernel: &[u8]) -> Option<String> {
+    let start = kernel
+        .windows(BANNER.len())
+        .position(|window| window == BANNER)?;
+    release(&kernel[start + BANNER.len()..])
+}
+
+/// The release at the start of a version string, i.e. up to the first whitespace or NUL.
+fn release(version: &[u8]) -> Option<String> {
+    let end = version
+        .iter()
+        .position(|byte| !byte.is_ascii_graphic())
+        .unwrap_or(version.len());
+    let release = std::str::from_utf8(&version[..end]).ok()?;
+    (!release.is_empty()).then(|| release.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_kernel_release() {
+        let mut bzimage = vec![0; 0x400];
+        bzimage[SETUP_HEADER_MAGIC_OFFSET..SETUP_HEADER_MAGIC_OFFSET + 4].copy_from_slice(b"HdrS");
+        bzimage[KERNEL_VERSION_OFFSET..KERNEL_VERSION_OFFSET + 2]
+            .copy_from_slice(&0x100u16.to_le_bytes());
+        let version = b"6.6.1 (nixbld@localhost) #1-NixOS SMP\0";
+        bzimage[0x300..0x300 + version.len()].copy_from_slice(version);
+        assert_eq!(kernel_release(&bzimage).as_deref(), Some("6.6.1"));
+
+        let image = b"\x7fELF...Linux version 6.1.0-rc1 (gcc 13.2.0) #1 SMP";
+        assert_eq!(kernel_release(image).as_deref(), Some("6.1.0-rc1"));
+
+        assert_eq!(kernel_release(b"compressed"), None);
+        assert_eq!(kernel_release(b"Linux version \0"), None);
+    }
+}
@@ -1,6 +1,6 @@
 use std::ffi::OsString;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Write};
 use std::iter::repeat_with;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
@@ -67,6 +67,51 @@ pub fn tmpname() -> OsString {
     buf
 }
 
+/// A temporary file next to its destination, which is removed when it is dropped unless it was
+/// moved to its destination.
+///
+/// Files are written to a temporary file first and then renamed, so that the destination is never
+/// partially written. If writing, syncing or signing the temporary file fails, the guard removes
+/// it, so that no debris is left behind, e.g. on the ESP.
+pub struct TempFileGuard {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFileGuard {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            persisted: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the temporary file to its destination.
+    pub fn persist(mut self, to: &Path) -> Result<()> {
+        fs::rename(&self.path, to).with_context(|| {
+            format!(
+                "Failed to move temporary file {:?} to final location {to:?}",
+                self.path
+            )
+        })?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFileGuard {
+    fn drop(&mut self) {
+        if !self.persisted {
+            // The file may not have been created yet.
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
 type Hash = sha2::digest::Output<Sha256>;
 
 /// Compute the SHA 256 hash of a file.
@@ -75,3 +120,39 @@ pub fn file_hash(file: &Path) -> Result<Hash> {
         format!("Failed to read file to hash: {file:?}")
     })?))
 }
+
+/// Open an output file for writing, `-` stands for stdout.
+///
+/// This allows piping build output into other tools, e.g. a signer, without a temporary file.
+pub fn open_output(path: &Path) -> Result<Box<dyn Write>> {
+    if path == Path::new("-") {
+        Ok(Box::new(io::stdout().lock()))
+    } else {
+        Ok(Box::new(fs::File::create(path).with_context(|| {
+            format!("Failed to create output file: {path:?}")
+        })?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_temporary_file_unless_persisted() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let to = tempdir.path().join("image.efi");
+
+        let failed = TempFileGuard::new(to.with_extension("tmp"));
+        fs::write(failed.path(), "partial")?;
+        drop(failed);
+        assert!(!to.with_extension("tmp").exists());
+
+        let succeeded = TempFileGuard::new(to.with_extension("tmp"));
+        fs::write(succeeded.path(), "complete")?;
+        succeeded.persist(&to)?;
+        assert!(!to.with_extension("tmp").exists());
+        assert_eq!(fs::read_to_string(&to)?, "complete");
+        Ok(())
+    }
+}
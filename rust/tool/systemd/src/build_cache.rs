@@ -0,0 +1,206 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use lanzaboote_tool::esp::ensure_parent_dir;
+use lanzaboote_tool::utils::{file_hash, TempFileGuard};
+
+/// The first line of a build cache file. Files with another header are ignored, so changing the
+/// format only costs one rebuild of every generation.
+const HEADER: &str = "lzbt-build-cache 1";
+
+/// What is known about an image that was built before.
+#[derive(Debug, PartialEq, Eq)]
+struct CacheEntry {
+    /// The fingerprint of the inputs the image was built from.
+    fingerprint: String,
+    /// The hash of the installed, signed image.
+    image_hash: String,
+}
+
+/// A cache of the inputs of the images on the ESP.
+///
+/// Building an image is skipped if its inputs have the same fingerprint as when it was last built
+/// and the installed image is unchanged. The inputs include the stub, so updating lanzaboote
+/// rebuilds every generation.
+///
+/// The cache is stored in a simple text file with one image per line, e.g.
+/// `<image hash> <fingerprint> /boot/EFI/Linux/nixos-generation-1-<hash>.efi`.
+pub struct BuildCache {
+    path: PathBuf,
+    entries: BTreeMap<PathBuf, CacheEntry>,
+    /// Hashes of input files, many generations share the same kernel and initrd.
+    file_hashes: HashMap<PathBuf, String>,
+    hits: usize,
+    misses: usize,
+}
+
+impl BuildCache {
+    /// Load the cache from a file.
+    ///
+    /// A cache is never worth failing the installation for. If the file cannot be read, the cache
+    /// starts out empty.
+    pub fn load(path: PathBuf) -> Self {
+        let entries = match fs::read_to_string(&path) {
+            Ok(contents) => parse_entries(&contents),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                log::warn!("Failed to read the build cache {path:?}, ignoring it: {err}");
+                BTreeMap::new()
+            }
+        };
+
+        Self {
+            path,
+            entries,
+            file_hashes: HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Compute the fingerprint of the inputs of an image, i.e. the contents of `files` and the
+    /// named `values`.
+    pub fn fingerprint(&mut self, files: &[&Path], values: &[(&str, &[u8])]) -> Result<String> {
+        let mut hasher = Sha256::new();
+        for file in files {
+            let hash = match self.file_hashes.get(*file) {
+                Some(hash) => hash.clone(),
+                None => {
+                    let hash = format!("{:x}", file_hash(file)?);
+                    self.file_hashes.insert(file.to_path_buf(), hash.clone());
+                    hash
+                }
+            };
+            hasher.update(hash.as_bytes());
+        }
+        for (name, value) in values {
+            // Length-prefix the values, so that moving bytes between them changes the hash.
+            hasher.update(name.as_bytes());
+            hasher.update((value.len() as u64).to_le_bytes());
+            hasher.update(value);
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Check whether `image` was built from inputs with this fingerprint and is still installed
+    /// unchanged.
+    pub fn is_fresh(&mut self, image: &Path, fingerprint: &str) -> bool {
+        let fresh = self.entries.get(image).is_some_and(|entry| {
+            entry.fingerprint == fingerprint
+                && file_hash(image).is_ok_and(|hash| format!("{hash:x}") == entry.image_hash)
+        });
+
+        if fresh {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        fresh
+    }
+
+    /// Record that `image` was built from inputs with this fingerprint.
+    pub fn record(&mut self, image: &Path, fingerprint: String) -> Result<()> {
+        let image_hash = format!("{:x}", file_hash(image)?);
+        self.entries.insert(
+            image.to_path_buf(),
+            CacheEntry {
+                fingerprint,
+                image_hash,
+            },
+        );
+        Ok(())
+    }
+
+    /// Log how many images were found in the cache.
+    pub fn report(&self) {
+        log::info!("Build cache: {} hits, {} misses.", self.hits, self.misses);
+    }
+
+    /// Write the cache back to its file.
+    ///
+    /// Images that no longer exist, e.g. because they were garbage collected, are dropped.
+    pub fn save(&mut self) -> Result<()> {
+        self.entries.retain(|image, _| image.exists());
+
+        let mut contents = format!("{HEADER}\n");
+        for (image, entry) in &self.entries {
+            contents.push_str(&format!(
+                "{} {} {}\n",
+                entry.image_hash,
+                entry.fingerprint,
+                image.display()
+            ));
+        }
+
+        ensure_parent_dir(&self.path);
+        let tmp = TempFileGuard::new(self.path.with_extension("tmp"));
+        fs::write(tmp.path(), contents)
+            .with_context(|| format!("Failed to write the build cache to {:?}", tmp.path()))?;
+        tmp.persist(&self.path)
+    }
+}
+
+/// Parse the entries of a build cache file, skipping malformed lines.
+fn parse_entries(contents: &str) -> BTreeMap<PathBuf, CacheEntry> {
+    let mut lines = contents.lines();
+    if lines.next() != Some(HEADER) {
+        return BTreeMap::new();
+    }
+
+    lines
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let image_hash = fields.next()?;
+            let fingerprint = fields.next()?;
+            let image = fields.next()?;
+            Some((
+                PathBuf::from(image),
+                CacheEntry {
+                    fingerprint: fingerprint.to_string(),
+                    image_hash: image_hash.to_string(),
+                },
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_unchanged_images() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let image = dir.path().join("nixos generation 1.efi");
+        let kernel = dir.path().join("kernel");
+        fs::write(&image, b"image")?;
+        fs::write(&kernel, b"kernel")?;
+
+        let mut cache = BuildCache::load(dir.path().join("cache"));
+        let fingerprint = cache.fingerprint(&[&kernel], &[("cmdline", b"quiet")])?;
+        assert!(!cache.is_fresh(&image, &fingerprint));
+        cache.record(&image, fingerprint.clone())?;
+        cache.save()?;
+
+        let mut cache = BuildCache::load(dir.path().join("cache"));
+        assert!(cache.is_fresh(&image, &fingerprint));
+        let other_cmdline = cache.fingerprint(&[&kernel], &[("cmdline", b"debug")])?;
+        assert!(!cache.is_fresh(&image, &other_cmdline));
+
+        fs::write(&image, b"tampered image")?;
+        assert!(!cache.is_fresh(&image, &fingerprint));
+        assert_eq!((cache.hits, cache.misses), (1, 2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_cache_with_unknown_header() {
+        let entries = parse_entries("lzbt-build-cache 0\naaaa bbbb /boot/EFI/Linux/nixos.efi\n");
+        assert!(entries.is_empty());
+    }
+}
@@ -1,10 +1,20 @@
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
 
-use crate::install;
-use lanzaboote_tool::{architecture::Architecture, signature::local::LocalKeyPair};
+use crate::list::{labels, list_images};
+use crate::reproduce::{reproduce, ReproduceOptions};
+use crate::{fsck, install};
+use lanzaboote_tool::{
+    architecture::Architecture,
+    pcrs::stub_measurements,
+    pe::{read_cmdline, read_section_data},
+    predict::{predict_pcrs, DEFAULT_PHASES},
+    signature::local::LocalKeyPair,
+    utils::open_output,
+};
 
 /// The default log level.
 ///
@@ -26,6 +36,22 @@ pub struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Install(InstallCommand),
+    /// List the PCRs that the stub extends when booting an image
+    Pcrs(PcrsCommand),
+    /// Predict the values of the PCRs that the stub extends when booting an image, as JSON
+    Predict(PredictCommand),
+    /// Extract the raw contents of a section of an image
+    Extract(ExtractCommand),
+    /// Print the kernel command line embedded in an image
+    Cmdline(CmdlineCommand),
+    /// Check the consistency of the lanzaboote deployment on an ESP
+    Fsck(FsckCommand),
+    /// Check a single image and the kernel and initrds it references, e.g. before installing it
+    Verify(VerifyCommand),
+    /// List the NixOS images on an ESP with labels that tell them apart
+    List(ListCommand),
+    /// Rebuild the image of a generation and check that it is identical to an existing one
+    Reproduce(ReproduceCommand),
 }
 
 #[derive(Parser)]
@@ -54,6 +80,50 @@ struct InstallCommand {
     #[arg(long, default_value_t = 1)]
     configuration_limit: usize,
 
+    /// Only keep generations built at most this many days ago, the newest and the booted generation are always kept
+    #[arg(long)]
+    max_generation_age: Option<u64>,
+
+    /// Message shown before booting, e.g. a legal notice
+    #[arg(long)]
+    boot_message: Option<String>,
+
+    /// Kernel command line parameters appended on the last boot attempt, e.g. systemd.unit=rescue.target
+    #[arg(long)]
+    cmdline_fallback: Option<String>,
+
+    /// Keep a copy of the newest generation's image at EFI/nixos/latest.efi
+    #[arg(long)]
+    latest_image: bool,
+
+    /// Let every generation boot the image of the generation before it if its kernel or initrd does not match its hash
+    #[arg(long)]
+    fallback_image: bool,
+
+    /// File in which to cache the inputs of the installed images, so that unchanged generations are not rebuilt
+    #[arg(long)]
+    build_cache: Option<PathBuf>,
+
+    /// Check that the kernel and initrds on the ESP match the hashes in every built image
+    #[arg(long)]
+    verify_images: bool,
+
+    /// File with SBAT data in CSV format to embed in every image
+    #[arg(long)]
+    sbat: Option<PathBuf>,
+
+    /// File with an SBAT revocation baseline, e.g. an upcoming SbatLevel, to check the stub and kernels against
+    #[arg(long)]
+    sbat_baseline: Option<PathBuf>,
+
+    /// RSA private key in PEM format with which to sign the predicted values of PCR 11 in every image
+    #[arg(long)]
+    pcr_private_key: Option<PathBuf>,
+
+    /// RSA public key in PEM format to embed in every image, against which systemd checks the PCR signatures
+    #[arg(long)]
+    pcr_public_key: Option<PathBuf>,
+
     /// EFI system partition mountpoint (e.g. efiSysMountPoint)
     esp: PathBuf,
 
@@ -61,6 +131,109 @@ struct InstallCommand {
     generations: Vec<PathBuf>,
 }
 
+#[derive(Parser)]
+struct PcrsCommand {
+    /// Lanzaboote image
+    image: PathBuf,
+}
+
+#[derive(Parser)]
+struct PredictCommand {
+    /// Lanzaboote image
+    image: PathBuf,
+
+    /// Phase of the boot to predict PCR 11 for, as `:`-separated words of systemd-pcrphase, e.g.
+    /// `enter-initrd:leave-initrd`. Can be given more than once, defaults to the phases of
+    /// systemd-measure
+    #[arg(long = "phase")]
+    phases: Vec<String>,
+
+    /// Output file, `-` for stdout
+    #[arg(long, default_value = "-")]
+    output: PathBuf,
+}
+
+#[derive(Parser)]
+struct ExtractCommand {
+    /// PE image, e.g. a lanzaboote image
+    image: PathBuf,
+
+    /// Name of the section, e.g. `.cmdline`
+    section: String,
+
+    /// Output file, `-` for stdout
+    output: PathBuf,
+}
+
+#[derive(Parser)]
+struct CmdlineCommand {
+    /// Lanzaboote image
+    image: PathBuf,
+}
+
+#[derive(Parser)]
+struct FsckCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct VerifyCommand {
+    /// EFI system partition mountpoint on which the referenced kernel and initrds are
+    #[arg(long)]
+    esp: PathBuf,
+
+    /// Lanzaboote image
+    image: PathBuf,
+}
+
+#[derive(Parser)]
+struct ListCommand {
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+}
+
+#[derive(Parser)]
+struct ReproduceCommand {
+    /// EFI system partition mountpoint that the image was installed to
+    #[arg(long)]
+    esp: PathBuf,
+
+    /// Rebuild the image of this specialisation of the generation
+    #[arg(long)]
+    specialisation: Option<String>,
+
+    /// Message shown before booting, as passed to install
+    #[arg(long)]
+    boot_message: Option<String>,
+
+    /// Kernel command line parameters appended on the last boot attempt, as passed to install
+    #[arg(long)]
+    cmdline_fallback: Option<String>,
+
+    /// File with SBAT data in CSV format, as passed to install
+    #[arg(long)]
+    sbat: Option<PathBuf>,
+
+    /// RSA private key in PEM format for PCR signatures, as passed to install
+    #[arg(long)]
+    pcr_private_key: Option<PathBuf>,
+
+    /// RSA public key in PEM format for PCR signatures, as passed to install
+    #[arg(long)]
+    pcr_public_key: Option<PathBuf>,
+
+    /// Image on the ESP that the image boots if its kernel or initrd does not match, if it was installed with --fallback-image
+    #[arg(long)]
+    fallback_image: Option<PathBuf>,
+
+    /// Generation link that the image was built from
+    generation: PathBuf,
+
+    /// Existing image, e.g. from EFI/Linux on the ESP
+    image: PathBuf,
+}
+
 impl Cli {
     pub fn call(self, module: &str) {
         stderrlog::new()
@@ -82,6 +255,14 @@ impl Commands {
     pub fn call(self) -> Result<()> {
         match self {
             Commands::Install(args) => install(args),
+            Commands::Pcrs(args) => pcrs(args),
+            Commands::Predict(args) => predict(args),
+            Commands::Extract(args) => extract(args),
+            Commands::Cmdline(args) => cmdline(args),
+            Commands::Fsck(args) => fsck(args),
+            Commands::Verify(args) => verify(args),
+            Commands::List(args) => list(args),
+            Commands::Reproduce(args) => reproduce_image(args),
         }
     }
 }
@@ -105,5 +286,158 @@ fn install(args: InstallCommand) -> Result<()> {
         args.esp,
         args.generations,
     )
+    .with_boot_message(args.boot_message)
+    .with_cmdline_fallback(args.cmdline_fallback)
+    .with_latest_image(args.latest_image)
+    .with_fallback_image(args.fallback_image)
+    .with_max_generation_age(args.max_generation_age)
+    .with_build_cache(args.build_cache)
+    .with_verify_images(args.verify_images)
+    .with_sbat(read_optional_file(args.sbat.as_deref())?)
+    .with_sbat_baseline(read_optional_file(args.sbat_baseline.as_deref())?)
+    .with_pcr_private_key(args.pcr_private_key)
+    .with_pcr_public_key(args.pcr_public_key)
     .install()
 }
+
+fn pcrs(args: PcrsCommand) -> Result<()> {
+    let image = std::fs::read(&args.image)
+        .with_context(|| format!("Failed to read image {:?}", args.image))?;
+
+    for measurement in stub_measurements(&image)? {
+        println!("{measurement}");
+    }
+
+    Ok(())
+}
+
+fn predict(args: PredictCommand) -> Result<()> {
+    let image = std::fs::read(&args.image)
+        .with_context(|| format!("Failed to read image {:?}", args.image))?;
+    let phases: Vec<&str> = if args.phases.is_empty() {
+        DEFAULT_PHASES.to_vec()
+    } else {
+        args.phases.iter().map(String::as_str).collect()
+    };
+    let predictions = predict_pcrs(&image, &phases)?;
+
+    let mut output = open_output(&args.output)?;
+    serde_json::to_writer_pretty(&mut output, &predictions)
+        .map_err(std::io::Error::from)
+        .and_then(|()| writeln!(output))
+        .and_then(|()| output.flush())
+        .with_context(|| format!("Failed to write PCR predictions to {:?}", args.output))
+}
+
+fn extract(args: ExtractCommand) -> Result<()> {
+    let image = std::fs::read(&args.image)
+        .with_context(|| format!("Failed to read image {:?}", args.image))?;
+    let data = read_section_data(&image, &args.section)
+        .with_context(|| format!("Image {:?} has no section {}", args.image, args.section))?;
+
+    let mut output = open_output(&args.output)?;
+    output
+        .write_all(data)
+        .and_then(|()| output.flush())
+        .with_context(|| format!("Failed to write section to {:?}", args.output))
+}
+
+fn cmdline(args: CmdlineCommand) -> Result<()> {
+    let image = std::fs::read(&args.image)
+        .with_context(|| format!("Failed to read image {:?}", args.image))?;
+    let cmdline = read_cmdline(&image)
+        .with_context(|| format!("Image {:?} has no .cmdline section", args.image))?;
+
+    println!("{cmdline}");
+
+    Ok(())
+}
+
+fn fsck(args: FsckCommand) -> Result<()> {
+    let report = fsck::check_esp(&args.esp)?;
+
+    for problem in &report.problems {
+        println!("{problem}");
+    }
+
+    if !report.is_healthy() {
+        bail!(
+            "Checked {} images, found {} problems",
+            report.images,
+            report.problems.len()
+        );
+    }
+    println!("Checked {} images, no problems found", report.images);
+
+    Ok(())
+}
+
+fn verify(args: VerifyCommand) -> Result<()> {
+    let report = fsck::check_image(&args.image, &args.esp)?;
+
+    for problem in &report.problems {
+        println!("{problem}");
+    }
+
+    if !report.is_healthy() {
+        bail!(
+            "{:?} has {} problems, it may not boot",
+            args.image,
+            report.problems.len()
+        );
+    }
+    println!(
+        "{:?}: sections and referenced kernel and initrds are intact",
+        args.image
+    );
+
+    Ok(())
+}
+
+fn list(args: ListCommand) -> Result<()> {
+    let images = list_images(&args.esp)?;
+
+    for (image, label) in images.iter().zip(labels(&images)) {
+        let file_name = image
+            .image
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        println!("{file_name}: {label}");
+    }
+
+    Ok(())
+}
+
+fn reproduce_image(args: ReproduceCommand) -> Result<()> {
+    let lanzaboote_stub =
+        std::env::var("LANZABOOTE_STUB").context("Failed to read LANZABOOTE_STUB env variable")?;
+
+    let sbat = read_optional_file(args.sbat.as_deref())?;
+    let options = ReproduceOptions {
+        lanzaboote_stub: Path::new(&lanzaboote_stub),
+        esp: &args.esp,
+        specialisation: args.specialisation.as_deref(),
+        boot_message: args.boot_message.as_deref(),
+        cmdline_fallback: args.cmdline_fallback.as_deref(),
+        sbat: sbat.as_deref(),
+        pcr_private_key: args.pcr_private_key.as_deref(),
+        pcr_public_key: args.pcr_public_key.as_deref(),
+        fallback_image: args.fallback_image.as_deref(),
+    };
+
+    if let Some(difference) = reproduce(&args.generation, &args.image, &options)? {
+        bail!("{:?} is not reproducible: {difference}", args.image);
+    }
+    println!("{:?}: reproducible match", args.image);
+
+    Ok(())
+}
+
+/// Read a text file that was passed as an optional argument.
+fn read_optional_file(path: Option<&Path>) -> Result<Option<String>> {
+    path.map(|path| {
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))
+    })
+    .transpose()
+}
@@ -1,5 +1,8 @@
 use std::path::{Path, PathBuf};
 
+use anyhow::{bail, Context, Result};
+use nix::sys::statvfs::statvfs;
+
 use crate::architecture::SystemdArchitectureExt;
 use lanzaboote_tool::architecture::Architecture;
 use lanzaboote_tool::esp::EspPaths;
@@ -68,3 +71,44 @@ impl EspPaths<10> for SystemdEspPaths {
         .into_iter()
     }
 }
+
+/// Check that the ESP has room for files of these sizes before installing them.
+///
+/// Small ESPs fill up quickly, and a write that fails halfway leaves a generation without its
+/// kernel or initrd. Every file needs its full size, rounded up to whole clusters, also if it
+/// replaces an existing file: it is written next to it first and only replaces it atomically
+/// afterwards. The space that replaced files free up is thus available to the files that are
+/// installed after them, which is why this is checked again before every generation.
+pub fn check_free_space(esp: &Path, sizes: &[u64]) -> Result<()> {
+    let stats =
+        statvfs(esp).with_context(|| format!("Failed to query the free space of {esp:?}"))?;
+    let cluster_size = stats.fragment_size() as u64;
+    let available = stats.blocks_available() as u64 * cluster_size;
+
+    let needed = space_needed(sizes, cluster_size);
+    if needed > available {
+        bail!("The ESP {esp:?} needs {needed} bytes of free space, but only {available} bytes are available");
+    }
+
+    Ok(())
+}
+
+/// The space that files of these sizes take up on a file system with this cluster size.
+fn space_needed(sizes: &[u64], cluster_size: u64) -> u64 {
+    let cluster_size = cluster_size.max(1);
+    sizes
+        .iter()
+        .map(|size| size.div_ceil(cluster_size) * cluster_size)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_needed_space_to_clusters() {
+        assert_eq!(space_needed(&[1, 4096, 4097, 0], 4096), 4096 + 4096 + 8192);
+        assert_eq!(space_needed(&[10, 20], 0), 30);
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use goblin::pe::{section_table::SectionTable, PE};
+use nix::sys::statvfs::statvfs;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::install::LATEST_IMAGE;
+use lanzaboote_tool::pe::{
+    esp_path, read_section_data, read_sections_data, INITRD_CONTINUATION_SECTIONS,
+};
+use lanzaboote_tool::utils::file_hash;
+
+/// The sections without which an image cannot be booted: systemd-boot only lists images with an
+/// `.osrel` section, and the stub needs the others.
+const REQUIRED_SECTIONS: &[&str] = &[".osrel", ".cmdline", ".linux", ".initrd"];
+
+/// A problem with the lanzaboote deployment on an ESP.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The image cannot be read or is not a PE file.
+    UnreadableImage { image: PathBuf, error: String },
+    /// Two sections of the image overlap in the file or in memory.
+    OverlappingSections {
+        image: PathBuf,
+        first: String,
+        second: String,
+    },
+    /// A section of the image does not match its hash in `.sectsum` or `.selfsum`.
+    CorruptSection { image: PathBuf, section: String },
+    /// The image lacks a section that is needed to boot it.
+    MissingSection { image: PathBuf, section: String },
+    /// The image references a kernel or initrd that does not exist.
+    MissingPayload { image: PathBuf, payload: PathBuf },
+    /// The image references a kernel or initrd whose hash does not match.
+    PayloadHashMismatch { image: PathBuf, payload: PathBuf },
+    /// A file in `EFI/nixos` that no image references.
+    OrphanedPayload { payload: PathBuf },
+    /// The ESP has not enough free space to install another generation.
+    LowFreeSpace { free: u64, needed: u64 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnreadableImage { image, error } => {
+                write!(f, "{image:?}: cannot be read: {error}")
+            }
+            Self::OverlappingSections {
+                image,
+                first,
+                second,
+            } => write!(f, "{image:?}: section {first} overlaps section {second}"),
+            Self::CorruptSection { image, section } => {
+                write!(f, "{image:?}: section {section} does not match its checksum")
+            }
+            Self::MissingSection { image, section } => {
+                write!(f, "{image:?}: has no {section} section")
+            }
+            Self::MissingPayload { image, payload } => {
+                write!(f, "{image:?}: references missing file {payload:?}")
+            }
+            Self::PayloadHashMismatch { image, payload } => {
+                write!(f, "{image:?}: hash of {payload:?} does not match")
+            }
+            Self::OrphanedPayload { payload } => {
+                write!(f, "{payload:?}: not referenced by any image")
+            }
+            Self::LowFreeSpace { free, needed } => write!(
+                f,
+                "only {free} bytes of free space, but installing another generation needs up to {needed} bytes"
+            ),
+        }
+    }
+}
+
+/// The result of checking the lanzaboote deployment on an ESP.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The number of images that were checked.
+    pub images: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check the consistency of the lanzaboote deployment on an ESP.
+///
+/// Every NixOS image in `EFI/Linux` is checked for overlapping sections and against its section
+/// checksums. The kernel and initrd that it references must exist and match their hashes. Files in
+/// `EFI/nixos` that no image references are reported as orphaned. Finally, the ESP must have
+/// enough free space to install one more generation of the size of the largest one.
+pub fn check_esp(esp: &Path) -> Result<Report> {
+    let images_directory = esp.join("EFI/Linux");
+    let payloads_directory = esp.join("EFI/nixos");
+
+    let mut report = Report::default();
+    let mut referenced = BTreeSet::new();
+    let mut largest_generation = 0;
+
+    for image in nixos_images(&images_directory)? {
+        report.images += 1;
+        let data = match fs::read(&image) {
+            Ok(data) => data,
+            Err(err) => {
+                report.problems.push(Problem::UnreadableImage {
+                    image,
+                    error: err.to_string(),
+                });
+                continue;
+            }
+        };
+        let mut generation_size = data.len() as u64;
+        report.problems.extend(image_problems(&image, &data));
+
+        for (payload, problem) in payload_problems(&image, &data, esp) {
+            referenced.insert(payload.clone());
+            match problem {
+                Some(problem) => report.problems.push(problem),
+                None => generation_size += fs::metadata(&payload).map_or(0, |m| m.len()),
+            }
+        }
+        largest_generation = largest_generation.max(generation_size);
+    }
+
+    for entry in WalkDir::new(&payloads_directory) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err)
+                if err.io_error().map(|err| err.kind()) == Some(std::io::ErrorKind::NotFound) =>
+            {
+                break
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {payloads_directory:?}"))
+            }
+        };
+        let path = entry.into_path();
+        if path.is_file()
+            && !referenced.contains(&path)
+            && path != payloads_directory.join(LATEST_IMAGE)
+        {
+            report
+                .problems
+                .push(Problem::OrphanedPayload { payload: path });
+        }
+    }
+
+    let stats =
+        statvfs(esp).with_context(|| format!("Failed to query the free space of {esp:?}"))?;
+    let free = stats.blocks_available() as u64 * stats.fragment_size() as u64;
+    if free < largest_generation {
+        report.problems.push(Problem::LowFreeSpace {
+            free,
+            needed: largest_generation,
+        });
+    }
+
+    Ok(report)
+}
+
+/// Check a single image before it is installed or booted, e.g. to gate a deployment.
+///
+/// This runs the same checks as [`check_esp`] on the image, i.e. its sections and the kernel and
+/// initrds that it references on the ESP, but nothing else on the ESP.
+pub fn check_image(image: &Path, esp: &Path) -> Result<Report> {
+    let data = fs::read(image).with_context(|| format!("Failed to read image {image:?}"))?;
+
+    let mut problems = image_problems(image, &data);
+    problems.extend(
+        payload_problems(image, &data, esp)
+            .into_iter()
+            .filter_map(|(_, problem)| problem),
+    );
+
+    Ok(Report {
+        images: 1,
+        problems,
+    })
+}
+
+/// Find the NixOS images in a directory, usually `EFI/Linux` on the ESP, sorted by path.
+///
+/// Like garbage collection, this leaves the images of other operating systems alone. A missing
+/// directory has no images.
+pub fn nixos_images(images_directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut images: Vec<PathBuf> = match fs::read_dir(images_directory) {
+        Ok(entries) => entries
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<_, std::io::Error>>()
+            .with_context(|| format!("Failed to read directory {images_directory:?}"))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to read directory {images_directory:?}"))
+        }
+    };
+    images.retain(|image| {
+        image
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("nixos-") && name.ends_with(".efi"))
+    });
+    images.sort();
+
+    Ok(images)
+}
+
+/// Find the problems of an image that do not depend on other files.
+fn image_problems(image: &Path, data: &[u8]) -> Vec<Problem> {
+    let pe = match PE::parse(data) {
+        Ok(pe) => pe,
+        Err(err) => {
+            return vec![Problem::UnreadableImage {
+                image: image.to_path_buf(),
+                error: err.to_string(),
+            }]
+        }
+    };
+
+    let mut problems: Vec<Problem> = overlapping_sections(&pe.sections)
+        .into_iter()
+        .map(|(first, second)| Problem::OverlappingSections {
+            image: image.to_path_buf(),
+            first,
+            second,
+        })
+        .collect();
+
+    problems.extend(
+        REQUIRED_SECTIONS
+            .iter()
+            .filter(|section| read_section_data(data, section).is_none())
+            .map(|section| Problem::MissingSection {
+                image: image.to_path_buf(),
+                section: section.to_string(),
+            }),
+    );
+
+    // The sections of the stub are checked as well, which the stub itself only does at startup.
+    for checksums in [".sectsum", ".selfsum"]
+        .into_iter()
+        .filter_map(|section| read_section_data(data, section))
+    {
+        let mut listed = Vec::new();
+        for line in String::from_utf8_lossy(checksums).lines() {
+            let Some((hash, section)) = line.trim().split_once(char::is_whitespace) else {
+                continue;
+            };
+            let section = section.trim_start();
+            // A section that occurs more than once, like `.dtbauto`, is listed once per occurrence.
+            let occurrence = listed.iter().filter(|name| *name == section).count();
+            listed.push(section.to_string());
+            let matches = read_sections_data(data, section)
+                .get(occurrence)
+                .is_some_and(|section_data| {
+                    format!("{:x}", Sha256::digest(section_data)) == hash.to_ascii_lowercase()
+                });
+            if !matches {
+                problems.push(Problem::CorruptSection {
+                    image: image.to_path_buf(),
+                    section: section.to_string(),
+                });
+            }
+        }
+    }
+
+    problems
+}
+
+/// Check the kernel and initrds that an image references on the ESP against their hashes.
+///
+/// Every referenced file is returned, along with its problem if it has one.
+fn payload_problems(image: &Path, data: &[u8], esp: &Path) -> Vec<(PathBuf, Option<Problem>)> {
+    let payload_sections = [(".linux", ".linuxh"), (".initrd", ".initrdh")]
+        .into_iter()
+        .chain(INITRD_CONTINUATION_SECTIONS.iter().copied());
+
+    let mut payloads = Vec::new();
+    for (section, hash_section) in payload_sections {
+        // Only thin images reference their kernel and initrd by path, and the initrd can also be
+        // embedded, compressed.
+        let Some(payload) = read_section_data(data, section).filter(|p| p.starts_with(b"\\"))
+        else {
+            continue;
+        };
+        let payload = esp_path(esp, &String::from_utf8_lossy(payload));
+
+        let problem = match read_section_data(data, hash_section) {
+            None => Some(Problem::MissingSection {
+                image: image.to_path_buf(),
+                section: hash_section.to_string(),
+            }),
+            Some(hash) => match file_hash(&payload) {
+                Ok(actual) if actual.as_slice() == hash => None,
+                Ok(_) => Some(Problem::PayloadHashMismatch {
+                    image: image.to_path_buf(),
+                    payload: payload.clone(),
+                }),
+                Err(_) => Some(Problem::MissingPayload {
+                    image: image.to_path_buf(),
+                    payload: payload.clone(),
+                }),
+            },
+        };
+        payloads.push((payload, problem));
+    }
+
+    payloads
+}
+
+/// Find the pairs of sections that overlap, either in the file or in memory.
+fn overlapping_sections(sections: &[SectionTable]) -> Vec<(String, String)> {
+    let name = |section: &SectionTable| section.name().unwrap_or("<invalid>").to_string();
+    let mut overlapping = Vec::new();
+
+    for (index, first) in sections.iter().enumerate() {
+        for second in &sections[index + 1..] {
+            let in_file = overlap(
+                first.pointer_to_raw_data,
+                first.size_of_raw_data,
+                second.pointer_to_raw_data,
+                second.size_of_raw_data,
+            );
+            let in_memory = overlap(
+                first.virtual_address,
+                first.virtual_size,
+                second.virtual_address,
+                second.virtual_size,
+            );
+            if in_file || in_memory {
+                overlapping.push((name(first), name(second)));
+            }
+        }
+    }
+
+    overlapping
+}
+
+/// Check whether two non-empty ranges overlap.
+fn overlap(first_start: u32, first_size: u32, second_start: u32, second_size: u32) -> bool {
+    let first_end = u64::from(first_start) + u64::from(first_size);
+    let second_end = u64::from(second_start) + u64::from(second_size);
+    first_size > 0
+        && second_size > 0
+        && u64::from(first_start) < second_end
+        && u64::from(second_start) < first_end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(name: &str, virtual_address: u32, pointer_to_raw_data: u32) -> SectionTable {
+        let mut section = SectionTable {
+            virtual_address,
+            virtual_size: 0x1000,
+            pointer_to_raw_data,
+            size_of_raw_data: 0x1000,
+            ..Default::default()
+        };
+        section.name[..name.len()].copy_from_slice(name.as_bytes());
+        section
+    }
+
+    #[test]
+    fn detect_overlapping_sections() {
+        let sections = [
+            section(".text", 0x1000, 0x400),
+            section(".linux", 0x2000, 0x1400),
+            section(".initrd", 0x2800, 0x2400),
+        ];
+        assert_eq!(
+            overlapping_sections(&sections),
+            [(".linux".to_string(), ".initrd".to_string())]
+        );
+    }
+}
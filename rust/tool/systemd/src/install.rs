@@ -2,7 +2,7 @@ use std::collections::BTreeSet;
 use std::ffi::OsStr;
 use std::fs::{self, File};
 use std::os::fd::AsRawFd;
-use std::os::unix::prelude::{OsStrExt, PermissionsExt};
+use std::os::unix::prelude::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::string::ToString;
 
@@ -13,16 +13,22 @@ use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 
 use crate::architecture::SystemdArchitectureExt;
-use crate::esp::SystemdEspPaths;
+use crate::build_cache::BuildCache;
+use crate::esp::{check_free_space, SystemdEspPaths};
+use crate::loader_conf::{matches_pattern, LoaderConfig};
+use crate::retention::{booted_generation, RetentionPolicy};
 use crate::version::SystemdVersion;
 use lanzaboote_tool::architecture::Architecture;
-use lanzaboote_tool::esp::EspPaths;
+use lanzaboote_tool::esp::{
+    content_addressed_path, ensure_parent_dir, install, install_content_addressed, EspPaths,
+};
 use lanzaboote_tool::gc::Roots;
 use lanzaboote_tool::generation::{Generation, GenerationLink};
 use lanzaboote_tool::os_release::OsRelease;
 use lanzaboote_tool::pe::{self, append_initrd_secrets, lanzaboote_image};
+use lanzaboote_tool::sbat::{baseline_revocations, installed_sbat_revocations, read_sbat};
 use lanzaboote_tool::signature::Signer;
-use lanzaboote_tool::utils::{file_hash, SecureTempDirExt};
+use lanzaboote_tool::utils::{SecureTempDirExt, TempFileGuard};
 
 pub struct Installer<S: Signer> {
     broken_gens: BTreeSet<u64>,
@@ -31,12 +37,26 @@ pub struct Installer<S: Signer> {
     systemd: PathBuf,
     systemd_boot_loader_config: PathBuf,
     signer: S,
-    configuration_limit: usize,
+    retention_policy: RetentionPolicy,
     esp_paths: SystemdEspPaths,
     generation_links: Vec<PathBuf>,
     arch: Architecture,
+    boot_message: Option<String>,
+    cmdline_fallback: Option<String>,
+    latest_image: bool,
+    fallback_image: bool,
+    build_cache: Option<BuildCache>,
+    verify_images: bool,
+    sbat: Option<String>,
+    sbat_baseline: Option<String>,
+    pcr_private_key: Option<PathBuf>,
+    pcr_public_key: Option<PathBuf>,
 }
 
+/// File name of the copy of the newest generation's image in `EFI/nixos`, see
+/// [`Installer::with_latest_image`].
+pub(crate) const LATEST_IMAGE: &str = "latest.efi";
+
 #[allow(clippy::too_many_arguments)]
 impl<S: Signer> Installer<S> {
     pub fn new(
@@ -60,41 +80,146 @@ impl<S: Signer> Installer<S> {
             systemd,
             systemd_boot_loader_config,
             signer,
-            configuration_limit,
+            retention_policy: RetentionPolicy {
+                configuration_limit,
+                max_age_days: None,
+            },
             esp_paths,
             generation_links,
             arch,
+            boot_message: None,
+            cmdline_fallback: None,
+            latest_image: false,
+            fallback_image: false,
+            build_cache: None,
+            verify_images: false,
+            sbat: None,
+            sbat_baseline: None,
+            pcr_private_key: None,
+            pcr_public_key: None,
         }
     }
 
+    /// Show a message before booting any of the installed generations.
+    pub fn with_boot_message(mut self, boot_message: Option<String>) -> Self {
+        self.boot_message = boot_message;
+        self
+    }
+
+    /// Append these kernel command line parameters when a generation is booted for the last time
+    /// according to its boot counter, e.g. to boot into a rescue shell.
+    pub fn with_cmdline_fallback(mut self, cmdline_fallback: Option<String>) -> Self {
+        self.cmdline_fallback = cmdline_fallback;
+        self
+    }
+
+    /// Keep a copy of the newest generation's image at `EFI/nixos/latest.efi`, so that firmware
+    /// boot entries can point at a stable path.
+    pub fn with_latest_image(mut self, latest_image: bool) -> Self {
+        self.latest_image = latest_image;
+        self
+    }
+
+    /// Let the image of every generation boot the image of the generation before it if its own
+    /// kernel or initrd does not match its hash, e.g. because it was corrupted on the ESP.
+    pub fn with_fallback_image(mut self, fallback_image: bool) -> Self {
+        self.fallback_image = fallback_image;
+        self
+    }
+
+    /// Only keep generations that were built at most this many days ago, in addition to the
+    /// configuration limit. The newest and the booted generation are kept regardless.
+    pub fn with_max_generation_age(mut self, max_age_days: Option<u64>) -> Self {
+        self.retention_policy.max_age_days = max_age_days;
+        self
+    }
+
+    /// Skip rebuilding generations whose inputs did not change since the last installation, see
+    /// [`BuildCache`]. The cache is stored at the given path.
+    pub fn with_build_cache(mut self, build_cache: Option<PathBuf>) -> Self {
+        self.build_cache = build_cache.map(BuildCache::load);
+        self
+    }
+
+    /// Check every built image against the kernel and initrds on the ESP before installing it, see
+    /// [`pe::verify_image`].
+    pub fn with_verify_images(mut self, verify_images: bool) -> Self {
+        self.verify_images = verify_images;
+        self
+    }
+
+    /// Embed this SBAT data in CSV format in every image, see [`pe::StubParameters::with_sbat`].
+    pub fn with_sbat(mut self, sbat: Option<String>) -> Self {
+        self.sbat = sbat;
+        self
+    }
+
+    /// Warn about generations whose stub or kernel this SBAT revocation baseline revokes, e.g. an
+    /// `SbatLevel` that is about to be rolled out.
+    pub fn with_sbat_baseline(mut self, sbat_baseline: Option<String>) -> Self {
+        self.sbat_baseline = sbat_baseline;
+        self
+    }
+
+    /// Sign the predicted values of PCR 11 of every image with this RSA private key in PEM format,
+    /// see [`pe::StubParameters::with_pcr_signing_key`].
+    pub fn with_pcr_private_key(mut self, pcr_private_key: Option<PathBuf>) -> Self {
+        self.pcr_private_key = pcr_private_key;
+        self
+    }
+
+    /// Embed this RSA public key in PEM format in every image, see
+    /// [`pe::StubParameters::with_pcr_public_key`].
+    pub fn with_pcr_public_key(mut self, pcr_public_key: Option<PathBuf>) -> Self {
+        self.pcr_public_key = pcr_public_key;
+        self
+    }
+
     pub fn install(&mut self) -> Result<()> {
         log::info!("Installing Lanzaboote to {:?}...", self.esp_paths.esp);
 
-        let mut links = self
+        // Firmware refuses to boot a revoked stub, which would leave the machine unbootable.
+        match installed_sbat_revocations(&self.lanzaboote_stub) {
+            Ok(revocations) => {
+                for revocation in revocations {
+                    log::warn!(
+                        "The stub is revoked by the SBAT policy of this machine: {revocation}"
+                    );
+                }
+            }
+            Err(err) => log::warn!("Failed to check the stub against the SBAT policy: {err:#}"),
+        }
+
+        let links = self
             .generation_links
             .iter()
             .map(GenerationLink::from_path)
             .collect::<Result<Vec<GenerationLink>>>()?;
 
-        // Sort the links by version, so that the limit actually skips the oldest generations.
-        links.sort_by_key(|l| l.version);
-
-        // A configuration limit of 0 means there is no limit.
-        if self.configuration_limit > 0 {
-            // Only install the number of generations configured. Reverse the list to only take the
-            // latest generations and then, after taking them, reverse the list again so that the
-            // generations are installed from oldest to newest, i.e. from smallest to largest
-            // generation version.
-            links = links
-                .into_iter()
-                .rev()
-                .take(self.configuration_limit)
-                .rev()
-                .collect()
-        };
+        // Generations that are not installed are garbage collected below, so the booted generation
+        // must never be skipped, or the running system would lose its boot entry.
+        let booted = booted_generation(&self.esp_paths.linux);
+        if let Some(booted) = booted {
+            if !links.iter().any(|link| link.version == booted) {
+                log::warn!(
+                    "The booted generation {booted} no longer exists and cannot be kept on the ESP."
+                );
+            }
+        }
+        let today = time::OffsetDateTime::now_utc().date();
+        // The generations are installed from oldest to newest, i.e. from smallest to largest
+        // generation version.
+        let links = self.retention_policy.retain(links, today, booted);
         self.install_generations_from_links(&links)?;
 
         self.install_systemd_boot()?;
+        if let Err(err) = self.check_default_entry() {
+            log::warn!("Failed to check the default boot entry: {err:#}");
+        }
+
+        if let Some(build_cache) = &self.build_cache {
+            build_cache.report();
+        }
 
         if self.broken_gens.is_empty() {
             log::info!("Collecting garbage...");
@@ -102,16 +227,23 @@ impl<S: Signer> Installer<S> {
             // the NixOS installation are deleted. Lanzatool takes full control over the esp/EFI/nixos
             // directory and deletes ALL files that it doesn't know about. Dual- or multiboot setups
             // that need files in this directory will NOT work.
-            self.gc_roots.collect_garbage(&self.esp_paths.nixos)?;
+            let mut removed = self.gc_roots.collect_garbage(&self.esp_paths.nixos)?;
             // The esp/EFI/Linux directory is assumed to be potentially shared with other distros.
-            // Thus, only files that start with "nixos-" are garbage collected (i.e. potentially
-            // deleted).
-            self.gc_roots
-                .collect_garbage_with_filter(&self.esp_paths.linux, |p| {
+            // Thus, only files that start with "nixos-" and that lzbt built are garbage collected
+            // (i.e. potentially deleted). Anything else, e.g. a UKI that was copied there by hand or
+            // a drop-in directory, is left alone.
+            removed.extend(self.gc_roots.collect_garbage_with_filter(
+                &self.esp_paths.linux,
+                |p| {
                     p.file_name()
                         .and_then(|n| n.to_str())
                         .map_or(false, |n| n.starts_with("nixos-"))
-                })?;
+                        && fs::read(p).map_or(false, |data| pe::is_lanzaboote_image(&data))
+                },
+            )?);
+            for path in &removed {
+                log::info!("Removed unused {path:?}");
+            }
         } else {
             // This might produce a ridiculous message if you have a lot of malformed generations.
             let warning = indoc::formatdoc! {"
@@ -124,6 +256,10 @@ impl<S: Signer> Installer<S> {
             log::warn!("{warning}");
         };
 
+        if let Some(build_cache) = &mut self.build_cache {
+            build_cache.save()?;
+        }
+
         log::info!("Successfully installed Lanzaboote.");
         Ok(())
     }
@@ -156,16 +292,33 @@ impl<S: Signer> Installer<S> {
             return Err(anyhow!("No bootable generations found! Aborting to avoid unbootable system. Please check for Lanzaboote updates!"));
         }
 
-        for generation in generations {
+        // The image of a generation is the known-good fallback of the next one, see
+        // `with_fallback_image`.
+        let mut previous_image: Option<PathBuf> = None;
+        for generation in &generations {
             // The kernels and initrds are content-addressed.
             // Thus, this cannot overwrite files of old generation with different content.
-            self.install_generation(&generation)
+            self.install_generation(generation, previous_image.as_deref())
                 .with_context(|| format!("Failed to install generation {}", generation.version))?;
             for (name, bootspec) in &generation.spec.bootspec.specialisations {
                 let specialised_generation = generation.specialise(name, bootspec);
-                self.install_generation(&specialised_generation)
+                self.install_generation(&specialised_generation, previous_image.as_deref())
                     .context("Failed to install specialisation.")?;
             }
+            if self.fallback_image {
+                let image = self
+                    .esp_paths
+                    .linux
+                    .join(stub_name(generation, &self.signer).context("Get stub name")?);
+                previous_image = image.exists().then_some(image);
+            }
+        }
+
+        if self.latest_image {
+            // The generations are sorted from oldest to newest.
+            if let Some(newest_generation) = generations.last() {
+                self.install_latest_image(newest_generation)?;
+            }
         }
 
         // Sync files to persistent storage. This may improve the
@@ -182,32 +335,131 @@ impl<S: Signer> Installer<S> {
     /// The kernel and initrd are content-addressed, and the stub name identifies the generation.
     /// Hence, this function cannot overwrite files of other generations with different contents.
     /// All installed files are added as garbage collector roots.
-    fn install_generation(&mut self, generation: &Generation) -> Result<()> {
-        // If the generation is already properly installed, don't overwrite it.
-        if self.register_installed_generation(generation).is_ok() {
-            return Ok(());
+    ///
+    /// `fallback_image` is the installed image that the stub boots instead if the kernel or an
+    /// initrd of this generation does not match its hash.
+    fn install_generation(
+        &mut self,
+        generation: &Generation,
+        fallback_image: Option<&Path>,
+    ) -> Result<()> {
+        let stub_target = self
+            .esp_paths
+            .linux
+            .join(stub_name(generation, &self.signer).context("Get stub name")?);
+        let installed = self.register_installed_generation(generation).is_ok();
+
+        // Without a build cache, an installed generation is never overwritten. With one, it is
+        // rebuilt if any of its inputs changed, e.g. the stub.
+        let fingerprint = match self.build_cache.take() {
+            None if installed => return Ok(()),
+            None => None,
+            Some(mut build_cache) => {
+                let fingerprint = self
+                    .fingerprint(&mut build_cache, generation, fallback_image)
+                    .inspect_err(|err| {
+                        log::warn!("Failed to fingerprint generation {generation}: {err:#}")
+                    })
+                    .ok();
+                let fresh = installed
+                    && fingerprint
+                        .as_ref()
+                        .is_some_and(|fingerprint| build_cache.is_fresh(&stub_target, fingerprint));
+                self.build_cache = Some(build_cache);
+                if fresh {
+                    return Ok(());
+                }
+                fingerprint
+            }
+        };
+
+        match self.build_generation(generation, &stub_target, fallback_image) {
+            Ok(()) => {}
+            // Old generations cannot always be rebuilt, e.g. because their initrd secrets are
+            // gone. Their installed image still boots.
+            Err(err) if installed => {
+                log::warn!(
+                    "Failed to rebuild generation {generation}, keeping the installed image: {err:#}"
+                );
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        }
+
+        if let (Some(build_cache), Some(fingerprint)) = (&mut self.build_cache, fingerprint) {
+            build_cache.record(&stub_target, fingerprint)?;
         }
 
+        Ok(())
+    }
+
+    /// Compute the fingerprint of the inputs of a generation's image for the build cache.
+    fn fingerprint(
+        &self,
+        build_cache: &mut BuildCache,
+        generation: &Generation,
+        fallback_image: Option<&Path>,
+    ) -> Result<String> {
+        let bootspec = &generation.spec.bootspec.bootspec;
+        let public_key = self.signer.get_public_key()?;
+        let os_release = OsRelease::from_generation(generation)
+            .context("Failed to build OsRelease from generation.")?
+            .to_string();
+        let kernel_cmdline =
+            assemble_kernel_cmdline(&bootspec.init, bootspec.kernel_params.clone()).join(" ");
+        let initrd_secrets = bootspec
+            .initrd_secrets
+            .as_ref()
+            .map_or(&[][..], |script| script.as_os_str().as_bytes());
+
+        let mut files = vec![self.lanzaboote_stub.as_path(), bootspec.kernel.as_path()];
+        files.extend(bootspec.initrd.as_deref());
+        files.extend(self.pcr_private_key.as_deref());
+        files.extend(self.pcr_public_key.as_deref());
+        files.extend(fallback_image);
+        build_cache.fingerprint(
+            &files,
+            &[
+                ("public_key", &public_key),
+                ("cmdline", kernel_cmdline.as_bytes()),
+                ("os_release", os_release.as_bytes()),
+                ("initrd_secrets", initrd_secrets),
+                (
+                    "boot_message",
+                    self.boot_message.as_deref().unwrap_or_default().as_bytes(),
+                ),
+                (
+                    "cmdline_fallback",
+                    self.cmdline_fallback
+                        .as_deref()
+                        .unwrap_or_default()
+                        .as_bytes(),
+                ),
+                ("sbat", self.sbat.as_deref().unwrap_or_default().as_bytes()),
+            ],
+        )
+    }
+
+    /// Build, sign and install the image of the given `Generation` to `stub_target`.
+    ///
+    /// Nothing is written to the ESP before it is clear that the kernel, the initrd and the image
+    /// fit on it.
+    fn build_generation(
+        &mut self,
+        generation: &Generation,
+        stub_target: &Path,
+        fallback_image: Option<&Path>,
+    ) -> Result<()> {
         let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
         let bootspec = &generation.spec.bootspec.bootspec;
+        let kernel_version = kernel_version(generation)?;
+        let kernel_label = format!("kernel-{}", kernel_version);
+        let initrd_label = format!("initrd-{}", kernel_version);
 
-        // The kernel is a file in /nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-<version>/.
-        // (On x86, that file is called bzImage, but other architectures may differ.)
-        let kernel_dirname = bootspec
-            .kernel
-            .parent()
-            .and_then(Path::file_name)
-            .and_then(OsStr::to_str)
-            .context("Failed to extract the kernel directory name.")?;
-        let kernel_version = kernel_dirname
-            .rsplit('-')
-            .next()
-            .context("Failed to extract the kernel version.")?;
-
-        // Install the kernel and record its path on the ESP.
-        let kernel_target = self
-            .install_nixos_ca(&bootspec.kernel, &format!("kernel-{}", kernel_version))
-            .context("Failed to install the kernel.")?;
+        // The kernel and initrd are content-addressed, so their paths on the ESP are known before
+        // they are installed.
+        let kernel_target =
+            content_addressed_path(&bootspec.kernel, &self.esp_paths.nixos, &kernel_label)?;
 
         // Assemble and install the initrd, and record its path on the ESP.
         // It is not needed to write the initrd in a temporary directory
@@ -234,44 +486,108 @@ impl<S: Signer> Installer<S> {
         if let Some(initrd_secrets_script) = &bootspec.initrd_secrets {
             append_initrd_secrets(initrd_secrets_script, &initrd_location, generation.version)?;
         }
-        let initrd_target = self
-            .install_nixos_ca(&initrd_location, &format!("initrd-{}", kernel_version))
-            .context("Failed to install the initrd.")?;
+        let initrd_target =
+            content_addressed_path(&initrd_location, &self.esp_paths.nixos, &initrd_label)?;
 
         // Assemble, sign and install the Lanzaboote stub.
-        let os_release = OsRelease::from_generation(generation)
-            .context("Failed to build OsRelease from generation.")?;
-
-        let os_release_contents = os_release.to_string();
-
-        let kernel_cmdline =
-            assemble_kernel_cmdline(&bootspec.init, bootspec.kernel_params.clone());
-
-        let parameters = pe::StubParameters::new(
+        let mut parameters = stub_parameters(
             &self.lanzaboote_stub,
-            &bootspec.kernel,
+            &self.esp_paths.esp,
+            generation,
             &initrd_location,
             &kernel_target,
             &initrd_target,
-            &self.esp_paths.esp,
-        )?
-        .with_cmdline(&kernel_cmdline)
-        .with_os_release_contents(os_release_contents.as_bytes());
+        )?;
+        if let Some(boot_message) = &self.boot_message {
+            parameters = parameters.with_boot_message(boot_message);
+        }
+        if let Some(cmdline_fallback) = &self.cmdline_fallback {
+            parameters = parameters.with_cmdline_fallback(cmdline_fallback);
+        }
+        if let Some(sbat) = &self.sbat {
+            parameters = parameters.with_sbat(sbat);
+        }
+        if let Some(pcr_private_key) = &self.pcr_private_key {
+            parameters = parameters.with_pcr_signing_key(pcr_private_key);
+        }
+        if let Some(pcr_public_key) = &self.pcr_public_key {
+            parameters = parameters.with_pcr_public_key(pcr_public_key);
+        }
+        if let Some(fallback_image) = fallback_image {
+            parameters = parameters.with_fallback_image(&self.esp_paths.esp, fallback_image)?;
+        }
+        if let Some(sbat_baseline) = &self.sbat_baseline {
+            if let Err(err) = self.check_sbat_baseline(generation, sbat_baseline) {
+                log::warn!(
+                    "Failed to check generation {generation} against the SBAT baseline: {err:#}"
+                );
+            }
+        }
 
         let lanzaboote_image_path = lanzaboote_image(&tempdir, &parameters)
             .context("Failed to build and sign lanzaboote stub image.")?;
 
-        let stub_target = self
-            .esp_paths
-            .linux
-            .join(stub_name(generation, &self.signer).context("Get stub name")?);
-        self.gc_roots.extend([&stub_target]);
-        install_signed(&self.signer, &lanzaboote_image_path, &stub_target)
+        // Content-addressed files that are already installed are not copied again.
+        let mut sizes = Vec::new();
+        for (from, to) in [
+            (&bootspec.kernel, &kernel_target),
+            (&initrd_location, &initrd_target),
+        ] {
+            if !to.exists() {
+                sizes.push(file_size(from)?);
+            }
+        }
+        sizes.push(file_size(&lanzaboote_image_path)? + SIGNATURE_RESERVE);
+        check_free_space(&self.esp_paths.esp, &sizes)
+            .with_context(|| format!("Not enough space to install generation {generation}"))?;
+
+        self.install_nixos_ca(&bootspec.kernel, &kernel_label)
+            .context("Failed to install the kernel.")?;
+        self.install_nixos_ca(&initrd_location, &initrd_label)
+            .context("Failed to install the initrd.")?;
+        if self.verify_images {
+            pe::verify_image(&lanzaboote_image_path, &self.esp_paths.esp)?;
+        }
+
+        self.gc_roots.extend([&stub_target.to_path_buf()]);
+        install_signed(&self.signer, &lanzaboote_image_path, stub_target)
             .context("Failed to install the Lanzaboote stub.")?;
 
         Ok(())
     }
 
+    /// Warn about the components of the stub and the kernel of a generation, including the SBAT
+    /// data that is embedded in its image, that an SBAT revocation baseline revokes.
+    fn check_sbat_baseline(&self, generation: &Generation, sbat_baseline: &str) -> Result<()> {
+        let kernel = &generation.spec.bootspec.bootspec.kernel;
+        let sbat = [
+            read_sbat(&self.lanzaboote_stub)?,
+            read_sbat(kernel)?,
+            self.sbat.clone(),
+        ];
+        let sbat: Vec<&str> = sbat.iter().flatten().map(String::as_str).collect();
+
+        for revocation in baseline_revocations(&sbat, sbat_baseline) {
+            log::warn!("Generation {generation} is revoked by the SBAT baseline: {revocation}");
+        }
+        Ok(())
+    }
+
+    /// Copy the image of the newest generation to `EFI/nixos/latest.efi`.
+    ///
+    /// FAT has no symlinks, so this is a copy of the image. It replaces the previous copy
+    /// atomically and is only written if the newest generation changed.
+    fn install_latest_image(&mut self, generation: &Generation) -> Result<()> {
+        let image = self
+            .esp_paths
+            .linux
+            .join(stub_name(generation, &self.signer).context("Get stub name")?);
+        let latest_image = self.esp_paths.nixos.join(LATEST_IMAGE);
+        self.gc_roots.extend([&latest_image]);
+        install(&image, &latest_image)
+            .with_context(|| format!("Failed to install the newest image to {latest_image:?}"))
+    }
+
     /// Register the files of an already installed generation as garbage collection roots.
     ///
     /// An error should not be considered fatal; the generation should be (re-)installed instead.
@@ -305,14 +621,8 @@ impl<S: Signer> Installer<S> {
     /// It is automatically added to the garbage collector roots.
     /// The full path to the target file is returned.
     fn install_nixos_ca(&mut self, from: &Path, label: &str) -> Result<PathBuf> {
-        let hash = file_hash(from).context("Failed to read the source file.")?;
-        let to = self.esp_paths.nixos.join(format!(
-            "{}-{}.efi",
-            label,
-            Base32Unpadded::encode_string(&hash)
-        ));
+        let to = install_content_addressed(from, &self.esp_paths.nixos, label)?;
         self.gc_roots.extend([&to]);
-        install(from, &to)?;
         Ok(to)
     }
 
@@ -364,6 +674,52 @@ impl<S: Signer> Installer<S> {
 
         Ok(())
     }
+
+    /// Warn if the `default` setting of the installed `loader.conf` matches none of the boot
+    /// entries on the ESP, in which case systemd-boot silently boots the first entry instead.
+    fn check_default_entry(&self) -> Result<()> {
+        let config = LoaderConfig::read(&self.esp_paths.systemd_boot_loader_config)?;
+        let Some(pattern) = config.default_pattern() else {
+            return Ok(());
+        };
+        // systemd-boot adds entries like `auto-windows` itself, depending on what it finds.
+        if pattern.starts_with("auto-") {
+            return Ok(());
+        }
+
+        let entries_directory = self.esp_paths.loader.join("entries");
+        let mut entries = entry_ids(&self.esp_paths.linux, ".efi")?;
+        entries.extend(entry_ids(&entries_directory, ".conf")?);
+        if !entries.iter().any(|entry| matches_pattern(pattern, entry)) {
+            log::warn!(
+                "The default boot entry `{pattern}` of loader.conf matches none of the boot entries on the ESP, systemd-boot will boot another one."
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// The IDs of the boot entries that systemd-boot finds in a directory, i.e. the names of the files
+/// with the given extension. A missing directory has no entries.
+fn entry_ids(directory: &Path, extension: &str) -> Result<Vec<String>> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read directory {directory:?}"))
+        }
+    };
+
+    let mut ids = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to read directory {directory:?}"))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.to_ascii_lowercase().ends_with(extension) {
+            ids.push(name);
+        }
+    }
+    Ok(ids)
 }
 
 /// Translate an EFI path to an absolute path on the mounted ESP.
@@ -407,46 +763,73 @@ fn stub_name<S: Signer>(generation: &Generation, signer: &S) -> Result<PathBuf>
 ///
 /// This is implemented as an atomic write. The file is first written to the destination with a
 /// `.tmp` suffix and then renamed to its final name. This is atomic, because a rename is an atomic
-/// operation on POSIX platforms.
+/// operation on POSIX platforms. If signing fails, the temporary file is removed again.
 fn install_signed(signer: &impl Signer, from: &Path, to: &Path) -> Result<()> {
     log::debug!("Signing and installing {to:?}...");
-    let to_tmp = to.with_extension(".tmp");
-    ensure_parent_dir(&to_tmp);
+    let to_tmp = TempFileGuard::new(to.with_extension(".tmp"));
+    ensure_parent_dir(to_tmp.path());
     signer
-        .sign_and_copy(from, &to_tmp)
+        .sign_and_copy(from, to_tmp.path())
         .with_context(|| format!("Failed to copy and sign file from {from:?} to {to:?}"))?;
-    fs::rename(&to_tmp, to).with_context(|| {
-        format!("Failed to move temporary file {to_tmp:?} to final location {to:?}")
-    })?;
-    Ok(())
+    to_tmp.persist(to)
 }
 
-/// Install an arbitrary file.
-///
-/// The file is only copied if
-///     (1) it doesn't exist at the destination or,
-///     (2) the hash of the file at the destination does not match the hash of the source file.
-fn install(from: &Path, to: &Path) -> Result<()> {
-    if !to.exists() || file_hash(from)? != file_hash(to)? {
-        force_install(from, to)?;
-    }
-    Ok(())
+/// The space to reserve for the Authenticode signature that is added to an image when it is
+/// installed, i.e. the certificate and the signature.
+const SIGNATURE_RESERVE: u64 = 16 * 1024;
+
+fn file_size(path: &Path) -> Result<u64> {
+    Ok(fs::metadata(path)
+        .with_context(|| format!("Failed to read the size of {path:?}"))?
+        .len())
 }
 
-/// Forcibly install an arbitrary file.
-///
-/// If the file already exists at the destination, it is overwritten.
+/// Extract the kernel version of a generation, e.g. `6.1.1`.
+pub(crate) fn kernel_version(generation: &Generation) -> Result<&str> {
+    // The kernel is a file in /nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-linux-<version>/.
+    // (On x86, that file is called bzImage, but other architectures may differ.)
+    let kernel_dirname = generation
+        .spec
+        .bootspec
+        .bootspec
+        .kernel
+        .parent()
+        .and_then(Path::file_name)
+        .and_then(OsStr::to_str)
+        .context("Failed to extract the kernel directory name.")?;
+    kernel_dirname
+        .rsplit('-')
+        .next()
+        .context("Failed to extract the kernel version.")
+}
+
+/// Assemble the parameters of the image of a generation.
 ///
-/// This function is only designed to copy files to the ESP. It sets the permission bits of the
-/// file at the destination to 0o755, the expected permissions for a vfat ESP. This is useful for
-/// producing file systems trees which can then be converted to a file system image.
-fn force_install(from: &Path, to: &Path) -> Result<()> {
-    log::debug!("Installing {to:?}...");
-    ensure_parent_dir(to);
-    atomic_copy(from, to)?;
-    set_permission_bits(to, 0o755)
-        .with_context(|| format!("Failed to set permission bits to 0o755 on file: {to:?}"))?;
-    Ok(())
+/// `initrd` is the initrd that the image references, including the initrd secrets, if any. The
+/// kernel and initrd are expected on the ESP at `kernel_target` and `initrd_target`.
+pub(crate) fn stub_parameters(
+    lanzaboote_stub: &Path,
+    esp: &Path,
+    generation: &Generation,
+    initrd: &Path,
+    kernel_target: &Path,
+    initrd_target: &Path,
+) -> Result<pe::StubParameters> {
+    let bootspec = &generation.spec.bootspec.bootspec;
+    let os_release = OsRelease::from_generation(generation)
+        .context("Failed to build OsRelease from generation.")?;
+    let kernel_cmdline = assemble_kernel_cmdline(&bootspec.init, bootspec.kernel_params.clone());
+
+    Ok(pe::StubParameters::new(
+        lanzaboote_stub,
+        &bootspec.kernel,
+        initrd,
+        kernel_target,
+        initrd_target,
+        esp,
+    )?
+    .with_cmdline(&kernel_cmdline)
+    .with_os_release_contents(os_release.to_string().as_bytes()))
 }
 
 fn assemble_kernel_cmdline(init: &Path, kernel_params: Vec<String>) -> Vec<String> {
@@ -459,50 +842,6 @@ fn assemble_kernel_cmdline(init: &Path, kernel_params: Vec<String>) -> Vec<Strin
     kernel_cmdline
 }
 
-/// Atomically copy a file.
-///
-/// First, the content is written to a temporary file (with a `.tmp` extension).
-/// Then, this file is synced, to ensure its data and metadata are fully on disk before continuing.
-/// In the last step, the temporary file is renamed to the final destination.
-///
-/// Due to the deficiencies of FAT32, it is possible for the filesystem to become corrupted after power loss.
-/// It is not possible to fully defend against this situation, so this operation is not actually fully atomic.
-/// However, in all other cases, the target file is either present with its correct content or not present at all.
-fn atomic_copy(from: &Path, to: &Path) -> Result<()> {
-    let tmp = to.with_extension(".tmp");
-    {
-        let mut from_file =
-            File::open(from).with_context(|| format!("Failed to read the source file {from:?}"))?;
-        let mut tmp_file = File::create(&tmp)
-            .with_context(|| format!("Failed to create the temporary file {tmp:?}"))?;
-        std::io::copy(&mut from_file, &mut tmp_file).with_context(|| {
-            format!("Failed to copy from {from:?} to the temporary file {tmp:?}")
-        })?;
-        tmp_file
-            .sync_all()
-            .with_context(|| format!("Failed to sync the temporary file {tmp:?}"))?;
-    }
-    fs::rename(&tmp, to)
-        .with_context(|| format!("Failed to move temporary file {tmp:?} to target {to:?}"))
-}
-
-/// Set the octal permission bits of the specified file.
-fn set_permission_bits(path: &Path, permission_bits: u32) -> Result<()> {
-    let mut perms = fs::metadata(path)
-        .with_context(|| format!("File {path:?} doesn't have any metadata"))?
-        .permissions();
-    perms.set_mode(permission_bits);
-    fs::set_permissions(path, perms)
-        .with_context(|| format!("Failed to set permissions on {path:?}"))
-}
-
-// Ensures the parent directory of an arbitrary path exists
-fn ensure_parent_dir(path: &Path) {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent).ok();
-    }
-}
-
 /// Determine if a newer systemd-boot version is available.
 ///
 /// "Newer" can mean
@@ -528,3 +867,67 @@ fn newer_systemd_boot(from: &Path, to: &Path) -> Result<bool> {
 
     Ok(from_version > to_version)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use lanzaboote_tool::signature::local::LocalKeyPair;
+    use serde_json::json;
+
+    #[test]
+    fn sbat_changes_invalidate_cached_images() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let stub = dir.path().join("stub.efi");
+        let kernel = dir.path().join("kernel");
+        let image = dir.path().join("image.efi");
+        fs::write(&stub, b"stub")?;
+        fs::write(&kernel, b"kernel")?;
+        fs::write(&image, b"image")?;
+
+        let link = dir.path().join("system-1-link");
+        fs::create_dir(&link)?;
+        let bootspec = json!({
+            "org.nixos.bootspec.v1": {
+                "init": "/init",
+                "initrd": null,
+                "kernel": kernel,
+                "kernelParams": [],
+                "label": "LanzaOS",
+                "toplevel": dir.path(),
+                "system": "x86_64-linux",
+            },
+        });
+        fs::write(link.join("boot.json"), serde_json::to_vec(&bootspec)?)?;
+        let generation = Generation::from_link(&GenerationLink::from_path(&link)?)?;
+
+        let installer = |sbat: &str| {
+            Installer::new(
+                stub.clone(),
+                Architecture::X86,
+                PathBuf::new(),
+                PathBuf::new(),
+                LocalKeyPair::new(
+                    Path::new("tests/fixtures/uefi-keys/db.pem"),
+                    Path::new("tests/fixtures/uefi-keys/db.key"),
+                ),
+                0,
+                dir.path().join("esp"),
+                Vec::new(),
+            )
+            .with_sbat(Some(sbat.to_string()))
+        };
+
+        let sbat = "sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n";
+        let mut cache = BuildCache::load(dir.path().join("cache"));
+        let fingerprint = installer(sbat).fingerprint(&mut cache, &generation, None)?;
+        cache.record(&image, fingerprint.clone())?;
+        assert!(cache.is_fresh(&image, &fingerprint));
+
+        let bumped_sbat = format!("{sbat}lanzaboote,2,Lanzaboote,lanzaboote,1,https://github.com/nix-community/lanzaboote\n");
+        let bumped = installer(&bumped_sbat).fingerprint(&mut cache, &generation, None)?;
+        assert!(!cache.is_fresh(&image, &bumped));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+use crate::fsck::nixos_images;
+use crate::retention::generation_of_entry;
+use lanzaboote_tool::os_release::OsRelease;
+use lanzaboote_tool::pe::read_section_data;
+
+/// What an installed image tells a human about itself.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImageIdentity {
+    pub image: PathBuf,
+    /// `PRETTY_NAME` of the `.osrel` section, i.e. what systemd-boot shows in its menu.
+    pub pretty_name: Option<String>,
+    /// The kernel version in the `.uname` section.
+    pub uname: Option<String>,
+}
+
+impl ImageIdentity {
+    /// Read the identity of an image from its sections.
+    pub fn read(image: &Path) -> Result<Self> {
+        let data = fs::read(image).with_context(|| format!("Failed to read image {image:?}"))?;
+        let section_string = |section_name| {
+            read_section_data(&data, section_name)
+                .map(|section| String::from_utf8_lossy(section).trim().to_string())
+                .filter(|section| !section.is_empty())
+        };
+
+        Ok(Self {
+            image: image.to_path_buf(),
+            pretty_name: section_string(".osrel")
+                .and_then(|os_release| OsRelease::from_str(&os_release).ok())
+                .and_then(|os_release| os_release.0.get("PRETTY_NAME").cloned()),
+            uname: section_string(".uname"),
+        })
+    }
+
+    fn file_name(&self) -> String {
+        self.image
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// The generation number in the file name of the image, e.g. `3` for
+    /// `nixos-generation-3-<hash>.efi`.
+    fn generation(&self) -> Option<u64> {
+        generation_of_entry(&self.file_name())
+    }
+}
+
+/// Read the identities of the NixOS images on an ESP, sorted by path.
+pub fn list_images(esp: &Path) -> Result<Vec<ImageIdentity>> {
+    nixos_images(&esp.join("EFI/Linux"))?
+        .iter()
+        .map(|image| ImageIdentity::read(image))
+        .collect()
+}
+
+/// Label every image so that they can be told apart.
+///
+/// An image is labelled with its pretty name, or its file name if it has none. Images that share a
+/// label, e.g. because they only differ in their kernel, are disambiguated by their kernel version
+/// from `.uname`, or by their generation number if they have none.
+pub fn labels(identities: &[ImageIdentity]) -> Vec<String> {
+    let names: Vec<String> = identities
+        .iter()
+        .map(|identity| {
+            identity
+                .pretty_name
+                .clone()
+                .unwrap_or_else(|| identity.file_name())
+        })
+        .collect();
+
+    names
+        .iter()
+        .zip(identities)
+        .map(|(name, identity)| {
+            if names.iter().filter(|other| *other == name).count() < 2 {
+                return name.clone();
+            }
+            match (&identity.uname, identity.generation()) {
+                (Some(uname), _) => format!("{name} [kernel {uname}]"),
+                (None, Some(generation)) => format!("{name} [generation {generation}]"),
+                (None, None) => format!("{name} [{}]", identity.file_name()),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(file_name: &str, pretty_name: &str, uname: Option<&str>) -> ImageIdentity {
+        ImageIdentity {
+            image: PathBuf::from("/boot/EFI/Linux").join(file_name),
+            pretty_name: Some(pretty_name.to_string()),
+            uname: uname.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn disambiguate_identical_os_releases() {
+        let identities = [
+            identity("nixos-generation-1-aaaa.efi", "NixOS", Some("6.6.1")),
+            identity("nixos-generation-2-bbbb.efi", "NixOS", Some("6.8.2")),
+            identity("nixos-generation-3+2-cccc.efi", "NixOS", None),
+            identity("nixos-generation-4-dddd.efi", "NixOS Unstable", None),
+        ];
+        assert_eq!(
+            labels(&identities),
+            [
+                "NixOS [kernel 6.6.1]",
+                "NixOS [kernel 6.8.2]",
+                "NixOS [generation 3]",
+                "NixOS Unstable",
+            ]
+        );
+    }
+
+    #[test]
+    fn fall_back_to_file_name() {
+        let identities = [ImageIdentity {
+            image: PathBuf::from("/boot/EFI/Linux/nixos-custom.efi"),
+            ..Default::default()
+        }];
+        assert_eq!(labels(&identities), ["nixos-custom.efi"]);
+    }
+}
@@ -0,0 +1,131 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// The settings of systemd-boot's `loader.conf` that lzbt cares about, see `loader.conf(5)`.
+///
+/// Lanzaboote has no menu of its own, systemd-boot applies all other settings, e.g. `timeout`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LoaderConfig {
+    /// A glob pattern of the entry to boot by default, or `@saved`.
+    pub default: Option<String>,
+}
+
+impl LoaderConfig {
+    /// Parse the contents of a `loader.conf`.
+    ///
+    /// Every line consists of a key and a value, separated by whitespace. Empty lines and lines
+    /// starting with `#` are ignored, as are unknown keys. Like systemd-boot, a later line
+    /// overrides an earlier one with the same key.
+    pub fn parse(contents: &str) -> Self {
+        let mut config = Self::default();
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(char::is_whitespace)
+                .map(|(key, value)| (key, value.trim()))
+                .unwrap_or((line, ""));
+            let value = (!value.is_empty()).then(|| value.to_string());
+            if key == "default" {
+                config.default = value;
+            }
+        }
+
+        config
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read loader.conf: {path:?}"))?;
+        Ok(Self::parse(&contents))
+    }
+
+    /// Whether the `default` setting selects a specific entry, as opposed to e.g. `@saved`, which
+    /// systemd-boot resolves at boot.
+    pub fn default_pattern(&self) -> Option<&str> {
+        self.default
+            .as_deref()
+            .filter(|default| !default.starts_with('@'))
+    }
+}
+
+/// Match a boot entry ID against a glob pattern of `loader.conf`, ignoring case like the FAT file
+/// systems that entries usually live on.
+///
+/// `*` matches any string, `?` any character and `[...]` any of the characters or ranges in the
+/// brackets.
+pub fn matches_pattern(pattern: &str, entry: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let entry: Vec<char> = entry.to_lowercase().chars().collect();
+    glob(&pattern, &entry)
+}
+
+fn glob(pattern: &[char], entry: &[char]) -> bool {
+    match pattern.split_first() {
+        None => entry.is_empty(),
+        Some(('*', rest)) => (0..=entry.len()).any(|skip| glob(rest, &entry[skip..])),
+        Some(('?', rest)) => !entry.is_empty() && glob(rest, &entry[1..]),
+        Some(('[', rest)) => {
+            let Some(end) = rest.iter().position(|&c| c == ']') else {
+                return entry.first() == Some(&'[') && glob(rest, &entry[1..]);
+            };
+            let Some(c) = entry.first() else {
+                return false;
+            };
+            let mut class = &rest[..end];
+            let mut in_class = false;
+            while let Some((from, tail)) = class.split_first() {
+                class = match tail {
+                    ['-', to, tail @ ..] => {
+                        in_class |= (from..=to).contains(&c);
+                        tail
+                    }
+                    _ => {
+                        in_class |= from == c;
+                        tail
+                    }
+                };
+            }
+            in_class && glob(&rest[end + 1..], &entry[1..])
+        }
+        Some((p, rest)) => entry.first() == Some(p) && glob(rest, &entry[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_loader_conf() {
+        let config = LoaderConfig::parse(
+            "# managed by NixOS\n\ntimeout 3\ndefault windows.conf\nconsole-mode keep\ndefault  nixos-*\n",
+        );
+        assert_eq!(
+            config,
+            LoaderConfig {
+                default: Some(String::from("nixos-*")),
+            }
+        );
+        assert_eq!(config.default_pattern(), Some("nixos-*"));
+        assert_eq!(
+            LoaderConfig::parse("default @saved").default_pattern(),
+            None
+        );
+    }
+
+    #[test]
+    fn match_entry_patterns() {
+        let entry = "nixos-generation-12-abcd.efi";
+        assert!(matches_pattern("nixos-*", entry));
+        assert!(matches_pattern("NixOS-generation-1?-*.efi", entry));
+        assert!(matches_pattern("nixos-generation-[0-9][1-3]-*", entry));
+        assert!(!matches_pattern("nixos-generation-[3-9]*", entry));
+        assert!(!matches_pattern("windows*", entry));
+        assert!(!matches_pattern("nixos-", entry));
+    }
+}
@@ -1,7 +1,13 @@
 mod architecture;
+mod build_cache;
 mod cli;
 mod esp;
+mod fsck;
 mod install;
+mod list;
+mod loader_conf;
+mod reproduce;
+mod retention;
 mod version;
 
 use clap::Parser;
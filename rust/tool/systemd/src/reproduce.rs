@@ -0,0 +1,155 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use tempfile::TempDir;
+
+use crate::install::{kernel_version, stub_parameters};
+use lanzaboote_tool::esp::content_addressed_path;
+use lanzaboote_tool::generation::{Generation, GenerationLink};
+use lanzaboote_tool::pe::{lanzaboote_image, section_at_offset, strip_signature};
+
+/// Where a rebuilt image differs from an existing one.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Difference {
+    /// The first offset at which the images differ, after removing their signatures.
+    pub offset: usize,
+    /// The section of the existing image at that offset, if it is in one.
+    pub section: Option<String>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "differs at offset {:#x}", self.offset)?;
+        match &self.section {
+            Some(section) => write!(f, " (section {section})"),
+            None => write!(f, " (headers or padding)"),
+        }
+    }
+}
+
+/// The inputs that an image was built from, apart from its generation.
+pub struct ReproduceOptions<'a> {
+    pub lanzaboote_stub: &'a Path,
+    pub esp: &'a Path,
+    pub specialisation: Option<&'a str>,
+    pub boot_message: Option<&'a str>,
+    pub cmdline_fallback: Option<&'a str>,
+    pub sbat: Option<&'a str>,
+    pub pcr_private_key: Option<&'a Path>,
+    pub pcr_public_key: Option<&'a Path>,
+    pub fallback_image: Option<&'a Path>,
+}
+
+/// Rebuild the image of a generation and compare it to an existing one.
+///
+/// The existing image is usually signed, while the rebuilt one is not, so the signatures are
+/// removed before comparing, see [`strip_signature`]. The kernel and initrd do not need to be on
+/// the ESP, only their paths and hashes end up in the image.
+///
+/// Returns `None` if the images are identical.
+pub fn reproduce(
+    generation_link: &Path,
+    image: &Path,
+    options: &ReproduceOptions,
+) -> Result<Option<Difference>> {
+    let link = GenerationLink::from_path(generation_link)?;
+    let mut generation = Generation::from_link(&link)
+        .with_context(|| format!("Failed to build generation from link: {link:?}"))?;
+    if let Some(name) = options.specialisation {
+        let (name, bootspec) = generation
+            .spec
+            .bootspec
+            .specialisations
+            .iter()
+            .find(|(specialisation_name, _)| specialisation_name.0 == name)
+            .with_context(|| format!("Generation has no specialisation {name}"))?;
+        generation = generation.specialise(name, bootspec);
+    }
+
+    let bootspec = &generation.spec.bootspec.bootspec;
+    // The secrets are appended to the initrd anew on every installation.
+    if bootspec.initrd_secrets.is_some() {
+        bail!("Images of generations with initrd secrets cannot be reproduced");
+    }
+    let initrd = bootspec
+        .initrd
+        .as_ref()
+        .context("Lanzaboote does not support missing initrd yet.")?;
+
+    let kernel_version = kernel_version(&generation)?;
+    let nixos = options.esp.join("EFI/nixos");
+    let kernel_target = content_addressed_path(
+        &bootspec.kernel,
+        &nixos,
+        &format!("kernel-{kernel_version}"),
+    )?;
+    let initrd_target =
+        content_addressed_path(initrd, &nixos, &format!("initrd-{kernel_version}"))?;
+
+    let mut parameters = stub_parameters(
+        options.lanzaboote_stub,
+        options.esp,
+        &generation,
+        initrd,
+        &kernel_target,
+        &initrd_target,
+    )?;
+    if let Some(boot_message) = options.boot_message {
+        parameters = parameters.with_boot_message(boot_message);
+    }
+    if let Some(cmdline_fallback) = options.cmdline_fallback {
+        parameters = parameters.with_cmdline_fallback(cmdline_fallback);
+    }
+    if let Some(sbat) = options.sbat {
+        parameters = parameters.with_sbat(sbat);
+    }
+    if let Some(pcr_private_key) = options.pcr_private_key {
+        parameters = parameters.with_pcr_signing_key(pcr_private_key);
+    }
+    if let Some(pcr_public_key) = options.pcr_public_key {
+        parameters = parameters.with_pcr_public_key(pcr_public_key);
+    }
+    if let Some(fallback_image) = options.fallback_image {
+        parameters = parameters.with_fallback_image(options.esp, fallback_image)?;
+    }
+
+    let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+    let rebuilt_image =
+        lanzaboote_image(&tempdir, &parameters).context("Failed to build lanzaboote image.")?;
+
+    let existing = fs::read(image).with_context(|| format!("Failed to read image {image:?}"))?;
+    let rebuilt = fs::read(&rebuilt_image)
+        .with_context(|| format!("Failed to read rebuilt image {rebuilt_image:?}"))?;
+
+    Ok(first_difference(
+        &strip_signature(&existing).context("Failed to remove the signature of the image")?,
+        &strip_signature(&rebuilt)?,
+    )
+    .map(|offset| Difference {
+        offset,
+        section: section_at_offset(&existing, offset),
+    }))
+}
+
+/// Find the first offset at which two byte strings differ, including where one of them ends.
+fn first_difference(first: &[u8], second: &[u8]) -> Option<usize> {
+    first
+        .iter()
+        .zip(second)
+        .position(|(a, b)| a != b)
+        .or_else(|| (first.len() != second.len()).then(|| first.len().min(second.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_first_difference() {
+        assert_eq!(first_difference(b"lanzaboote", b"lanzaboote"), None);
+        assert_eq!(first_difference(b"lanzaboote", b"lanzabooth"), Some(9));
+        assert_eq!(first_difference(b"lanzaboote", b"lanza"), Some(5));
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs;
+use std::path::Path;
+
+use time::{Date, Duration};
+
+use lanzaboote_tool::generation::GenerationLink;
+
+/// The `LoaderEntrySelected` EFI variable of systemd-boot in efivarfs, i.e. the boot entry that
+/// was booted.
+pub const LOADER_ENTRY_SELECTED_PATH: &str =
+    "/sys/firmware/efi/efivars/LoaderEntrySelected-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
+/// Which generations to keep on the ESP.
+///
+/// The newest generation and the booted generation are always kept, so that neither a
+/// successful deployment nor the running system is left without a boot entry.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Keep at most this many of the most recent generations, 0 means there is no limit.
+    pub configuration_limit: usize,
+    /// Keep only generations that were built at most this many days ago.
+    pub max_age_days: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Select the links of the generations to keep, sorted by version.
+    ///
+    /// `today` is the date against which the age of a generation is measured, `booted` the version
+    /// of the booted generation, if known. Generations of unknown age are not considered too old.
+    pub fn retain(
+        &self,
+        mut links: Vec<GenerationLink>,
+        today: Date,
+        booted: Option<u64>,
+    ) -> Vec<GenerationLink> {
+        links.sort_by_key(|link| link.version);
+
+        let newest = links.last().map(|link| link.version);
+        let oldest_kept = (self.configuration_limit > 0)
+            .then(|| links.len().saturating_sub(self.configuration_limit))
+            .and_then(|index| links.get(index))
+            .map_or(0, |link| link.version);
+        let cutoff = self
+            .max_age_days
+            .and_then(|days| today.checked_sub(Duration::days(i64::try_from(days).ok()?)));
+
+        links
+            .into_iter()
+            .filter(|link| {
+                let recent = link.version >= oldest_kept;
+                let young = match (cutoff, link.build_time) {
+                    (Some(cutoff), Some(build_time)) => build_time >= cutoff,
+                    _ => true,
+                };
+                (recent && young) || Some(link.version) == newest || Some(link.version) == booted
+            })
+            .collect()
+    }
+}
+
+/// The version of the generation that systemd-boot booted, according to `LoaderEntrySelected`.
+///
+/// There is none if the machine was not booted by systemd-boot, or not from a NixOS image in
+/// `images_directory`, usually `EFI/Linux` on the ESP. The latter ensures that a generation that
+/// was booted from another ESP does not count.
+pub fn booted_generation(images_directory: &Path) -> Option<u64> {
+    let data = fs::read(LOADER_ENTRY_SELECTED_PATH).ok()?;
+    let entry = efi_string(&data)?;
+    if !images_directory.join(&entry).is_file() {
+        return None;
+    }
+    generation_of_entry(&entry)
+}
+
+/// Decode a string EFI variable as read from efivarfs, i.e. its 4 bytes of attributes followed by
+/// a NUL-terminated UTF-16LE string.
+fn efi_string(data: &[u8]) -> Option<String> {
+    let utf16: Vec<u16> = data
+        .get(4..)?
+        .chunks_exact(2)
+        .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+        .take_while(|&c| c != 0)
+        .collect();
+    String::from_utf16(&utf16).ok()
+}
+
+/// The generation version in the name of a boot entry or image, e.g. `3` for
+/// `nixos-generation-3-<hash>.efi`, also with a boot counter or of a specialisation.
+pub fn generation_of_entry(entry: &str) -> Option<u64> {
+    let rest = entry.strip_prefix("nixos-generation-")?;
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use time::Month;
+
+    use super::*;
+
+    fn date(day: u8) -> Date {
+        Date::from_calendar_date(2024, Month::March, day).unwrap()
+    }
+
+    fn link(version: u64, build_day: Option<u8>) -> GenerationLink {
+        GenerationLink {
+            version,
+            path: PathBuf::from(format!("/nix/var/nix/profiles/system-{version}-link")),
+            build_time: build_day.map(date),
+        }
+    }
+
+    fn versions(links: &[GenerationLink]) -> Vec<u64> {
+        links.iter().map(|link| link.version).collect()
+    }
+
+    #[test]
+    fn keep_most_recent_and_booted() {
+        let policy = RetentionPolicy {
+            configuration_limit: 2,
+            max_age_days: None,
+        };
+        let links = (1..=5).map(|version| link(version, None)).collect();
+
+        assert_eq!(
+            versions(&policy.retain(links, date(30), Some(1))),
+            [1, 4, 5]
+        );
+    }
+
+    #[test]
+    fn keep_young_and_newest() {
+        let policy = RetentionPolicy {
+            configuration_limit: 0,
+            max_age_days: Some(7),
+        };
+        let links = vec![
+            link(4, Some(1)),
+            link(1, Some(1)),
+            link(2, None),
+            link(3, Some(23)),
+            link(5, Some(2)),
+        ];
+
+        assert_eq!(versions(&policy.retain(links, date(30), None)), [2, 3, 5]);
+    }
+
+    #[test]
+    fn combine_limit_and_age() {
+        let policy = RetentionPolicy {
+            configuration_limit: 2,
+            max_age_days: Some(7),
+        };
+        let links = vec![link(1, Some(29)), link(2, Some(1)), link(3, Some(30))];
+
+        assert_eq!(versions(&policy.retain(links, date(30), None)), [3]);
+    }
+
+    #[test]
+    fn parse_loader_entry_selected() {
+        let mut data = vec![0x06, 0, 0, 0];
+        data.extend(
+            "nixos-generation-12-specialisation-foo-abcd.efi\0"
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes),
+        );
+
+        let entry = efi_string(&data).unwrap();
+        assert_eq!(entry, "nixos-generation-12-specialisation-foo-abcd.efi");
+        assert_eq!(generation_of_entry(&entry), Some(12));
+        assert_eq!(
+            generation_of_entry("nixos-generation-3+2-1-abcd.efi"),
+            Some(3)
+        );
+        assert_eq!(generation_of_entry("windows.efi"), None);
+    }
+}
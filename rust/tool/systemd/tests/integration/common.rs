@@ -116,7 +116,7 @@ pub fn setup_toplevel(tmpdir: &Path) -> Result<PathBuf> {
 
     // To simplify the test setup, we use the systemd stub for all PE binaries used by lanzatool.
     // Lanzatool doesn't care whether its actually a kernel or initrd but only whether it can
-    // manipulate the PE binary with objcopy and/or sign it with sbsigntool. For testing lanzatool
+    // add sections to the PE binary and/or sign it with sbsigntool. For testing lanzatool
     // in isolation this should suffice.
     fs::copy(&test_systemd_stub, initrd_path)?;
     fs::copy(&test_systemd_stub, kernel_path)?;
@@ -140,15 +140,18 @@ pub fn lanzaboote_install(
     esp_mountpoint: &Path,
     generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
 ) -> Result<Output> {
-    // To simplify the test setup, we use the systemd stub here instead of the lanzaboote stub. See
-    // the comment in setup_toplevel for details.
-    let architecture = Architecture::from_nixos_system(SYSTEM)?;
+    lanzaboote_install_with_args(config_limit, esp_mountpoint, generation_links, &[])
+}
+
+/// Call the `lanzaboote install` command with additional arguments.
+pub fn lanzaboote_install_with_args(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    extra_args: &[&str],
+) -> Result<Output> {
     let test_systemd = systemd_location_from_env()?;
-    let systemd_stub_filename = systemd_stub_filename(&architecture);
-    let test_systemd_stub = format!(
-        "{test_systemd}/lib/systemd/boot/efi/{systemd_stub_filename}",
-        systemd_stub_filename = systemd_stub_filename.display()
-    );
+    let test_systemd_stub = test_systemd_stub()?;
 
     let test_loader_config_path = tempfile::NamedTempFile::new()?;
     let test_loader_config = r"timeout 0\nconsole-mode 1\n";
@@ -171,6 +174,7 @@ pub fn lanzaboote_install(
         .arg("tests/fixtures/uefi-keys/db.key")
         .arg("--configuration-limit")
         .arg(config_limit.to_string())
+        .args(extra_args)
         .arg(esp_mountpoint)
         .args(generation_links)
         .output()?;
@@ -189,6 +193,43 @@ pub fn lanzaboote_install(
     Ok(output)
 }
 
+/// Rebuild the image of a generation with lzbt and compare it to an existing image.
+pub fn lanzaboote_reproduce(
+    esp_mountpoint: &Path,
+    generation_link: &Path,
+    image: &Path,
+    extra_args: &[&str],
+) -> Result<Output> {
+    let output = Command::cargo_bin("lzbt-systemd")?
+        .env("LANZABOOTE_STUB", test_systemd_stub()?)
+        .arg("reproduce")
+        .arg("--esp")
+        .arg(esp_mountpoint)
+        .args(extra_args)
+        .arg(generation_link)
+        .arg(image)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stdout.clone())?);
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    Ok(output)
+}
+
+/// Location of the stub that the tests use as the lanzaboote stub.
+///
+/// To simplify the test setup, we use the systemd stub here instead of the lanzaboote stub. See
+/// the comment in setup_toplevel for details.
+fn test_systemd_stub() -> Result<String> {
+    let architecture = Architecture::from_nixos_system(SYSTEM)?;
+    let test_systemd = systemd_location_from_env()?;
+    let systemd_stub_filename = systemd_stub_filename(&architecture);
+    Ok(format!(
+        "{test_systemd}/lib/systemd/boot/efi/{systemd_stub_filename}",
+        systemd_stub_filename = systemd_stub_filename.display()
+    ))
+}
+
 /// Read location of systemd installation from an environment variable.
 fn systemd_location_from_env() -> Result<String> {
     let error_msg = "TEST_SYSTEMD environment variable is not set. TEST_SYSTEMD has to point to a systemd installation.
@@ -0,0 +1,93 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn report_missing_and_orphaned_payloads() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links)?;
+    assert!(output0.status.success());
+
+    let fsck = || {
+        Command::cargo_bin("lzbt-systemd")
+            .unwrap()
+            .arg("fsck")
+            .arg(esp_mountpoint.path())
+            .output()
+    };
+
+    let output1 = fsck()?;
+    assert!(output1.status.success());
+
+    let nixos = esp_mountpoint.path().join("EFI/nixos");
+    let kernel = fs::read_dir(&nixos)?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.to_string_lossy().contains("kernel"))
+        .expect("No kernel installed");
+    fs::remove_file(&kernel)?;
+    fs::write(nixos.join("garbage.efi"), "garbage")?;
+
+    let output2 = fsck()?;
+    assert!(!output2.status.success());
+    let report = String::from_utf8(output2.stdout)?;
+    assert!(report.contains("references missing file"));
+    assert!(report.contains("garbage.efi\": not referenced by any image"));
+
+    Ok(())
+}
+
+#[test]
+fn verify_single_image() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let image = fs::read_dir(esp_mountpoint.path().join("EFI/Linux"))?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.to_string_lossy().contains("nixos-generation-1"))
+        .expect("No image installed");
+    let verify = || {
+        Command::cargo_bin("lzbt-systemd")
+            .unwrap()
+            .arg("verify")
+            .arg("--esp")
+            .arg(esp_mountpoint.path())
+            .arg(&image)
+            .output()
+    };
+
+    let output1 = verify()?;
+    assert!(output1.status.success());
+
+    let initrd = fs::read_dir(esp_mountpoint.path().join("EFI/nixos"))?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.to_string_lossy().contains("initrd"))
+        .expect("No initrd installed");
+    fs::write(&initrd, "corrupted")?;
+
+    let output2 = verify()?;
+    assert!(!output2.status.success());
+    let report = String::from_utf8(output2.stdout)?;
+    assert!(report.contains("does not match"));
+
+    Ok(())
+}
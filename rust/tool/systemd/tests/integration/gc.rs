@@ -104,10 +104,12 @@ fn keep_unrelated_files_on_esp() -> Result<()> {
 
     let unrelated_loader_config = esp_mountpoint.path().join("loader/loader.conf");
     let unrelated_uki = esp_mountpoint.path().join("EFI/Linux/ubuntu.efi");
+    let unrelated_nixos_uki = esp_mountpoint.path().join("EFI/Linux/nixos-custom.efi");
     let unrelated_os = esp_mountpoint.path().join("EFI/windows");
     let unrelated_firmware = esp_mountpoint.path().join("dell");
     fs::File::create(&unrelated_loader_config)?;
     fs::File::create(&unrelated_uki)?;
+    fs::File::create(&unrelated_nixos_uki)?;
     fs::create_dir(&unrelated_os)?;
     fs::create_dir(&unrelated_firmware)?;
 
@@ -117,6 +119,7 @@ fn keep_unrelated_files_on_esp() -> Result<()> {
 
     assert!(unrelated_loader_config.exists());
     assert!(unrelated_uki.exists());
+    assert!(unrelated_nixos_uki.exists());
     assert!(unrelated_os.exists());
     assert!(unrelated_firmware.exists());
 
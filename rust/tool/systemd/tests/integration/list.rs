@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn list_installed_generations() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links)?;
+    assert!(output0.status.success());
+
+    let output1 = Command::cargo_bin("lzbt-systemd")
+        .unwrap()
+        .arg("list")
+        .arg(esp_mountpoint.path())
+        .output()?;
+    assert!(output1.status.success());
+
+    let listing = String::from_utf8(output1.stdout)?;
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("nixos-generation-1-"));
+    assert!(lines[0].contains("(Generation 1, "));
+    assert!(lines[1].contains("(Generation 2, "));
+
+    Ok(())
+}
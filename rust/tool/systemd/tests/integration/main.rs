@@ -1,5 +1,8 @@
 mod common;
+mod fsck;
 mod gc;
 mod install;
+mod list;
 mod os_release;
+mod reproduce;
 mod systemd_boot;
@@ -0,0 +1,72 @@
+use anyhow::Result;
+use tempfile::tempdir;
+
+use crate::common;
+
+#[test]
+fn reproduce_installed_image() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let image = common::image_path(&esp, 1, &toplevel)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output0.status.success());
+
+    let output1 = common::lanzaboote_reproduce(esp.path(), &generation_link, &image, &[])?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stdout)?.contains("reproducible match"));
+
+    // The image was not built with a boot message.
+    let output2 = common::lanzaboote_reproduce(
+        esp.path(),
+        &generation_link,
+        &image,
+        &["--boot-message", "Hello"],
+    )?;
+    assert!(!output2.status.success());
+    assert!(String::from_utf8(output2.stderr)?.contains("is not reproducible: differs at offset"));
+
+    Ok(())
+}
+
+#[test]
+fn reproduce_image_with_fallback_image() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link1 =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+    let generation_link2 =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?;
+    let image1 = common::image_path(&esp, 1, &toplevel)?;
+    let image2 = common::image_path(&esp, 2, &toplevel)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link1, &generation_link2],
+        &["--fallback-image"],
+    )?;
+    assert!(output0.status.success());
+
+    let fallback_image = image1.to_str().expect("Image path is not UTF-8");
+    let output1 = common::lanzaboote_reproduce(
+        esp.path(),
+        &generation_link2,
+        &image2,
+        &["--fallback-image", fallback_image],
+    )?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stdout)?.contains("reproducible match"));
+
+    // Without the fallback image, the sections that point at it are missing.
+    let output2 = common::lanzaboote_reproduce(esp.path(), &generation_link2, &image2, &[])?;
+    assert!(!output2.status.success());
+
+    Ok(())
+}
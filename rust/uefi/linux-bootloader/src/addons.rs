@@ -0,0 +1,126 @@
+//! Command line addons, as supported by systemd-stub.
+//!
+//! An addon is a PE binary whose `.cmdline` section is appended to the kernel command line, e.g.
+//! `\loader\addons\debug.addon.efi` for every image, or `debug.addon.efi` in the drop-in directory
+//! of one image. This allows adding kernel parameters without rebuilding the image.
+//!
+//! Addons are loaded with the firmware's `LoadImage`, which verifies their signature if Secure
+//! Boot is enabled, but they are never started.
+
+use alloc::{string::String, vec::Vec};
+use core::str::from_utf8;
+use log::warn;
+use uefi::{
+    boot::{self, LoadImageSource},
+    cstr16,
+    fs::{FileSystem, Path, PathBuf},
+    proto::loaded_image::LoadedImage,
+    CString16, Handle, Status,
+};
+
+use crate::companions::find_files;
+
+/// File name extensions of addons.
+pub const ADDON_EXTENSIONS: &[&str] = &[".addon.efi"];
+
+/// Find the addons in a directory, sorted by name, so that their order does not depend on the
+/// file system.
+fn find_addons(fs: &mut FileSystem, directory: &Path) -> uefi::Result<Vec<PathBuf>> {
+    let is_directory = fs
+        .metadata(directory)
+        .map(|metadata| metadata.is_directory())
+        .unwrap_or(false);
+    if !is_directory {
+        return Ok(Vec::new());
+    }
+
+    let mut addons = find_files(fs, directory, ADDON_EXTENSIONS)?;
+    addons.sort();
+    Ok(addons)
+}
+
+/// Find a section of an image that the firmware loaded.
+///
+/// Unlike [`crate::pe_section::pe_section`], this does not assume that the image is trusted, an
+/// addon is only signed if Secure Boot is enabled.
+fn loaded_section<'a>(image: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
+    let pe = goblin::pe::PE::parse(image).ok()?;
+    let section = pe.sections.iter().find(|section| {
+        section
+            .name()
+            .map(|name| name == section_name)
+            .unwrap_or(false)
+    })?;
+
+    let start = usize::try_from(section.virtual_address).ok()?;
+    let size = usize::try_from(section.virtual_size).ok()?;
+    image.get(start..start.checked_add(size)?)
+}
+
+/// Read the `.cmdline` section of an addon, after the firmware has loaded and thereby verified it.
+fn addon_cmdline(handle: Handle, addon: &[u8]) -> uefi::Result<String> {
+    let addon_handle = boot::load_image(
+        handle,
+        LoadImageSource::FromBuffer {
+            buffer: addon,
+            file_path: None,
+        },
+    )?;
+
+    let cmdline =
+        boot::open_protocol_exclusive::<LoadedImage>(addon_handle).and_then(|loaded_image| {
+            let (image_base, image_size) = loaded_image.info();
+            // SAFETY: The firmware loaded the addon to this memory, which stays allocated until the
+            // addon is unloaded below.
+            let image = unsafe {
+                core::slice::from_raw_parts(
+                    image_base as *const u8,
+                    usize::try_from(image_size).map_err(|_| Status::INVALID_PARAMETER)?,
+                )
+            };
+            let cmdline = loaded_section(image, ".cmdline").ok_or(Status::NOT_FOUND)?;
+            Ok(String::from(
+                from_utf8(cmdline)
+                    .map_err(|_| Status::INVALID_PARAMETER)?
+                    .trim(),
+            ))
+        });
+
+    let _ = boot::unload_image(addon_handle);
+    cmdline
+}
+
+/// Load the command lines of the addons for this image, i.e. `\loader\addons\*.addon.efi` followed
+/// by `*.addon.efi` in its drop-in directory.
+///
+/// Addons that cannot be loaded, e.g. because they are not signed by a trusted key while Secure
+/// Boot is enabled, or that have no `.cmdline` section are skipped with a warning. The command
+/// lines are not measured.
+pub fn load_cmdline_addons(
+    handle: Handle,
+    fs: &mut FileSystem,
+    default_dropin_dir: Option<&Path>,
+) -> uefi::Result<Vec<CString16>> {
+    let mut addons = find_addons(fs, cstr16!("\\loader\\addons").as_ref())?;
+    if let Some(default_dropin_dir) = default_dropin_dir {
+        addons.append(&mut find_addons(fs, default_dropin_dir)?);
+    }
+
+    let mut cmdlines = Vec::new();
+    for addon in addons {
+        let cmdline = fs
+            .read(&*addon)
+            .map_err(|_| Status::LOAD_ERROR.into())
+            .and_then(|data| addon_cmdline(handle, &data))
+            .and_then(|cmdline| {
+                CString16::try_from(cmdline.as_str()).map_err(|_| Status::INVALID_PARAMETER.into())
+            });
+        match cmdline {
+            Ok(cmdline) if cmdline.is_empty() => {}
+            Ok(cmdline) => cmdlines.push(cmdline),
+            Err(err) => warn!("Skipping the command line addon {addon}: {err}"),
+        }
+    }
+
+    Ok(cmdlines)
+}
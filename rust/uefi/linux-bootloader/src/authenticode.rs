@@ -0,0 +1,434 @@
+//! A minimal Authenticode verifier for PE binaries.
+//!
+//! This supports what `sbsign` produces: a PKCS#7 `SignedData` structure
+//! signed with an RSA key (PKCS#1 v1.5) over a SHA-256 digest. No
+//! certificate chain is validated, the signer is checked directly against
+//! a trusted public key.
+
+use alloc::vec::Vec;
+use cms::{content_info::ContentInfo, signed_data::SignedData};
+use der::{
+    asn1::{AnyRef, ObjectIdentifier, OctetStringRef},
+    Decode, Encode, Sequence, SliceReader,
+};
+use goblin::pe::PE;
+use rsa::{
+    pkcs1v15::{Signature, VerifyingKey},
+    pkcs8::DecodePublicKey,
+    signature::Verifier,
+    RsaPublicKey,
+};
+use sha2::{Digest, Sha256};
+use uefi::Status;
+
+type Hash = sha2::digest::Output<Sha256>;
+
+const OID_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.1");
+const OID_MESSAGE_DIGEST: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+
+/// `WIN_CERT_REVISION_2_0`
+const WIN_CERT_REVISION: u16 = 0x0200;
+/// `WIN_CERT_TYPE_PKCS_SIGNED_DATA`
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+
+/// Size of the PE signature (`PE\0\0`) and the COFF file header that precede the optional header.
+const OPTIONAL_HEADER_OFFSET: usize = 4 + 20;
+/// Offset of the `CheckSum` field in the optional header.
+const CHECKSUM_OFFSET: usize = 64;
+/// Offset of the data directories in a PE32+ and a PE32 optional header respectively.
+const DATA_DIRECTORIES_OFFSET_PE32_PLUS: usize = 112;
+const DATA_DIRECTORIES_OFFSET_PE32: usize = 96;
+/// Index of the certificate table in the data directories.
+const CERTIFICATE_TABLE_INDEX: usize = 4;
+
+#[derive(Sequence)]
+struct AlgorithmIdentifier<'a> {
+    algorithm: ObjectIdentifier,
+    parameters: Option<AnyRef<'a>>,
+}
+
+#[derive(Sequence)]
+struct DigestInfo<'a> {
+    digest_algorithm: AlgorithmIdentifier<'a>,
+    digest: OctetStringRef<'a>,
+}
+
+/// The content signed by an Authenticode signature, see the
+/// "Windows Authenticode Portable Executable Signature Format" specification.
+#[derive(Sequence)]
+struct SpcIndirectDataContent<'a> {
+    data: AnyRef<'a>,
+    message_digest: DigestInfo<'a>,
+}
+
+/// Location of the certificate table in a PE file.
+///
+/// Contrary to the other data directories, its address is a file offset.
+fn certificate_table(pe: &PE) -> Option<(usize, usize)> {
+    pe.header
+        .optional_header
+        .and_then(|h| *h.data_directories.get_certificate_table())
+        .map(|table| (table.virtual_address as usize, table.size as usize))
+        .filter(|(_, size)| *size > 0)
+}
+
+/// Compute the Authenticode SHA-256 digest of a PE file.
+///
+/// This is the hash over the whole file, excluding the checksum, the
+/// certificate table entry in the data directories and the certificate
+/// table itself.
+pub fn authenticode_digest(pe_data: &[u8]) -> Option<Hash> {
+    let pe = PE::parse(pe_data).ok()?;
+    let optional_header = pe.header.optional_header?;
+
+    let optional_header_offset =
+        usize::try_from(pe.header.dos_header.pe_pointer).ok()? + OPTIONAL_HEADER_OFFSET;
+    let checksum_offset = optional_header_offset + CHECKSUM_OFFSET;
+    let certificate_entry_offset = optional_header_offset
+        + if pe.is_64 {
+            DATA_DIRECTORIES_OFFSET_PE32_PLUS
+        } else {
+            DATA_DIRECTORIES_OFFSET_PE32
+        }
+        + CERTIFICATE_TABLE_INDEX * 8;
+    let size_of_headers = usize::try_from(optional_header.windows_fields.size_of_headers).ok()?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(pe_data.get(..checksum_offset)?);
+    hasher.update(pe_data.get(checksum_offset + 4..certificate_entry_offset)?);
+    hasher.update(pe_data.get(certificate_entry_offset + 8..size_of_headers)?);
+
+    let mut sections: Vec<_> = pe
+        .sections
+        .iter()
+        .filter(|section| section.size_of_raw_data > 0)
+        .collect();
+    sections.sort_by_key(|section| section.pointer_to_raw_data);
+
+    let mut bytes_hashed = size_of_headers;
+    for section in sections {
+        let start = usize::try_from(section.pointer_to_raw_data).ok()?;
+        let size = usize::try_from(section.size_of_raw_data).ok()?;
+        hasher.update(pe_data.get(start..start.checked_add(size)?)?);
+        bytes_hashed = bytes_hashed.checked_add(size)?;
+    }
+
+    // Anything after the last section, except the certificate table, is hashed as well.
+    let certificate_table_size = certificate_table(&pe).map(|(_, size)| size).unwrap_or(0);
+    let end_of_data = pe_data.len().checked_sub(certificate_table_size)?;
+    if end_of_data > bytes_hashed {
+        hasher.update(&pe_data[bytes_hashed..end_of_data]);
+    }
+
+    Some(hasher.finalize())
+}
+
+/// Iterate over the PKCS#7 signatures in the certificate table of a PE file.
+fn signatures(pe_data: &[u8]) -> Vec<&[u8]> {
+    let mut signatures = Vec::new();
+    let Some((start, size)) = PE::parse(pe_data)
+        .ok()
+        .and_then(|pe| certificate_table(&pe))
+    else {
+        return signatures;
+    };
+    let Some(mut table) = start
+        .checked_add(size)
+        .and_then(|end| pe_data.get(start..end))
+    else {
+        return signatures;
+    };
+
+    // Each entry is a WIN_CERTIFICATE structure, aligned on 8 bytes.
+    while table.len() >= 8 {
+        let length = u32::from_le_bytes(table[0..4].try_into().unwrap()) as usize;
+        let revision = u16::from_le_bytes(table[4..6].try_into().unwrap());
+        let certificate_type = u16::from_le_bytes(table[6..8].try_into().unwrap());
+
+        if length < 8 || length > table.len() {
+            break;
+        }
+        if revision == WIN_CERT_REVISION && certificate_type == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+            signatures.push(&table[8..length]);
+        }
+
+        table = &table[usize::min((length + 7) & !7, table.len())..];
+    }
+
+    signatures
+}
+
+/// Check a single PKCS#7 signature against the Authenticode digest and the trusted key.
+///
+/// Returns `None` if the signature cannot be parsed.
+fn verify_signature(
+    signature: &[u8],
+    digest: &Hash,
+    trusted_key: &VerifyingKey<Sha256>,
+) -> Option<bool> {
+    // The certificate is padded to 8 bytes, so there may be trailing garbage after the
+    // DER structure.
+    let mut reader = SliceReader::new(signature).ok()?;
+    let content_info = ContentInfo::decode(&mut reader).ok()?;
+    let signed_data = content_info.content.decode_as::<SignedData>().ok()?;
+    let content = signed_data.encap_content_info.econtent?;
+
+    // The signed content must describe the PE file we have in front of us.
+    let indirect_data = content.decode_as::<SpcIndirectDataContent>().ok()?;
+    if indirect_data.message_digest.digest_algorithm.algorithm != OID_SHA256
+        || indirect_data.message_digest.digest.as_bytes() != digest.as_slice()
+    {
+        return Some(false);
+    }
+
+    // Authenticode hashes the value of the content, without its tag and length.
+    let content_digest = Sha256::digest(content.value());
+
+    let signer_info = signed_data.signer_infos.0.iter().next()?;
+    let signed_attributes = signer_info.signed_attrs.as_ref()?;
+    let message_digest = signed_attributes
+        .iter()
+        .find(|attribute| attribute.oid == OID_MESSAGE_DIGEST)?
+        .values
+        .iter()
+        .next()?
+        .decode_as::<OctetStringRef>()
+        .ok()?;
+    if message_digest.as_bytes() != content_digest.as_slice() {
+        return Some(false);
+    }
+
+    // The signature is computed over the signed attributes encoded as a SET OF.
+    let signed_attributes = signed_attributes.to_der().ok()?;
+    let signature = Signature::try_from(signer_info.signature.as_bytes()).ok()?;
+
+    Some(trusted_key.verify(&signed_attributes, &signature).is_ok())
+}
+
+/// Verify that a PE file carries a valid Authenticode signature made by `trusted_key`.
+///
+/// `trusted_key` is a DER-encoded `SubjectPublicKeyInfo` of an RSA key.
+///
+/// Returns `SECURITY_VIOLATION` if none of the signatures of the file was made by the trusted key,
+/// `LOAD_ERROR` if the file cannot be parsed, and `UNSUPPORTED` if the trusted key is not an RSA
+/// key.
+pub fn verify_authenticode(pe_data: &[u8], trusted_key: &[u8]) -> uefi::Result<()> {
+    let trusted_key = RsaPublicKey::from_public_key_der(trusted_key)
+        .map(VerifyingKey::<Sha256>::new)
+        .map_err(|_| Status::UNSUPPORTED)?;
+    let digest = authenticode_digest(pe_data).ok_or(Status::LOAD_ERROR)?;
+
+    if signatures(pe_data)
+        .into_iter()
+        .any(|signature| verify_signature(signature, &digest, &trusted_key) == Some(true))
+    {
+        Ok(())
+    } else {
+        Err(Status::SECURITY_VIOLATION.into())
+    }
+}
+
+/// Verify a detached signature of `data` made by `trusted_key`, e.g. of a recovery override.
+///
+/// The signature is an RSA PKCS#1 v1.5 signature over the SHA-256 digest of `data`, as made by
+/// `openssl dgst -sha256 -sign`. `trusted_key` is a DER-encoded `SubjectPublicKeyInfo` of an RSA
+/// key, like for [`verify_authenticode`].
+///
+/// Returns `SECURITY_VIOLATION` if the signature was not made by the trusted key and
+/// `UNSUPPORTED` if the trusted key is not an RSA key.
+pub fn verify_detached_signature(
+    data: &[u8],
+    signature: &[u8],
+    trusted_key: &[u8],
+) -> uefi::Result<()> {
+    let trusted_key = RsaPublicKey::from_public_key_der(trusted_key)
+        .map(VerifyingKey::<Sha256>::new)
+        .map_err(|_| Status::UNSUPPORTED)?;
+    let signature = Signature::try_from(signature).map_err(|_| Status::SECURITY_VIOLATION)?;
+
+    trusted_key
+        .verify(data, &signature)
+        .map_err(|_| Status::SECURITY_VIOLATION.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal PE file, signed by `lanzaboote_tool::sign::sign` with the `db` key of the
+    /// integration tests of lzbt.
+    const SIGNED: &[u8] = include_bytes!("../tests/fixtures/signed.efi");
+    /// The same PE file, before it was signed.
+    const UNSIGNED: &[u8] = include_bytes!("../tests/fixtures/unsigned.efi");
+    /// DER-encoded public keys of the `db` key and of an unrelated key.
+    const DB_KEY: &[u8] = include_bytes!("../tests/fixtures/db.pub.der");
+    const OTHER_KEY: &[u8] = include_bytes!("../tests/fixtures/other.pub.der");
+
+    /// A command line override and its signature, made with `openssl dgst -sha256 -sign` and the
+    /// `db` key.
+    const OVERRIDE: &[u8] = include_bytes!("../tests/fixtures/override.cmdline");
+    const OVERRIDE_SIGNATURE: &[u8] = include_bytes!("../tests/fixtures/override.cmdline.sig");
+
+    /// Offset of the certificate table in `SIGNED`, right after the unsigned file.
+    const CERTIFICATE_TABLE: usize = 0x400;
+
+    fn status(result: uefi::Result<()>) -> Status {
+        result.map_or_else(|err| err.status(), |()| Status::SUCCESS)
+    }
+
+    #[test]
+    fn digest_matches_lzbt_and_ignores_the_signature() {
+        let digest = authenticode_digest(SIGNED).unwrap();
+        assert_eq!(
+            format!("{digest:x}"),
+            "2368bfd863edfd5077d93417fdfc2b425a6932c45dda26d8678f84c1bc5545e7"
+        );
+        assert_eq!(authenticode_digest(UNSIGNED), Some(digest));
+    }
+
+    #[test]
+    fn find_the_signature_in_the_certificate_table() {
+        assert_eq!(signatures(SIGNED).len(), 1);
+        assert!(signatures(UNSIGNED).is_empty());
+    }
+
+    #[test]
+    fn check_signature_against_digest_and_key() {
+        let signature = signatures(SIGNED)[0];
+        let digest = authenticode_digest(SIGNED).unwrap();
+        let key =
+            |der| VerifyingKey::<Sha256>::new(RsaPublicKey::from_public_key_der(der).unwrap());
+
+        assert_eq!(
+            verify_signature(signature, &digest, &key(DB_KEY)),
+            Some(true)
+        );
+        assert_eq!(
+            verify_signature(signature, &digest, &key(OTHER_KEY)),
+            Some(false)
+        );
+        assert_eq!(
+            verify_signature(signature, &Sha256::digest("other image"), &key(DB_KEY)),
+            Some(false)
+        );
+        assert_eq!(verify_signature(b"not DER", &digest, &key(DB_KEY)), None);
+    }
+
+    #[test]
+    fn accept_image_signed_by_trusted_key() {
+        assert_eq!(status(verify_authenticode(SIGNED, DB_KEY)), Status::SUCCESS);
+    }
+
+    #[test]
+    fn reject_image_signed_by_other_key() {
+        assert_eq!(
+            status(verify_authenticode(SIGNED, OTHER_KEY)),
+            Status::SECURITY_VIOLATION
+        );
+    }
+
+    #[test]
+    fn reject_tampered_image() {
+        let mut image = SIGNED.to_vec();
+        // The first byte of the `.text` section.
+        image[0x200] ^= 1;
+        assert_eq!(
+            status(verify_authenticode(&image, DB_KEY)),
+            Status::SECURITY_VIOLATION
+        );
+    }
+
+    #[test]
+    fn reject_unsigned_image() {
+        assert_eq!(
+            status(verify_authenticode(UNSIGNED, DB_KEY)),
+            Status::SECURITY_VIOLATION
+        );
+    }
+
+    #[test]
+    fn reject_truncated_certificate_table() {
+        // The data directory still points at the whole certificate table.
+        for end in [
+            SIGNED.len() - 1,
+            CERTIFICATE_TABLE + 16,
+            CERTIFICATE_TABLE + 4,
+        ] {
+            assert!(
+                verify_authenticode(&SIGNED[..end], DB_KEY).is_err(),
+                "{end}"
+            );
+        }
+
+        // The length of the `WIN_CERTIFICATE` does not fit the table.
+        for length in [0, 7, 9, 0xffff_ffff] {
+            let mut image = SIGNED.to_vec();
+            image[CERTIFICATE_TABLE..CERTIFICATE_TABLE + 4]
+                .copy_from_slice(&u32::to_le_bytes(length));
+            assert_eq!(
+                status(verify_authenticode(&image, DB_KEY)),
+                Status::SECURITY_VIOLATION,
+                "{length}"
+            );
+        }
+    }
+
+    #[test]
+    fn reject_keys_other_than_rsa() {
+        assert_eq!(
+            status(verify_authenticode(SIGNED, b"not a key")),
+            Status::UNSUPPORTED
+        );
+    }
+
+    #[test]
+    fn accept_detached_signature_by_trusted_key() {
+        assert_eq!(
+            status(verify_detached_signature(
+                OVERRIDE,
+                OVERRIDE_SIGNATURE,
+                DB_KEY
+            )),
+            Status::SUCCESS
+        );
+    }
+
+    #[test]
+    fn reject_detached_signature_over_other_data() {
+        assert_eq!(
+            status(verify_detached_signature(
+                b"systemd.unit=emergency.target",
+                OVERRIDE_SIGNATURE,
+                DB_KEY
+            )),
+            Status::SECURITY_VIOLATION
+        );
+    }
+
+    #[test]
+    fn reject_detached_signature_by_other_key() {
+        assert_eq!(
+            status(verify_detached_signature(
+                OVERRIDE,
+                OVERRIDE_SIGNATURE,
+                OTHER_KEY
+            )),
+            Status::SECURITY_VIOLATION
+        );
+    }
+
+    #[test]
+    fn reject_truncated_detached_signature() {
+        for end in [OVERRIDE_SIGNATURE.len() - 1, 1, 0] {
+            assert_eq!(
+                status(verify_detached_signature(
+                    OVERRIDE,
+                    &OVERRIDE_SIGNATURE[..end],
+                    DB_KEY
+                )),
+                Status::SECURITY_VIOLATION,
+                "{end}"
+            );
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! A baseline of the measured sections of an image, kept across boots.
+//!
+//! On the first boot of an image, a digest over its measured sections is stored in an EFI
+//! variable. On later boots of the same image, the digest is computed again and compared to the
+//! stored one. A mismatch means that the sections were modified in place, without installing a new
+//! image.
+//!
+//! Only the baseline of the most recently booted image is kept, so booting another generation
+//! starts over with a new baseline.
+
+use alloc::string::String;
+use goblin::pe::PE;
+use sha2::{Digest, Sha256};
+use uefi::{
+    cstr16,
+    proto::device_path::text::{AllowShortcuts, DisplayOnly},
+    runtime::{self, VariableAttributes},
+    Status,
+};
+
+use crate::{
+    efivars::BOOT_LOADER_VENDOR_UUID, pe_section::pe_section_data, uefi_helpers::PeInMemory,
+    unified_sections::MEASURED_SECTIONS,
+};
+
+/// Size of the stored baseline: the digest of the image path, followed by the digest of the
+/// measured sections.
+const BASELINE_SIZE: usize = 64;
+
+/// Result of comparing an image against the stored baseline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BaselineCheck {
+    /// There was no baseline for this image yet, it has been created.
+    Created,
+    /// The measured sections match the baseline.
+    Matches,
+    /// The measured sections differ from the baseline, although the image is the same.
+    Changed,
+}
+
+/// Compute a digest over the name and contents of all measured sections, in the order in which
+/// they are measured.
+fn measured_sections_digest(pe_binary: &[u8]) -> uefi::Result<[u8; 32]> {
+    let pe = PE::parse(pe_binary).map_err(|_err| Status::LOAD_ERROR)?;
+
+    let mut hasher = Sha256::new();
+    for section_name in MEASURED_SECTIONS {
+        let sections = pe.sections.iter().filter(|section| {
+            section
+                .name()
+                .map(|name| name == *section_name)
+                .unwrap_or(false)
+        });
+        for data in sections.filter_map(|section| pe_section_data(pe_binary, section)) {
+            hasher.update(section_name.as_bytes());
+            hasher.update(Sha256::digest(data));
+        }
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Compare the measured sections of an image against the stored baseline.
+///
+/// Images are told apart by their path on the ESP. If the baseline belongs to another image, e.g.
+/// because a new generation was installed, it is replaced by one for this image.
+pub fn check_baseline(image: &PeInMemory) -> uefi::Result<BaselineCheck> {
+    let image_path = image
+        .file_path()
+        .ok_or(Status::NOT_FOUND)?
+        .to_string(DisplayOnly(false), AllowShortcuts(false))
+        .map_err(|_err| Status::NOT_FOUND)?;
+
+    let mut baseline = [0u8; BASELINE_SIZE];
+    baseline[..32].copy_from_slice(&Sha256::digest(String::from(&*image_path).as_bytes()));
+    // SAFETY: We get a slice that represents our currently running
+    // image and then parse the PE data structures from it. This is
+    // safe, because we don't touch any data in the data sections that
+    // might conceivably change while we look at the slice.
+    baseline[32..].copy_from_slice(&measured_sections_digest(unsafe { image.as_slice() })?);
+
+    let name = cstr16!("LanzabooteMeasurementBaseline");
+    let mut stored = [0u8; BASELINE_SIZE];
+    let stored = match runtime::get_variable(name, &BOOT_LOADER_VENDOR_UUID, &mut stored) {
+        Ok((stored, _)) => Some(&*stored),
+        Err(err) if err.status() == Status::NOT_FOUND => None,
+        // A baseline of the wrong size cannot be ours, replace it.
+        Err(err) if err.status() == Status::BUFFER_TOO_SMALL => None,
+        Err(err) => return Err(err.to_err_without_payload()),
+    };
+
+    match stored {
+        Some(stored) if stored == baseline => Ok(BaselineCheck::Matches),
+        Some(stored) if stored.get(..32) == Some(&baseline[..32]) => Ok(BaselineCheck::Changed),
+        _ => {
+            // Without runtime access, the baseline cannot be rewritten from the booted system.
+            runtime::set_variable(
+                name,
+                &BOOT_LOADER_VENDOR_UUID,
+                VariableAttributes::NON_VOLATILE | VariableAttributes::BOOTSERVICE_ACCESS,
+                &baseline,
+            )?;
+            Ok(BaselineCheck::Created)
+        }
+    }
+}
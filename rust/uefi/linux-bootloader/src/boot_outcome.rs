@@ -0,0 +1,96 @@
+//! Reporting how the stub booted, so that a degraded boot does not go unnoticed.
+//!
+//! Without Secure Boot, the stub boots even if e.g. the hash of the kernel does not match. The
+//! booted system then looks like any other, until Secure Boot is enabled and the next boot fails.
+//! The stub therefore sets the volatile `LanzabooteBootOutcome` EFI variable, a UTF-16 string that
+//! is either `verified`, or lists every degraded path that the boot took along with the reason,
+//! one per line, e.g. `fallback-sb-off: Kernel hash does not match`.
+
+use alloc::{format, string::String, vec::Vec};
+use uefi::{
+    cstr16,
+    runtime::{self, VariableAttributes},
+    CStr16, Status,
+};
+
+use crate::efivars::BOOT_LOADER_VENDOR_UUID;
+
+/// The variable that tells the booted system how it was booted.
+const BOOT_OUTCOME: &CStr16 = cstr16!("LanzabooteBootOutcome");
+
+/// A path that a boot took which is not the verified one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DegradedPath {
+    /// An integrity check failed, but the boot continued because Secure Boot is disabled.
+    FallbackSecureBootOff,
+    /// A file on the ESP that is signed by the recovery key replaced part of the generation.
+    Recovery,
+    /// The fallback command line was appended on the last boot attempt.
+    CmdlineFallback,
+    /// The user edited the kernel command line before booting.
+    CmdlineEdited,
+    /// The measured sections differ from the stored baseline.
+    BaselineChanged,
+}
+
+impl DegradedPath {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::FallbackSecureBootOff => "fallback-sb-off",
+            Self::Recovery => "recovery",
+            Self::CmdlineFallback => "cmdline-fallback",
+            Self::CmdlineEdited => "cmdline-edited",
+            Self::BaselineChanged => "baseline-changed",
+        }
+    }
+}
+
+fn read_boot_outcome() -> Option<String> {
+    let (data, _) = runtime::get_variable_boxed(BOOT_OUTCOME, &BOOT_LOADER_VENDOR_UUID).ok()?;
+    let utf16: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+        .collect();
+    String::from_utf16(&utf16).ok()
+}
+
+fn write_boot_outcome(outcome: &str) -> uefi::Result<()> {
+    let data: Vec<u8> = outcome
+        .encode_utf16()
+        .flat_map(|c| c.to_le_bytes())
+        .collect();
+    runtime::set_variable(
+        BOOT_OUTCOME,
+        &BOOT_LOADER_VENDOR_UUID,
+        VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        &data,
+    )
+}
+
+/// Forget the outcome of an earlier boot attempt, e.g. of an image that failed to boot before the
+/// boot menu started this one.
+pub fn clear_boot_outcome() -> uefi::Result<()> {
+    match runtime::delete_variable(BOOT_OUTCOME, &BOOT_LOADER_VENDOR_UUID) {
+        Err(err) if err.status() != Status::NOT_FOUND => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Record that the boot took a degraded path, and why.
+pub fn report_degraded_boot(path: DegradedPath, reason: &str) -> uefi::Result<()> {
+    let line = format!("{}: {reason}", path.as_str());
+    match read_boot_outcome() {
+        Some(outcome) if !outcome.is_empty() => write_boot_outcome(&format!("{outcome}\n{line}")),
+        _ => write_boot_outcome(&line),
+    }
+}
+
+/// Record that the boot is verified, unless it took a degraded path before.
+///
+/// This is called right before the kernel is started.
+pub fn report_verified_boot() -> uefi::Result<()> {
+    if read_boot_outcome().is_some() {
+        return Ok(());
+    }
+    write_boot_outcome("verified")
+}
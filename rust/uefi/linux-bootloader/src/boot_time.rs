@@ -0,0 +1,136 @@
+//! Timing of the boot phases of the stub.
+//!
+//! The durations are taken from the timestamp protocol of the firmware and reported to userspace
+//! in the `LanzabooteBootTimeUSec` EFI variable, e.g. `total=81234 measure=5012 companions=1830
+//! read-kernel=41219 read-initrd=20000 cmdline=12 verify-kernel=6011 verify-initrd=3200
+//! load=3962`. This allows monitoring how the boot time changes with growing kernels and initrds.
+//!
+//! Every phase is also logged as it ends, so that a serial console shows where a slow boot spends
+//! its time even if it never reaches the kernel.
+
+use alloc::{format, vec::Vec};
+use log::{info, log_enabled, warn, Level};
+use uefi::{
+    boot, cstr16,
+    proto::misc::Timestamp,
+    runtime::{self, VariableAttributes},
+};
+
+use crate::efivars::BOOT_LOADER_VENDOR_UUID;
+
+/// Read the current value of the timestamp counter of the firmware.
+fn timestamp() -> uefi::Result<u64> {
+    let handle = boot::get_handle_for_protocol::<Timestamp>()?;
+    Ok(boot::open_protocol_exclusive::<Timestamp>(handle)?.get_timestamp())
+}
+
+/// Measures the durations of consecutive boot phases.
+pub struct BootTimer {
+    /// Ticks per second of the timestamp counter, or 0 if there is none.
+    frequency: u64,
+    /// The timestamp at which the first phase started.
+    boot_start: u64,
+    /// The timestamp at which the current phase started.
+    phase_start: u64,
+    /// The completed phases with their durations in ticks.
+    phases: Vec<(&'static str, u64)>,
+}
+
+impl BootTimer {
+    /// Start timing the first phase.
+    ///
+    /// Firmware without the timestamp protocol, which was added in UEFI 2.4, gets a timer that
+    /// does nothing.
+    pub fn start() -> Self {
+        let handle = boot::get_handle_for_protocol::<Timestamp>();
+        let timer = handle
+            .and_then(boot::open_protocol_exclusive::<Timestamp>)
+            .and_then(|timestamp| {
+                Ok((
+                    timestamp.get_properties()?.frequency,
+                    timestamp.get_timestamp(),
+                ))
+            });
+
+        match timer {
+            Ok((frequency, phase_start)) => Self {
+                frequency,
+                boot_start: phase_start,
+                phase_start,
+                phases: Vec::new(),
+            },
+            Err(_) => {
+                info!("No timestamp protocol found, the boot time is not reported.");
+                Self {
+                    frequency: 0,
+                    boot_start: 0,
+                    phase_start: 0,
+                    phases: Vec::new(),
+                }
+            }
+        }
+    }
+
+    /// End the current phase under the given name and start the next one.
+    ///
+    /// The phase is logged along with the time since the first phase started.
+    pub fn end_phase(&mut self, name: &'static str) {
+        if self.frequency == 0 {
+            return;
+        }
+
+        if let Ok(now) = timestamp() {
+            // The counter may wrap around at its end value, which is not worth handling for the
+            // few seconds that the stub runs.
+            let ticks = now.saturating_sub(self.phase_start);
+            self.phases.push((name, ticks));
+            self.phase_start = now;
+
+            // Formatting is the only cost that is not needed for the export.
+            if log_enabled!(Level::Info) {
+                info!(
+                    "[{} ms] Finished {name} in {} ms.",
+                    self.microseconds(now.saturating_sub(self.boot_start)) / 1000,
+                    self.microseconds(ticks) / 1000
+                );
+            }
+        }
+    }
+
+    /// Convert ticks of the timestamp counter to microseconds.
+    fn microseconds(&self, ticks: u64) -> u64 {
+        u64::try_from(u128::from(ticks) * 1_000_000 / u128::from(self.frequency))
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Export the total boot time and its phases in microseconds to the `LanzabooteBootTimeUSec`
+    /// EFI variable.
+    ///
+    /// The boot time is purely informational, so a failure is only logged.
+    pub fn export(&self) {
+        if self.frequency == 0 {
+            return;
+        }
+
+        let total: u64 = self.phases.iter().map(|(_, ticks)| ticks).sum();
+        let mut summary = format!("total={}", self.microseconds(total));
+        for (name, ticks) in &self.phases {
+            summary.push_str(&format!(" {name}={}", self.microseconds(*ticks)));
+        }
+
+        info!("Boot time in microseconds: {summary}");
+
+        let data: Vec<u8> = summary
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+        if let Err(err) = runtime::set_variable(
+            cstr16!("LanzabooteBootTimeUSec"),
+            &BOOT_LOADER_VENDOR_UUID,
+            VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+            &data,
+        ) {
+            warn!("Failed to export the boot time: {err}");
+        }
+    }
+}
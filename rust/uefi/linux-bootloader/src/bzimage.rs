@@ -0,0 +1,342 @@
+//! Booting of x86-64 Linux kernels without an EFI stub, i.e. raw bzImages.
+//!
+//! Kernels with an EFI stub are PE files and loaded with [`crate::pe_loader`]. Raw bzImages are
+//! booted with the 64-bit boot protocol of Linux instead: the boot parameters ("zero page") are
+//! filled in, boot services are exited and the 64-bit entry point of the kernel is called.
+//!
+//! See <https://www.kernel.org/doc/html/latest/arch/x86/boot.html>.
+
+use core::ptr::NonNull;
+
+use goblin::pe::PE;
+use uefi::{
+    boot::{self, AllocateType, MemoryType},
+    mem::memory_map::{MemoryMap, MemoryMapMut},
+    table, Status,
+};
+
+/// Offsets into the boot parameters, which start with a copy of the setup header of the kernel.
+mod offsets {
+    pub const EXT_RAMDISK_IMAGE: usize = 0x0c0;
+    pub const EXT_RAMDISK_SIZE: usize = 0x0c4;
+    pub const EXT_CMD_LINE_PTR: usize = 0x0c8;
+    pub const EFI_INFO: usize = 0x1c0;
+    pub const E820_ENTRIES: usize = 0x1e8;
+    pub const SETUP_SECTS: usize = 0x1f1;
+    pub const SETUP_HEADER_LENGTH: usize = 0x201;
+    pub const HEADER: usize = 0x202;
+    pub const VERSION: usize = 0x206;
+    pub const TYPE_OF_LOADER: usize = 0x210;
+    pub const CODE32_START: usize = 0x214;
+    pub const RAMDISK_IMAGE: usize = 0x218;
+    pub const RAMDISK_SIZE: usize = 0x21c;
+    pub const CMD_LINE_PTR: usize = 0x228;
+    pub const INITRD_ADDR_MAX: usize = 0x22c;
+    pub const KERNEL_ALIGNMENT: usize = 0x230;
+    pub const RELOCATABLE_KERNEL: usize = 0x234;
+    pub const XLOADFLAGS: usize = 0x236;
+    pub const CMDLINE_SIZE: usize = 0x238;
+    pub const PREF_ADDRESS: usize = 0x258;
+    pub const INIT_SIZE: usize = 0x260;
+    pub const E820_TABLE: usize = 0x2d0;
+}
+
+/// Magic of the setup header, "HdrS".
+const HEADER_MAGIC: &[u8] = b"HdrS";
+/// The first protocol version with `xloadflags`, which tell whether a 64-bit entry point exists.
+const MIN_PROTOCOL_VERSION: u16 = 0x020c;
+/// `xloadflags`: the kernel has a 64-bit entry point at offset 0x200.
+const XLF_KERNEL_64: u16 = 1 << 0;
+/// `xloadflags`: the initrd may be placed above 4 GiB.
+const XLF_CAN_BE_LOADED_ABOVE_4G: u16 = 1 << 1;
+/// Offset of the 64-bit entry point into the protected-mode kernel.
+const ENTRY_64_OFFSET: u64 = 0x200;
+/// Loader type for boot loaders without an assigned ID.
+const LOADER_TYPE_UNDEFINED: u8 = 0xff;
+
+const E820_MAX_ENTRIES: usize = 128;
+const E820_ENTRY_SIZE: usize = 20;
+const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
+const E820_ACPI: u32 = 3;
+const E820_NVS: u32 = 4;
+const E820_UNUSABLE: u32 = 5;
+const E820_PMEM: u32 = 7;
+
+const PAGE_SIZE: usize = 4096;
+const BELOW_4G: u64 = 0xffff_ffff;
+
+/// Whether a kernel is a raw bzImage, i.e. has a setup header, but is no PE file.
+pub fn is_bzimage(kernel: &[u8]) -> bool {
+    kernel.get(offsets::HEADER..offsets::HEADER + HEADER_MAGIC.len()) == Some(HEADER_MAGIC)
+        && PE::parse(kernel).is_err()
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Write a 64-bit address split into the 32-bit field at `low` and its extension at `high`.
+fn write_split_u64(data: &mut [u8], low: usize, high: usize, value: u64) {
+    write_u32(data, low, value as u32);
+    write_u32(data, high, (value >> 32) as u32);
+}
+
+/// Allocate zeroed pages and return them as a slice.
+fn allocate(
+    allocate_type: AllocateType,
+    memory_type: MemoryType,
+    length: usize,
+) -> uefi::Result<&'static mut [u8]> {
+    let pages = ((length + PAGE_SIZE - 1) / PAGE_SIZE).max(1);
+    let base = boot::allocate_pages(allocate_type, memory_type, pages)?;
+
+    // SAFETY: The pages were just allocated for us and are never freed, because the kernel takes
+    // them over.
+    unsafe {
+        core::ptr::write_bytes(base.as_ptr(), 0, pages * PAGE_SIZE);
+        Ok(core::slice::from_raw_parts_mut(
+            base.as_ptr(),
+            pages * PAGE_SIZE,
+        ))
+    }
+}
+
+/// The E820 type of memory of a UEFI memory type.
+fn e820_type(memory_type: MemoryType) -> u32 {
+    match memory_type {
+        MemoryType::CONVENTIONAL
+        | MemoryType::LOADER_CODE
+        | MemoryType::LOADER_DATA
+        | MemoryType::BOOT_SERVICES_CODE
+        | MemoryType::BOOT_SERVICES_DATA => E820_RAM,
+        MemoryType::ACPI_RECLAIM => E820_ACPI,
+        MemoryType::ACPI_NON_VOLATILE => E820_NVS,
+        MemoryType::UNUSABLE => E820_UNUSABLE,
+        MemoryType::PERSISTENT_MEMORY => E820_PMEM,
+        _ => E820_RESERVED,
+    }
+}
+
+/// A raw bzImage loaded into memory, along with its boot parameters.
+pub struct BzImage {
+    boot_params: &'static mut [u8],
+    entry: u64,
+}
+
+impl BzImage {
+    /// Load a raw bzImage with its command line and initrd.
+    ///
+    /// `load_options` is the command line in UCS-2, as passed to kernels with an EFI stub. Boot
+    /// services are not exited until [`BzImage::start`] is called.
+    pub fn load(kernel: &[u8], load_options: &[u8], initrd: &[u8]) -> uefi::Result<BzImage> {
+        if !is_bzimage(kernel) || kernel.len() <= offsets::INIT_SIZE + 4 {
+            return Err(Status::LOAD_ERROR.into());
+        }
+        if read_u16(kernel, offsets::VERSION) < MIN_PROTOCOL_VERSION
+            || read_u16(kernel, offsets::XLOADFLAGS) & XLF_KERNEL_64 == 0
+        {
+            log::error!("The kernel has no 64-bit entry point.");
+            return Err(Status::UNSUPPORTED.into());
+        }
+
+        let boot_params = allocate(
+            AllocateType::MaxAddress(BELOW_4G),
+            MemoryType::LOADER_DATA,
+            PAGE_SIZE,
+        )?;
+        let header_end = offsets::HEADER + usize::from(kernel[offsets::SETUP_HEADER_LENGTH]);
+        if header_end > kernel.len() || header_end > PAGE_SIZE {
+            return Err(Status::LOAD_ERROR.into());
+        }
+        boot_params[offsets::SETUP_SECTS..header_end]
+            .copy_from_slice(&kernel[offsets::SETUP_SECTS..header_end]);
+        boot_params[offsets::TYPE_OF_LOADER] = LOADER_TYPE_UNDEFINED;
+
+        // The protected-mode kernel follows the boot sector and the real-mode setup code.
+        let setup_sects = match kernel[offsets::SETUP_SECTS] {
+            0 => 4,
+            setup_sects => usize::from(setup_sects),
+        };
+        let protected_mode_kernel = kernel
+            .get((setup_sects + 1) * 512..)
+            .ok_or(Status::LOAD_ERROR)?;
+        let init_size = usize::try_from(read_u32(kernel, offsets::INIT_SIZE))
+            .unwrap()
+            .max(protected_mode_kernel.len());
+        let kernel_memory = if kernel[offsets::RELOCATABLE_KERNEL] != 0 {
+            let alignment = usize::try_from(read_u32(kernel, offsets::KERNEL_ALIGNMENT))
+                .unwrap()
+                .max(PAGE_SIZE);
+            let memory = allocate(
+                AllocateType::AnyPages,
+                MemoryType::LOADER_CODE,
+                init_size + alignment,
+            )?;
+            let misalignment = (memory.as_ptr() as usize) % alignment;
+            let start = if misalignment == 0 {
+                0
+            } else {
+                alignment - misalignment
+            };
+            &mut memory[start..start + init_size]
+        } else {
+            let pref_address = u64::from_le_bytes(
+                kernel[offsets::PREF_ADDRESS..offsets::PREF_ADDRESS + 8]
+                    .try_into()
+                    .unwrap(),
+            );
+            &mut allocate(
+                AllocateType::Address(pref_address),
+                MemoryType::LOADER_CODE,
+                init_size,
+            )?[..init_size]
+        };
+        kernel_memory[..protected_mode_kernel.len()].copy_from_slice(protected_mode_kernel);
+        let kernel_address = kernel_memory.as_ptr() as u64;
+        write_u32(boot_params, offsets::CODE32_START, kernel_address as u32);
+
+        // The command line is passed in UCS-2 to kernels with an EFI stub, but in ASCII here.
+        let cmdline_size = usize::try_from(read_u32(kernel, offsets::CMDLINE_SIZE)).unwrap();
+        let cmdline = allocate(
+            AllocateType::MaxAddress(BELOW_4G),
+            MemoryType::LOADER_DATA,
+            cmdline_size + 1,
+        )?;
+        let characters = load_options
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .take(cmdline_size);
+        for (byte, character) in cmdline.iter_mut().zip(characters) {
+            *byte = u8::try_from(character)
+                .ok()
+                .filter(u8::is_ascii)
+                .unwrap_or(b'?');
+        }
+        write_split_u64(
+            boot_params,
+            offsets::CMD_LINE_PTR,
+            offsets::EXT_CMD_LINE_PTR,
+            cmdline.as_ptr() as u64,
+        );
+
+        if !initrd.is_empty() {
+            let initrd_addr_max =
+                if read_u16(kernel, offsets::XLOADFLAGS) & XLF_CAN_BE_LOADED_ABOVE_4G != 0 {
+                    u64::MAX
+                } else {
+                    u64::from(read_u32(kernel, offsets::INITRD_ADDR_MAX))
+                };
+            let initrd_memory = allocate(
+                AllocateType::MaxAddress(initrd_addr_max),
+                MemoryType::LOADER_DATA,
+                initrd.len(),
+            )?;
+            initrd_memory[..initrd.len()].copy_from_slice(initrd);
+            write_split_u64(
+                boot_params,
+                offsets::RAMDISK_IMAGE,
+                offsets::EXT_RAMDISK_IMAGE,
+                initrd_memory.as_ptr() as u64,
+            );
+            write_split_u64(
+                boot_params,
+                offsets::RAMDISK_SIZE,
+                offsets::EXT_RAMDISK_SIZE,
+                initrd.len() as u64,
+            );
+        }
+
+        Ok(BzImage {
+            boot_params,
+            entry: kernel_address + ENTRY_64_OFFSET,
+        })
+    }
+
+    /// Exit boot services and start the kernel.
+    ///
+    /// The memory map is handed to the kernel both as E820 table and as EFI memory map, so that it
+    /// can still use the runtime services.
+    ///
+    /// # Safety
+    /// The kernel is assumed to be trusted, it takes over the machine. Nothing may use boot
+    /// services after this function has been called, which includes logging.
+    pub unsafe fn start(self) -> ! {
+        let boot_params = self.boot_params;
+        let system_table = table::system_table_raw()
+            .map(|system_table| system_table.as_ptr() as u64)
+            .unwrap_or(0);
+
+        // SAFETY: No protocols are open any more and nothing is allocated from here on.
+        let mut memory_map = unsafe { boot::exit_boot_services(MemoryType::LOADER_DATA) };
+        memory_map.sort();
+
+        let mut e820_entries = 0;
+        let mut last: Option<(u64, u64, u32)> = None;
+        let mut push = |boot_params: &mut [u8], (address, size, kind): (u64, u64, u32)| {
+            if e820_entries < E820_MAX_ENTRIES {
+                let entry = offsets::E820_TABLE + e820_entries * E820_ENTRY_SIZE;
+                boot_params[entry..entry + 8].copy_from_slice(&address.to_le_bytes());
+                boot_params[entry + 8..entry + 16].copy_from_slice(&size.to_le_bytes());
+                write_u32(boot_params, entry + 16, kind);
+                e820_entries += 1;
+            }
+        };
+        for descriptor in memory_map.entries() {
+            let kind = e820_type(descriptor.ty);
+            let size = descriptor.page_count * PAGE_SIZE as u64;
+            last = match last {
+                // Merge adjacent regions of the same type.
+                Some((address, last_size, last_kind))
+                    if last_kind == kind && address + last_size == descriptor.phys_start =>
+                {
+                    Some((address, last_size + size, kind))
+                }
+                Some(region) => {
+                    push(boot_params, region);
+                    Some((descriptor.phys_start, size, kind))
+                }
+                None => Some((descriptor.phys_start, size, kind)),
+            };
+        }
+        if let Some(region) = last {
+            push(boot_params, region);
+        }
+        boot_params[offsets::E820_ENTRIES] = e820_entries as u8;
+
+        let meta = memory_map.meta();
+        let efi_info = offsets::EFI_INFO;
+        boot_params[efi_info..efi_info + 4].copy_from_slice(b"EL64");
+        write_split_u64(boot_params, efi_info + 4, efi_info + 24, system_table);
+        write_u32(boot_params, efi_info + 8, meta.desc_size as u32);
+        write_u32(boot_params, efi_info + 12, meta.desc_version);
+        write_split_u64(
+            boot_params,
+            efi_info + 16,
+            efi_info + 28,
+            memory_map.buffer().as_ptr() as u64,
+        );
+        write_u32(boot_params, efi_info + 20, meta.map_size as u32);
+        // The kernel keeps using the memory map, it must never be freed.
+        core::mem::forget(memory_map);
+
+        // SAFETY: The entry point has been checked to exist through `xloadflags`. It expects the
+        // boot parameters in `rsi`, which is the second argument in the System V calling
+        // convention.
+        unsafe {
+            let entry: extern "sysv64" fn(u64, NonNull<u8>) -> ! =
+                core::mem::transmute(self.entry as usize);
+            core::arch::asm!("cli");
+            entry(0, NonNull::new_unchecked(boot_params.as_mut_ptr()))
+        }
+    }
+}
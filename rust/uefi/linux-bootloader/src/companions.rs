@@ -1,21 +1,43 @@
-use crate::cpio::{pack_cpio, Cpio};
-use alloc::{string::ToString, vec::Vec};
+use crate::{
+    cpio::{pack_cpio, pack_cpio_literal, Cpio},
+    credential_manifest::CredentialManifest,
+    pe_section::pe_section,
+};
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
 use uefi::{
+    boot::{self, OpenProtocolAttributes, OpenProtocolParams},
     cstr16,
     fs::{Path, PathBuf},
-    proto::device_path::{
-        text::{AllowShortcuts, DisplayOnly},
-        DevicePath,
+    guid,
+    proto::{
+        device_path::{
+            text::{AllowShortcuts, DisplayOnly},
+            DevicePath,
+        },
+        media::{fs::SimpleFileSystem, partition::PartitionInfo},
     },
-    CString16,
+    CString16, Guid, Handle,
 };
 
-/// Locate files with ASCII filenames and matching the suffix passed as a parameter.
-/// Returns a list of their paths.
+/// Partition type GUID of the Extended Boot Loader Partition (XBOOTLDR).
+/// https://uapi-group.org/specifications/specs/discoverable_partitions_specification/
+const XBOOTLDR_PARTITION_TYPE: Guid = guid!("bc13c2ff-59e6-4262-a352-b275fd6f7172");
+
+/// File name extensions of credentials.
+pub const CREDENTIAL_EXTENSIONS: &[&str] = &[".cred"];
+
+/// File name extensions of system extension images.
+pub const SYSTEM_EXTENSION_EXTENSIONS: &[&str] = &[".raw"];
+
+/// Locate files with ASCII filenames and matching any of the suffixes passed as a parameter.
+/// Returns a list of their paths, in directory order; [`pack_cpio`] sorts them.
 pub fn find_files(
     fs: &mut uefi::fs::FileSystem,
     search_path: &Path,
-    suffix: &str,
+    suffixes: &[&str],
 ) -> uefi::Result<Vec<PathBuf>> {
     let mut results = Vec::new();
 
@@ -23,7 +45,8 @@ pub fn find_files(
         let entry = maybe_entry?;
         if entry.is_regular_file() {
             let fname = entry.file_name();
-            if fname.is_ascii() && fname.to_string().ends_with(suffix) {
+            let utf8_fname = fname.to_string();
+            if fname.is_ascii() && suffixes.iter().any(|suffix| utf8_fname.ends_with(suffix)) {
                 let mut full_path = CString16::from(search_path.to_cstr16());
                 full_path.push_str(cstr16!("\\"));
                 full_path.push_str(fname);
@@ -35,6 +58,18 @@ pub fn find_files(
     Ok(results)
 }
 
+/// Name of a credential, i.e. the file name of its path without its extension, see
+/// [`CREDENTIAL_EXTENSIONS`].
+pub fn credential_name(path: &Path) -> String {
+    let path = path.to_cstr16().to_string();
+    let file_name = path.rsplit('\\').next().unwrap_or(&path);
+    CREDENTIAL_EXTENSIONS
+        .iter()
+        .find_map(|extension| file_name.strip_suffix(extension))
+        .unwrap_or(file_name)
+        .to_string()
+}
+
 /// Returns the "default" drop-in directory if it exists.
 /// This will be in general $loaded_image_path.extra/
 pub fn get_default_dropin_directory(
@@ -77,6 +112,8 @@ pub enum CompanionInitrdType {
 pub struct CompanionInitrd {
     pub r#type: CompanionInitrdType,
     pub cpio: Cpio,
+    /// The files packed into `cpio`.
+    pub files: Vec<PathBuf>,
 }
 
 /// Collect all credentials and return them as CPIO archive.
@@ -85,13 +122,53 @@ pub struct CompanionInitrd {
 ///   - global: `$ESP/loader.credentials/*.cred`
 ///   - image-specific: `$path_to_image.extra/*.cred`
 ///
-/// The credentials are not measured.
+/// The credentials are not measured. If a `manifest` is given, only credentials that match it
+/// are packed, see [`pack_cpio`].
 pub fn discover_credentials(
     fs: &mut uefi::fs::FileSystem,
     default_dropin_dir: Option<&Path>,
+    manifest: Option<&CredentialManifest>,
 ) -> uefi::Result<Vec<CompanionInitrd>> {
     let mut companions = Vec::new();
 
+    if let Some(global_credentials) = discover_global_credentials(fs, manifest)? {
+        companions.push(global_credentials);
+    }
+
+    if let Some(default_dropin_dir) = default_dropin_dir {
+        let mut local_credentials: Vec<PathBuf> =
+            find_files(fs, default_dropin_dir, CREDENTIAL_EXTENSIONS)?;
+
+        if !local_credentials.is_empty() {
+            companions.push(CompanionInitrd {
+                r#type: CompanionInitrdType::Credentials,
+                cpio: pack_cpio(
+                    fs,
+                    &mut local_credentials,
+                    ".extra/credentials",
+                    0o500,
+                    0o400,
+                    manifest,
+                )?,
+                files: local_credentials,
+            });
+        }
+    }
+
+    Ok(companions)
+}
+
+/// Collect the global credentials of a file system, i.e. `\loader\credentials\*.cred`, and
+/// return them as CPIO archive.
+///
+/// This is used for the ESP and the XBOOTLDR partition, if there is one.
+///
+/// The credentials are not measured. If a `manifest` is given, only credentials that match it
+/// are packed, see [`pack_cpio`].
+pub fn discover_global_credentials(
+    fs: &mut uefi::fs::FileSystem,
+    manifest: Option<&CredentialManifest>,
+) -> uefi::Result<Option<CompanionInitrd>> {
     let default_global_dropin_dir = cstr16!("\\loader\\credentials");
     if fs.try_exists(default_global_dropin_dir).unwrap() {
         let metadata = fs.metadata(default_global_dropin_dir).map_err(|_err| {
@@ -99,39 +176,103 @@ pub fn discover_credentials(
             uefi::Error::new(uefi::Status::VOLUME_CORRUPTED, ())
         })?;
         if metadata.is_directory() {
-            let global_credentials: Vec<PathBuf> =
-                find_files(fs, default_global_dropin_dir.as_ref(), ".cred")?;
+            let mut global_credentials: Vec<PathBuf> = find_files(
+                fs,
+                default_global_dropin_dir.as_ref(),
+                CREDENTIAL_EXTENSIONS,
+            )?;
 
             if !global_credentials.is_empty() {
-                companions.push(CompanionInitrd {
+                return Ok(Some(CompanionInitrd {
                     r#type: CompanionInitrdType::GlobalCredentials,
                     cpio: pack_cpio(
                         fs,
-                        global_credentials,
+                        &mut global_credentials,
                         ".extra/global_credentials",
                         0o500,
                         0o400,
-                    )
-                    .map_err(|_err| uefi::Status::LOAD_ERROR)?,
-                });
+                        manifest,
+                    )?,
+                    files: global_credentials,
+                }));
             }
         }
     }
 
-    if let Some(default_dropin_dir) = default_dropin_dir {
-        let local_credentials: Vec<PathBuf> = find_files(fs, default_dropin_dir, ".cred")?;
+    Ok(None)
+}
 
-        if !local_credentials.is_empty() {
+/// Check whether a partition is an Extended Boot Loader Partition.
+fn is_xbootldr(handle: Handle) -> bool {
+    // SAFETY: The protocol is only read from, and the reference does not outlive this function.
+    // Opening it non-exclusively ensures that the drivers of the partition stay connected.
+    let partition_info = unsafe {
+        boot::open_protocol::<PartitionInfo>(
+            OpenProtocolParams {
+                handle,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )
+    };
+
+    partition_info
+        .ok()
+        .and_then(|partition_info| {
+            partition_info.gpt_partition_entry().map(|entry| {
+                let partition_type = entry.partition_type_guid;
+                partition_type.0 == XBOOTLDR_PARTITION_TYPE
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// Open the file system of the Extended Boot Loader Partition (XBOOTLDR), if there is one.
+pub fn open_xbootldr_file_system() -> Option<uefi::fs::FileSystem> {
+    boot::find_handles::<PartitionInfo>()
+        .ok()?
+        .into_iter()
+        .find(|handle| is_xbootldr(*handle))
+        .and_then(|handle| boot::open_protocol_exclusive::<SimpleFileSystem>(handle).ok())
+        .map(uefi::fs::FileSystem::new)
+}
+
+/// Pass the signed PCR policies and the public key of an image to the initrd, like systemd-stub
+/// does, as `/.extra/tpm2-pcr-signature.json` and `/.extra/tpm2-pcr-public-key.pem`.
+///
+/// They come from the `.pcrsig` and `.pcrpkey` sections, if the image has them. systemd-cryptsetup
+/// uses them to unlock volumes that are bound to a signed PCR policy. They are not measured as
+/// initrds: `.pcrpkey` is already measured as a unified section, and `.pcrsig` contains
+/// signatures over the measurements.
+pub fn pcr_companions(pe_data: &[u8]) -> uefi::Result<Vec<CompanionInitrd>> {
+    let mut companions = Vec::new();
+
+    for (r#type, section_name, file_name) in [
+        (
+            CompanionInitrdType::PcrSignature,
+            ".pcrsig",
+            cstr16!("tpm2-pcr-signature.json"),
+        ),
+        (
+            CompanionInitrdType::PcrPublicKey,
+            ".pcrpkey",
+            cstr16!("tpm2-pcr-public-key.pem"),
+        ),
+    ] {
+        if let Some(contents) = pe_section(pe_data, section_name) {
             companions.push(CompanionInitrd {
-                r#type: CompanionInitrdType::Credentials,
-                cpio: pack_cpio(fs, local_credentials, ".extra/credentials", 0o500, 0o400)
+                r#type,
+                cpio: pack_cpio_literal(contents, Path::new(file_name), ".extra", 0o555, 0o444)
                     .map_err(|_err| uefi::Status::LOAD_ERROR)?,
+                files: Vec::new(),
             });
         }
     }
 
     Ok(companions)
 }
+
 /// Discover any system image extension, i.e. files ending by .raw
 /// They must be present inside $path_to_image.extra/*.raw, specific to this image.
 ///
@@ -142,13 +283,13 @@ pub fn discover_system_extensions(
     default_dropin_dir: &Path,
 ) -> uefi::Result<Vec<CompanionInitrd>> {
     let mut companions = Vec::new();
-    let sysexts = find_files(fs, default_dropin_dir, ".raw")?;
+    let mut sysexts = find_files(fs, default_dropin_dir, SYSTEM_EXTENSION_EXTENSIONS)?;
 
     if !sysexts.is_empty() {
         companions.push(CompanionInitrd {
             r#type: CompanionInitrdType::SystemExtension,
-            cpio: pack_cpio(fs, sysexts, ".extra/sysext", 0o555, 0o444)
-                .map_err(|_err| uefi::Status::LOAD_ERROR)?,
+            cpio: pack_cpio(fs, &mut sysexts, ".extra/sysext", 0o555, 0o444, None)?,
+            files: sysexts,
         });
     }
 
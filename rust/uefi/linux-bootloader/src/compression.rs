@@ -0,0 +1,147 @@
+//! Decompression of kernels and embedded sections.
+//!
+//! The compression format is detected by the magic bytes at the start of the data, like the Linux
+//! kernel does for its initrd. This way, the stub does not depend on the compressor that was
+//! chosen at build time. Data without a known magic is passed through unchanged.
+
+use alloc::vec::Vec;
+use ruzstd::{io::Read, StreamingDecoder};
+use uefi::Status;
+
+/// Compression formats that can be detected by their magic bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// No known magic, the data is used as is.
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    const GZIP_MAGIC: &'static [u8] = &[0x1f, 0x8b];
+    const ZSTD_MAGIC: &'static [u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+    const XZ_MAGIC: &'static [u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+
+    /// Detect the compression format of the data from its magic bytes.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(Self::GZIP_MAGIC) {
+            Self::Gzip
+        } else if data.starts_with(Self::ZSTD_MAGIC) {
+            Self::Zstd
+        } else if data.starts_with(Self::XZ_MAGIC) {
+            Self::Xz
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// Decompress data in whatever format its magic bytes indicate.
+///
+/// Data without a known magic is returned unchanged, without being copied.
+pub fn decompress(data: Vec<u8>) -> uefi::Result<Vec<u8>> {
+    match Compression::detect(&data) {
+        Compression::None => Ok(data),
+        Compression::Gzip => decompress_gzip(&data),
+        Compression::Zstd => decompress_zstd(&data),
+        Compression::Xz => crate::xz::decompress(&data),
+    }
+}
+
+/// Check the structure of compressed data without decompressing it.
+///
+/// This catches truncated or otherwise corrupt data, e.g. a compressed initrd, before it is handed
+/// to the kernel, which would only fail with a confusing panic while unpacking it. Only the
+/// headers and the minimum length are checked, so this is cheap even for large data. Data without
+/// a known magic is always accepted.
+pub fn validate(data: &[u8]) -> uefi::Result<()> {
+    let well_formed = match Compression::detect(data) {
+        Compression::None => true,
+        // The deflate stream needs at least 2 bytes, followed by the 8 bytes of the trailer.
+        Compression::Gzip => gzip_payload(data)?.len() >= 10,
+        Compression::Zstd => {
+            let mut rest = data;
+            // A frame header is followed by at least one block header of 3 bytes.
+            ruzstd::frame::read_frame_header(&mut rest).is_ok() && rest.len() >= 3
+        }
+        // Every xz stream ends with the magic bytes of its footer, only followed by zero padding.
+        Compression::Xz => {
+            let end = data
+                .iter()
+                .rposition(|&b| b != 0)
+                .map_or(0, |last| last + 1);
+            end >= 24 && data[..end].ends_with(b"YZ")
+        }
+    };
+
+    if well_formed {
+        Ok(())
+    } else {
+        Err(Status::COMPROMISED_DATA.into())
+    }
+}
+
+/// Decompress a single gzip member, see RFC 1952.
+///
+/// The trailer with the CRC of the uncompressed data is not checked, because the stub verifies
+/// the compressed data by its hash anyway.
+fn decompress_gzip(data: &[u8]) -> uefi::Result<Vec<u8>> {
+    miniz_oxide::inflate::decompress_to_vec(gzip_payload(data)?)
+        .map_err(|_err| Status::COMPROMISED_DATA.into())
+}
+
+/// Skip the header of a gzip member and return the deflate stream and trailer that follow it.
+fn gzip_payload(data: &[u8]) -> uefi::Result<&[u8]> {
+    const FHCRC: u8 = 1 << 1;
+    const FEXTRA: u8 = 1 << 2;
+    const FNAME: u8 = 1 << 3;
+    const FCOMMENT: u8 = 1 << 4;
+    // The only compression method defined for gzip.
+    const CM_DEFLATE: u8 = 8;
+
+    let invalid = || uefi::Error::from(Status::COMPROMISED_DATA);
+
+    let header = data.get(..10).ok_or_else(invalid)?;
+    if header[2] != CM_DEFLATE {
+        return Err(Status::UNSUPPORTED.into());
+    }
+    let flags = header[3];
+    let mut rest = &data[10..];
+
+    if flags & FEXTRA != 0 {
+        let length = rest.get(..2).ok_or_else(invalid)?;
+        let length = usize::from(u16::from_le_bytes([length[0], length[1]]));
+        rest = rest.get(2 + length..).ok_or_else(invalid)?;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            // Skip the zero-terminated string.
+            let end = rest.iter().position(|&b| b == 0).ok_or_else(invalid)?;
+            rest = &rest[end + 1..];
+        }
+    }
+    if flags & FHCRC != 0 {
+        rest = rest.get(2..).ok_or_else(invalid)?;
+    }
+
+    Ok(rest)
+}
+
+/// Decompress a zstd frame.
+fn decompress_zstd(mut data: &[u8]) -> uefi::Result<Vec<u8>> {
+    let mut decoder = StreamingDecoder::new(&mut data)
+        .map_err(|_err| uefi::Error::from(Status::COMPROMISED_DATA))?;
+
+    let mut decompressed = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        match decoder.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => decompressed.extend_from_slice(&buffer[..read]),
+            Err(_err) => return Err(Status::COMPROMISED_DATA.into()),
+        }
+    }
+
+    Ok(decompressed)
+}
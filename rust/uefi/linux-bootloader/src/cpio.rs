@@ -1,8 +1,14 @@
 use core::convert::Infallible;
 
 use alloc::{string::String, vec::Vec};
+use log::{error, warn};
 use pio::errors::CPIOError;
-use uefi::fs::{Path, PathBuf};
+use uefi::{
+    fs::{Path, PathBuf},
+    Status,
+};
+
+use crate::credential_manifest::{CredentialManifest, MismatchPolicy};
 
 pub type Cpio = pio::writer::Cpio<Infallible>;
 pub type Result = core::result::Result<Cpio, CPIOError<Infallible>>;
@@ -35,7 +41,12 @@ pub fn pack_cpio_literal(
 ///
 /// In the CPIO archives, only the basename is retained as a filename.
 ///
-/// For consistency of TPM2 measurements, the `files` list will be sorted in this function.
+/// For consistency of TPM2 measurements, the `files` list will be sorted in this function, also
+/// if it was collected for several file name extensions.
+///
+/// If a credential `manifest` is given, every file is checked against it. Files that do not match
+/// are either skipped, in which case they are removed from `files`, or the whole archive is
+/// refused with `SECURITY_VIOLATION`.
 ///
 /// Target directory prefix will be created with `dir_mode` access privileges,
 /// files will be created with `access_mode`.
@@ -44,28 +55,52 @@ pub fn pack_cpio_literal(
 /// permission bits.
 pub fn pack_cpio(
     fs: &mut uefi::fs::FileSystem,
-    mut files: Vec<PathBuf>,
+    files: &mut Vec<PathBuf>,
     target_dir_prefix: &str,
     dir_mode: u32,
     access_mode: u32,
-) -> Result {
+    manifest: Option<&CredentialManifest>,
+) -> uefi::Result<Cpio> {
     let mut cpio = Cpio::new();
 
     // Ensure consistency of the CPIO archive layout for future potential measurements via TPM2.
     files.sort();
 
-    cpio.pack_prefix(target_dir_prefix, dir_mode)?;
-    for file in files {
+    let mut packed_files = Vec::with_capacity(files.len());
+    cpio.pack_prefix(target_dir_prefix, dir_mode)
+        .map_err(|_err| Status::LOAD_ERROR)?;
+    for file in files.drain(..) {
         let utf8_filename = String::from(
             &file
                 .components()
                 .last()
                 .expect("Expected the filename to possess a file name!"),
         );
-        let contents = fs.read(file).expect("failed to read");
-        cpio.pack_one(&utf8_filename, &contents, target_dir_prefix, access_mode)?;
+        let contents = fs.read(&file).expect("failed to read");
+
+        if let Some(manifest) = manifest {
+            if !manifest.allows(&file, &contents) {
+                match manifest.on_mismatch {
+                    MismatchPolicy::Skip => {
+                        warn!(
+                            "{utf8_filename} does not match the credential manifest, skipping it."
+                        );
+                        continue;
+                    }
+                    MismatchPolicy::Refuse => {
+                        error!("{utf8_filename} does not match the credential manifest, refusing to pack credentials.");
+                        return Err(Status::SECURITY_VIOLATION.into());
+                    }
+                }
+            }
+        }
+
+        cpio.pack_one(&utf8_filename, &contents, target_dir_prefix, access_mode)
+            .map_err(|_err| Status::LOAD_ERROR)?;
+        packed_files.push(file);
     }
-    cpio.pack_trailer()?;
+    cpio.pack_trailer().map_err(|_err| Status::LOAD_ERROR)?;
 
+    *files = packed_files;
     Ok(cpio)
 }
@@ -0,0 +1,72 @@
+//! A manifest of the credentials that may be passed to the system.
+//!
+//! Credentials are read from the ESP, which is not protected by Secure Boot. Their packed CPIO
+//! archive is measured, but a tampered credential still ends up in the initrd. A manifest that is
+//! embedded in the image pins the exact contents of every credential that is allowed in.
+
+use alloc::{collections::BTreeMap, string::String};
+use sha2::{Digest, Sha256};
+use uefi::fs::Path;
+
+use crate::companions::credential_name;
+
+/// What to do with a credential that does not match the manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MismatchPolicy {
+    /// Leave the credential out of the CPIO archive and continue.
+    Skip,
+    /// Refuse to pack the credentials at all.
+    Refuse,
+}
+
+/// The expected SHA-256 hashes of credentials, by credential name.
+pub struct CredentialManifest {
+    hashes: BTreeMap<String, [u8; 32]>,
+    pub on_mismatch: MismatchPolicy,
+}
+
+impl CredentialManifest {
+    /// Parse a manifest with one `<hex SHA-256> <credential name>` line per credential, i.e. the
+    /// format of `sha256sum`.
+    ///
+    /// Malformed lines are ignored, so the credentials they were meant for do not match.
+    pub fn parse(manifest: &[u8], on_mismatch: MismatchPolicy) -> Self {
+        let hashes = core::str::from_utf8(manifest)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let (hash, name) = line.trim().split_once(char::is_whitespace)?;
+                Some((String::from(name.trim_start()), parse_hash(hash)?))
+            })
+            .collect();
+
+        Self {
+            hashes,
+            on_mismatch,
+        }
+    }
+
+    /// Whether a credential file with these contents is allowed.
+    ///
+    /// Credentials that are not listed in the manifest are not allowed.
+    pub fn allows(&self, file: &Path, contents: &[u8]) -> bool {
+        self.hashes
+            .get(&credential_name(file))
+            .map(|hash| *hash == <[u8; 32]>::from(Sha256::digest(contents)))
+            .unwrap_or(false)
+    }
+}
+
+/// Parse a SHA-256 hash in hexadecimal notation.
+pub(crate) fn parse_hash(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut hash = [0u8; 32];
+    for (byte, digits) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+
+    Some(hash)
+}
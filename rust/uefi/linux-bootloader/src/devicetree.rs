@@ -0,0 +1,324 @@
+//! Installing a device tree, as systemd-stub does.
+//!
+//! ARM and RISC-V kernels find the description of the hardware in a flattened device tree (FDT)
+//! that the firmware passes in its configuration table. An image can carry its own device tree in
+//! the `.dtb` section, which then replaces the one of the firmware, e.g. for boards whose firmware
+//! ships an outdated one.
+//!
+//! An image that boots on several boards can instead carry one device tree per board in several
+//! `.dtbauto` sections. The one whose `compatible` property matches the device tree of the
+//! firmware is installed.
+
+use core::ffi::c_void;
+use log::{error, info};
+use uefi::{
+    boot::{self, MemoryType},
+    guid, system, Guid, Status,
+};
+
+use crate::pe_section::{pe_section, pe_sections};
+
+/// Configuration table in which the kernel looks for a flattened device tree.
+pub const DTB_TABLE_GUID: Guid = guid!("b1b621d5-f19c-41a5-830b-d9152c69aae0");
+
+/// The magic number at the start of a flattened device tree, stored big-endian.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/// Size of the header of a flattened device tree.
+const FDT_HEADER_SIZE: usize = 40;
+
+/// Tokens of the structure block of a flattened device tree.
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+/// Read a big-endian 32-bit value of a flattened device tree.
+fn read_be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// The offset of the token that follows `offset` and `size` bytes of data, which are padded to a
+/// multiple of 4 bytes.
+fn next_token(offset: usize, size: usize) -> Option<usize> {
+    Some(offset.checked_add(size)?.checked_add(3)? & !3)
+}
+
+/// Find the `compatible` property of the root node of a flattened device tree, i.e. the
+/// NUL-separated list of the models of the board, from the most to the least specific.
+fn root_compatible(dtb: &[u8]) -> Option<&[u8]> {
+    if read_be32(dtb, 0)? != FDT_MAGIC {
+        return None;
+    }
+    let dtb = dtb.get(..usize::try_from(read_be32(dtb, 4)?).ok()?)?;
+    if dtb.len() < FDT_HEADER_SIZE {
+        return None;
+    }
+    let structure_start = usize::try_from(read_be32(dtb, 8)?).ok()?;
+    let strings = dtb.get(usize::try_from(read_be32(dtb, 12)?).ok()?..)?;
+
+    let mut offset = structure_start;
+    while read_be32(dtb, offset)? == FDT_NOP {
+        offset += 4;
+    }
+    // The root node has an empty name, which is padded to 4 bytes.
+    if read_be32(dtb, offset)? != FDT_BEGIN_NODE || *dtb.get(offset + 4)? != 0 {
+        return None;
+    }
+    offset += 8;
+
+    // The properties of a node precede its children.
+    loop {
+        match read_be32(dtb, offset)? {
+            FDT_NOP => offset += 4,
+            FDT_PROP => {
+                let size = usize::try_from(read_be32(dtb, offset + 4)?).ok()?;
+                let name_offset = usize::try_from(read_be32(dtb, offset + 8)?).ok()?;
+                let value_start = offset + 12;
+                let value = dtb.get(value_start..value_start.checked_add(size)?)?;
+                let name = strings.get(name_offset..)?;
+                if name.starts_with(b"compatible\0") {
+                    return Some(value);
+                }
+                offset = next_token(value_start, size)?;
+            }
+            // A child node, or the end of the root node.
+            _ => return None,
+        }
+    }
+}
+
+/// Select the device tree for this board among `candidates`, i.e. the first one whose `compatible`
+/// property contains the most specific model of the firmware's device tree.
+///
+/// Malformed device trees never match.
+pub fn select_devicetree<'a>(firmware_dtb: &[u8], candidates: &[&'a [u8]]) -> Option<&'a [u8]> {
+    let model = root_compatible(firmware_dtb)?
+        .split(|&byte| byte == 0)
+        .next()
+        .filter(|model| !model.is_empty())?;
+
+    candidates.iter().copied().find(|candidate| {
+        root_compatible(candidate)
+            .map(|compatible| compatible.split(|&byte| byte == 0).any(|c| c == model))
+            .unwrap_or(false)
+    })
+}
+
+/// The device tree that the firmware installed in the configuration table, if any.
+fn firmware_devicetree() -> Option<&'static [u8]> {
+    let table = system::with_config_table(|entries| {
+        entries
+            .iter()
+            .find(|entry| entry.guid == DTB_TABLE_GUID)
+            .map(|entry| entry.address as *const u8)
+            .filter(|address| !address.is_null())
+    })?;
+
+    // SAFETY: The firmware keeps the device tree allocated. Its header is read before its size.
+    unsafe {
+        let header = core::slice::from_raw_parts(table, FDT_HEADER_SIZE);
+        if read_be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let size = usize::try_from(read_be32(header, 4)?).ok()?;
+        Some(core::slice::from_raw_parts(
+            table,
+            size.max(FDT_HEADER_SIZE),
+        ))
+    }
+}
+
+/// Choose among the `.dtbauto` sections of an image, `candidates`, the one that matches the
+/// device tree of the firmware, see [`select_devicetree`], or else fall back to its `.dtb` section.
+fn choose_devicetree<'a>(
+    firmware_dtb: Option<&[u8]>,
+    candidates: &[&'a [u8]],
+    dtb: Option<&'a [u8]>,
+) -> Option<&'a [u8]> {
+    if !candidates.is_empty() {
+        if let Some(selected) =
+            firmware_dtb.and_then(|firmware| select_devicetree(firmware, candidates))
+        {
+            info!("Selected the embedded device tree that matches this board.");
+            return Some(selected);
+        }
+        info!("None of the embedded device trees matches this board.");
+    }
+
+    dtb
+}
+
+/// Find the device tree of an image to install on this board, see [`choose_devicetree`].
+pub fn embedded_devicetree(pe_data: &[u8]) -> Option<&[u8]> {
+    let candidates = pe_sections(pe_data, ".dtbauto");
+    let firmware_dtb = if candidates.is_empty() {
+        None
+    } else {
+        firmware_devicetree()
+    };
+
+    choose_devicetree(firmware_dtb, &candidates, pe_section(pe_data, ".dtb"))
+}
+
+/// Install a flattened device tree in the configuration table, replacing the one of the firmware
+/// if there is one.
+///
+/// The device tree is only checked to be a flattened device tree, it is not validated any further.
+/// Like the other unified sections, the `.dtb` and `.dtbauto` sections are covered by the signature
+/// of the image and measured into PCR 11.
+pub fn install_devicetree(dtb: &[u8]) -> uefi::Result<()> {
+    let header = |offset: usize| {
+        dtb.get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    let size = match (header(0), header(4)) {
+        (Some(FDT_MAGIC), Some(size)) if (size as usize) <= dtb.len() => size as usize,
+        _ => {
+            error!("The embedded device tree is not a flattened device tree.");
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+    };
+
+    let replaces_firmware_dtb = system::with_config_table(|entries| {
+        entries.iter().any(|entry| entry.guid == DTB_TABLE_GUID)
+    });
+
+    // The kernel keeps ACPI reclaim memory around until it has read the table.
+    let table = boot::allocate_pool(MemoryType::ACPI_RECLAIM, size)?;
+    // SAFETY: The allocation is `size` bytes large and not used by anything else yet.
+    unsafe { core::ptr::copy_nonoverlapping(dtb.as_ptr(), table.as_ptr(), size) };
+
+    // SAFETY: The table is never freed once it is installed.
+    if let Err(err) = unsafe {
+        boot::install_configuration_table(&DTB_TABLE_GUID, table.as_ptr() as *const c_void)
+    } {
+        // SAFETY: The table was not installed, so nothing refers to it.
+        unsafe { boot::free_pool(table) }.ok();
+        return Err(err);
+    }
+
+    if replaces_firmware_dtb {
+        info!("Replaced the device tree of the firmware with the embedded one.");
+    } else {
+        info!("Installed the embedded device tree.");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FDT_END_NODE: u32 = 2;
+    const FDT_END: u32 = 9;
+
+    /// Build a flattened device tree whose root node only has a `compatible` property.
+    fn fdt(compatible: &[&str]) -> Vec<u8> {
+        let mut value: Vec<u8> = compatible
+            .iter()
+            .flat_map(|model| model.bytes().chain([0]))
+            .collect();
+        let size = value.len() as u32;
+        value.resize(next_token(0, value.len()).unwrap(), 0);
+
+        let mut structure = Vec::new();
+        structure.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+        structure.extend_from_slice(&[0; 4]);
+        structure.extend_from_slice(&FDT_PROP.to_be_bytes());
+        structure.extend_from_slice(&size.to_be_bytes());
+        structure.extend_from_slice(&0u32.to_be_bytes());
+        structure.extend_from_slice(&value);
+        structure.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+        structure.extend_from_slice(&FDT_END.to_be_bytes());
+        let strings = b"compatible\0";
+
+        let structure_offset = FDT_HEADER_SIZE as u32;
+        let strings_offset = structure_offset + structure.len() as u32;
+        let total_size = strings_offset + strings.len() as u32;
+        let header = [
+            FDT_MAGIC,
+            total_size,
+            structure_offset,
+            strings_offset,
+            // The memory reservation map, version, last compatible version, boot CPU and sizes of
+            // the blocks are not looked at.
+            0,
+            17,
+            16,
+            0,
+            strings.len() as u32,
+            structure.len() as u32,
+        ];
+
+        let mut dtb: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        dtb.extend_from_slice(&structure);
+        dtb.extend_from_slice(strings);
+        dtb
+    }
+
+    #[test]
+    fn selects_the_matching_devicetree() {
+        let firmware = fdt(&["vendor,board-b", "vendor,soc"]);
+        let board_a = fdt(&["vendor,board-a", "vendor,soc"]);
+        let board_b = fdt(&["vendor,board-b", "vendor,soc"]);
+
+        assert_eq!(
+            select_devicetree(&firmware, &[&board_a, &board_b]),
+            Some(board_b.as_slice())
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_dtb_section_without_a_match() {
+        let firmware = fdt(&["vendor,board-c", "vendor,soc"]);
+        let board_a = fdt(&["vendor,board-a", "vendor,soc"]);
+        let dtb = fdt(&["vendor,generic"]);
+
+        assert_eq!(select_devicetree(&firmware, &[&board_a]), None);
+        assert_eq!(
+            choose_devicetree(Some(&firmware), &[&board_a], Some(&dtb)),
+            Some(dtb.as_slice())
+        );
+        assert_eq!(
+            choose_devicetree(None, &[&board_a], Some(&dtb)),
+            Some(dtb.as_slice())
+        );
+    }
+
+    #[test]
+    fn selects_the_first_of_several_candidates_for_the_most_specific_model() {
+        let firmware = fdt(&["vendor,board-b", "vendor,soc"]);
+        // Only the most specific model of the firmware is matched.
+        let soc = fdt(&["vendor,soc"]);
+        let first = fdt(&["vendor,board-b-rev2", "vendor,board-b"]);
+        let second = fdt(&["vendor,board-b"]);
+
+        assert_eq!(
+            select_devicetree(&firmware, &[&soc, &first, &second]),
+            Some(first.as_slice())
+        );
+    }
+
+    #[test]
+    fn malformed_firmware_devicetrees_never_match() {
+        let board = fdt(&["vendor,board"]);
+        let dtb = fdt(&["vendor,generic"]);
+
+        let mut bad_magic = board.clone();
+        bad_magic[0] ^= 0xff;
+        let truncated = &board[..board.len() - 1];
+        let too_short = &board[..FDT_HEADER_SIZE - 1];
+        let mut no_root_node = board.clone();
+        no_root_node[FDT_HEADER_SIZE + 3] = FDT_PROP as u8;
+
+        for firmware in [&bad_magic[..], truncated, too_short, &no_root_node, &[]] {
+            assert_eq!(select_devicetree(firmware, &[&board]), None);
+            assert_eq!(
+                choose_devicetree(Some(firmware), &[&board], Some(&dtb)),
+                Some(dtb.as_slice())
+            );
+        }
+    }
+}
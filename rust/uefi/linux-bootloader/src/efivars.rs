@@ -1,11 +1,16 @@
-use alloc::{format, string::ToString, vec::Vec};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
 use core::mem::size_of;
+use log::{info, warn};
 use uefi::{
     boot, cstr16, guid,
     proto::{
         device_path::{
             media::{HardDrive, PartitionSignature},
-            text::DevicePathToText,
+            text::{AllowShortcuts, DevicePathToText, DisplayOnly},
             DevicePath, DeviceSubType, DeviceType,
         },
         loaded_image::LoadedImage,
@@ -43,6 +48,11 @@ fn disk_get_part_uuid(disk_handle: Handle) -> Result<Guid> {
 pub const BOOT_LOADER_VENDOR_UUID: VariableVendor =
     VariableVendor(guid!("4a67b082-0a4c-41cf-b6c7-440b29bb8c4f"));
 
+/// Attributes of the variables that describe the current boot, e.g. `LoaderEntrySelected`. They
+/// are volatile, so that they never outlive the boot that they describe.
+pub const CURRENT_BOOT_ATTRIBUTES: VariableAttributes =
+    VariableAttributes::BOOTSERVICE_ACCESS.union(VariableAttributes::RUNTIME_ACCESS);
+
 bitflags! {
     #[repr(transparent)]
     #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
@@ -159,7 +169,65 @@ where
     Ok(())
 }
 
+/// The identifier of a boot loader entry for a unified kernel image, i.e. its file name, e.g.
+/// `nixos-generation-1-xyz.efi`, as systemd-boot names entries for images in `\EFI\Linux`.
+fn loader_entry_id(file_path: &DevicePath) -> Option<String> {
+    let file_path = file_path
+        .to_string(DisplayOnly(false), AllowShortcuts(false))
+        .ok()?;
+    let file_path = String::from(&*file_path);
+    let file_name = file_path.rsplit('\\').next()?;
+    (!file_name.is_empty()).then(|| file_name.to_string())
+}
+
+/// Honor a `LoaderEntryOneShot` that no boot loader consumed, e.g. because the firmware started
+/// this image directly.
+///
+/// The stub cannot boot another entry, so the variable is only deleted if it names this one.
+/// Otherwise, it is left for the boot loader on the next boot.
+fn consume_one_shot_entry(entry_id: &str) -> Result<()> {
+    let one_shot = match runtime::get_variable_boxed(
+        cstr16!("LoaderEntryOneShot"),
+        &BOOT_LOADER_VENDOR_UUID,
+    ) {
+        Ok((data, _)) => data,
+        Err(err) if err.status() == Status::NOT_FOUND => return Ok(()),
+        Err(err) => return Err(err.status().into()),
+    };
+
+    if one_shot_names_entry(&one_shot, entry_id) {
+        runtime::delete_variable(cstr16!("LoaderEntryOneShot"), &BOOT_LOADER_VENDOR_UUID)?;
+        info!("Booting the one-shot entry {entry_id}.");
+    } else {
+        let one_shot = decode_entry_id(&one_shot);
+        warn!("The one-shot entry is {one_shot}, not this image, leaving it for the boot loader.");
+    }
+    Ok(())
+}
+
+/// Decode an entry identifier from a variable like `LoaderEntryOneShot`, which holds it as a
+/// zero-terminated UTF-16 string.
+fn decode_entry_id(data: &[u8]) -> String {
+    let utf16: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+        .take_while(|&c| c != 0)
+        .collect();
+    String::from_utf16_lossy(&utf16)
+}
+
+/// Whether the value of `LoaderEntryOneShot` names the entry with this identifier.
+///
+/// Entry identifiers on FAT are case-insensitive, like the file names that they come from.
+fn one_shot_names_entry(one_shot: &[u8], entry_id: &str) -> bool {
+    decode_entry_id(one_shot).eq_ignore_ascii_case(entry_id)
+}
+
 /// Exports systemd-stub style EFI variables
+///
+/// If no boot loader reported the entry that it booted, e.g. because the firmware started this
+/// image directly, `LoaderEntrySelected` is set to this image, and a `LoaderEntryOneShot` that
+/// names it is consumed.
 pub fn export_efi_variables(stub_info_name: &str) -> Result<()> {
     let stub_features: EfiStubFeatures = EfiStubFeatures::ReportBootPartition;
 
@@ -209,6 +277,27 @@ pub fn export_efi_variables(stub_info_name: &str) -> Result<()> {
         },
     )
     .ok();
+    // LoaderEntrySelected
+    if let Some(entry_id) = loaded_image.file_path().and_then(loader_entry_id) {
+        // A boot loader that sets the variable also consumes the one-shot entry.
+        if variable_exists(cstr16!("LoaderEntrySelected"), &BOOT_LOADER_VENDOR_UUID) != Ok(true) {
+            if let Err(err) = consume_one_shot_entry(&entry_id) {
+                warn!("Failed to process the one-shot entry: {err}");
+            }
+        }
+        ensure_efi_variable(
+            cstr16!("LoaderEntrySelected"),
+            &BOOT_LOADER_VENDOR_UUID,
+            CURRENT_BOOT_ATTRIBUTES,
+            || {
+                Ok(entry_id
+                    .encode_utf16()
+                    .flat_map(|c| c.to_le_bytes())
+                    .collect::<Vec<u8>>())
+            },
+        )
+        .ok();
+    }
     // LoaderFirmwareInfo
     ensure_efi_variable(
         cstr16!("LoaderFirmwareInfo"),
@@ -265,3 +354,70 @@ pub fn export_efi_variables(stub_info_name: &str) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_loader_vendor_uuid_matches_systemd() {
+        // The variables show up as `<name>-<GUID>` in efivarfs.
+        assert_eq!(
+            BOOT_LOADER_VENDOR_UUID.0.to_string(),
+            "4a67b082-0a4c-41cf-b6c7-440b29bb8c4f"
+        );
+        // `LOADER_GUID` of systemd-boot, in the mixed-endian layout of EFI GUIDs.
+        assert_eq!(
+            BOOT_LOADER_VENDOR_UUID.0.to_bytes(),
+            [
+                0x82, 0xb0, 0x67, 0x4a, 0x4c, 0x0a, 0xcf, 0x41, 0xb6, 0xc7, 0x44, 0x0b, 0x29, 0xbb,
+                0x8c, 0x4f
+            ]
+        );
+    }
+
+    /// Encode an entry identifier like `bootctl set-oneshot` does.
+    fn utf16(entry_id: &str) -> Vec<u8> {
+        entry_id
+            .encode_utf16()
+            .chain([0])
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+
+    #[test]
+    fn one_shot_names_matching_entry() {
+        let entry_id = "nixos-generation-1-abc.efi";
+        assert!(one_shot_names_entry(&utf16(entry_id), entry_id));
+        // Without the terminating zero.
+        let unterminated = utf16(entry_id);
+        assert!(one_shot_names_entry(
+            &unterminated[..unterminated.len() - 2],
+            entry_id
+        ));
+    }
+
+    #[test]
+    fn one_shot_names_entry_regardless_of_case() {
+        assert!(one_shot_names_entry(
+            &utf16("NixOS-Generation-1-ABC.EFI"),
+            "nixos-generation-1-abc.efi"
+        ));
+    }
+
+    #[test]
+    fn one_shot_does_not_name_other_entries() {
+        let entry_id = "nixos-generation-1-abc.efi";
+        for one_shot in [
+            "nixos-generation-2-abc.efi",
+            "nixos-generation-1-abc",
+            "nixos-generation-1-abc.efi.bak",
+            "",
+        ] {
+            assert!(
+                !one_shot_names_entry(&utf16(one_shot), entry_id),
+                "{one_shot}"
+            );
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Provisioning boots, for sealing secrets against the PCRs without user interaction.
+//!
+//! The booted system requests a provisioning boot by setting the non-volatile
+//! `LanzabooteFirstBoot` EFI variable, with any contents. On the next boot, the stub deletes it
+//! and sets the volatile `LanzabooteProvisioningBoot` EFI variable to `1`, so that the booted
+//! system knows that it can now seal freshly generated secrets against the PCR values that were
+//! established by this boot.
+//!
+//! A provisioning boot is measured like any other boot, and whether it is one is not measured at
+//! all. Otherwise, the PCR values of later boots would differ from those that the secrets were
+//! sealed against.
+
+use alloc::vec::Vec;
+use uefi::{
+    cstr16,
+    runtime::{self, VariableAttributes},
+    CStr16,
+};
+
+use crate::efivars::BOOT_LOADER_VENDOR_UUID;
+
+/// The variable with which the booted system requests a provisioning boot.
+const FIRST_BOOT_MARKER: &CStr16 = cstr16!("LanzabooteFirstBoot");
+
+/// The variable that tells the booted system that this is a provisioning boot.
+const PROVISIONING_BOOT: &CStr16 = cstr16!("LanzabooteProvisioningBoot");
+
+/// Consume the first boot marker, and tell the booted system whether this is a provisioning
+/// boot.
+///
+/// Returns whether this is a provisioning boot. The marker is only used once: it is deleted before
+/// the booted system is told, so that a failure cannot turn every boot into a provisioning boot.
+pub fn consume_first_boot_marker() -> uefi::Result<bool> {
+    if !runtime::variable_exists(FIRST_BOOT_MARKER, &BOOT_LOADER_VENDOR_UUID)? {
+        return Ok(false);
+    }
+    runtime::delete_variable(FIRST_BOOT_MARKER, &BOOT_LOADER_VENDOR_UUID)?;
+
+    let data: Vec<u8> = "1".encode_utf16().flat_map(|c| c.to_le_bytes()).collect();
+    runtime::set_variable(
+        PROVISIONING_BOOT,
+        &BOOT_LOADER_VENDOR_UUID,
+        VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        &data,
+    )?;
+
+    Ok(true)
+}
@@ -0,0 +1,39 @@
+//! Comparison of hashes against their expected values.
+
+use subtle::ConstantTimeEq;
+
+/// Check whether a hash matches its expected hash.
+///
+/// The comparison takes the same time no matter where the hashes differ, so that its timing does
+/// not reveal how much of a forged hash is right. Hashes of different lengths never match.
+pub fn verify_hash(hash: &[u8], expected_hash: &[u8]) -> bool {
+    hash.ct_eq(expected_hash).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_identical_hashes() {
+        let hash = [0x5a; 32];
+        assert!(verify_hash(&hash, &hash));
+    }
+
+    #[test]
+    fn rejects_hashes_that_differ_anywhere() {
+        let expected_hash = [0x5a; 32];
+        for position in [0, 15, 31] {
+            let mut hash = expected_hash;
+            hash[position] ^= 1;
+            assert!(!verify_hash(&hash, &expected_hash), "byte {position}");
+        }
+    }
+
+    #[test]
+    fn rejects_hashes_of_different_lengths() {
+        let expected_hash = [0x5a; 32];
+        assert!(!verify_hash(&expected_hash[..31], &expected_hash));
+        assert!(!verify_hash(&[], &expected_hash));
+    }
+}
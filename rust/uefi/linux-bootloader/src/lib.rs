@@ -1,14 +1,30 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 extern crate alloc;
 
+pub mod addons;
+pub mod authenticode;
+pub mod baseline;
+pub mod boot_outcome;
+pub mod boot_time;
+#[cfg(target_arch = "x86_64")]
+pub mod bzimage;
 pub mod companions;
+pub mod compression;
 pub mod cpio;
+pub mod credential_manifest;
+pub mod devicetree;
 pub mod efivars;
+pub mod first_boot;
+pub mod hash;
 pub mod linux_loader;
 pub mod measure;
 pub mod pe_loader;
 pub mod pe_section;
+pub mod random_seed;
+pub mod rng;
+pub mod smbios;
 pub mod tpm;
 pub mod uefi_helpers;
 pub mod unified_sections;
+pub mod xz;
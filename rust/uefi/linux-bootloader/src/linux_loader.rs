@@ -9,12 +9,12 @@ use core::{ffi::c_void, pin::Pin, ptr::slice_from_raw_parts_mut};
 
 use alloc::{boxed::Box, vec::Vec};
 use uefi::{
-    boot,
+    boot, guid,
     proto::{
         device_path::{DevicePath, FfiDevicePath},
         unsafe_protocol,
     },
-    Handle, Identify, Result, ResultExt, Status,
+    Guid, Handle, Identify, Result, ResultExt, Status,
 };
 
 /// The Linux kernel's initrd loading device path.
@@ -45,6 +45,20 @@ static mut DEVICE_PATH_PROTOCOL: [u8; 24] = [
     0x52, 0x31, 0xcc, 0x68, 0x7f, 0xff, 0x04, 0x00,
 ];
 
+/// `LINUX_EFI_INITRD_MEDIA_GUID`, which is also the vendor GUID in the device path above.
+static LINUX_EFI_INITRD_MEDIA_GUID: Guid = guid!("5568e427-68fc-4f3d-ac74-ca555231cc68");
+
+/// The configuration table that describes an initrd in memory.
+///
+/// This is `struct linux_efi_initrd` of the Linux kernel, which looks for it when parsing the
+/// configuration tables at early boot. Its own EFI stub installs this table after loading the
+/// initrd, kernels that do not load the initrd themselves still pick it up from there.
+#[repr(C)]
+struct LinuxEfiInitrd {
+    base: usize,
+    size: usize,
+}
+
 /// The UEFI LoadFile2 protocol.
 ///
 /// This protocol has a single method to load a file.
@@ -110,6 +124,8 @@ unsafe extern "efiapi" fn raw_load_file(
 /// this is dropped.
 pub struct InitrdLoader {
     proto: Pin<Box<LoadFile2Protocol>>,
+    /// The configuration table, if it is installed in addition to the protocol.
+    config_table: Option<Box<LinuxEfiInitrd>>,
     handle: Handle,
     registered: bool,
 }
@@ -119,7 +135,14 @@ impl InitrdLoader {
     ///
     /// `handle` is the handle where the protocols are registered
     /// on. `file` is the file that is served to Linux.
-    pub fn new(handle: Handle, initrd_data: Vec<u8>) -> Result<Self> {
+    ///
+    /// If `install_config_table` is set, the initrd is additionally
+    /// described by the `LINUX_EFI_INITRD_MEDIA_GUID` configuration
+    /// table. The table points to the same buffer that the LoadFile2
+    /// protocol copies from, so the kernel gets the same bytes either
+    /// way. This is optional, because some firmware does not cope
+    /// well with both being installed.
+    pub fn new(handle: Handle, initrd_data: Vec<u8>, install_config_table: bool) -> Result<Self> {
         let mut proto = Box::pin(LoadFile2Protocol {
             load_file: raw_load_file,
             initrd_data,
@@ -146,9 +169,28 @@ impl InitrdLoader {
             )?;
         }
 
+        // The initrd data is never modified or moved while the
+        // loader exists, so the table can point into it.
+        let config_table = if install_config_table {
+            let table = Box::new(LinuxEfiInitrd {
+                base: proto.initrd_data.as_ptr() as usize,
+                size: proto.initrd_data.len(),
+            });
+            unsafe {
+                boot::install_configuration_table(
+                    &LINUX_EFI_INITRD_MEDIA_GUID,
+                    &*table as *const LinuxEfiInitrd as *const c_void,
+                )?;
+            }
+            Some(table)
+        } else {
+            None
+        };
+
         Ok(InitrdLoader {
             handle,
             proto,
+            config_table,
             registered: true,
         })
     }
@@ -172,6 +214,11 @@ impl InitrdLoader {
                 &LoadFile2Protocol::GUID,
                 lf_proto as *mut c_void,
             )?;
+
+            // Installing a null pointer removes the table.
+            if self.config_table.take().is_some() {
+                boot::install_configuration_table(&LINUX_EFI_INITRD_MEDIA_GUID, core::ptr::null())?;
+            }
         }
 
         self.registered = false;
@@ -1,18 +1,30 @@
-use alloc::{string::ToString, vec::Vec};
-use log::info;
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use log::{info, warn};
 use uefi::{
+    boot::{self, OpenProtocolAttributes, OpenProtocolParams},
     cstr16,
-    proto::tcg::PcrIndex,
+    proto::{
+        device_path::{
+            text::{AllowShortcuts, DisplayOnly},
+            DevicePath,
+        },
+        loaded_image::LoadedImage,
+        tcg::PcrIndex,
+    },
     runtime::{self, VariableAttributes},
+    CStr16, Status,
 };
 
 use crate::{
     companions::{CompanionInitrd, CompanionInitrdType},
     efivars::BOOT_LOADER_VENDOR_UUID,
-    pe_section::pe_section_data,
+    pe_section::{pe_cmdline, pe_section, pe_section_data, CMDLINE_CONTINUATION_SECTIONS},
     tpm::tpm_log_event_ascii,
     uefi_helpers::PeInMemory,
-    unified_sections::UnifiedSection,
+    unified_sections::MEASURED_SECTIONS,
 };
 
 /// This is where any stub payloads are extended, e.g. kernel ELF image, embedded initrd
@@ -34,44 +46,63 @@ pub fn measure_image(image: &PeInMemory) -> uefi::Result<u32> {
     let pe = goblin::pe::PE::parse(pe_binary).map_err(|_err| uefi::Status::LOAD_ERROR)?;
 
     let mut measurements = 0;
-    for section in pe.sections {
-        let section_name = section.name().map_err(|_err| uefi::Status::UNSUPPORTED)?;
-        if let Ok(unified_section) = UnifiedSection::try_from(section_name) {
-            // UNSTABLE: && in the previous if is an unstable feature
-            // https://github.com/rust-lang/rust/issues/53667
-            if unified_section.should_be_measured() {
-                // Here, perform the TPM log event in ASCII.
-                if let Some(data) = pe_section_data(pe_binary, &section) {
-                    info!("Measuring section `{}`...", section_name);
-                    if tpm_log_event_ascii(TPM_PCR_INDEX_KERNEL_IMAGE, data, section_name)? {
-                        measurements += 1;
-                    }
-                }
+    for section_name in MEASURED_SECTIONS {
+        let sections = pe.sections.iter().filter(|section| {
+            section
+                .name()
+                .map(|name| name == *section_name)
+                .unwrap_or(false)
+        });
+        // Here, perform the TPM log event in ASCII.
+        for data in sections.filter_map(|section| pe_section_data(pe_binary, section)) {
+            info!("Measuring section `{}`...", section_name);
+            if tpm_log_event_ascii(TPM_PCR_INDEX_KERNEL_IMAGE, data, section_name)? {
+                measurements += 1;
             }
         }
     }
 
-    if measurements > 0 {
-        let pcr_index_encoded = TPM_PCR_INDEX_KERNEL_IMAGE
-            .0
-            .to_string()
-            .encode_utf16()
-            .flat_map(|c| c.to_le_bytes())
-            .collect::<Vec<u8>>();
+    // The continuation sections of the command line are not unified sections and thus not part
+    // of PCR 11. Measure the complete command line instead, the same way as a command line
+    // override.
+    if CMDLINE_CONTINUATION_SECTIONS
+        .iter()
+        .any(|section_name| pe_section(pe_binary, section_name).is_some())
+    {
+        if let Some(cmdline) = pe_cmdline(pe_binary) {
+            info!("Measuring the complete kernel command line...");
+            measure_kernel_config(cmdline.as_bytes(), "Kernel command line")?;
+        }
+    }
 
+    if measurements > 0 {
         // If we did some measurements, expose a variable encoding the PCR where
         // we have done the measurements.
-        runtime::set_variable(
-            cstr16!("StubPcrKernelImage"),
-            &BOOT_LOADER_VENDOR_UUID,
-            VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
-            &pcr_index_encoded,
-        )?;
+        export_pcr_variable(cstr16!("StubPcrKernelImage"), TPM_PCR_INDEX_KERNEL_IMAGE);
     }
 
     Ok(measurements)
 }
 
+/// The PCR that a kind of companion initrd is measured into, and the description of the measurement.
+///
+/// PCR signatures and public keys are not measured: they describe the expected values of the PCRs
+/// rather than configure the system.
+fn companion_initrd_measurement(r#type: &CompanionInitrdType) -> Option<(PcrIndex, &'static str)> {
+    match r#type {
+        CompanionInitrdType::PcrSignature | CompanionInitrdType::PcrPublicKey => None,
+        CompanionInitrdType::Credentials => {
+            Some((TPM_PCR_INDEX_KERNEL_CONFIG, "Credentials initrd"))
+        }
+        CompanionInitrdType::GlobalCredentials => {
+            Some((TPM_PCR_INDEX_KERNEL_CONFIG, "Global credentials initrd"))
+        }
+        CompanionInitrdType::SystemExtension => {
+            Some((TPM_PCR_INDEX_SYSEXTS, "System extension initrd"))
+        }
+    }
+}
+
 /// Performs all the expected measurements for any list of
 /// companion initrds of any form.
 ///
@@ -79,64 +110,141 @@ pub fn measure_image(image: &PeInMemory) -> uefi::Result<u32> {
 /// A stable order is expected for measurement stability.
 pub fn measure_companion_initrds(companions: &[CompanionInitrd]) -> uefi::Result<u32> {
     let mut measurements = 0;
-    let mut credentials_measured = 0;
     let mut sysext_measured = false;
 
     for initrd in companions {
-        match initrd.r#type {
-            CompanionInitrdType::PcrSignature | CompanionInitrdType::PcrPublicKey => {
-                continue;
-            }
-            CompanionInitrdType::Credentials => {
-                if tpm_log_event_ascii(
-                    TPM_PCR_INDEX_KERNEL_CONFIG,
-                    initrd.cpio.as_ref(),
-                    "Credentials initrd",
-                )? {
-                    measurements += 1;
-                    credentials_measured += 1;
-                }
-            }
-            CompanionInitrdType::GlobalCredentials => {
-                if tpm_log_event_ascii(
-                    TPM_PCR_INDEX_KERNEL_CONFIG,
-                    initrd.cpio.as_ref(),
-                    "Global credentials initrd",
-                )? {
-                    measurements += 1;
-                    credentials_measured += 1;
-                }
-            }
-            CompanionInitrdType::SystemExtension => {
-                if tpm_log_event_ascii(
-                    TPM_PCR_INDEX_SYSEXTS,
-                    initrd.cpio.as_ref(),
-                    "System extension initrd",
-                )? {
-                    measurements += 1;
-                    sysext_measured = true;
-                }
-            }
+        let Some((pcr_index, description)) = companion_initrd_measurement(&initrd.r#type) else {
+            continue;
+        };
+        let measured = if pcr_index == TPM_PCR_INDEX_KERNEL_CONFIG {
+            measure_kernel_config(initrd.cpio.as_ref(), description)?
+        } else {
+            tpm_log_event_ascii(pcr_index, initrd.cpio.as_ref(), description)?
+        };
+        if measured {
+            measurements += 1;
+            sysext_measured |= pcr_index == TPM_PCR_INDEX_SYSEXTS;
         }
     }
 
-    if credentials_measured > 0 {
-        runtime::set_variable(
-            cstr16!("StubPcrKernelParameters"),
-            &BOOT_LOADER_VENDOR_UUID,
-            VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
-            &TPM_PCR_INDEX_KERNEL_CONFIG.0.to_le_bytes(),
-        )?;
-    }
-
     if sysext_measured {
-        runtime::set_variable(
-            cstr16!("StubPcrInitRDSysExts"),
-            &BOOT_LOADER_VENDOR_UUID,
-            VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
-            &TPM_PCR_INDEX_SYSEXTS.0.to_le_bytes(),
-        )?;
+        export_pcr_variable(cstr16!("StubPcrInitRDSysExts"), TPM_PCR_INDEX_SYSEXTS);
     }
 
     Ok(measurements)
 }
+
+/// Expose the PCR that a kind of measurement went into to userspace.
+///
+/// Like systemd-stub, the PCR index is encoded as a decimal UTF-16 string. These variables are
+/// purely informational, so failing to set them is logged, but neither fails the measurement nor
+/// the boot.
+fn export_pcr_variable(name: &CStr16, pcr_index: PcrIndex) {
+    let value = pcr_index
+        .0
+        .to_string()
+        .encode_utf16()
+        .flat_map(|c| c.to_le_bytes())
+        .collect::<Vec<u8>>();
+    if let Err(err) = runtime::set_variable(
+        name,
+        &BOOT_LOADER_VENDOR_UUID,
+        VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        &value,
+    ) {
+        warn!(
+            "Failed to export the PCR variable {name}: {:?}",
+            err.status()
+        );
+    }
+}
+
+/// Measures anything that configures the kernel beyond the unified sections into PCR 12, and
+/// tells userspace that it did so.
+///
+/// The embedded `.cmdline` section is not measured here, but into PCR 11 with the other unified
+/// sections, like systemd-stub does. Otherwise, the PCR 12 values predicted by `systemd-measure`
+/// would not match.
+fn measure_kernel_config(data: &[u8], description: &str) -> uefi::Result<bool> {
+    let measured = tpm_log_event_ascii(TPM_PCR_INDEX_KERNEL_CONFIG, data, description)?;
+    if measured {
+        export_pcr_variable(
+            cstr16!("StubPcrKernelParameters"),
+            TPM_PCR_INDEX_KERNEL_CONFIG,
+        );
+    }
+    Ok(measured)
+}
+
+/// Measures a kernel command line that does not come from the unified sections, e.g. a trusted
+/// override read from the ESP.
+pub fn measure_cmdline(cmdline: &[u8], description: &str) -> uefi::Result<bool> {
+    measure_kernel_config(cmdline, description)
+}
+
+/// Measures a file that replaces part of the generation for recovery, e.g. an initrd that is signed
+/// by the recovery key, so that a recovery boot can be told apart from a normal one.
+pub fn measure_recovery_override(data: &[u8], description: &str) -> uefi::Result<bool> {
+    measure_kernel_config(data, description)
+}
+
+/// Measures an image that is booted instead of the generation because the generation does not
+/// match its hashes, so that a fallback boot can be told apart from a normal one.
+pub fn measure_fallback_image(data: &[u8], description: &str) -> uefi::Result<bool> {
+    measure_kernel_config(data, description)
+}
+
+/// Measures the device path of the device that the stub was loaded from, e.g. the partition of
+/// the ESP.
+///
+/// The device path is measured in its canonical text form, e.g.
+/// `PciRoot(0x0)/Pci(0x1D,0x0)/NVMe(0x1,...)/HD(1,GPT,...)`. This allows sealing secrets to
+/// booting from the internal disk rather than an external one.
+pub fn measure_boot_device() -> uefi::Result<bool> {
+    let loaded_image = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())?;
+    let device = loaded_image.device().ok_or(Status::NOT_FOUND)?;
+
+    // SAFETY: The protocol is only read from, and the reference does not outlive this function.
+    // Opening it non-exclusively ensures that the drivers of the device stay connected.
+    let device_path = unsafe {
+        boot::open_protocol::<DevicePath>(
+            OpenProtocolParams {
+                handle: device,
+                agent: boot::image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol,
+        )?
+    };
+    let device_path = device_path
+        .to_string(DisplayOnly(false), AllowShortcuts(false))
+        .map_err(|_err| Status::NOT_FOUND)?;
+
+    info!("Measuring the boot device path `{device_path}`...");
+    measure_kernel_config(String::from(&*device_path).as_bytes(), "Boot device path")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `lzbt` predicts and lists the measurements of the stub with the same PCR indices, see
+    // `lanzaboote_tool::pcrs`. Changing them breaks every secret that is sealed against them.
+
+    #[test]
+    fn companion_initrds_are_measured_by_type() {
+        let pcr = |r#type| companion_initrd_measurement(&r#type).map(|(pcr_index, _)| pcr_index);
+
+        assert_eq!(pcr(CompanionInitrdType::Credentials), Some(PcrIndex(12)));
+        assert_eq!(
+            pcr(CompanionInitrdType::GlobalCredentials),
+            Some(PcrIndex(12))
+        );
+        assert_eq!(
+            pcr(CompanionInitrdType::SystemExtension),
+            Some(PcrIndex(13))
+        );
+        assert_eq!(pcr(CompanionInitrdType::PcrSignature), None);
+        assert_eq!(pcr(CompanionInitrdType::PcrPublicKey), None);
+    }
+}
@@ -2,11 +2,15 @@ use core::ffi::c_void;
 use core::ptr::NonNull;
 
 use alloc::vec::Vec;
-use goblin::pe::PE;
+use goblin::pe::{
+    section_table::{IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_WRITE},
+    PE,
+};
+use log::warn;
 use uefi::{
-    boot::{self, AllocateType, MemoryType},
-    proto::loaded_image::LoadedImage,
-    table, Handle, Status,
+    boot::{self, AllocateType, MemoryAttribute, MemoryType},
+    proto::{loaded_image::LoadedImage, unsafe_protocol},
+    table, Handle, Status, StatusExt,
 };
 
 /// UEFI mandates 4 KiB pages.
@@ -65,6 +69,125 @@ fn make_instruction_cache_coherent(_memory: &[u8]) {
     // x86_64 mandates coherent instruction cache
 }
 
+/// The EFI Memory Attribute Protocol.
+///
+/// Firmware that enforces W^X, i.e. that no memory is both writable and executable, provides this
+/// protocol to make the code of a loaded image executable.
+#[repr(C)]
+#[unsafe_protocol("f4560cf6-40ec-4b4a-a192-bf1d57d0b189")]
+struct MemoryAttributeProtocol {
+    _get_memory_attributes: unsafe extern "efiapi" fn(
+        this: *const MemoryAttributeProtocol,
+        base_address: u64,
+        length: u64,
+        attributes: *mut MemoryAttribute,
+    ) -> Status,
+    set_memory_attributes: unsafe extern "efiapi" fn(
+        this: *const MemoryAttributeProtocol,
+        base_address: u64,
+        length: u64,
+        attributes: MemoryAttribute,
+    ) -> Status,
+    clear_memory_attributes: unsafe extern "efiapi" fn(
+        this: *const MemoryAttributeProtocol,
+        base_address: u64,
+        length: u64,
+        attributes: MemoryAttribute,
+    ) -> Status,
+}
+
+impl MemoryAttributeProtocol {
+    /// Set and clear attributes of whole pages, starting at the page that contains `address`.
+    fn update(
+        &self,
+        address: *const u8,
+        length: usize,
+        set: MemoryAttribute,
+        clear: MemoryAttribute,
+    ) -> uefi::Result<()> {
+        let base_address = (address as usize & !UEFI_PAGE_MASK) as u64;
+        let length = (bytes_to_pages(length) << UEFI_PAGE_BITS) as u64;
+
+        // Setting before clearing never leaves memory writable and executable, as long as the
+        // attributes to set are the protections.
+        unsafe {
+            if !set.is_empty() {
+                (self.set_memory_attributes)(self, base_address, length, set).to_result()?;
+            }
+            if !clear.is_empty() {
+                (self.clear_memory_attributes)(self, base_address, length, clear).to_result()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Apply memory attributes to the sections of a loaded image, if the firmware supports it.
+///
+/// Code sections become read-only and executable, all other sections non-executable, and those
+/// that are not writable read-only as well. Firmware without the memory attribute protocol does
+/// not enforce W^X and keeps the memory it allocates for code executable.
+fn protect_sections(image: &[u8], pe: &PE) -> uefi::Result<()> {
+    let Ok(handle) = boot::get_handle_for_protocol::<MemoryAttributeProtocol>() else {
+        return Ok(());
+    };
+    let protocol = boot::open_protocol_exclusive::<MemoryAttributeProtocol>(handle)?;
+
+    // Attributes can only be applied to whole pages.
+    if pe
+        .sections
+        .iter()
+        .any(|section| usize::try_from(section.virtual_address).unwrap() & UEFI_PAGE_MASK != 0)
+    {
+        warn!("The sections of the kernel are not page-aligned, leaving them writable and executable.");
+        return Ok(());
+    }
+
+    protocol.update(
+        image.as_ptr(),
+        image.len(),
+        MemoryAttribute::EXECUTE_PROTECT,
+        MemoryAttribute::READ_ONLY,
+    )?;
+
+    for section in &pe.sections {
+        let start = image[usize::try_from(section.virtual_address).unwrap()..].as_ptr();
+        let length = usize::try_from(section.virtual_size).unwrap();
+
+        if section.characteristics & IMAGE_SCN_MEM_EXECUTE != 0 {
+            protocol.update(
+                start,
+                length,
+                MemoryAttribute::READ_ONLY,
+                MemoryAttribute::EXECUTE_PROTECT,
+            )?;
+        } else if section.characteristics & IMAGE_SCN_MEM_WRITE == 0 {
+            protocol.update(
+                start,
+                length,
+                MemoryAttribute::READ_ONLY,
+                MemoryAttribute::empty(),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Make the memory of a loaded image writable again, so that it can be freed.
+fn unprotect_image(image: &[u8]) {
+    let protocol = boot::get_handle_for_protocol::<MemoryAttributeProtocol>()
+        .and_then(boot::open_protocol_exclusive::<MemoryAttributeProtocol>);
+    if let Ok(protocol) = protocol {
+        let _ = protocol.update(
+            image.as_ptr(),
+            image.len(),
+            MemoryAttribute::empty(),
+            MemoryAttribute::READ_ONLY | MemoryAttribute::EXECUTE_PROTECT,
+        );
+    }
+}
+
 pub struct Image {
     image: &'static mut [u8],
     entry: extern "efiapi" fn(Handle, Option<NonNull<c_void>>) -> Status,
@@ -147,6 +270,13 @@ impl Image {
         // Platform-specific flushes need to be performed to prevent this from happening.
         make_instruction_cache_coherent(image);
 
+        // Firmware that enforces W^X refuses to execute the kernel otherwise. Failing to apply
+        // the attributes is not fatal, because the firmware then most likely does not enforce
+        // them either.
+        if let Err(err) = protect_sections(image, &pe) {
+            warn!("Failed to apply memory attributes to the kernel: {err}");
+        }
+
         if pe.entry >= image.len() {
             return Err(Status::LOAD_ERROR.into());
         }
@@ -196,6 +326,7 @@ impl Image {
         // If the kernel has exited boot services, it must not return any more, and has full control over the entire machine.
         // If the kernel entry point returned, deallocate its image, and restore our loaded image handle.
         // If it calls Exit(), that call returns directly to systemd-boot. This unfortunately causes a resource leak.
+        unprotect_image(self.image);
         let image = NonNull::new(self.image.as_ptr().cast_mut()).unwrap();
         boot::free_pages(image, bytes_to_pages(self.image.len())).expect("Double free attempted");
 
@@ -4,8 +4,12 @@
 // and_then below and this can't be expressed with map.
 #![allow(clippy::bind_instead_of_map)]
 
-use alloc::{borrow::ToOwned, string::String};
-use goblin::pe::section_table::SectionTable;
+use alloc::{borrow::ToOwned, string::String, vec, vec::Vec};
+use core::str::from_utf8;
+use goblin::pe::section_table::{SectionTable, IMAGE_SCN_CNT_CODE, IMAGE_SCN_MEM_EXECUTE};
+use sha2::{Digest, Sha256};
+
+use crate::credential_manifest::parse_hash;
 
 /// Extracts the data of a section in a loaded PE file
 /// based on the section table.
@@ -30,7 +34,150 @@ pub fn pe_section<'a>(pe_data: &'a [u8], section_name: &str) -> Option<&'a [u8]>
         .and_then(|s| pe_section_data(pe_data, s))
 }
 
+/// Extracts the data of all sections of a loaded PE image with this name, in the order of the
+/// section table.
+///
+/// Some sections, e.g. `.dtbauto`, can occur more than once.
+pub fn pe_sections<'a>(pe_data: &'a [u8], section_name: &str) -> Vec<&'a [u8]> {
+    let Ok(pe_binary) = goblin::pe::PE::parse(pe_data) else {
+        return Vec::new();
+    };
+
+    pe_binary
+        .sections
+        .iter()
+        .filter(|s| s.name().map(|n| n == section_name).unwrap_or(false))
+        .filter_map(|s| pe_section_data(pe_data, s))
+        .collect()
+}
+
 /// Extracts the data of a section of a loaded PE image and returns it as a string.
 pub fn pe_section_as_string<'a>(pe_data: &'a [u8], section_name: &str) -> Option<String> {
-    pe_section(pe_data, section_name).map(|data| core::str::from_utf8(data).unwrap().to_owned())
+    pe_section(pe_data, section_name).map(|data| from_utf8(data).unwrap().to_owned())
+}
+
+/// Sections that continue the `.cmdline` section, in this order.
+///
+/// They allow composing the command line from several parts at build time. Names like
+/// `.cmdline2` are not possible, because section names of PE images are limited to 8 characters.
+pub const CMDLINE_CONTINUATION_SECTIONS: &[&str] = &[
+    ".cmdl2", ".cmdl3", ".cmdl4", ".cmdl5", ".cmdl6", ".cmdl7", ".cmdl8", ".cmdl9",
+];
+
+/// Extracts the kernel command line of a loaded PE image, i.e. the `.cmdline` section followed by
+/// its continuation sections, separated by spaces.
+pub fn pe_cmdline(pe_data: &[u8]) -> Option<String> {
+    let mut cmdline = pe_section_as_string(pe_data, ".cmdline")?;
+
+    for section_name in CMDLINE_CONTINUATION_SECTIONS {
+        if let Some(continuation) = pe_section(pe_data, section_name) {
+            cmdline.push(' ');
+            cmdline.push_str(from_utf8(continuation).ok()?);
+        }
+    }
+
+    Some(cmdline)
+}
+
+/// Find the sections of a loaded PE image that do not match their SHA-256 hashes in the
+/// `.sectsum` section.
+///
+/// `.sectsum` has one `<hex SHA-256> <section name>` line per section, like `sha256sum`. A section
+/// that occurs more than once, like `.dtbauto`, has one line per occurrence, in order. Sections
+/// that are listed but missing count as corrupt, as does a malformed `.sectsum`. Images without a
+/// `.sectsum` section have nothing to check.
+pub fn corrupt_sections(pe_data: &[u8]) -> Vec<String> {
+    let Some(checksums) = pe_section(pe_data, ".sectsum") else {
+        return Vec::new();
+    };
+    let Ok(checksums) = from_utf8(checksums) else {
+        return vec![String::from(".sectsum")];
+    };
+
+    let mut listed: Vec<&str> = Vec::new();
+    checksums
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            let Some((hash, section_name)) = line.split_once(char::is_whitespace) else {
+                return Some(String::from(".sectsum"));
+            };
+            let section_name = section_name.trim_start();
+            let occurrence = listed.iter().filter(|name| **name == section_name).count();
+            listed.push(section_name);
+            let matches = parse_hash(hash)
+                .zip(pe_sections(pe_data, section_name).get(occurrence).copied())
+                .map(|(hash, data)| hash == <[u8; 32]>::from(Sha256::digest(data)))
+                .unwrap_or(false);
+            (!matches).then(|| String::from(section_name))
+        })
+        .collect()
+}
+
+/// Find the code sections of a loaded PE image whose contents in the file of the image do not
+/// match their SHA-256 hashes in the `.selfsum` section.
+///
+/// `.selfsum` has the format of `.sectsum`. The hashes are checked against the file instead of the
+/// loaded image, because the firmware applies relocations to the loaded image. `read_file` reads
+/// `size` bytes at `offset` from the file. Code sections that are not listed count as modified, as
+/// does a malformed `.selfsum`. Images without a `.selfsum` section have nothing to check.
+pub fn modified_code_sections<F>(pe_data: &[u8], mut read_file: F) -> Vec<String>
+where
+    F: FnMut(u64, usize) -> Option<Vec<u8>>,
+{
+    let Some(checksums) = pe_section(pe_data, ".selfsum") else {
+        return Vec::new();
+    };
+    let (Ok(checksums), Ok(pe_binary)) = (from_utf8(checksums), goblin::pe::PE::parse(pe_data))
+    else {
+        return vec![String::from(".selfsum")];
+    };
+
+    let mut modified = Vec::new();
+    let mut listed = Vec::new();
+    for line in checksums
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        let Some((hash, section_name)) = line.split_once(char::is_whitespace) else {
+            modified.push(String::from(".selfsum"));
+            continue;
+        };
+        let section_name = section_name.trim_start();
+        listed.push(section_name);
+
+        // Sections can be larger in memory than in the file, only the part in the file is hashed.
+        let matches = pe_binary
+            .sections
+            .iter()
+            .find(|s| s.name().map(|n| n == section_name).unwrap_or(false))
+            .and_then(|s| {
+                read_file(
+                    u64::from(s.pointer_to_raw_data),
+                    usize::try_from(s.virtual_size.min(s.size_of_raw_data)).ok()?,
+                )
+            })
+            .zip(parse_hash(hash))
+            .map(|(data, hash)| hash == <[u8; 32]>::from(Sha256::digest(data)))
+            .unwrap_or(false);
+        if !matches {
+            modified.push(String::from(section_name));
+        }
+    }
+
+    for section in &pe_binary.sections {
+        let name = section.name().unwrap_or("<invalid>");
+        if is_code_section(section) && !listed.contains(&name) {
+            modified.push(String::from(name));
+        }
+    }
+
+    modified
+}
+
+/// Whether a section contains executable code.
+fn is_code_section(section: &SectionTable) -> bool {
+    section.characteristics & (IMAGE_SCN_CNT_CODE | IMAGE_SCN_MEM_EXECUTE) != 0
 }
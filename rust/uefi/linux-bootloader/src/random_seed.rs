@@ -0,0 +1,234 @@
+//! Random seed processing, as done by systemd-boot.
+//!
+//! The seed in `\loader\random-seed` on the ESP is mixed with a fresh draw from the EFI RNG
+//! protocol, the `LoaderSystemToken` EFI variable, a seed that an earlier boot stage may have
+//! passed on, the monotonic counter and the current time. Two seeds are derived from the result:
+//! one replaces the seed on the ESP, the other is passed to the kernel in the
+//! `LINUX_EFI_RANDOM_SEED` configuration table, which it credits as entropy.
+//!
+//! Like systemd-boot, nothing of this is measured. The seeds are secret and differ on every boot,
+//! so measuring them would leak them into the TPM event log and make PCR values unpredictable.
+
+use alloc::vec;
+use core::{ffi::c_void, mem::size_of};
+use log::{error, warn};
+use sha2::{Digest, Sha256};
+use uefi::{
+    boot::{self, MemoryType},
+    cstr16, guid,
+    proto::media::file::{Directory, File, FileAttribute, FileInfo, FileMode},
+    runtime, system, table, Guid, Status, StatusExt,
+};
+
+use crate::efivars::BOOT_LOADER_VENDOR_UUID;
+use crate::rng::get_random_bytes;
+
+/// Configuration table in which Linux looks for a random seed. It consists of the size of the seed
+/// as 32-bit integer, followed by the seed itself.
+pub const LINUX_EFI_RANDOM_SEED_GUID: Guid = guid!("1ce1e5bc-7ceb-42f2-81e5-8aadf180f57b");
+
+/// Size of the derived seeds.
+const SEED_SIZE: usize = 32;
+
+/// Bounds of the size of the seed file, the same as in systemd-boot.
+const SEED_FILE_MIN_SIZE: u64 = 32;
+const SEED_FILE_MAX_SIZE: u64 = 32 * 1024;
+
+/// Domain separation, the same as in systemd-boot.
+const HASH_LABEL: &[u8] = b"systemd-boot random seed label v1";
+
+/// Hash `data`, prefixed by its size, so that moving bytes between inputs changes the hash.
+fn hash_sized(hasher: &mut Sha256, data: &[u8]) {
+    hasher.update((data.len() as u64).to_le_bytes());
+    hasher.update(data);
+}
+
+/// Find the seed that an earlier boot stage passed to the kernel, if any.
+fn previous_seed() -> Option<&'static mut [u8]> {
+    let address = system::with_config_table(|entries| {
+        entries
+            .iter()
+            .find(|entry| entry.guid == LINUX_EFI_RANDOM_SEED_GUID)
+            .map(|entry| entry.address)
+    })
+    .filter(|address| !address.is_null())?;
+
+    // SAFETY: The table consists of its size and the seed, and it stays allocated until the
+    // kernel takes it over. Nothing else refers to it while the stub runs.
+    unsafe {
+        let size = (address as *const u32).read_unaligned();
+        Some(core::slice::from_raw_parts_mut(
+            (address as *mut u8).add(size_of::<u32>()),
+            size as usize,
+        ))
+    }
+}
+
+/// Get the next value of the monotonic counter, which increases on every boot.
+fn next_monotonic_count() -> uefi::Result<u64> {
+    let system_table = table::system_table_raw().ok_or(Status::UNSUPPORTED)?;
+    let mut count = 0;
+    // SAFETY: The boot services are still available, and `count` is valid for writing.
+    unsafe {
+        let boot_services = system_table.as_ref().boot_services;
+        ((*boot_services).get_next_monotonic_count)(&mut count).to_result()?;
+    }
+    Ok(count)
+}
+
+/// Refresh the random seed on the ESP and pass a seed derived from it to the kernel.
+///
+/// `root_dir` is the root directory of the ESP. If it has no seed file, or it is read-only, this
+/// does nothing and returns `false`.
+///
+/// If the firmware provides no randomness, the seed file is the main source of entropy. As anyone
+/// can modify the ESP, it is not trusted alone if `is_secure_boot_enabled` returns true. Without
+/// randomness from the firmware, a system token of at least 32 bytes is also required, to not pass
+/// the same seed to every machine that was installed from the same image.
+pub fn process_random_seed<F>(
+    root_dir: &mut Directory,
+    is_secure_boot_enabled: F,
+) -> uefi::Result<bool>
+where
+    F: FnOnce() -> bool,
+{
+    let mut file = match root_dir.open(
+        cstr16!("\\loader\\random-seed"),
+        FileMode::ReadWrite,
+        FileAttribute::empty(),
+    ) {
+        Ok(file) => file.into_regular_file().ok_or(Status::INVALID_PARAMETER)?,
+        Err(err) if matches!(err.status(), Status::NOT_FOUND | Status::WRITE_PROTECTED) => {
+            return Ok(false)
+        }
+        Err(err) => return Err(err),
+    };
+
+    let mut hasher = Sha256::new();
+    hasher.update(HASH_LABEL);
+
+    let previous_seed = previous_seed();
+    let mut seeded_by_efi = previous_seed
+        .as_ref()
+        .map(|seed| seed.len() >= SEED_SIZE)
+        .unwrap_or(false);
+    hash_sized(&mut hasher, previous_seed.as_deref().unwrap_or(&[]));
+
+    let mut rng_bytes = [0; SEED_SIZE];
+    match get_random_bytes(&mut rng_bytes) {
+        Ok(()) => {
+            seeded_by_efi = true;
+            hash_sized(&mut hasher, &rng_bytes);
+        }
+        Err(_) if !seeded_by_efi && is_secure_boot_enabled() => {
+            error!("The firmware provides no randomness, not trusting the random seed on the ESP alone.");
+            return Err(Status::NOT_FOUND.into());
+        }
+        Err(err) => {
+            warn!("Failed to draw randomness from the firmware: {err}. Relying on the other sources of the random seed.");
+            hash_sized(&mut hasher, &[]);
+        }
+    }
+
+    let system_token =
+        runtime::get_variable_boxed(cstr16!("LoaderSystemToken"), &BOOT_LOADER_VENDOR_UUID)
+            .ok()
+            .map(|(token, _)| token);
+    let has_system_token = system_token
+        .as_ref()
+        .map(|token| token.len() >= SEED_SIZE)
+        .unwrap_or(false);
+    if !seeded_by_efi && !has_system_token {
+        error!("Neither the firmware nor a system token provide randomness, not using the random seed on the ESP.");
+        return Err(Status::NOT_FOUND.into());
+    }
+    hash_sized(&mut hasher, system_token.as_deref().unwrap_or(&[]));
+
+    let file_size = file.get_boxed_info::<FileInfo>()?.file_size();
+    if !(SEED_FILE_MIN_SIZE..=SEED_FILE_MAX_SIZE).contains(&file_size) {
+        error!("The random seed file has an invalid size of {file_size} bytes.");
+        return Err(Status::BAD_BUFFER_SIZE.into());
+    }
+    let mut seed = vec![0; file_size as usize];
+    if file.read(&mut seed)? != seed.len() {
+        error!("Short read on the random seed file.");
+        return Err(Status::PROTOCOL_ERROR.into());
+    }
+    hash_sized(&mut hasher, &seed);
+    seed.fill(0);
+
+    // Even if writing back to the ESP does not persist, this makes the seed differ on every boot.
+    match next_monotonic_count() {
+        Ok(count) => hash_sized(&mut hasher, &count.to_le_bytes()),
+        Err(err) if !seeded_by_efi => return Err(err),
+        Err(_) => hash_sized(&mut hasher, &[]),
+    }
+
+    // The clock is known to be flaky, so it is only used if it works.
+    match runtime::get_time() {
+        Ok(time) => {
+            let mut time_bytes = [0; 11];
+            time_bytes[..2].copy_from_slice(&time.year().to_le_bytes());
+            time_bytes[2..7].copy_from_slice(&[
+                time.month(),
+                time.day(),
+                time.hour(),
+                time.minute(),
+                time.second(),
+            ]);
+            time_bytes[7..].copy_from_slice(&time.nanosecond().to_le_bytes());
+            hash_sized(&mut hasher, &time_bytes);
+        }
+        Err(_) => hash_sized(&mut hasher, &[]),
+    }
+
+    let key = hasher.finalize();
+    let derive_seed = |domain: u8| -> [u8; SEED_SIZE] {
+        Sha256::new()
+            .chain_update(key)
+            .chain_update([domain])
+            .finalize()
+            .into()
+    };
+
+    // The seed on the ESP is replaced before the kernel gets one, so that a seed is never used
+    // twice. Truncating files is risky with some EFI file system drivers, so the rest of the file
+    // is zeroed instead, the booted system truncates it when it writes a new seed.
+    if file_size > SEED_SIZE as u64 {
+        file.set_position(SEED_SIZE as u64)?;
+        file.write(&seed[SEED_SIZE..])
+            .map_err(|err| err.to_err_without_payload())?;
+    }
+    file.set_position(0)?;
+    file.write(&derive_seed(0))
+        .map_err(|err| err.to_err_without_payload())?;
+    file.flush()?;
+
+    // The kernel keeps ACPI reclaim memory around until it has read the table.
+    let table_size = size_of::<u32>() + SEED_SIZE;
+    let table = boot::allocate_pool(MemoryType::ACPI_RECLAIM, table_size)?;
+    // SAFETY: The allocation is `table_size` bytes large and not used by anything else yet.
+    let table_data = unsafe { core::slice::from_raw_parts_mut(table.as_ptr(), table_size) };
+    table_data[..size_of::<u32>()].copy_from_slice(&(SEED_SIZE as u32).to_le_bytes());
+    table_data[size_of::<u32>()..].copy_from_slice(&derive_seed(1));
+
+    // SAFETY: The table is never freed once it is installed.
+    if let Err(err) = unsafe {
+        boot::install_configuration_table(
+            &LINUX_EFI_RANDOM_SEED_GUID,
+            table.as_ptr() as *const c_void,
+        )
+    } {
+        table_data.fill(0);
+        // SAFETY: The table was not installed, so nothing refers to it.
+        unsafe { boot::free_pool(table) }.ok();
+        return Err(err);
+    }
+
+    // The previous table was allocated by someone else, so it is only wiped, not freed.
+    if let Some(previous_seed) = previous_seed {
+        previous_seed.fill(0);
+    }
+
+    Ok(true)
+}
@@ -0,0 +1,23 @@
+//! Randomness from the EFI RNG protocol.
+//!
+//! Not every firmware provides the protocol, and those that do may not support every algorithm.
+//! Callers have to cope with no randomness at all, e.g. by falling back to other sources.
+
+use uefi::{
+    boot,
+    proto::rng::{Rng, RngAlgorithmType},
+    Status,
+};
+
+/// Fill `buffer` with random bytes from the EFI RNG protocol.
+///
+/// Raw entropy is preferred, as it does not depend on a deterministic random bit generator of the
+/// firmware. If the firmware does not support it, its default algorithm is used. An error is
+/// returned if the firmware has no RNG protocol, in which case `buffer` is left untouched.
+pub fn get_random_bytes(buffer: &mut [u8]) -> uefi::Result<()> {
+    let mut rng = boot::open_protocol_exclusive::<Rng>(boot::get_handle_for_protocol::<Rng>()?)?;
+    match rng.get_rng(Some(RngAlgorithmType::ALGORITHM_RAW), buffer) {
+        Err(err) if err.status() == Status::UNSUPPORTED => rng.get_rng(None, buffer),
+        result => result,
+    }
+}
@@ -0,0 +1,121 @@
+//! Reading OEM strings from the SMBIOS tables, as systemd-stub does.
+//!
+//! Firmware, and in particular the host of a virtual machine, can pass strings to the booted
+//! system in SMBIOS structures of type 11. systemd-stub looks for
+//! `io.systemd.stub.kernel-cmdline-extra=` there and appends its value to the kernel command line.
+
+use core::slice;
+use uefi::{
+    system,
+    table::cfg::{SMBIOS3_GUID, SMBIOS_GUID},
+    CString16,
+};
+
+/// The key of the OEM string whose value is appended to the kernel command line.
+const KERNEL_CMDLINE_EXTRA: &[u8] = b"io.systemd.stub.kernel-cmdline-extra=";
+
+/// SMBIOS structure type of OEM strings.
+const TYPE_OEM_STRINGS: u8 = 11;
+
+/// SMBIOS structure type that ends the table.
+const TYPE_END_OF_TABLE: u8 = 127;
+
+/// Size of the SMBIOS 3.0 entry point.
+const SMBIOS3_ENTRY_POINT_SIZE: usize = 24;
+
+/// Size of the SMBIOS 2.1 entry point.
+const SMBIOS_ENTRY_POINT_SIZE: usize = 31;
+
+/// Find the structure table that an SMBIOS entry point in the configuration table refers to.
+///
+/// The SMBIOS 3.0 entry point is preferred, as it can refer to tables above 4 GiB.
+fn smbios_table() -> Option<&'static [u8]> {
+    let (smbios3, smbios) = system::with_config_table(|entries| {
+        let find = |guid| {
+            entries
+                .iter()
+                .find(|entry| entry.guid == guid)
+                .map(|entry| entry.address as *const u8)
+                .filter(|address| !address.is_null())
+        };
+        (find(SMBIOS3_GUID), find(SMBIOS_GUID))
+    });
+
+    // SAFETY: The firmware keeps the entry points and the structure table allocated and
+    // identity-mapped. The anchors are checked before anything else is read from them.
+    unsafe {
+        if let Some(entry_point) = smbios3 {
+            let entry_point = slice::from_raw_parts(entry_point, SMBIOS3_ENTRY_POINT_SIZE);
+            if entry_point.starts_with(b"_SM3_") {
+                let size = u32::from_le_bytes(entry_point[12..16].try_into().ok()?);
+                let address = u64::from_le_bytes(entry_point[16..24].try_into().ok()?);
+                return Some(slice::from_raw_parts(
+                    usize::try_from(address).ok()? as *const u8,
+                    usize::try_from(size).ok()?,
+                ));
+            }
+        }
+
+        if let Some(entry_point) = smbios {
+            let entry_point = slice::from_raw_parts(entry_point, SMBIOS_ENTRY_POINT_SIZE);
+            if entry_point.starts_with(b"_SM_") && &entry_point[16..21] == b"_DMI_" {
+                let size = u16::from_le_bytes(entry_point[22..24].try_into().ok()?);
+                let address = u32::from_le_bytes(entry_point[24..28].try_into().ok()?);
+                return Some(slice::from_raw_parts(
+                    usize::try_from(address).ok()? as *const u8,
+                    usize::from(size),
+                ));
+            }
+        }
+    }
+
+    None
+}
+
+/// Find the value of the first OEM string with the prefix `key` in an SMBIOS structure table.
+///
+/// Every structure consists of a formatted area, whose size is in its header, followed by a set of
+/// NUL-terminated strings that ends with an additional NUL.
+fn find_oem_string<'a>(mut table: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    while table.len() >= 4 {
+        let (structure_type, length) = (table[0], usize::from(table[1]));
+        if length < 4 || table.len() < length {
+            return None;
+        }
+
+        let strings = &table[length..];
+        let strings_size = strings.windows(2).position(|end| end == [0, 0])? + 2;
+        if structure_type == TYPE_OEM_STRINGS {
+            let value = strings[..strings_size]
+                .split(|&byte| byte == 0)
+                .find_map(|string| string.strip_prefix(key));
+            if value.is_some() {
+                return value;
+            }
+        }
+        if structure_type == TYPE_END_OF_TABLE {
+            return None;
+        }
+
+        table = &strings[strings_size..];
+    }
+
+    None
+}
+
+/// Get the kernel command line addition that the firmware passes in the
+/// `io.systemd.stub.kernel-cmdline-extra=` SMBIOS OEM string, if any.
+///
+/// Security: anyone who controls the firmware settings or the host of a virtual machine can set
+/// this string, and it is neither signed nor covered by the hashes in the image. It must thus only
+/// be used if Secure Boot is disabled, and it must be measured before it is used, so that a
+/// command line that was injected this way shows up in the PCRs.
+pub fn smbios_cmdline_addon() -> Option<CString16> {
+    let addon = find_oem_string(smbios_table()?, KERNEL_CMDLINE_EXTRA)?;
+    let addon = core::str::from_utf8(addon).ok()?.trim();
+    if addon.is_empty() {
+        return None;
+    }
+
+    CString16::try_from(addon).ok()
+}
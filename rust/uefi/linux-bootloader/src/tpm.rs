@@ -2,7 +2,7 @@ use alloc::vec::Vec;
 use log::warn;
 use uefi::{
     boot::{self, ScopedProtocol},
-    proto::tcg::{v2, EventType, PcrIndex},
+    proto::tcg::{v2, EventType, HashAlgorithm, PcrIndex},
     ResultExt,
 };
 
@@ -28,12 +28,56 @@ fn open_capable_tpm2() -> uefi::Result<ScopedProtocol<v2::Tcg>> {
     Ok(tpm_protocol)
 }
 
+/// Whether a TPM can be used for measurements.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TpmState {
+    /// There is no TPM, or the firmware does not expose it.
+    Absent,
+    /// There is a TPM, but it cannot be used, e.g. because it is in failure mode.
+    Unusable,
+    /// The TPM can be used for measurements.
+    Available,
+}
+
+pub fn tpm_state() -> TpmState {
+    if boot::get_handle_for_protocol::<v2::Tcg>().is_err() {
+        TpmState::Absent
+    } else if open_capable_tpm2().is_err() {
+        TpmState::Unusable
+    } else {
+        TpmState::Available
+    }
+}
+
+/// The PCR banks into which measurements are extended.
+///
+/// Every measurement is extended into all of them, see [`tpm_log_event_ascii`]. If the firmware
+/// cannot compute the digest of an active bank, that bank is left behind and no longer matches
+/// the event log, which breaks attestation against it. This is logged as a warning.
+pub fn active_pcr_banks() -> uefi::Result<HashAlgorithm> {
+    let mut tpm2 = open_capable_tpm2()?;
+    let active_pcr_banks = tpm2.get_active_pcr_banks()?;
+    let supported = tpm2.get_capability()?.hash_algorithm_bitmap;
+
+    let unsupported = active_pcr_banks.difference(supported);
+    if !unsupported.is_empty() {
+        warn!("The firmware cannot compute digests for the active PCR banks {unsupported:?}, they will be inconsistent with the event log");
+    }
+
+    Ok(active_pcr_banks)
+}
+
 pub fn tpm_available() -> bool {
-    open_capable_tpm2().is_ok()
+    tpm_state() == TpmState::Available
 }
 
 /// Log an event in the TPM with `buffer` as data.
-/// Returns a boolean whether the measurement has been done or not in case of success.
+/// Returns a boolean whether the measurement has been done or not in case of success, i.e. `false`
+/// if there is no usable TPM.
+///
+/// The firmware hashes `buffer` with the algorithm of every active PCR bank and extends all banks
+/// in this single call, so the event log records one digest per bank and all banks stay
+/// consistent with it.
 pub fn tpm_log_event_ascii(
     pcr_index: PcrIndex,
     buffer: &[u8],
@@ -42,17 +86,19 @@ pub fn tpm_log_event_ascii(
     if pcr_index.0 == u32::MAX {
         return Ok(false);
     }
-    if let Ok(mut tpm2) = open_capable_tpm2() {
-        let description_encoded = description
-            .encode_utf16()
-            .flat_map(|c| c.to_le_bytes())
-            .collect::<Vec<_>>();
-
-        let event = v2::PcrEventInputs::new_in_box(pcr_index, EventType::IPL, &description_encoded)
-            .discard_errdata()?;
-        // FIXME: what do we want as flags here?
-        tpm2.hash_log_extend_event(Default::default(), buffer, &event)?;
-    }
+    let Ok(mut tpm2) = open_capable_tpm2() else {
+        return Ok(false);
+    };
+
+    let description_encoded = description
+        .encode_utf16()
+        .flat_map(|c| c.to_le_bytes())
+        .collect::<Vec<_>>();
+
+    let event = v2::PcrEventInputs::new_in_box(pcr_index, EventType::IPL, &description_encoded)
+        .discard_errdata()?;
+    // FIXME: what do we want as flags here?
+    tpm2.hash_log_extend_event(Default::default(), buffer, &event)?;
 
     Ok(true)
 }
@@ -1,14 +1,25 @@
 use core::ffi::c_void;
 
+use log::{error, warn};
 use uefi::{
-    boot,
+    boot::{self, AllocateType, MemoryType},
+    cstr16,
     proto::{
         device_path::{DevicePath, FfiDevicePath},
         loaded_image::LoadedImage,
+        media::{
+            file::{File, FileSystemInfo},
+            fs::SimpleFileSystem,
+        },
     },
-    Result,
+    runtime::{self, VariableVendor},
+    Result, Status,
 };
 
+/// Below this amount of free space, writing state back to a file system, e.g. a random seed or a
+/// boot counter, is likely to fail.
+pub const LOW_FREE_SPACE_THRESHOLD: u64 = 1024 * 1024;
+
 #[derive(Debug, Clone, Copy)]
 pub struct PeInMemory {
     image_device_path: Option<*const FfiDevicePath>,
@@ -60,3 +71,100 @@ pub fn booted_image_file() -> Result<PeInMemory> {
         image_size: usize::try_from(image_size).map_err(|_| uefi::Status::INVALID_PARAMETER)?,
     })
 }
+
+/// Check the free space of a file system and warn prominently if it is almost full.
+///
+/// Writes to a full ESP fail without much of a trace, so this should be called before writing
+/// anything back to it. Returns the free space in bytes.
+pub fn check_free_space(fs: &mut SimpleFileSystem) -> Result<u64> {
+    let info = fs.open_volume()?.get_boxed_info::<FileSystemInfo>()?;
+    let free_space = info.free_space();
+
+    if free_space < LOW_FREE_SPACE_THRESHOLD {
+        warn!(
+            "The ESP is almost full ({free_space} of {} bytes free), writing files back to it will likely fail!",
+            info.volume_size()
+        );
+    }
+
+    Ok(free_space)
+}
+
+/// Check that `size` bytes of memory can be allocated for `purpose`, e.g. before reading a large
+/// initrd.
+///
+/// UEFI pool allocations cannot be reserved in advance, so the memory is only probed and freed
+/// right away. Running out of memory in the middle of loading panics, this reports what the memory
+/// was needed for instead.
+pub fn check_memory(size: usize, purpose: &str) -> Result<()> {
+    const PAGE_SIZE: usize = 4096;
+    let pages = size / PAGE_SIZE + 1;
+
+    let memory = boot::allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)
+        .map_err(|_err| {
+            error!("Insufficient memory: need {size} bytes for {purpose}.");
+            Status::OUT_OF_RESOURCES
+        })?;
+    // SAFETY: The pages were allocated above and are not used.
+    unsafe { boot::free_pages(memory, pages) }
+}
+
+/// The Secure Boot state of the firmware, according to the `SecureBoot` and `SetupMode` global
+/// EFI variables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecureBootState {
+    /// The firmware verifies the signatures of the images that it loads.
+    Enabled,
+    /// Secure Boot is supported, but the firmware does not verify signatures.
+    Disabled,
+    /// No platform key is enrolled, so the firmware does not verify signatures and anyone can
+    /// enroll keys.
+    SetupMode,
+    /// The firmware logs signature failures, but does not enforce them.
+    AuditMode,
+    /// The variables do not exist, cannot be read or hold unexpected values, e.g. because the
+    /// firmware does not support Secure Boot.
+    Unknown,
+}
+
+impl SecureBootState {
+    /// Whether the stub should enforce its own integrity checks.
+    ///
+    /// In case of doubt, integrity checks are enforced. This includes audit mode: someone who set it
+    /// up expects verification, so a tampered kernel must not boot just because the firmware lets
+    /// it through.
+    pub fn is_enforcing(self) -> bool {
+        match self {
+            SecureBootState::Enabled | SecureBootState::AuditMode | SecureBootState::Unknown => {
+                true
+            }
+            SecureBootState::Disabled | SecureBootState::SetupMode => false,
+        }
+    }
+}
+
+/// Read a global EFI variable that consists of a single byte.
+fn global_variable_byte(name: &uefi::CStr16) -> Option<u8> {
+    match runtime::get_variable(name, &VariableVendor::GLOBAL_VARIABLE, &mut [0]) {
+        Ok(([value], _)) => Some(*value),
+        _ => None,
+    }
+}
+
+/// Determine the Secure Boot state of the firmware.
+pub fn secure_boot_state() -> SecureBootState {
+    // The `AuditMode` variable only exists since UEFI 2.5, older firmware is not in audit mode.
+    if global_variable_byte(cstr16!("AuditMode")) == Some(1) {
+        return SecureBootState::AuditMode;
+    }
+
+    match (
+        global_variable_byte(cstr16!("SetupMode")),
+        global_variable_byte(cstr16!("SecureBoot")),
+    ) {
+        (Some(1), _) => SecureBootState::SetupMode,
+        (_, Some(1)) => SecureBootState::Enabled,
+        (_, Some(0)) => SecureBootState::Disabled,
+        _ => SecureBootState::Unknown,
+    }
+}
@@ -1,40 +1,14 @@
-/// List of PE sections that have a special meaning with respect to
-/// UKI specification.
-/// This is the canonical order in which they are measured into TPM
-/// PCR 11.
-/// !!! DO NOT REORDER !!!
-#[repr(u8)]
-pub enum UnifiedSection {
-    Linux = 0,
-    OsRel = 1,
-    CmdLine = 2,
-    Initrd = 3,
-    Splash = 4,
-    Dtb = 5,
-    PcrSig = 6,
-    PcrPkey = 7,
-}
-
-impl TryFrom<&str> for UnifiedSection {
-    type Error = uefi::Error;
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        Ok(match value {
-            ".linux" => Self::Linux,
-            ".osrel" => Self::OsRel,
-            ".cmdline" => Self::CmdLine,
-            ".initrd" => Self::Initrd,
-            ".splash" => Self::Splash,
-            ".dtb" => Self::Dtb,
-            ".pcrsig" => Self::PcrSig,
-            ".pcrpkey" => Self::PcrPkey,
-            _ => return Err(uefi::Status::INVALID_PARAMETER.into()),
-        })
-    }
-}
-
-impl UnifiedSection {
-    /// Whether this section should be measured into TPM.
-    pub fn should_be_measured(&self) -> bool {
-        !matches!(self, UnifiedSection::PcrSig)
-    }
-}
+/// The unified sections that are measured into PCR 11, in the order in which they are measured.
+///
+/// The measurements follow this table rather than the order of the sections in the image, so
+/// that they are deterministic however an image was built. The order is the one of the unified
+/// sections in the UKI specification, in which systemd-stub measures them as well. Reordering the
+/// table changes the value of PCR 11 for every image. `.pcrsig` is deliberately missing: it
+/// contains signatures over the expected value of PCR 11 and can thus not be part of it. Sections
+/// that occur more than once, like `.dtbauto`, are all measured, in the order in which they appear
+/// in the image.
+///
+/// Measuring another section only takes a new entry here.
+pub const MEASURED_SECTIONS: &[&str] = &[
+    ".linux", ".osrel", ".cmdline", ".initrd", ".splash", ".dtb", ".uname", ".pcrpkey", ".dtbauto",
+];
@@ -0,0 +1,723 @@
+//! Decompression of xz streams, see "The .xz File Format" specification.
+//!
+//! Only the LZMA2 filter is supported, which is what `xz` uses unless it is told to add a BCJ
+//! filter, e.g. for kernels. The checks of the blocks (CRC32, CRC64 or SHA-256) are skipped, like
+//! the CRC of gzip, because the stub verifies the data by its hash anyway.
+//!
+//! The whole output is kept in memory, so it serves as the dictionary of LZMA2 and the dictionary
+//! size of a stream does not matter.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use uefi::Status;
+
+const STREAM_HEADER_MAGIC: &[u8] = &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
+const STREAM_FOOTER_MAGIC: &[u8] = b"YZ";
+const FILTER_LZMA2: u64 = 0x21;
+
+fn invalid() -> uefi::Error {
+    Status::COMPROMISED_DATA.into()
+}
+
+/// Decompress all xz streams in `data`, which may be concatenated and followed by padding.
+pub fn decompress(data: &[u8]) -> uefi::Result<Vec<u8>> {
+    let mut input = Input::new(data);
+    let mut output = Vec::new();
+
+    loop {
+        decode_stream(&mut input, &mut output)?;
+
+        // Stream padding consists of null bytes, in multiples of four.
+        while input.remaining().starts_with(&[0; 4]) {
+            input.take(4)?;
+        }
+        if input.remaining().is_empty() {
+            return Ok(output);
+        }
+    }
+}
+
+/// Decode a single stream, from its header to its footer.
+fn decode_stream(input: &mut Input, output: &mut Vec<u8>) -> uefi::Result<()> {
+    let header = input.take(12)?;
+    if !header.starts_with(STREAM_HEADER_MAGIC) || header[6] != 0 || header[7] & 0xf0 != 0 {
+        return Err(invalid());
+    }
+    // The size of the check of every block is determined by its type.
+    let check_size = match header[7] & 0x0f {
+        0 => 0,
+        check_type => 4 << ((check_type - 1) / 3),
+    };
+
+    // Blocks follow until the index, which starts with a null byte instead of a header size.
+    while input.peek()? != 0 {
+        decode_block(input, output)?;
+        input.align(4)?;
+        input.take(check_size)?;
+    }
+
+    // The index only repeats the sizes of the blocks.
+    input.take(1)?;
+    let records = input.vli()?;
+    for _ in 0..records {
+        input.vli()?;
+        input.vli()?;
+    }
+    input.align(4)?;
+    // CRC32 of the index.
+    input.take(4)?;
+
+    let footer = input.take(12)?;
+    if !footer.ends_with(STREAM_FOOTER_MAGIC) {
+        return Err(invalid());
+    }
+
+    Ok(())
+}
+
+/// Decode a block whose only filter is LZMA2.
+fn decode_block(input: &mut Input, output: &mut Vec<u8>) -> uefi::Result<()> {
+    let header_size = (usize::from(input.peek()?) + 1) * 4;
+    // The header ends with its CRC32.
+    let header = input.take(header_size)?;
+    let mut header = Input::new(&header[1..header_size - 4]);
+
+    let flags = header.byte()?;
+    if flags & 0x3c != 0 {
+        return Err(Status::UNSUPPORTED.into());
+    }
+    // BCJ filters are followed by LZMA2, so any chain of more than one filter is unsupported.
+    if flags & 0x03 != 0 {
+        return Err(Status::UNSUPPORTED.into());
+    }
+    // The compressed and the uncompressed size are optional and only used for checking.
+    if flags & 0x40 != 0 {
+        header.vli()?;
+    }
+    if flags & 0x80 != 0 {
+        header.vli()?;
+    }
+    if header.vli()? != FILTER_LZMA2 {
+        return Err(Status::UNSUPPORTED.into());
+    }
+    // The only property of LZMA2 is the dictionary size.
+    if header.vli()? != 1 || header.byte()? > 40 {
+        return Err(invalid());
+    }
+
+    decode_lzma2(input, output)
+}
+
+/// Decode LZMA2 chunks until the end marker.
+///
+/// Every chunk is either stored uncompressed or compressed with LZMA. The first chunk of a block
+/// resets the dictionary, and the first LZMA chunk after that sets the properties of LZMA.
+fn decode_lzma2(input: &mut Input, output: &mut Vec<u8>) -> uefi::Result<()> {
+    let mut lzma: Option<LzmaDecoder> = None;
+    let mut need_dictionary_reset = true;
+    let mut need_properties = true;
+    let mut dictionary_start = output.len();
+
+    loop {
+        let control = input.byte()?;
+        if control == 0x00 {
+            return Ok(());
+        }
+
+        if control >= 0xe0 || control == 0x01 {
+            need_dictionary_reset = false;
+            need_properties = true;
+            dictionary_start = output.len();
+        } else if need_dictionary_reset {
+            return Err(invalid());
+        }
+
+        if control < 0x80 {
+            if control > 0x02 {
+                return Err(invalid());
+            }
+            let size = input.u16_be()? + 1;
+            output.extend_from_slice(input.take(size)?);
+            continue;
+        }
+
+        let unpacked_size = (usize::from(control & 0x1f) << 16) + input.u16_be()? + 1;
+        let packed_size = input.u16_be()? + 1;
+        if control >= 0xc0 {
+            need_properties = false;
+            lzma = Some(LzmaDecoder::new(input.byte()?)?);
+        } else if need_properties {
+            return Err(invalid());
+        } else if control >= 0xa0 {
+            if let Some(lzma) = &mut lzma {
+                lzma.reset();
+            }
+        }
+
+        let lzma = lzma.as_mut().ok_or_else(invalid)?;
+        let mut dictionary = Dictionary {
+            output,
+            start: dictionary_start,
+        };
+        lzma.decode_chunk(
+            &mut RangeDecoder::new(input.take(packed_size)?)?,
+            &mut dictionary,
+            unpacked_size,
+        )?;
+    }
+}
+
+/// The compressed data that is left to decode.
+struct Input<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Input<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.data[self.position..]
+    }
+
+    fn take(&mut self, size: usize) -> uefi::Result<&'a [u8]> {
+        let data = self.remaining().get(..size).ok_or_else(invalid)?;
+        self.position += size;
+        Ok(data)
+    }
+
+    fn peek(&self) -> uefi::Result<u8> {
+        self.remaining().first().copied().ok_or_else(invalid)
+    }
+
+    fn byte(&mut self) -> uefi::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16_be(&mut self) -> uefi::Result<usize> {
+        let bytes = self.take(2)?;
+        Ok(usize::from(u16::from_be_bytes([bytes[0], bytes[1]])))
+    }
+
+    /// Read a variable-length integer of up to 63 bits, seven bits per byte.
+    fn vli(&mut self) -> uefi::Result<u64> {
+        let mut value = 0;
+        for shift in (0..63).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid())
+    }
+
+    /// Skip the padding up to the next multiple of `alignment` bytes from the start of the stream.
+    fn align(&mut self, alignment: usize) -> uefi::Result<()> {
+        let padding = self.position.wrapping_neg() % alignment;
+        if self.take(padding)?.iter().any(|&byte| byte != 0) {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+}
+
+/// The part of the output that LZMA can refer back to, i.e. everything since the last dictionary
+/// reset.
+struct Dictionary<'a> {
+    output: &'a mut Vec<u8>,
+    start: usize,
+}
+
+impl Dictionary<'_> {
+    fn len(&self) -> usize {
+        self.output.len() - self.start
+    }
+
+    /// The byte `distance + 1` bytes back, or zero if there is none.
+    fn get(&self, distance: u32) -> u8 {
+        let distance = distance as usize;
+        if distance < self.len() {
+            self.output[self.output.len() - distance - 1]
+        } else {
+            0
+        }
+    }
+
+    /// Repeat `length` bytes from `distance + 1` bytes back.
+    fn repeat(&mut self, distance: u32, length: usize) -> uefi::Result<()> {
+        let distance = distance as usize;
+        if distance >= self.len() {
+            return Err(invalid());
+        }
+        for _ in 0..length {
+            self.output
+                .push(self.output[self.output.len() - distance - 1]);
+        }
+        Ok(())
+    }
+}
+
+/// Number of bits of the probabilities of the range coder.
+const PROBABILITY_BITS: u32 = 11;
+const PROBABILITY_INIT: u16 = 1 << (PROBABILITY_BITS - 1);
+/// Number of bits by which the probabilities are adapted.
+const MOVE_BITS: u32 = 5;
+
+/// The binary range decoder of LZMA.
+struct RangeDecoder<'a> {
+    input: Input<'a>,
+    range: u32,
+    code: u32,
+}
+
+impl<'a> RangeDecoder<'a> {
+    fn new(data: &'a [u8]) -> uefi::Result<Self> {
+        let mut input = Input::new(data);
+        if input.byte()? != 0 {
+            return Err(invalid());
+        }
+        let code = input.take(4)?;
+        Ok(Self {
+            input,
+            range: u32::MAX,
+            code: u32::from_be_bytes([code[0], code[1], code[2], code[3]]),
+        })
+    }
+
+    fn normalize(&mut self) -> uefi::Result<()> {
+        if self.range < 1 << 24 {
+            self.range <<= 8;
+            self.code = (self.code << 8) | u32::from(self.input.byte()?);
+        }
+        Ok(())
+    }
+
+    /// Whether all input was used up, as it is at the end of an LZMA chunk.
+    fn is_finished(&self) -> bool {
+        self.code == 0 && self.input.remaining().is_empty()
+    }
+
+    fn bit(&mut self, probability: &mut u16) -> uefi::Result<u32> {
+        self.normalize()?;
+        let bound = (self.range >> PROBABILITY_BITS) * u32::from(*probability);
+        if self.code < bound {
+            self.range = bound;
+            *probability += ((1 << PROBABILITY_BITS) - *probability) >> MOVE_BITS;
+            Ok(0)
+        } else {
+            self.range -= bound;
+            self.code -= bound;
+            *probability -= *probability >> MOVE_BITS;
+            Ok(1)
+        }
+    }
+
+    /// Decode a symbol below `limit`, a power of two, most significant bit first.
+    fn bittree(&mut self, probabilities: &mut [u16], limit: u32) -> uefi::Result<u32> {
+        let mut symbol = 1;
+        while symbol < limit {
+            symbol = (symbol << 1) + self.bit(&mut probabilities[symbol as usize])?;
+        }
+        Ok(symbol - limit)
+    }
+
+    /// Decode a symbol of `bits` bits, least significant bit first.
+    ///
+    /// Unlike in `bittree`, the probabilities start at index 0.
+    fn bittree_reverse(&mut self, probabilities: &mut [u16], bits: u32) -> uefi::Result<u32> {
+        let mut symbol = 1;
+        let mut value = 0;
+        for bit in 0..bits {
+            let decoded = self.bit(&mut probabilities[symbol - 1])?;
+            symbol = (symbol << 1) + decoded as usize;
+            value |= decoded << bit;
+        }
+        Ok(value)
+    }
+
+    /// Decode `bits` bits with fixed probabilities of one half.
+    fn direct(&mut self, bits: u32) -> uefi::Result<u32> {
+        let mut value = 0u32;
+        for _ in 0..bits {
+            self.normalize()?;
+            self.range >>= 1;
+            self.code = self.code.wrapping_sub(self.range);
+            let mask = 0u32.wrapping_sub(self.code >> 31);
+            self.code = self.code.wrapping_add(self.range & mask);
+            value = (value << 1).wrapping_add(mask.wrapping_add(1));
+        }
+        Ok(value)
+    }
+}
+
+const STATES: usize = 12;
+/// States below this one were reached by a literal.
+const LITERAL_STATES: usize = 7;
+const POSITION_STATES_MAX: usize = 1 << 4;
+
+const MATCH_LENGTH_MIN: usize = 2;
+const LENGTH_LOW_SYMBOLS: u32 = 8;
+const LENGTH_MID_SYMBOLS: u32 = 8;
+const LENGTH_HIGH_SYMBOLS: u32 = 256;
+
+/// Number of match lengths with their own distance slot probabilities.
+const DISTANCE_STATES: usize = 4;
+const DISTANCE_SLOTS: u32 = 64;
+/// Distance slots below this one are decoded entirely with `distance_special`.
+const DISTANCE_MODEL_END: u32 = 14;
+const FULL_DISTANCES: usize = 1 << (DISTANCE_MODEL_END / 2);
+const ALIGN_BITS: u32 = 4;
+
+const LITERAL_CODER_SIZE: usize = 0x300;
+
+/// The probabilities of a match length.
+struct LengthDecoder {
+    choice: u16,
+    choice2: u16,
+    low: [[u16; LENGTH_LOW_SYMBOLS as usize]; POSITION_STATES_MAX],
+    mid: [[u16; LENGTH_MID_SYMBOLS as usize]; POSITION_STATES_MAX],
+    high: [u16; LENGTH_HIGH_SYMBOLS as usize],
+}
+
+impl LengthDecoder {
+    fn new() -> Self {
+        Self {
+            choice: PROBABILITY_INIT,
+            choice2: PROBABILITY_INIT,
+            low: [[PROBABILITY_INIT; LENGTH_LOW_SYMBOLS as usize]; POSITION_STATES_MAX],
+            mid: [[PROBABILITY_INIT; LENGTH_MID_SYMBOLS as usize]; POSITION_STATES_MAX],
+            high: [PROBABILITY_INIT; LENGTH_HIGH_SYMBOLS as usize],
+        }
+    }
+
+    fn decode(&mut self, rc: &mut RangeDecoder, position_state: usize) -> uefi::Result<usize> {
+        let length = if rc.bit(&mut self.choice)? == 0 {
+            rc.bittree(&mut self.low[position_state], LENGTH_LOW_SYMBOLS)?
+        } else if rc.bit(&mut self.choice2)? == 0 {
+            LENGTH_LOW_SYMBOLS + rc.bittree(&mut self.mid[position_state], LENGTH_MID_SYMBOLS)?
+        } else {
+            LENGTH_LOW_SYMBOLS
+                + LENGTH_MID_SYMBOLS
+                + rc.bittree(&mut self.high, LENGTH_HIGH_SYMBOLS)?
+        };
+        Ok(MATCH_LENGTH_MIN + length as usize)
+    }
+}
+
+/// The state of LZMA, which LZMA2 carries over from one chunk to the next unless it resets it.
+struct LzmaDecoder {
+    /// Number of high bits of the previous byte that select the literal coder.
+    lc: u32,
+    /// Number of low bits of the position that select the literal coder.
+    lp: u32,
+    /// Number of low bits of the position that select the position state.
+    pb: u32,
+
+    state: usize,
+    /// The distances of the last four matches, minus one.
+    reps: [u32; 4],
+
+    literal: Vec<u16>,
+    is_match: [[u16; POSITION_STATES_MAX]; STATES],
+    is_rep: [u16; STATES],
+    is_rep0: [u16; STATES],
+    is_rep1: [u16; STATES],
+    is_rep2: [u16; STATES],
+    is_rep0_long: [[u16; POSITION_STATES_MAX]; STATES],
+    distance_slot: [[u16; DISTANCE_SLOTS as usize]; DISTANCE_STATES],
+    distance_special: [u16; FULL_DISTANCES - DISTANCE_MODEL_END as usize],
+    distance_align: [u16; 1 << ALIGN_BITS],
+    match_length: LengthDecoder,
+    rep_length: LengthDecoder,
+}
+
+impl LzmaDecoder {
+    /// Create a decoder from the properties byte that encodes `lc`, `lp` and `pb`.
+    fn new(properties: u8) -> uefi::Result<Self> {
+        let properties = u32::from(properties);
+        if properties >= 9 * 5 * 5 {
+            return Err(invalid());
+        }
+        let (lc, lp, pb) = (properties % 9, properties / 9 % 5, properties / 45);
+        // LZMA2 limits the number of literal coders.
+        if lc + lp > 4 {
+            return Err(invalid());
+        }
+        Ok(Self::with_properties(lc, lp, pb))
+    }
+
+    fn with_properties(lc: u32, lp: u32, pb: u32) -> Self {
+        Self {
+            lc,
+            lp,
+            pb,
+            state: 0,
+            reps: [0; 4],
+            literal: vec![PROBABILITY_INIT; LITERAL_CODER_SIZE << (lc + lp)],
+            is_match: [[PROBABILITY_INIT; POSITION_STATES_MAX]; STATES],
+            is_rep: [PROBABILITY_INIT; STATES],
+            is_rep0: [PROBABILITY_INIT; STATES],
+            is_rep1: [PROBABILITY_INIT; STATES],
+            is_rep2: [PROBABILITY_INIT; STATES],
+            is_rep0_long: [[PROBABILITY_INIT; POSITION_STATES_MAX]; STATES],
+            distance_slot: [[PROBABILITY_INIT; DISTANCE_SLOTS as usize]; DISTANCE_STATES],
+            distance_special: [PROBABILITY_INIT; FULL_DISTANCES - DISTANCE_MODEL_END as usize],
+            distance_align: [PROBABILITY_INIT; 1 << ALIGN_BITS],
+            match_length: LengthDecoder::new(),
+            rep_length: LengthDecoder::new(),
+        }
+    }
+
+    /// Reset the state and the probabilities, but keep the properties.
+    fn reset(&mut self) {
+        *self = Self::with_properties(self.lc, self.lp, self.pb);
+    }
+
+    /// Decode one LZMA chunk of `unpacked_size` bytes, which must use up the input exactly.
+    fn decode_chunk(
+        &mut self,
+        rc: &mut RangeDecoder,
+        dictionary: &mut Dictionary,
+        unpacked_size: usize,
+    ) -> uefi::Result<()> {
+        let end = dictionary.output.len() + unpacked_size;
+        let position_mask = (1 << self.pb) - 1;
+
+        while dictionary.output.len() < end {
+            let position_state = dictionary.len() & position_mask;
+
+            if rc.bit(&mut self.is_match[self.state][position_state])? == 0 {
+                self.decode_literal(rc, dictionary)?;
+                continue;
+            }
+
+            let length = if rc.bit(&mut self.is_rep[self.state])? == 0 {
+                self.decode_match(rc, position_state)?
+            } else {
+                match self.decode_rep(rc, position_state)? {
+                    Some(length) => length,
+                    None => {
+                        // A single byte from the last distance.
+                        dictionary.repeat(self.reps[0], 1)?;
+                        continue;
+                    }
+                }
+            };
+
+            // Matches do not cross the end of a chunk.
+            if length > end - dictionary.output.len() {
+                return Err(invalid());
+            }
+            dictionary.repeat(self.reps[0], length)?;
+        }
+
+        rc.normalize()?;
+        if !rc.is_finished() {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+
+    fn decode_literal(
+        &mut self,
+        rc: &mut RangeDecoder,
+        dictionary: &mut Dictionary,
+    ) -> uefi::Result<()> {
+        let previous_byte = u32::from(dictionary.get(0));
+        let position = dictionary.len() as u32 & ((1 << self.lp) - 1);
+        let coder = ((position << self.lc) + (previous_byte >> (8 - self.lc))) as usize;
+        let probabilities = &mut self.literal[coder * LITERAL_CODER_SIZE..][..LITERAL_CODER_SIZE];
+
+        let symbol = if self.state < LITERAL_STATES {
+            rc.bittree(probabilities, 0x100)?
+        } else {
+            // After a match, the byte at the last distance predicts the literal until the
+            // first bit that differs.
+            let mut match_byte = u32::from(dictionary.get(self.reps[0])) << 1;
+            let mut offset = 0x100;
+            let mut symbol = 1;
+            while symbol < 0x100 {
+                let match_bit = match_byte & offset;
+                match_byte <<= 1;
+                let index = (offset + match_bit + symbol) as usize;
+                if rc.bit(&mut probabilities[index])? == 0 {
+                    symbol <<= 1;
+                    offset &= !match_bit;
+                } else {
+                    symbol = (symbol << 1) | 1;
+                    offset &= match_bit;
+                }
+            }
+            symbol - 0x100
+        };
+
+        dictionary.output.push(symbol as u8);
+        self.state = match self.state {
+            0..=3 => 0,
+            4..=9 => self.state - 3,
+            _ => self.state - 6,
+        };
+        Ok(())
+    }
+
+    /// Decode a match with a new distance, and return its length.
+    fn decode_match(
+        &mut self,
+        rc: &mut RangeDecoder,
+        position_state: usize,
+    ) -> uefi::Result<usize> {
+        self.state = if self.state < LITERAL_STATES { 7 } else { 10 };
+        let length = self.match_length.decode(rc, position_state)?;
+
+        let distance_state = usize::min(length - MATCH_LENGTH_MIN, DISTANCE_STATES - 1);
+        let slot = rc.bittree(&mut self.distance_slot[distance_state], DISTANCE_SLOTS)?;
+        let distance = if slot < 4 {
+            slot
+        } else {
+            // The slot holds the two highest bits of the distance and its number of bits.
+            let bits = (slot >> 1) - 1;
+            let high = (2 | (slot & 1)) << bits;
+            if slot < DISTANCE_MODEL_END {
+                let start = (high - slot) as usize;
+                high + rc.bittree_reverse(&mut self.distance_special[start..], bits)?
+            } else {
+                let middle = rc.direct(bits - ALIGN_BITS)? << ALIGN_BITS;
+                high.wrapping_add(middle)
+                    .wrapping_add(rc.bittree_reverse(&mut self.distance_align, ALIGN_BITS)?)
+            }
+        };
+
+        self.reps = [distance, self.reps[0], self.reps[1], self.reps[2]];
+        Ok(length)
+    }
+
+    /// Decode a match with one of the last four distances, and return its length, or `None` for
+    /// a single byte from the last distance.
+    fn decode_rep(
+        &mut self,
+        rc: &mut RangeDecoder,
+        position_state: usize,
+    ) -> uefi::Result<Option<usize>> {
+        if rc.bit(&mut self.is_rep0[self.state])? == 0 {
+            if rc.bit(&mut self.is_rep0_long[self.state][position_state])? == 0 {
+                self.state = if self.state < LITERAL_STATES { 9 } else { 11 };
+                return Ok(None);
+            }
+        } else {
+            let index = if rc.bit(&mut self.is_rep1[self.state])? == 0 {
+                1
+            } else if rc.bit(&mut self.is_rep2[self.state])? == 0 {
+                2
+            } else {
+                3
+            };
+            // The distance moves to the front.
+            self.reps[..=index].rotate_right(1);
+        }
+
+        self.state = if self.state < LITERAL_STATES { 8 } else { 11 };
+        self.rep_length.decode(rc, position_state).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    /// `TEXT` compressed by `xz` with different checks.
+    const TEXT_CRC32: &[u8] = include_bytes!("../tests/fixtures/text.xz");
+    const TEXT_SHA256: &[u8] = include_bytes!("../tests/fixtures/text-sha256.xz");
+    const TEXT_NONE: &[u8] = include_bytes!("../tests/fixtures/text-none.xz");
+    /// `TEXT` split into blocks of 2000 bytes by `xz --block-size=2000`.
+    const TEXT_BLOCKS: &[u8] = include_bytes!("../tests/fixtures/text-blocks.xz");
+    /// `TEXT` filtered by `xz --x86 --lzma2`.
+    const TEXT_BCJ: &[u8] = include_bytes!("../tests/fixtures/text-bcj.xz");
+    /// 300000 repetitions, more than fit into a single LZMA2 chunk.
+    const LARGE: &[u8] = include_bytes!("../tests/fixtures/large.xz");
+    /// 4 KiB of random bytes, which `xz` stores in an uncompressed chunk.
+    const RANDOM: &[u8] = include_bytes!("../tests/fixtures/random.xz");
+
+    fn text(repetitions: usize) -> Vec<u8> {
+        b"lanzaboote ".repeat(repetitions)
+    }
+
+    fn status(result: uefi::Result<Vec<u8>>) -> Status {
+        result.map_or_else(|err| err.status(), |_| Status::SUCCESS)
+    }
+
+    #[test]
+    fn decompress_with_any_check() {
+        for data in [TEXT_CRC32, TEXT_SHA256, TEXT_NONE] {
+            assert_eq!(decompress(data).unwrap(), text(1000));
+        }
+    }
+
+    #[test]
+    fn decompress_multiple_blocks() {
+        assert_eq!(decompress(TEXT_BLOCKS).unwrap(), text(1000));
+    }
+
+    #[test]
+    fn decompress_multiple_chunks() {
+        assert_eq!(decompress(LARGE).unwrap(), text(300000));
+    }
+
+    #[test]
+    fn decompress_uncompressed_chunks() {
+        let output = decompress(RANDOM).unwrap();
+        assert_eq!(
+            format!("{:x}", Sha256::digest(output)),
+            "815bbc54cf6c8ad87905950d9cc0a644cc3c41bc500a382025b6c9b2a9f33f29"
+        );
+    }
+
+    #[test]
+    fn decompress_concatenated_streams_with_padding() {
+        let data = [TEXT_CRC32, &[0; 8], TEXT_NONE, &[0; 4]].concat();
+        assert_eq!(decompress(&data).unwrap(), text(2000));
+    }
+
+    #[test]
+    fn compression_dispatches_to_xz() {
+        let data = crate::compression::decompress(TEXT_CRC32.to_vec()).unwrap();
+        assert_eq!(data, text(1000));
+    }
+
+    #[test]
+    fn reject_bcj_filters() {
+        assert_eq!(status(decompress(TEXT_BCJ)), Status::UNSUPPORTED);
+    }
+
+    #[test]
+    fn reject_truncated_data() {
+        for end in 0..TEXT_CRC32.len() {
+            assert_eq!(
+                status(decompress(&TEXT_CRC32[..end])),
+                Status::COMPROMISED_DATA,
+                "truncated to {end} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn reject_padding_that_is_not_zero() {
+        let data = [TEXT_CRC32, &[0, 0, 0, 1]].concat();
+        assert_eq!(status(decompress(&data)), Status::COMPROMISED_DATA);
+    }
+
+    #[test]
+    fn survive_corrupt_data() {
+        // The checks are skipped, so some corruption goes unnoticed, but none may panic.
+        for data in [TEXT_CRC32, RANDOM] {
+            for position in 0..data.len() {
+                let mut corrupt = data.to_vec();
+                corrupt[position] ^= 0x55;
+                let _ = decompress(&corrupt);
+            }
+        }
+    }
+}
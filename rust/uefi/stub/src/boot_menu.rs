@@ -0,0 +1,177 @@
+//! A countdown before booting, during which the kernel command line can be edited.
+//!
+//! Like the `e` key of GRUB, this allows changing the command line for a single boot, e.g. to boot
+//! into a rescue target. The edited command line is measured into PCR 12, so that it can be told
+//! apart from the embedded one. As it would allow anyone at the keyboard to bypass the signed
+//! command line, it is never offered under Secure Boot.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+use log::{info, warn};
+use uefi::{
+    boot::{self, EventType, TimerTrigger, Tpl},
+    proto::console::text::{Key, ScanCode},
+    system, Result,
+};
+
+use crate::common::{check_measurement, to_cstring16};
+use linux_bootloader::boot_outcome::{report_degraded_boot, DegradedPath};
+use linux_bootloader::measure::measure_cmdline;
+
+/// Wait for a key press, but at most `timeout_ms` milliseconds if given.
+///
+/// Nothing is returned if the timeout expired or the console has no input.
+fn wait_for_key(timeout_ms: Option<u64>) -> Option<Key> {
+    let key_event = system::with_stdin(|stdin| stdin.wait_for_key_event())?;
+    match timeout_ms {
+        Some(timeout_ms) => {
+            // SAFETY: The event has no notification function.
+            let timer =
+                unsafe { boot::create_event(EventType::TIMER, Tpl::CALLBACK, None, None) }.ok()?;
+            let waited = boot::set_timer(&timer, TimerTrigger::Relative(timeout_ms * 10_000))
+                // SAFETY: The clone is only used until the timer is closed below.
+                .and_then(|()| {
+                    boot::wait_for_event(&mut [key_event, unsafe { timer.unsafe_clone() }])
+                        .map(|_| ())
+                        .map_err(|err| err.status().into())
+                });
+            let _ = boot::close_event(timer);
+            waited.ok()?;
+        }
+        None => {
+            boot::wait_for_event(&mut [key_event]).ok()?;
+        }
+    }
+
+    system::with_stdin(|stdin| stdin.read_key().ok().flatten())
+}
+
+/// Decode a command line as passed to the kernel, i.e. a UCS-2 string that may be terminated by
+/// a NUL character.
+fn decode_cmdline(cmdline: &[u8]) -> String {
+    let ucs2 = cmdline
+        .chunks_exact(2)
+        .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+        .take_while(|&c| c != 0);
+    char::decode_utf16(ucs2)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// Show the line being edited in the current row of the console.
+///
+/// Only its end is shown if it does not fit, like the cursor is always at the end.
+fn show_line(line: &str) {
+    system::with_stdout(|stdout| {
+        let columns = match stdout.current_mode() {
+            Ok(Some(mode)) => mode.columns(),
+            _ => 80,
+        };
+        // Writing into the last column would wrap to the next row.
+        let width = columns.saturating_sub(3).max(1);
+        let shown = line.chars().count().saturating_sub(width);
+        let shown: String = line.chars().skip(shown).collect();
+        let _ = write!(stdout, "\r> {shown:<width$}\r> {shown}");
+    });
+}
+
+/// Let the user edit `cmdline` on the console.
+///
+/// Printable characters are appended, backspace removes the last character. Enter accepts the
+/// edited line, escape discards it.
+fn edit_line(cmdline: &str) -> Option<String> {
+    let mut line = String::from(cmdline);
+    system::with_stdout(|stdout| {
+        let _ = writeln!(
+            stdout,
+            "\nEdit the kernel command line, press Enter to boot or Esc to cancel:"
+        );
+        let _ = stdout.enable_cursor(true);
+    });
+
+    let edited = loop {
+        show_line(&line);
+        let Some(key) = wait_for_key(None) else {
+            break None;
+        };
+        match key {
+            Key::Special(ScanCode::ESCAPE) => break None,
+            Key::Printable(c) => match char::from(c) {
+                '\r' => break Some(line),
+                '\u{8}' => {
+                    line.pop();
+                }
+                c if !c.is_control() => line.push(c),
+                _ => {}
+            },
+            Key::Special(_) => {}
+        }
+    };
+
+    system::with_stdout(|stdout| {
+        let _ = stdout.enable_cursor(false);
+        let _ = writeln!(stdout);
+    });
+    edited
+}
+
+/// Count down `timeout_seconds` before booting, during which pressing `e` allows editing the
+/// kernel command line for this boot, and any other key boots right away.
+///
+/// `cmdline` is the command line as passed to the kernel, and so is the returned one. Nothing is
+/// shown if Secure Boot is enabled or the timeout is 0. An error is only returned if the edited
+/// command line cannot be measured while measurements are required.
+pub fn edit_cmdline(
+    cmdline: Vec<u8>,
+    timeout_seconds: u64,
+    secure_boot_enabled: bool,
+    measure_required: bool,
+) -> Result<Vec<u8>> {
+    if secure_boot_enabled || timeout_seconds == 0 {
+        return Ok(cmdline);
+    }
+
+    system::with_stdin(|stdin| {
+        // Discard keys that were pressed before, e.g. to select the boot entry.
+        let _ = stdin.reset(false);
+    });
+
+    let mut edit = false;
+    for remaining in (1..=timeout_seconds).rev() {
+        system::with_stdout(|stdout| {
+            let _ = write!(
+                stdout,
+                "\rBooting in {remaining} s, press e to edit the kernel command line. "
+            );
+        });
+        if let Some(key) = wait_for_key(Some(1000)) {
+            edit = matches!(key, Key::Printable(c) if char::from(c) == 'e');
+            break;
+        }
+    }
+    system::with_stdout(|stdout| {
+        let _ = writeln!(stdout);
+    });
+    if !edit {
+        return Ok(cmdline);
+    }
+
+    let Some(edited) = edit_line(&decode_cmdline(&cmdline)) else {
+        info!("Keeping the kernel command line.");
+        return Ok(cmdline);
+    };
+    let edited = edited.trim();
+    let Ok(edited_cmdline) = to_cstring16(edited, "the edited command line") else {
+        warn!("Keeping the kernel command line, the edited one is not a valid string.");
+        return Ok(cmdline);
+    };
+
+    check_measurement(
+        measure_cmdline(edited.as_bytes(), "Edited kernel command line"),
+        measure_required,
+    )?;
+    info!("Booting with the edited kernel command line `{edited}`.");
+    let _ = report_degraded_boot(DegradedPath::CmdlineEdited, edited);
+
+    Ok(edited_cmdline.as_bytes().to_vec())
+}
@@ -1,94 +1,857 @@
-use alloc::vec::Vec;
-use log::warn;
+use alloc::{format, string::String, vec, vec::Vec};
+use core::fmt::Write;
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
 use uefi::{
-    boot, guid, prelude::*, proto::loaded_image::LoadedImage, runtime, runtime::VariableVendor,
-    CStr16, CString16, Result,
+    boot::{self, EventType, TimerTrigger, Tpl},
+    fs::FileSystem,
+    prelude::*,
+    proto::{
+        device_path::text::{AllowShortcuts, DisplayOnly},
+        loaded_image::LoadedImage,
+        media::file::{File, FileAttribute, FileMode},
+    },
+    runtime,
+    runtime::{ResetType, VariableAttributes},
+    system, CStr16, CString16, Result,
 };
 
+use crate::config::ConsoleMode;
+use linux_bootloader::authenticode::verify_detached_signature;
+use linux_bootloader::boot_outcome::{report_degraded_boot, report_verified_boot, DegradedPath};
+use linux_bootloader::boot_time::BootTimer;
+#[cfg(target_arch = "x86_64")]
+use linux_bootloader::bzimage::{is_bzimage, BzImage};
+use linux_bootloader::compression::{validate, Compression};
+use linux_bootloader::efivars::BOOT_LOADER_VENDOR_UUID;
+use linux_bootloader::hash::verify_hash;
 use linux_bootloader::linux_loader::InitrdLoader;
+use linux_bootloader::measure::measure_cmdline;
 use linux_bootloader::pe_loader::Image;
-use linux_bootloader::pe_section::pe_section_as_string;
+use linux_bootloader::pe_section::{
+    corrupt_sections, modified_code_sections, pe_cmdline, pe_section,
+};
+use linux_bootloader::smbios::smbios_cmdline_addon;
+use linux_bootloader::uefi_helpers::{booted_image_file, secure_boot_state};
+
+pub type Hash = sha2::digest::Output<Sha256>;
 
-/// Extract a string, stored as UTF-8, from a PE section.
-pub fn extract_string(pe_data: &[u8], section: &str) -> Result<CString16> {
-    let string = pe_section_as_string(pe_data, section).ok_or(Status::INVALID_PARAMETER)?;
+/// How many characters of a string to show when it cannot be converted to UCS-2.
+const CONVERSION_CONTEXT_CHARS: usize = 64;
 
-    Ok(CString16::try_from(string.as_str()).map_err(|_| Status::INVALID_PARAMETER)?)
+/// Convert a string to UCS-2, as UEFI expects it.
+///
+/// Characters outside the Basic Multilingual Plane, e.g. most emoji, and NUL characters cannot be
+/// converted. In that case, the offending character is logged along with the start of the string,
+/// so that the user knows what to fix. `what` names the string in this message, e.g. `section
+/// .cmdline`.
+pub fn to_cstring16(string: &str, what: &str) -> Result<CString16> {
+    CString16::try_from(string).map_err(|_| {
+        let offending = string
+            .char_indices()
+            .find(|(_, c)| *c == '\0' || u32::from(*c) > 0xffff);
+        let excerpt: String = string.chars().take(CONVERSION_CONTEXT_CHARS).collect();
+        let ellipsis = if excerpt.len() < string.len() {
+            "..."
+        } else {
+            ""
+        };
+        match offending {
+            Some((offset, c)) => warn!(
+                "Cannot convert {what} to UCS-2, character {c:?} at byte {offset} is not supported: `{excerpt}{ellipsis}`"
+            ),
+            None => warn!("Cannot convert {what} to UCS-2: `{excerpt}{ellipsis}`"),
+        }
+        Status::INVALID_PARAMETER.into()
+    })
 }
 
-/// Obtain the kernel command line that should be used for booting.
+/// Handle the result of a measurement.
 ///
-/// If Secure Boot is active, this is always the embedded one (since the one passed from the bootloader may come from a malicious type 1 entry).
-/// If Secure Boot is not active, the command line passed from the bootloader is used, falling back to the embedded one.
-pub fn get_cmdline(embedded: &CStr16, secure_boot_enabled: bool) -> Vec<u8> {
-    if secure_boot_enabled {
-        // The command line passed from the bootloader cannot be trusted, so it is not used when Secure Boot is active.
-        embedded.as_bytes().to_vec()
-    } else {
-        let passed = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())
-            .map(|loaded_image| loaded_image.load_options_as_bytes().map(|b| b.to_vec()));
-        match passed {
-            Ok(Some(passed)) => passed,
-            // If anything went wrong, fall back to the embedded command line.
-            _ => embedded.as_bytes().to_vec(),
+/// A TPM can be present and yet fail to extend PCRs, e.g. because it is in failure mode. By
+/// default, this only results in a warning. If measurements are required, the boot is stopped,
+/// see [`crate::config::StubConfig::measure_required`].
+pub fn check_measurement<T>(result: Result<T>, measure_required: bool) -> Result<()> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) if measure_required => {
+            error!("Failed to perform measurements: {err}. Measurements are required, refusing to boot.");
+            Err(Status::SECURITY_VIOLATION.into())
+        }
+        Err(err) => {
+            warn!("Failed to perform measurements, the TPM may be in failure mode: {err}. Continuing anyway.");
+            Ok(())
+        }
+    }
+}
+
+/// Extract the embedded kernel command line, i.e. `.cmdline` and its continuation sections.
+pub fn extract_cmdline(pe_data: &[u8]) -> Result<CString16> {
+    let cmdline = pe_cmdline(pe_data).ok_or(Status::INVALID_PARAMETER)?;
+
+    to_cstring16(&cmdline, "the embedded kernel command line")
+}
+
+/// Extract a SHA256 hash from a PE section.
+pub fn extract_hash(pe_data: &[u8], section: &str) -> Result<Hash> {
+    let array: [u8; 32] = pe_section(pe_data, section)
+        .ok_or(Status::INVALID_PARAMETER)?
+        .try_into()
+        .map_err(|_| Status::INVALID_PARAMETER)?;
+
+    Ok(array.into())
+}
+
+/// Verify some data against its expected hash.
+///
+/// In case of a mismatch:
+/// * If Secure Boot is active, an error message is logged, and the SECURITY_VIOLATION error is returned to stop the boot.
+/// * If Secure Boot is not active, only a warning is logged, and the boot process is allowed to continue.
+pub fn check_hash(
+    data: &[u8],
+    expected_hash: Hash,
+    name: &str,
+    secure_boot: bool,
+) -> uefi::Result<()> {
+    check_digest(Sha256::digest(data), expected_hash, name, secure_boot)
+}
+
+/// Verify the hash of some data, which was computed while reading it, against its expected hash.
+///
+/// Failures are handled as in [`check_hash`].
+pub fn check_digest(
+    hash: Hash,
+    expected_hash: Hash,
+    name: &str,
+    secure_boot: bool,
+) -> uefi::Result<()> {
+    if !verify_hash(&hash, &expected_hash) {
+        if secure_boot {
+            error!("{name} hash does not match!");
+            return Err(Status::SECURITY_VIOLATION.into());
+        } else {
+            warn!("{name} hash does not match! Continuing anyway.");
+            let _ = report_degraded_boot(
+                DegradedPath::FallbackSecureBootOff,
+                &format!("{name} hash does not match"),
+            );
         }
     }
+    Ok(())
 }
 
-/// Check whether Secure Boot is active, and we should be enforcing integrity checks.
+/// Verify the embedded os-release against its embedded hash.
 ///
-/// In case of doubt, true is returned to be on the safe side.
-pub fn get_secure_boot_status() -> bool {
-    // The firmware initialized SecureBoot to 1 if performing signature checks, and 0 if it doesn't.
-    // Applications are not supposed to modify this variable (in particular, don't change the value from 1 to 0).
-    let secure_boot_enabled = runtime::get_variable(
-        cstr16!("SecureBoot"),
-        &VariableVendor(guid!("8be4df61-93ca-11d2-aa0d-00e098032b8c")),
-        &mut [1],
+/// Images built before the `.osrelh` section was introduced do not carry a hash, they are accepted
+/// as is. Failures are handled as in [`check_hash`].
+pub fn check_os_release(pe_data: &[u8]) -> Result<()> {
+    if pe_section(pe_data, ".osrelh").is_none() {
+        return Ok(());
+    }
+
+    let expected_hash = extract_hash(pe_data, ".osrelh")?;
+    let os_release = pe_section(pe_data, ".osrel").unwrap_or_default();
+
+    check_hash(
+        os_release,
+        expected_hash,
+        "os-release",
+        secure_boot_state().is_enforcing(),
     )
-    .discard_errdata()
-    .and_then(|(value, _)| match value {
-        [0] => Ok(false),
-        [1] => Ok(true),
-        [v] => {
-            warn!("Unexpected value of SecureBoot variable: {v}. Performing verification anyway.");
-            Ok(true)
+}
+
+/// Check the sections that lzbt embedded against their hashes in the `.sectsum` section.
+///
+/// Unlike the Authenticode signature of the image, this does not rely on Secure Boot, so it also
+/// detects a corrupt image on machines without it. A corrupt image is never booted, because none
+/// of its embedded configuration can be trusted.
+pub fn check_section_checksums(pe_data: &[u8]) -> Result<()> {
+    let corrupt = corrupt_sections(pe_data);
+    if corrupt.is_empty() {
+        return Ok(());
+    }
+
+    for section_name in corrupt {
+        error!("Section {section_name} does not match its checksum!");
+    }
+    error!("The image is corrupt, refusing to boot.");
+    Err(Status::COMPROMISED_DATA.into())
+}
+
+/// Check the code sections of the stub against their hashes in the `.selfsum` section.
+///
+/// This detects a stub that was modified in place on the ESP, e.g. by patching its code, even
+/// without Secure Boot. It is no replacement for Secure Boot: whoever can modify the stub can also
+/// update `.selfsum` and `.sectsum`. If the file of the image cannot be read, e.g. because it was
+/// not loaded from a file, there is nothing to check against and this only warns.
+pub fn check_self_hash(handle: Handle, pe_data: &[u8]) -> Result<()> {
+    if pe_section(pe_data, ".selfsum").is_none() {
+        return Ok(());
+    }
+
+    let file = booted_image_path().and_then(|image_path| {
+        let image_path = CString16::try_from(image_path.as_str()).ok()?;
+        boot::get_image_file_system(handle)
+            .ok()?
+            .open_volume()
+            .ok()?
+            .open(&image_path, FileMode::Read, FileAttribute::empty())
+            .ok()?
+            .into_regular_file()
+    });
+    let Some(mut file) = file else {
+        warn!("Cannot read the stub from the ESP, skipping the check of its code sections.");
+        return Ok(());
+    };
+
+    let modified = modified_code_sections(pe_data, |offset, size| {
+        file.set_position(offset).ok()?;
+        let mut data = vec![0; size];
+        let mut read = 0;
+        while read < size {
+            match file.read(&mut data[read..]) {
+                Ok(0) | Err(_) => return None,
+                Ok(chunk) => read += chunk,
+            }
         }
-        _ => Err(Status::BAD_BUFFER_SIZE.into()),
-    })
-    .unwrap_or_else(|err| {
-        if err.status() == Status::NOT_FOUND {
-            warn!("SecureBoot variable not found. Assuming Secure Boot is not supported.");
-            false
-        } else {
-            warn!("Failed to read SecureBoot variable: {err}. Performing verification anyway.");
-            true
+        Some(data)
+    });
+    if modified.is_empty() {
+        return Ok(());
+    }
+
+    for section_name in modified {
+        error!("Code section {section_name} of the stub does not match its hash!");
+    }
+    error!("The stub was modified, refusing to boot.");
+    Err(Status::COMPROMISED_DATA.into())
+}
+
+/// How long to wait for a key press after booting failed, so that unattended machines still fall
+/// back to another boot entry.
+const FAILURE_PAUSE_SECONDS: u64 = 30;
+
+/// Keep the reason why booting failed on the console until a key is pressed, or at most
+/// [`FAILURE_PAUSE_SECONDS`], before control returns to the boot menu.
+///
+/// The reason itself is logged where the failure happens, e.g. which file could not be read.
+pub fn pause_after_failure(status: Status) {
+    error!(
+        "Booting failed: {status}. Press any key to return to the boot menu, continuing in {FAILURE_PAUSE_SECONDS} seconds."
+    );
+
+    let key_event = system::with_stdin(|stdin| {
+        // Discard keys that were pressed before, e.g. to select the boot entry.
+        let _ = stdin.reset(false);
+        stdin.wait_for_key_event()
+    });
+    // SAFETY: The event has no notification function.
+    let timer = unsafe { boot::create_event(EventType::TIMER, Tpl::CALLBACK, None, None) }
+        .and_then(|timer| {
+            boot::set_timer(
+                &timer,
+                TimerTrigger::Relative(FAILURE_PAUSE_SECONDS * 10_000_000),
+            )
+            .map(|()| timer)
+        });
+
+    match (key_event, timer) {
+        (Some(key_event), Ok(timer)) => {
+            // SAFETY: The clone is only used until the timer is closed below.
+            let _ = boot::wait_for_event(&mut [key_event, unsafe { timer.unsafe_clone() }]);
+            let _ = boot::close_event(timer);
+            // The key must not end up in the boot menu.
+            system::with_stdin(|stdin| {
+                let _ = stdin.read_key();
+            });
         }
+        _ => boot::stall(usize::try_from(FAILURE_PAUSE_SECONDS * 1_000_000).unwrap_or(usize::MAX)),
+    }
+}
+
+/// Switch the console to a text mode.
+///
+/// If the firmware does not support the mode, the console is left as it is.
+pub fn set_console_mode(console_mode: ConsoleMode) {
+    if console_mode == ConsoleMode::Keep {
+        return;
+    }
+
+    // Nothing may be logged while stdout is borrowed, the logger writes to it as well.
+    let result = system::with_stdout(|stdout| {
+        let mode = match console_mode {
+            ConsoleMode::Keep => None,
+            ConsoleMode::Max => stdout
+                .modes()
+                .max_by_key(|mode| mode.columns() * mode.rows()),
+            ConsoleMode::Number(number) => stdout.modes().find(|mode| mode.index() == number),
+        };
+        mode.map(|mode| (mode.index(), stdout.set_mode(mode)))
     });
 
+    match result {
+        None => warn!("The console does not support the text mode {console_mode:?}."),
+        Some((number, Err(err))) => {
+            warn!("Failed to switch the console to text mode {number}: {err}")
+        }
+        Some((_, Ok(()))) => {}
+    }
+}
+
+/// Show the boot message embedded in the `.bootmsg` section, if there is one.
+///
+/// Lines that are too long for the console are truncated, as are messages with more lines than
+/// the console has rows. The message is part of the signed image, so it is not measured.
+pub fn show_boot_message(pe_data: &[u8]) {
+    let Some(message) = pe_section(pe_data, ".bootmsg") else {
+        return;
+    };
+    let message = String::from_utf8_lossy(message);
+
+    system::with_stdout(|stdout| {
+        // Keep the last row free, so that the console does not scroll the first line away.
+        let (columns, rows) = match stdout.current_mode() {
+            Ok(Some(mode)) => (mode.columns(), mode.rows().saturating_sub(1).max(1)),
+            _ => (80, 24),
+        };
+
+        for line in message.trim_end().lines().take(rows) {
+            let line: String = line.chars().take(columns).collect();
+            let _ = writeln!(stdout, "{line}");
+        }
+    });
+}
+
+/// Obtain the kernel command line that should be used for booting, before anything is appended to
+/// it.
+///
+/// The first one of these that exists and is trusted is used:
+/// 1. The command line that a boot loader passes in the load options, see [`get_passed_cmdline`].
+/// 2. The command line override on the ESP, see [`get_cmdline_override`].
+/// 3. The `embedded` command line.
+///
+/// Only the command line that is used is measured. An error is only returned if this measurement
+/// fails while measurements are required.
+///
+/// The stub then appends, in this order, the command lines of the addons, see
+/// [`with_cmdline_addons`], the fallback command line on the last boot attempt, see
+/// [`with_cmdline_fallback`], and the SMBIOS command line addition, see
+/// [`with_smbios_cmdline_addon`]. Finally, the user can edit the result, see
+/// [`crate::boot_menu::edit_cmdline`]. Every addition is only measured once it is appended.
+pub fn get_cmdline(
+    handle: Handle,
+    embedded: CString16,
+    trusted_hashes: &[u8],
+    recovery_key: Option<&[u8]>,
+    secure_boot_enabled: bool,
+    measure_required: bool,
+) -> Result<Vec<u8>> {
+    if let Some(passed) = get_passed_cmdline(trusted_hashes, secure_boot_enabled, measure_required)?
+    {
+        return Ok(passed);
+    }
+
+    let cmdline = get_cmdline_override(handle, trusted_hashes, recovery_key, measure_required)?
+        .unwrap_or(embedded);
+    Ok(cmdline.as_bytes().to_vec())
+}
+
+/// Obtain the kernel command line that a boot loader passes in the load options of the stub.
+///
+/// A boot loader that starts the stub, e.g. a menu that chainloads it, passes the kernel command
+/// line to boot with as the load options of the stub, a UCS-2 string. They are used as follows:
+/// * If Secure Boot is not active, the load options are used as is, if there are any.
+/// * If Secure Boot is active, the load options may come from a malicious type 1 entry. They are
+///   only used if they are one of the trusted command lines of `trusted_hashes`, ignoring leading
+///   and trailing whitespace, and are then measured like a command line override on the ESP.
+///
+/// An error is only returned if the measurement fails while measurements are required.
+fn get_passed_cmdline(
+    trusted_hashes: &[u8],
+    secure_boot_enabled: bool,
+    measure_required: bool,
+) -> Result<Option<Vec<u8>>> {
+    let Ok(loaded_image) = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())
+    else {
+        return Ok(None);
+    };
+
     if !secure_boot_enabled {
-        warn!("Secure Boot is not active!");
+        return Ok(loaded_image.load_options_as_bytes().map(<[u8]>::to_vec));
     }
 
-    secure_boot_enabled
+    let passed = loaded_image
+        .load_options_as_cstr16()
+        .ok()
+        .map(|passed| String::from(String::from(passed).trim()))
+        .filter(|passed| is_trusted_cmdline(passed.as_bytes(), trusted_hashes))
+        .and_then(|passed| {
+            let cmdline = to_cstring16(&passed, "the command line from the load options").ok()?;
+            Some((passed, cmdline))
+        });
+    let Some((passed, cmdline)) = passed else {
+        return Ok(None);
+    };
+
+    check_measurement(
+        measure_cmdline(passed.as_bytes(), "Kernel command line override"),
+        measure_required,
+    )?;
+    info!("Using the trusted command line from the load options.");
+    Ok(Some(cmdline.as_bytes().to_vec()))
+}
+
+/// Check whether the SHA-256 hash of a command line is one of the concatenated hashes in
+/// `trusted_hashes`.
+fn is_trusted_cmdline(cmdline: &[u8], trusted_hashes: &[u8]) -> bool {
+    let hash = Sha256::digest(cmdline);
+    trusted_hashes
+        .chunks_exact(32)
+        .any(|trusted_hash| verify_hash(&hash, trusted_hash))
+}
+
+/// The path of the booted image on its file system.
+///
+/// There is none if the image was not loaded from a file, e.g. by a boot loader that loaded it
+/// into memory itself.
+fn booted_image_path() -> Option<String> {
+    let image_path = booted_image_file()
+        .ok()?
+        .file_path()?
+        .to_string(DisplayOnly(false), AllowShortcuts(false))
+        .ok()?;
+    Some(String::from(&*image_path))
+}
+
+/// The file name of the booted image without its `.efi` extension.
+fn booted_image_stem() -> Option<String> {
+    let image_path = booted_image_path()?;
+    let image_name = image_path.rsplit('\\').next()?;
+    let stem = match image_name.len().checked_sub(4) {
+        Some(stem) if image_name[stem..].eq_ignore_ascii_case(".efi") => &image_name[..stem],
+        _ => image_name,
+    };
+
+    Some(String::from(stem))
+}
+
+/// Directory on the ESP that holds per-generation overrides.
+const OVERRIDE_DIRECTORY: &str = "\\loader\\overrides";
+
+/// Read an override of this image from the ESP, i.e. `\loader\overrides\<image>.<extension>`,
+/// where `<image>` is the file name of the image without its `.efi` extension. There is one
+/// override of each kind per generation.
+///
+/// Returns the path of the override and its contents, if there is one.
+pub fn read_override(handle: Handle, extension: &str) -> Option<(CString16, Vec<u8>)> {
+    let generation = booted_image_stem()?;
+    let override_path = to_cstring16(
+        &format!("{OVERRIDE_DIRECTORY}\\{generation}.{extension}"),
+        "the override path",
+    )
+    .ok()?;
+
+    let mut file_system = FileSystem::new(boot::get_image_file_system(handle).ok()?);
+    let contents = file_system.read(&*override_path).ok()?;
+
+    Some((override_path, contents))
+}
+
+/// Check whether an override is signed by the recovery key.
+///
+/// The signature is expected next to the override, in `<override>.sig`, see
+/// [`verify_detached_signature`] for its format. The recovery key is meant to be kept offline, so
+/// that overrides can be signed for a machine that fails to boot without trusting them
+/// beforehand.
+pub fn is_signed_by_recovery_key(
+    handle: Handle,
+    override_path: &CStr16,
+    contents: &[u8],
+    recovery_key: &[u8],
+) -> bool {
+    let signature_path = to_cstring16(
+        &format!("{override_path}.sig"),
+        "the override signature path",
+    );
+    let signature = signature_path.ok().and_then(|signature_path| {
+        let mut file_system = FileSystem::new(boot::get_image_file_system(handle).ok()?);
+        file_system.read(&*signature_path).ok()
+    });
+    let Some(signature) = signature else {
+        return false;
+    };
+
+    match verify_detached_signature(contents, &signature, recovery_key) {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("The signature of {override_path} was not made by the recovery key: {err}");
+            false
+        }
+    }
+}
+
+/// Read a trusted command line override for this image from the ESP.
+///
+/// The override of an image lives in `\loader\overrides\<image>.cmdline`, see [`read_override`].
+/// It is used if the SHA-256 hash of its contents, without trailing newlines, is one of the
+/// concatenated hashes in `trusted_hashes`, or if it is signed by the `recovery_key`, see
+/// [`is_signed_by_recovery_key`]. Untrusted overrides are ignored.
+///
+/// A trusted override is measured into the same PCR as the credentials, so that it can be told
+/// apart from the embedded command line. An error is only returned if this measurement fails
+/// while measurements are required.
+fn get_cmdline_override(
+    handle: Handle,
+    trusted_hashes: &[u8],
+    recovery_key: Option<&[u8]>,
+    measure_required: bool,
+) -> Result<Option<CString16>> {
+    if trusted_hashes.is_empty() && recovery_key.is_none() {
+        return Ok(None);
+    }
+
+    let Some((override_path, file_contents)) = read_override(handle, "cmdline") else {
+        return Ok(None);
+    };
+    let contents = file_contents.strip_suffix(b"\n").unwrap_or(&file_contents);
+    let contents = contents.strip_suffix(b"\r").unwrap_or(contents);
+
+    let (description, is_recovery) = if is_trusted_cmdline(contents, trusted_hashes) {
+        ("Kernel command line override", false)
+    } else if recovery_key
+        .map(|recovery_key| {
+            is_signed_by_recovery_key(handle, &override_path, &file_contents, recovery_key)
+        })
+        .unwrap_or(false)
+    {
+        ("Kernel command line recovery override", true)
+    } else {
+        warn!("Ignoring untrusted command line override {override_path}.");
+        return Ok(None);
+    };
+
+    let cmdline = core::str::from_utf8(contents)
+        .ok()
+        .and_then(|cmdline| to_cstring16(cmdline, "the command line override").ok());
+    let Some(cmdline) = cmdline else {
+        warn!("Ignoring command line override {override_path}, it is not a valid string.");
+        return Ok(None);
+    };
+
+    check_measurement(measure_cmdline(contents, description), measure_required)?;
+    info!("Using the command line override {override_path}.");
+    if is_recovery {
+        let _ = report_degraded_boot(
+            DegradedPath::Recovery,
+            &format!("command line override {override_path}"),
+        );
+    }
+
+    Ok(Some(cmdline))
+}
+
+/// Parse the boot attempts that are left from the boot counter in the file name of an image, i.e.
+/// the `<left>` of `<name>+<left>[-<done>]`.
+fn tries_left(image_stem: &str) -> Option<u32> {
+    let (_, counter) = image_stem.rsplit_once('+')?;
+    counter.split('-').next()?.parse().ok()
+}
+
+/// Append `addition` to a kernel command line as it is passed to the kernel, i.e. a UCS-2 string.
+///
+/// The command line may come from the load options, which are not necessarily null-terminated, so
+/// it ends at its first null character or at its end, whichever comes first.
+fn append_cmdline(cmdline: Vec<u8>, addition: &CStr16) -> Vec<u8> {
+    let mut combined: Vec<u8> = cmdline
+        .chunks_exact(2)
+        .take_while(|chunk| *chunk != [0, 0])
+        .flatten()
+        .copied()
+        .collect();
+    if !combined.is_empty() {
+        combined.extend_from_slice(&u16::from(b' ').to_le_bytes());
+    }
+    combined.extend_from_slice(addition.as_bytes());
+    combined
+}
+
+/// Append the fallback command line of the `.cmdfb` section on the last boot attempt.
+///
+/// With boot counting, systemd-boot decrements the tries left in the file name of an image, e.g.
+/// `nixos-generation-1+0-3.efi`, before it boots the image. If no tries are left after this one,
+/// the generation has failed to boot with its normal command line so far. The fallback, e.g.
+/// `systemd.unit=rescue.target`, then gives a chance to repair the system instead of ending up in
+/// a boot loop.
+///
+/// The fallback is measured into the same PCR as a command line override, so that a rescue boot
+/// can be told apart from a normal one. An error is only returned if this measurement fails while
+/// measurements are required.
+pub fn with_cmdline_fallback(
+    cmdline: Vec<u8>,
+    fallback: &[u8],
+    measure_required: bool,
+) -> Result<Vec<u8>> {
+    if fallback.is_empty() || booted_image_stem().as_deref().and_then(tries_left) != Some(0) {
+        return Ok(cmdline);
+    }
+
+    let Some(fallback) = core::str::from_utf8(fallback)
+        .ok()
+        .map(str::trim)
+        .filter(|fallback| !fallback.is_empty())
+    else {
+        warn!("Ignoring the fallback command line, it is not a valid string.");
+        return Ok(cmdline);
+    };
+    let Ok(addition) = to_cstring16(fallback, "section .cmdfb") else {
+        warn!("Ignoring the fallback command line, it is not a valid string.");
+        return Ok(cmdline);
+    };
+
+    check_measurement(
+        measure_cmdline(fallback.as_bytes(), "Kernel command line fallback"),
+        measure_required,
+    )?;
+    warn!("This is the last boot attempt, appending the fallback command line `{fallback}`.");
+    let _ = report_degraded_boot(DegradedPath::CmdlineFallback, "last boot attempt");
+
+    Ok(append_cmdline(cmdline, &addition))
+}
+
+/// Append the command lines of the addons, see [`linux_bootloader::addons::load_cmdline_addons`].
+///
+/// Under Secure Boot, the firmware already refused to load addons that are not signed by a trusted
+/// key. The command lines of all addons are measured as a whole into the same PCR as a command line
+/// override. An error is only returned if this measurement fails while measurements are required.
+pub fn with_cmdline_addons(
+    cmdline: Vec<u8>,
+    addons: &[CString16],
+    measure_required: bool,
+) -> Result<Vec<u8>> {
+    if addons.is_empty() {
+        return Ok(cmdline);
+    }
+
+    let addition = addons
+        .iter()
+        .map(String::from)
+        .collect::<Vec<_>>()
+        .join(" ");
+    let Ok(addition_ucs2) = to_cstring16(&addition, "the command line addons") else {
+        return Ok(cmdline);
+    };
+
+    check_measurement(
+        measure_cmdline(addition.as_bytes(), "Kernel command line addons"),
+        measure_required,
+    )?;
+    info!("Appending the command line addons `{addition}`.");
+
+    Ok(append_cmdline(cmdline, &addition_ucs2))
+}
+
+/// Append the kernel command line addition that the firmware passes in the SMBIOS OEM strings, see
+/// [`smbios_cmdline_addon`].
+///
+/// The addition is not covered by any signature or hash, so it is ignored if Secure Boot is
+/// active. Otherwise, it is measured into the same PCR as a command line override. An error is
+/// only returned if this measurement fails while measurements are required.
+pub fn with_smbios_cmdline_addon(
+    cmdline: Vec<u8>,
+    secure_boot_enabled: bool,
+    measure_required: bool,
+) -> Result<Vec<u8>> {
+    if secure_boot_enabled {
+        return Ok(cmdline);
+    }
+    let Some(addon) = smbios_cmdline_addon() else {
+        return Ok(cmdline);
+    };
+
+    check_measurement(
+        measure_cmdline(
+            String::from(&*addon).as_bytes(),
+            "Kernel command line SMBIOS addition",
+        ),
+        measure_required,
+    )?;
+    info!("Appending the command line addition `{addon}` from SMBIOS.");
+
+    Ok(append_cmdline(cmdline, &addon))
+}
+
+/// Check the structure of a compressed initrd before it is handed to the kernel.
+///
+/// A truncated or corrupt initrd otherwise only shows up as a kernel panic while unpacking it.
+pub fn check_initrd_compression(initrd_data: &[u8]) -> Result<()> {
+    validate(initrd_data).inspect_err(|_err| {
+        error!(
+            "Corrupt compressed initrd ({:?}), refusing to boot.",
+            Compression::detect(initrd_data)
+        )
+    })
+}
+
+/// Append an initrd to the concatenation of initrds that is handed to the kernel.
+///
+/// The initrds before it are first padded with zeros to a multiple of `alignment` bytes, see
+/// [`crate::config::StubConfig::initrd_alignment`]. The kernel only finds a cpio archive that
+/// follows another one at such an offset, and skips the zeros in between.
+pub fn append_initrd(initrd_data: &mut Vec<u8>, mut part: Vec<u8>, alignment: usize) {
+    let misalignment = initrd_data.len() % alignment;
+    if misalignment != 0 {
+        initrd_data.resize(initrd_data.len() + alignment - misalignment, 0);
+    }
+    initrd_data.append(&mut part);
+}
+
+/// Wait before handing over to the kernel, see [`crate::config::StubConfig::pre_handoff_delay_ms`].
+fn pre_handoff_delay(pre_handoff_delay_ms: u64, boot_timer: &mut BootTimer) {
+    if pre_handoff_delay_ms > 0 {
+        info!("Waiting {pre_handoff_delay_ms} ms before starting the kernel.");
+        boot::stall(
+            usize::try_from(pre_handoff_delay_ms.saturating_mul(1000)).unwrap_or(usize::MAX),
+        );
+        boot_timer.end_phase("delay");
+    }
 }
 
 /// Boot the Linux kernel without checking the PE signature.
 ///
 /// We assume that the caller has made sure that the image is safe to
 /// be loaded using other means.
+///
+/// If `pre_handoff_delay_ms` is not zero, the boot is stalled for that long right before the
+/// kernel is started.
+///
+/// If `initrd_config_table` is set, the initrd is also described in a configuration table, see
+/// [`InitrdLoader::new`].
+///
+/// The boot time is exported right before the kernel is started, see [`BootTimer::export`].
 pub fn boot_linux_unchecked(
     handle: Handle,
     kernel_data: Vec<u8>,
     kernel_cmdline: &[u8],
     initrd_data: Vec<u8>,
+    initrd_config_table: bool,
+    pre_handoff_delay_ms: u64,
+    mut boot_timer: BootTimer,
 ) -> uefi::Result<()> {
-    let kernel = Image::load(&kernel_data).expect("Failed to load the kernel");
+    let _ = report_verified_boot();
 
-    let mut initrd_loader = InitrdLoader::new(handle, initrd_data)?;
+    // Kernels without an EFI stub cannot be loaded as PE files, they get their initrd and command
+    // line through the boot parameters instead.
+    #[cfg(target_arch = "x86_64")]
+    if is_bzimage(&kernel_data) {
+        let kernel = BzImage::load(&kernel_data, kernel_cmdline, &initrd_data)?;
+        boot_timer.end_phase("load");
+        pre_handoff_delay(pre_handoff_delay_ms, &mut boot_timer);
+        boot_timer.export();
+        unsafe { kernel.start() }
+    }
 
-    let status = unsafe { kernel.start(handle, kernel_cmdline) };
+    // Our PE loader is minimal. Firmware may still be able to load a kernel that it cannot
+    // handle. Without Secure Boot, this is not less safe, as the hash of the kernel has already
+    // been checked. While integrity checks are enforced, a kernel that our loader rejects is not
+    // handed to the firmware's loader, which was not written with images that only passed our
+    // checks in mind.
+    let kernel = match Image::load(&kernel_data) {
+        Ok(kernel) => LoadedKernel::Internal(kernel),
+        Err(err) if secure_boot_state().is_enforcing() => {
+            error!("Failed to load the kernel: {err}. Integrity checks are enforced, not falling back to the firmware's loader.");
+            return Err(err);
+        }
+        Err(err) => {
+            warn!("Failed to load the kernel: {err}. Falling back to the firmware's loader.");
+            LoadedKernel::Firmware(load_with_firmware(handle, &kernel_data, kernel_cmdline)?)
+        }
+    };
+
+    let mut initrd_loader = InitrdLoader::new(handle, initrd_data, initrd_config_table)?;
+    boot_timer.end_phase("load");
+
+    pre_handoff_delay(pre_handoff_delay_ms, &mut boot_timer);
+    boot_timer.export();
+
+    let result = match kernel {
+        LoadedKernel::Internal(kernel) => {
+            unsafe { kernel.start(handle, kernel_cmdline) }.to_result()
+        }
+        LoadedKernel::Firmware(kernel_handle) => {
+            let result = boot::start_image(kernel_handle);
+            let _ = boot::unload_image(kernel_handle);
+            result
+        }
+    };
 
     initrd_loader.uninstall()?;
-    status.to_result()
+    result
+}
+
+/// A kernel that is ready to be started.
+enum LoadedKernel {
+    /// Loaded by our own PE loader, it reuses the image handle of the stub.
+    Internal(Image),
+    /// Loaded by the firmware, with its own image handle.
+    Firmware(Handle),
+}
+
+/// Load the kernel with the `LoadImage` boot service of the firmware and pass it its command line.
+fn load_with_firmware(
+    handle: Handle,
+    kernel_data: &[u8],
+    kernel_cmdline: &[u8],
+) -> uefi::Result<Handle> {
+    let kernel_handle = boot::load_image(
+        handle,
+        boot::LoadImageSource::FromBuffer {
+            buffer: kernel_data,
+            file_path: None,
+        },
+    )?;
+
+    let mut loaded_image = boot::open_protocol_exclusive::<LoadedImage>(kernel_handle)?;
+    // SAFETY: The command line outlives the kernel, which is started and returns, if at all,
+    // before the caller drops it.
+    unsafe {
+        loaded_image.set_load_options(
+            kernel_cmdline.as_ptr(),
+            u32::try_from(kernel_cmdline.len()).map_err(|_| Status::INVALID_PARAMETER)?,
+        );
+    }
+
+    Ok(kernel_handle)
+}
+
+/// Report the outcome of a verify-only boot and reset the machine.
+///
+/// `checks` lists every integrity check that was performed along with whether it passed. The
+/// summary is stored in the non-volatile `LanzabooteVerifyResult` EFI variable, e.g. `pass
+/// kernel=ok initrd=ok` or `fail kernel=ok initrd=mismatch`, so that it can be inspected from the
+/// next boot.
+pub fn report_verification_and_reset(checks: &[(&str, bool)]) -> ! {
+    let passed = checks.iter().all(|(_, ok)| *ok);
+    let mut summary = String::from(if passed { "pass" } else { "fail" });
+    for (name, ok) in checks {
+        summary.push_str(&format!(" {name}={}", if *ok { "ok" } else { "mismatch" }));
+    }
+
+    info!("Verify-only boot finished: {summary}");
+
+    let data: Vec<u8> = summary
+        .encode_utf16()
+        .flat_map(|c| c.to_le_bytes())
+        .collect();
+    if let Err(err) = runtime::set_variable(
+        cstr16!("LanzabooteVerifyResult"),
+        &BOOT_LOADER_VENDOR_UUID,
+        VariableAttributes::NON_VOLATILE
+            | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS,
+        &data,
+    ) {
+        error!("Failed to store the verification result: {err}");
+    }
+
+    runtime::reset(ResetType::COLD, Status::SUCCESS, None)
 }
@@ -0,0 +1,195 @@
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use log::warn;
+
+use linux_bootloader::credential_manifest::MismatchPolicy;
+use linux_bootloader::pe_section::pe_section;
+
+/// What to do when the measured sections of an image differ from the stored baseline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BaselinePolicy {
+    /// Do not keep a baseline.
+    Off,
+    /// Log a warning and continue booting.
+    Warn,
+    /// Refuse to boot.
+    Refuse,
+}
+
+/// Which text mode to switch the console to before printing anything.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsoleMode {
+    /// Keep the mode that the firmware set up.
+    Keep,
+    /// The supported mode with the most characters.
+    Max,
+    /// The mode with this number, as numbered by the firmware. Mode 0 is always 80x25.
+    Number(usize),
+}
+
+/// Runtime settings of the stub, embedded at build time in the `.conf` section.
+///
+/// The section contains one `key=value` pair per line. Empty lines and lines starting with `#` are
+/// ignored. Unknown keys are ignored as well, so that a stub keeps booting images that were built
+/// by a newer version of lzbt.
+#[derive(Default)]
+pub struct StubConfig {
+    entries: BTreeMap<String, String>,
+}
+
+impl StubConfig {
+    /// Read the settings from the `.conf` section of a PE file.
+    ///
+    /// If there is no such section, all settings have their default value.
+    pub fn new(pe_data: &[u8]) -> Self {
+        match pe_section(pe_data, ".conf").map(core::str::from_utf8) {
+            Some(Ok(text)) => Self::parse(text),
+            Some(Err(_)) => {
+                warn!("The .conf section is not valid UTF-8, ignoring it.");
+                Self::default()
+            }
+            None => Self::default(),
+        }
+    }
+
+    fn parse(text: &str) -> Self {
+        let entries = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (String::from(key.trim()), String::from(value.trim())))
+            .collect();
+
+        Self { entries }
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    fn get_bool(&self, key: &str) -> Option<bool> {
+        self.get(key).and_then(|value| match value {
+            "1" | "yes" | "true" | "on" => Some(true),
+            "0" | "no" | "false" | "off" => Some(false),
+            _ => {
+                warn!("Invalid boolean value for {key} in .conf: {value}");
+                None
+            }
+        })
+    }
+
+    fn get_u64(&self, key: &str) -> Option<u64> {
+        self.get(key).and_then(|value| {
+            value
+                .parse()
+                .inspect_err(|_| warn!("Invalid number for {key} in .conf: {value}"))
+                .ok()
+        })
+    }
+
+    /// Verify and measure everything, report the result in an EFI variable and reset the machine
+    /// instead of booting the kernel.
+    pub fn verify_only(&self) -> bool {
+        self.get_bool("verify-only").unwrap_or(false)
+    }
+
+    /// Refuse to boot if the measurements into the TPM cannot be made, instead of only warning.
+    pub fn measure_required(&self) -> bool {
+        self.get_bool("measure-required").unwrap_or(false)
+    }
+
+    /// Measure the device path of the device that the stub was loaded from into PCR 12.
+    pub fn measure_boot_device(&self) -> bool {
+        self.get_bool("measure-boot-device").unwrap_or(false)
+    }
+
+    /// Names of the credentials that must be passed to the system, separated by whitespace or
+    /// commas. The stub refuses to boot if one of them is missing.
+    pub fn required_credentials(&self) -> Vec<&str> {
+        self.get("required-credentials")
+            .map(|names| {
+                names
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Milliseconds to wait right before handing over to the kernel. Some firmware and storage
+    /// controllers need this to finish initializing, otherwise the kernel fails to detect devices.
+    pub fn pre_handoff_delay_ms(&self) -> u64 {
+        self.get_u64("bootdelay-pre-handoff").unwrap_or(0)
+    }
+
+    /// Seconds to count down before booting, during which the kernel command line can be edited,
+    /// see [`crate::boot_menu`]. The countdown is never shown under Secure Boot.
+    pub fn cmdline_edit_timeout(&self) -> u64 {
+        self.get_u64("cmdline-edit-timeout").unwrap_or(0)
+    }
+
+    /// Describe the initrd in a configuration table in addition to serving it with the LoadFile2
+    /// protocol. Kernels that do not load their initrd with LoadFile2 still find it there.
+    pub fn initrd_config_table(&self) -> bool {
+        self.get_bool("initrd-config-table").unwrap_or(false)
+    }
+
+    /// Alignment in bytes of each initrd in the concatenation of initrds that is handed to the
+    /// kernel. The kernel needs at least 4 bytes, which is the default, and the alignment must be
+    /// a power of two.
+    pub fn initrd_alignment(&self) -> usize {
+        match self.get_u64("initrd-alignment").map(usize::try_from) {
+            None => 4,
+            Some(Ok(alignment)) if alignment >= 4 && alignment.is_power_of_two() => alignment,
+            Some(_) => {
+                warn!("Invalid value for initrd-alignment in .conf, it must be a power of two of at least 4.");
+                4
+            }
+        }
+    }
+
+    /// Bytes of memory that must be available at startup. Firmware with little memory otherwise
+    /// runs out of it while loading large initrds, which only shows up as a hang.
+    pub fn heap_size(&self) -> u64 {
+        self.get_u64("heap-size").unwrap_or(0)
+    }
+
+    /// Whether to keep a baseline of the measured sections across boots and what to do if they
+    /// change, see [`linux_bootloader::baseline`].
+    pub fn measurement_baseline(&self) -> BaselinePolicy {
+        match self.get("measurement-baseline") {
+            None | Some("off") => BaselinePolicy::Off,
+            Some("warn") => BaselinePolicy::Warn,
+            Some("refuse") => BaselinePolicy::Refuse,
+            Some(value) => {
+                warn!("Invalid value for measurement-baseline in .conf: {value}");
+                BaselinePolicy::Warn
+            }
+        }
+    }
+
+    /// The text mode of the console, so that the logo and messages are legible on firmware with a
+    /// tiny or huge default mode.
+    pub fn console_mode(&self) -> ConsoleMode {
+        match self.get("console-mode") {
+            None | Some("keep") => ConsoleMode::Keep,
+            Some("max") => ConsoleMode::Max,
+            Some(value) => value.parse().map(ConsoleMode::Number).unwrap_or_else(|_| {
+                warn!("Invalid value for console-mode in .conf: {value}");
+                ConsoleMode::Keep
+            }),
+        }
+    }
+
+    /// What to do with credentials that do not match the credential manifest in `.credh`.
+    pub fn credential_mismatch(&self) -> MismatchPolicy {
+        match self.get("credential-mismatch") {
+            None | Some("skip") => MismatchPolicy::Skip,
+            Some("refuse") => MismatchPolicy::Refuse,
+            Some(value) => {
+                warn!("Invalid value for credential-mismatch in .conf: {value}");
+                MismatchPolicy::Skip
+            }
+        }
+    }
+}
@@ -1,9 +1,19 @@
 use alloc::vec::Vec;
+use log::error;
 use uefi::{prelude::*, CString16, Result};
 
-use crate::common::{boot_linux_unchecked, extract_string, get_cmdline, get_secure_boot_status};
+use crate::boot_menu::edit_cmdline;
+use crate::common::{
+    append_initrd, boot_linux_unchecked, check_initrd_compression, extract_cmdline, get_cmdline,
+    report_verification_and_reset, with_cmdline_addons, with_cmdline_fallback,
+    with_smbios_cmdline_addon,
+};
+use crate::config::StubConfig;
+use linux_bootloader::boot_time::BootTimer;
+use linux_bootloader::compression::decompress;
+use linux_bootloader::devicetree::{embedded_devicetree, install_devicetree};
 use linux_bootloader::pe_section::pe_section;
-use linux_bootloader::uefi_helpers::booted_image_file;
+use linux_bootloader::uefi_helpers::{booted_image_file, secure_boot_state};
 
 /// Extract bytes from a PE section.
 pub fn extract_bytes(pe_data: &[u8], section: &str) -> Result<Vec<u8>> {
@@ -22,46 +32,148 @@ struct EmbeddedConfiguration {
     /// The kernel command-line.
     cmdline: CString16,
 
-    /// The kernel as raw bytes.
+    /// The concatenated SHA-256 hashes of the command lines that may override `cmdline` from the
+    /// ESP.
+    trusted_cmdline_overrides: Vec<u8>,
+
+    /// The command line that is appended to `cmdline` on the last boot attempt, or empty.
+    cmdline_fallback: Vec<u8>,
+
+    /// A DER-encoded public key that may sign an override of the command line on the ESP, for
+    /// recovery.
+    recovery_key: Option<Vec<u8>>,
+
+    /// The kernel as raw bytes, decompressed if it was embedded compressed.
     kernel: Vec<u8>,
 
     /// The initrd as raw bytes.
     initrd: Vec<u8>,
+
+    /// The flattened device tree of the `.dtb` section, which replaces the one of the firmware.
+    /// Always `None` on x86_64, where kernels do not use device trees.
+    devicetree: Option<Vec<u8>>,
 }
 
 impl EmbeddedConfiguration {
     fn new(file_data: &[u8]) -> Result<Self> {
         Ok(Self {
-            kernel: extract_bytes(file_data, ".linux")?,
+            kernel: decompress(extract_bytes(file_data, ".linux")?)?,
             initrd: extract_bytes(file_data, ".initrd")?,
-            cmdline: extract_string(file_data, ".cmdline")?,
+            cmdline: extract_cmdline(file_data)?,
+            trusted_cmdline_overrides: pe_section(file_data, ".cmdovrh")
+                .map(<[u8]>::to_vec)
+                .unwrap_or_default(),
+            cmdline_fallback: pe_section(file_data, ".cmdfb")
+                .map(<[u8]>::to_vec)
+                .unwrap_or_default(),
+            recovery_key: pe_section(file_data, ".recpk").map(<[u8]>::to_vec),
+            devicetree: if cfg!(target_arch = "x86_64") {
+                None
+            } else {
+                embedded_devicetree(file_data).map(<[u8]>::to_vec)
+            },
         })
     }
 }
 
-pub fn boot_linux(handle: Handle, dynamic_initrds: Vec<Vec<u8>>) -> Status {
+pub fn boot_linux(
+    handle: Handle,
+    stub_config: &StubConfig,
+    dynamic_initrds: Vec<Vec<u8>>,
+    cmdline_addons: Vec<CString16>,
+    mut boot_timer: BootTimer,
+) -> Status {
     // SAFETY: We get a slice that represents our currently running
     // image and then parse the PE data structures from it. This is
     // safe, because we don't touch any data in the data sections that
     // might conceivably change while we look at the slice.
-    let mut config = unsafe {
-        EmbeddedConfiguration::new(booted_image_file().unwrap().as_slice())
-            .expect("Failed to extract configuration from binary.")
+    let config = booted_image_file()
+        .and_then(|image| unsafe { EmbeddedConfiguration::new(image.as_slice()) });
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            error!(
+                "Failed to extract the configuration from the image, was it built by lzbt? {err}"
+            );
+            return err.status();
+        }
     };
 
-    let secure_boot_enabled = get_secure_boot_status();
-    let cmdline = get_cmdline(&config.cmdline, secure_boot_enabled);
+    // The kernel and initrd are part of this image and thus covered by its signature and
+    // measurement, there is nothing left to verify.
+    if stub_config.verify_only() {
+        report_verification_and_reset(&[]);
+    }
+
+    let secure_boot_enabled = secure_boot_state().is_enforcing();
+    let measure_required = stub_config.measure_required();
+    // The base command line is chosen first, and the additions are appended to it in the order
+    // that `get_cmdline` describes.
+    let cmdline = match get_cmdline(
+        handle,
+        config.cmdline,
+        &config.trusted_cmdline_overrides,
+        config.recovery_key.as_deref(),
+        secure_boot_enabled,
+        measure_required,
+    ) {
+        Ok(cmdline) => cmdline,
+        Err(err) => return err.status(),
+    };
+    let cmdline = match with_cmdline_addons(cmdline, &cmdline_addons, measure_required) {
+        Ok(cmdline) => cmdline,
+        Err(err) => return err.status(),
+    };
+    let cmdline = match with_cmdline_fallback(cmdline, &config.cmdline_fallback, measure_required) {
+        Ok(cmdline) => cmdline,
+        Err(err) => return err.status(),
+    };
+    let cmdline = match with_smbios_cmdline_addon(cmdline, secure_boot_enabled, measure_required) {
+        Ok(cmdline) => cmdline,
+        Err(err) => return err.status(),
+    };
+    let cmdline = match edit_cmdline(
+        cmdline,
+        stub_config.cmdline_edit_timeout(),
+        secure_boot_enabled,
+        measure_required,
+    ) {
+        Ok(cmdline) => cmdline,
+        Err(err) => return err.status(),
+    };
+    // Time spent by the user editing the command line is not spent verifying.
+    boot_timer.end_phase("cmdline");
+
+    if let Err(err) = check_initrd_compression(&config.initrd) {
+        return err.status();
+    }
+    boot_timer.end_phase("verify");
+
+    if let Some(devicetree) = &config.devicetree {
+        if let Err(err) = install_devicetree(devicetree) {
+            return err.status();
+        }
+    }
 
-    let mut final_initrd = Vec::new();
-    final_initrd.append(&mut config.initrd);
+    let initrd_alignment = stub_config.initrd_alignment();
+    let mut final_initrd = config.initrd;
 
     // Correctness: dynamic initrds are supposed to be validated by caller,
     // i.e. they are system extension images or credentials
     // that are supposedly measured in TPM2.
     // Therefore, it is normal to not verify their hashes against a configuration.
-    for mut extra_initrd in dynamic_initrds {
-        final_initrd.append(&mut extra_initrd);
+    for extra_initrd in dynamic_initrds {
+        append_initrd(&mut final_initrd, extra_initrd, initrd_alignment);
     }
 
-    boot_linux_unchecked(handle, config.kernel, &cmdline, final_initrd).status()
+    boot_linux_unchecked(
+        handle,
+        config.kernel,
+        &cmdline,
+        final_initrd,
+        stub_config.initrd_config_table(),
+        stub_config.pre_handoff_delay_ms(),
+        boot_timer,
+    )
+    .status()
 }
@@ -4,7 +4,9 @@
 
 extern crate alloc;
 
+mod boot_menu;
 mod common;
+mod config;
 
 #[cfg(feature = "fat")]
 mod fat;
@@ -15,15 +17,32 @@ mod thin;
 #[cfg(all(feature = "fat", feature = "thin"))]
 compile_error!("A thin and fat stub cannot be produced at the same time, disable either `thin` or `fat` feature");
 
+use alloc::string::String;
 use alloc::vec::Vec;
+use common::{
+    check_measurement, check_os_release, check_section_checksums, check_self_hash,
+    pause_after_failure, set_console_mode, show_boot_message,
+};
+use config::{BaselinePolicy, StubConfig};
+use linux_bootloader::addons::load_cmdline_addons;
+use linux_bootloader::baseline::{check_baseline, BaselineCheck};
+use linux_bootloader::boot_outcome::{clear_boot_outcome, report_degraded_boot, DegradedPath};
+use linux_bootloader::boot_time::BootTimer;
 use linux_bootloader::companions::{
-    discover_credentials, discover_system_extensions, get_default_dropin_directory,
+    credential_name, discover_credentials, discover_global_credentials, discover_system_extensions,
+    get_default_dropin_directory, open_xbootldr_file_system, pcr_companions, CompanionInitrdType,
 };
+use linux_bootloader::credential_manifest::CredentialManifest;
 use linux_bootloader::efivars::{export_efi_variables, get_loader_features, EfiLoaderFeatures};
-use linux_bootloader::measure::{measure_companion_initrds, measure_image};
-use linux_bootloader::tpm::tpm_available;
-use linux_bootloader::uefi_helpers::booted_image_file;
-use log::{info, warn};
+use linux_bootloader::first_boot::consume_first_boot_marker;
+use linux_bootloader::measure::{measure_boot_device, measure_companion_initrds, measure_image};
+use linux_bootloader::pe_section::pe_section;
+use linux_bootloader::random_seed::process_random_seed;
+use linux_bootloader::tpm::{active_pcr_banks, tpm_state, TpmState};
+use linux_bootloader::uefi_helpers::{
+    booted_image_file, check_free_space, check_memory, secure_boot_state, SecureBootState,
+};
+use log::{error, info, warn};
 use uefi::boot;
 use uefi::prelude::*;
 
@@ -47,38 +66,165 @@ fn print_logo() {
 
 #[entry]
 fn main() -> Status {
-    uefi::helpers::init().unwrap();
+    if let Err(err) = uefi::helpers::init() {
+        return err.status();
+    }
+
+    let status = run();
+    if status.is_error() {
+        pause_after_failure(status);
+    }
+    status
+}
+
+/// Boot the kernel of this image.
+///
+/// This only returns if booting failed, after the reason was logged.
+fn run() -> Status {
+    let mut boot_timer = BootTimer::start();
 
+    if let Err(err) = clear_boot_outcome() {
+        warn!("Failed to clear the outcome of an earlier boot attempt: {err}");
+    }
+
+    let pe_in_memory = match booted_image_file() {
+        Ok(pe_in_memory) => pe_in_memory,
+        Err(err) => {
+            error!("Failed to find the image of the stub in memory: {err}");
+            return err.status();
+        }
+    };
+    // Nothing that is embedded in the image may be used before this.
+    // SAFETY: The image is not modified while we look at it.
+    if let Err(err) = check_section_checksums(unsafe { pe_in_memory.as_slice() }) {
+        return err.status();
+    }
+    // SAFETY: The image is not modified while we look at it.
+    if let Err(err) = check_self_hash(boot::image_handle(), unsafe { pe_in_memory.as_slice() }) {
+        return err.status();
+    }
+    // SAFETY: The image is not modified while we parse the section.
+    let stub_config = unsafe { StubConfig::new(pe_in_memory.as_slice()) };
+
+    set_console_mode(stub_config.console_mode());
     print_logo();
 
-    let is_tpm_available = tpm_available();
-    let pe_in_memory = booted_image_file()
-        .expect("Failed to extract the in-memory information about our own image");
+    match secure_boot_state() {
+        SecureBootState::Enabled => info!("Secure Boot is enabled."),
+        SecureBootState::Disabled => warn!("Secure Boot is not active!"),
+        SecureBootState::SetupMode => {
+            warn!("The firmware is in Secure Boot setup mode, signatures are not verified!")
+        }
+        SecureBootState::AuditMode => warn!("Firmware is in Secure Boot audit mode, which does not enforce signatures. Enforcing integrity checks anyway."),
+        SecureBootState::Unknown => {
+            warn!("Failed to determine the Secure Boot state. Enforcing integrity checks anyway.")
+        }
+    }
+
+    let heap_size = stub_config.heap_size();
+    if heap_size > 0 {
+        if let Err(err) = check_memory(usize::try_from(heap_size).unwrap_or(usize::MAX), "the heap")
+        {
+            return err.status();
+        }
+    }
+
+    // The os-release must be checked before it is measured, so that a forged one never ends up
+    // in the TPM event log.
+    // SAFETY: The image is not modified while we look at it.
+    if let Err(err) = check_os_release(unsafe { pe_in_memory.as_slice() }) {
+        return err.status();
+    }
+
+    let measure_required = stub_config.measure_required();
+    let is_tpm_available = match tpm_state() {
+        TpmState::Available => true,
+        TpmState::Absent => {
+            info!("No TPM found, skipping measurements.");
+            false
+        }
+        TpmState::Unusable => {
+            warn!("A TPM is present, but unusable (e.g. in failure mode), skipping measurements.");
+            false
+        }
+    };
+    if !is_tpm_available && measure_required {
+        error!("Measurements are required, refusing to boot.");
+        return Status::SECURITY_VIOLATION;
+    }
 
     if is_tpm_available {
         info!("TPM available, will proceed to measurements.");
+        match active_pcr_banks() {
+            Ok(banks) => info!("Measuring into the PCR banks {banks:?}."),
+            Err(err) => warn!("Failed to query the active PCR banks: {err}"),
+        }
         // Iterate over unified sections and measure them
-        // For now, ignore failures during measurements.
-        // TODO: in the future, devise a threat model where this can fail
-        // and ensure this hard-fail correctly.
-        let _ = measure_image(&pe_in_memory);
+        if let Err(err) = check_measurement(measure_image(&pe_in_memory), measure_required) {
+            return err.status();
+        }
+        if stub_config.measure_boot_device() {
+            if let Err(err) = check_measurement(measure_boot_device(), measure_required) {
+                return err.status();
+            }
+        }
     }
 
-    if let Ok(features) = get_loader_features() {
-        if !features.contains(EfiLoaderFeatures::RandomSeed) {
-            // FIXME: process random seed then on the disk.
-            info!("Random seed is available, but lanzaboote does not support it yet.");
+    let baseline_policy = stub_config.measurement_baseline();
+    if baseline_policy != BaselinePolicy::Off {
+        match check_baseline(&pe_in_memory) {
+            Ok(BaselineCheck::Created) => info!("Stored a baseline of the measured sections."),
+            Ok(BaselineCheck::Matches) => {}
+            Ok(BaselineCheck::Changed) if baseline_policy == BaselinePolicy::Refuse => {
+                error!("The measured sections differ from the baseline, refusing to boot.");
+                return Status::SECURITY_VIOLATION;
+            }
+            Ok(BaselineCheck::Changed) => {
+                warn!("The measured sections differ from the baseline! Continuing anyway.");
+                let _ = report_degraded_boot(
+                    DegradedPath::BaselineChanged,
+                    "the measured sections differ from the baseline",
+                );
+            }
+            Err(err) => warn!("Failed to check the baseline of the measured sections: {err}"),
         }
     }
 
+    boot_timer.end_phase("measure");
+
+    // If systemd-boot started us, it already processed the random seed.
+    let loader_processed_random_seed = get_loader_features()
+        .map(|features| features.contains(EfiLoaderFeatures::RandomSeed))
+        .unwrap_or(false);
+
     if export_efi_variables(STUB_NAME).is_err() {
         warn!("Failed to export stub EFI variables, some features related to measured boot will not be available");
     }
 
+    // Without a TPM, the booted system could not seal anything, so the marker is kept for a boot
+    // with one.
+    if is_tpm_available {
+        match consume_first_boot_marker() {
+            Ok(true) => info!(
+                "This is a provisioning boot, the booted system may seal secrets against the PCRs."
+            ),
+            Ok(false) => {}
+            Err(err) => warn!("Failed to process the first boot marker: {err}"),
+        }
+    }
+
+    // SAFETY: The image is not modified while we parse the section.
+    let credential_manifest = unsafe { pe_section(pe_in_memory.as_slice(), ".credh") }
+        .map(|manifest| CredentialManifest::parse(manifest, stub_config.credential_mismatch()));
+
     let status;
     // A list of dynamically assembled initrds, e.g. credential initrds or system extension
     // initrds.
     let mut dynamic_initrds: Vec<Vec<u8>> = Vec::new();
+    // The names of all credentials passed in those initrds.
+    let mut credential_names: Vec<String> = Vec::new();
+    // The command lines of the addons, appended to the embedded command line.
+    let mut cmdline_addons = Vec::new();
 
     {
         // This is a block for doing filesystem operations once and for all, related to companion
@@ -87,7 +233,23 @@ fn main() -> Status {
         let mut companions = Vec::new();
         let image_fs = uefi::boot::get_image_file_system(boot::image_handle());
 
-        if let Ok(image_fs) = image_fs {
+        if let Ok(mut image_fs) = image_fs {
+            // This must happen before anything is written back to the ESP, so that a failing
+            // write can be traced back to a full ESP.
+            if check_free_space(&mut image_fs).is_err() {
+                warn!("Failed to query the free space of the ESP");
+            }
+
+            if !loader_processed_random_seed {
+                match image_fs.open_volume().and_then(|mut root_dir| {
+                    process_random_seed(&mut root_dir, || secure_boot_state().is_enforcing())
+                }) {
+                    Ok(true) => info!("Passing a random seed to the kernel."),
+                    Ok(false) => {}
+                    Err(err) => warn!("Failed to process the random seed: {err}"),
+                }
+            }
+
             let mut filesystem = uefi::fs::FileSystem::new(image_fs);
             let default_dropin_directory;
 
@@ -105,13 +267,40 @@ fn main() -> Status {
             }
 
             // TODO: how to do the proper .as_ref()? Should I take AsRef in the call definition… ?
-            if let Ok(mut system_credentials) = discover_credentials(
+            match discover_credentials(
                 &mut filesystem,
                 default_dropin_directory.as_ref().map(|x| x.as_ref()),
+                credential_manifest.as_ref(),
             ) {
-                companions.append(&mut system_credentials);
-            } else {
-                warn!("Failed to discover any system credential");
+                Ok(mut system_credentials) => companions.append(&mut system_credentials),
+                // The credentials do not match the manifest.
+                Err(err) if err.status() == Status::SECURITY_VIOLATION => return err.status(),
+                Err(_) => warn!("Failed to discover any system credential"),
+            }
+
+            // Like systemd, also pick up global credentials from the XBOOTLDR partition. If we
+            // were booted from it, its file system is already open and cannot be opened twice.
+            if let Some(mut xbootldr_filesystem) = open_xbootldr_file_system() {
+                match discover_global_credentials(
+                    &mut xbootldr_filesystem,
+                    credential_manifest.as_ref(),
+                ) {
+                    Ok(Some(global_credentials)) => companions.push(global_credentials),
+                    Ok(None) => {}
+                    Err(err) if err.status() == Status::SECURITY_VIOLATION => return err.status(),
+                    Err(_) => {
+                        warn!("Failed to discover global credentials on the XBOOTLDR partition")
+                    }
+                }
+            }
+
+            match load_cmdline_addons(
+                boot::image_handle(),
+                &mut filesystem,
+                default_dropin_directory.as_ref().map(|x| x.as_ref()),
+            ) {
+                Ok(addons) => cmdline_addons = addons,
+                Err(_) => warn!("Failed to discover any command line addon"),
             }
 
             if let Some(default_dropin_dir) = default_dropin_directory {
@@ -124,10 +313,26 @@ fn main() -> Status {
                 }
             }
 
+            credential_names.extend(
+                companions
+                    .iter()
+                    .filter(|initrd| {
+                        matches!(
+                            initrd.r#type,
+                            CompanionInitrdType::Credentials
+                                | CompanionInitrdType::GlobalCredentials
+                        )
+                    })
+                    .flat_map(|initrd| initrd.files.iter())
+                    .map(|file| credential_name(file)),
+            );
+
             if is_tpm_available {
-                // TODO: in the future, devise a threat model where this can fail, see above
-                // measurements to understand the context.
-                let _ = measure_companion_initrds(&companions);
+                if let Err(err) =
+                    check_measurement(measure_companion_initrds(&companions), measure_required)
+                {
+                    return err.status();
+                }
             }
 
             dynamic_initrds.append(
@@ -141,14 +346,55 @@ fn main() -> Status {
         }
     }
 
+    // These come from the image itself, so they are passed to netbooted systems as well.
+    // SAFETY: The image is not modified while we look at it.
+    match pcr_companions(unsafe { pe_in_memory.as_slice() }) {
+        Ok(companions) => dynamic_initrds.extend(
+            companions
+                .into_iter()
+                .map(|initrd| initrd.cpio.into_inner()),
+        ),
+        Err(err) => warn!("Failed to pass the PCR signatures to the initrd: {err}"),
+    }
+
+    boot_timer.end_phase("companions");
+
+    let missing_credentials: Vec<&str> = stub_config
+        .required_credentials()
+        .into_iter()
+        .filter(|required| !credential_names.iter().any(|name| name == required))
+        .collect();
+    if !missing_credentials.is_empty() {
+        for name in missing_credentials {
+            error!("Required credential {name} is missing, refusing to boot.");
+        }
+        return Status::NOT_FOUND;
+    }
+
+    // SAFETY: The image is not modified while we look at it.
+    show_boot_message(unsafe { pe_in_memory.as_slice() });
+
     #[cfg(feature = "fat")]
     {
-        status = fat::boot_linux(boot::image_handle(), dynamic_initrds)
+        status = fat::boot_linux(
+            boot::image_handle(),
+            &stub_config,
+            dynamic_initrds,
+            cmdline_addons,
+            boot_timer,
+        )
     }
 
     #[cfg(feature = "thin")]
     {
-        status = thin::boot_linux(boot::image_handle(), dynamic_initrds).status()
+        status = thin::boot_linux(
+            boot::image_handle(),
+            &stub_config,
+            dynamic_initrds,
+            cmdline_addons,
+            boot_timer,
+        )
+        .status()
     }
 
     status
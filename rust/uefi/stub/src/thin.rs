@@ -1,14 +1,40 @@
+use alloc::format;
+use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use log::{error, warn};
 use sha2::{Digest, Sha256};
-use uefi::{fs::FileSystem, prelude::*, CString16, Result};
+use uefi::{
+    boot::{self, LoadImageSource, ScopedProtocol},
+    prelude::*,
+    proto::{
+        device_path::{build, DevicePath},
+        loaded_image::LoadedImage,
+        media::{
+            file::{File, FileAttribute, FileInfo, FileMode, RegularFile},
+            fs::SimpleFileSystem,
+        },
+    },
+    CStr16, CString16, Result,
+};
 
-use crate::common::{boot_linux_unchecked, extract_string, get_cmdline, get_secure_boot_status};
-use linux_bootloader::pe_section::pe_section;
-use linux_bootloader::uefi_helpers::booted_image_file;
-
-type Hash = sha2::digest::Output<Sha256>;
+use crate::boot_menu::edit_cmdline;
+use crate::common::{
+    append_initrd, boot_linux_unchecked, check_digest, check_initrd_compression, check_measurement,
+    extract_cmdline, extract_hash, get_cmdline, is_signed_by_recovery_key, read_override,
+    report_verification_and_reset, to_cstring16, with_cmdline_addons, with_cmdline_fallback,
+    with_smbios_cmdline_addon, Hash,
+};
+use crate::config::StubConfig;
+use linux_bootloader::authenticode::verify_authenticode;
+use linux_bootloader::boot_outcome::{report_degraded_boot, DegradedPath};
+use linux_bootloader::boot_time::BootTimer;
+use linux_bootloader::compression::{decompress, Compression};
+use linux_bootloader::devicetree::{embedded_devicetree, install_devicetree};
+use linux_bootloader::hash::verify_hash;
+use linux_bootloader::measure::{measure_fallback_image, measure_recovery_override};
+use linux_bootloader::pe_section::{pe_section, pe_section_as_string};
+use linux_bootloader::uefi_helpers::{booted_image_file, check_memory, secure_boot_state};
 
 /// The configuration that is embedded at build time.
 ///
@@ -23,123 +49,597 @@ struct EmbeddedConfiguration {
     /// The cryptographic hash of the kernel.
     kernel_hash: Hash,
 
-    /// The filename of the initrd to be passed to the kernel. See
-    /// `kernel_filename` for how to interpret these filenames.
-    initrd_filename: CString16,
-
-    /// The cryptographic hash of the initrd. This hash is computed
-    /// over the whole PE binary, not only the embedded initrd.
-    initrd_hash: Hash,
+    /// The initrds to be passed to the kernel, concatenated in this order. The first one is
+    /// always there.
+    initrds: Vec<InitrdPart>,
 
     /// The kernel command-line.
     cmdline: CString16,
+
+    /// The concatenated SHA-256 hashes of the command lines that may override `cmdline` from the
+    /// ESP.
+    trusted_cmdline_overrides: Vec<u8>,
+
+    /// The command line that is appended to `cmdline` on the last boot attempt, or empty.
+    cmdline_fallback: Vec<u8>,
+
+    /// The directory on the ESP that holds the files of this
+    /// generation, if the image was built for a per-generation
+    /// layout. Both `kernel_filename` and `initrd_filename` are
+    /// expected to live directly inside of it.
+    generation_directory: Option<CString16>,
+
+    /// A DER-encoded public key that must have signed the kernel
+    /// itself, in addition to the kernel matching `kernel_hash`.
+    kernel_signing_key: Option<Vec<u8>>,
+
+    /// A DER-encoded public key that may sign overrides of the
+    /// command line and the initrd on the ESP, for recovery.
+    recovery_key: Option<Vec<u8>>,
+
+    /// The flattened device tree of the `.dtb` section, which replaces the one of the firmware.
+    /// Always `None` on x86_64, where kernels do not use device trees.
+    devicetree: Option<Vec<u8>>,
+
+    /// A known-good image that is booted instead if the kernel or an initrd does not match its
+    /// hash.
+    fallback_image: Option<FallbackImage>,
 }
 
-/// Extract a SHA256 hash from a PE section.
-fn extract_hash(pe_data: &[u8], section: &str) -> Result<Hash> {
-    let array: [u8; 32] = pe_section(pe_data, section)
-        .ok_or(Status::INVALID_PARAMETER)?
-        .try_into()
-        .map_err(|_| Status::INVALID_PARAMETER)?;
+/// A known-good image on the ESP, typically of the previous generation.
+struct FallbackImage {
+    /// The filename of the image. See `kernel_filename` for how to interpret it.
+    filename: CString16,
+
+    /// The cryptographic hash of the image, as it is installed on the ESP.
+    hash: Hash,
+}
+
+/// The sections that hold the initrds and their hashes, in the order in which they are
+/// concatenated.
+///
+/// Only `.initrd` is required, the others allow layering e.g. CPU microcode, the main initrd and
+/// further initrds. `.initrd2h` and so on would exceed the 8 characters that PE section names are
+/// limited to.
+const INITRD_SECTIONS: &[(&str, &str)] = &[
+    (".initrd", ".initrdh"),
+    (".initrd2", ".initrh2"),
+    (".initrd3", ".initrh3"),
+    (".initrd4", ".initrh4"),
+    (".initrd5", ".initrh5"),
+    (".initrd6", ".initrh6"),
+    (".initrd7", ".initrh7"),
+    (".initrd8", ".initrh8"),
+    (".initrd9", ".initrh9"),
+];
+
+/// One of the initrds that are concatenated into the initrd passed to the kernel.
+struct InitrdPart {
+    /// The section that references or embeds the initrd, e.g. `.initrd2`.
+    section: &'static str,
+
+    initrd: Initrd,
+
+    /// The cryptographic hash of the initrd, as it is in the Nix store, i.e. decompressed if it
+    /// is embedded.
+    hash: Hash,
+}
+
+impl InitrdPart {
+    fn new(file_data: &[u8], section: &'static str, hash_section: &str) -> Result<Self> {
+        Ok(Self {
+            section,
+            initrd: Initrd::new(file_data, section)?,
+            hash: extract_hash(file_data, hash_section)?,
+        })
+    }
+
+    /// The name of the initrd in messages, e.g. `Initrd .initrd2`.
+    fn description(&self) -> String {
+        if self.section == ".initrd" {
+            String::from("Initrd")
+        } else {
+            format!("Initrd {}", self.section)
+        }
+    }
+}
+
+/// Where an initrd comes from.
+enum Initrd {
+    /// The filename of the initrd. See `kernel_filename` for how to interpret these filenames.
+    File(CString16),
+    /// The compressed initrd, embedded in its section instead of its filename.
+    Embedded(Vec<u8>),
+}
+
+impl Initrd {
+    fn new(file_data: &[u8], section: &str) -> Result<Self> {
+        let initrd = pe_section(file_data, section).ok_or(Status::INVALID_PARAMETER)?;
 
-    Ok(array.into())
+        // A filename never starts with the magic bytes of a compression format.
+        if Compression::detect(initrd) == Compression::None {
+            Ok(Self::File(extract_string(file_data, section)?))
+        } else {
+            Ok(Self::Embedded(initrd.to_vec()))
+        }
+    }
+}
+
+/// Extract a string, stored as UTF-8, from a PE section.
+fn extract_string(pe_data: &[u8], section: &str) -> Result<CString16> {
+    let string = pe_section_as_string(pe_data, section).ok_or(Status::INVALID_PARAMETER)?;
+
+    to_cstring16(&string, &format!("section {section}"))
 }
 
 impl EmbeddedConfiguration {
     fn new(file_data: &[u8]) -> Result<Self> {
+        let mut initrds = Vec::new();
+        for (index, (section, hash_section)) in INITRD_SECTIONS.iter().enumerate() {
+            if index == 0 || pe_section(file_data, section).is_some() {
+                initrds.push(InitrdPart::new(file_data, section, hash_section)?);
+            }
+        }
+
         Ok(Self {
             kernel_filename: extract_string(file_data, ".linux")?,
             kernel_hash: extract_hash(file_data, ".linuxh")?,
 
-            initrd_filename: extract_string(file_data, ".initrd")?,
-            initrd_hash: extract_hash(file_data, ".initrdh")?,
+            initrds,
 
-            cmdline: extract_string(file_data, ".cmdline")?,
+            cmdline: extract_cmdline(file_data)?,
+            trusted_cmdline_overrides: pe_section(file_data, ".cmdovrh")
+                .map(<[u8]>::to_vec)
+                .unwrap_or_default(),
+            cmdline_fallback: pe_section(file_data, ".cmdfb")
+                .map(<[u8]>::to_vec)
+                .unwrap_or_default(),
+
+            generation_directory: extract_string(file_data, ".gendir").ok(),
+
+            kernel_signing_key: pe_section(file_data, ".linuxpk").map(<[u8]>::to_vec),
+
+            recovery_key: pe_section(file_data, ".recpk").map(<[u8]>::to_vec),
+
+            devicetree: if cfg!(target_arch = "x86_64") {
+                None
+            } else {
+                embedded_devicetree(file_data).map(<[u8]>::to_vec)
+            },
+
+            fallback_image: match pe_section(file_data, ".fallbkp") {
+                Some(_) => Some(FallbackImage {
+                    filename: extract_string(file_data, ".fallbkp")?,
+                    hash: extract_hash(file_data, ".fallbkh")?,
+                }),
+                None => None,
+            },
         })
     }
 }
 
-/// Verify some data against its expected hash.
+/// Check that a file referenced by the embedded configuration lives in the expected generation
+/// directory.
+///
+/// The hash check would catch a file from another generation as well, but a mismatch there is
+/// much harder to diagnose than a path that points into the wrong directory.
+fn check_generation_directory(filename: &CStr16, directory: &CStr16, name: &str) -> Result<()> {
+    let filename = String::from(filename);
+    let directory = String::from(directory);
+    let prefix = format!("{}\\", directory.trim_end_matches('\\'));
+
+    // FAT is case-insensitive, so the comparison has to be as well.
+    let in_directory = match (filename.get(..prefix.len()), filename.get(prefix.len()..)) {
+        (Some(head), Some(basename)) => {
+            head.eq_ignore_ascii_case(&prefix) && !basename.is_empty() && !basename.contains('\\')
+        }
+        _ => false,
+    };
+
+    if !in_directory {
+        error!("{name} {filename} is not in the expected generation directory {directory}!");
+        return Err(Status::LOAD_ERROR.into());
+    }
+
+    Ok(())
+}
+
+/// Verify the Authenticode signature of a PE binary against a trusted key.
 ///
-/// In case of a mismatch:
-/// * If Secure Boot is active, an error message is logged, and the SECURITY_VIOLATION error is returned to stop the boot.
-/// * If Secure Boot is not active, only a warning is logged, and the boot process is allowed to continue.
-fn check_hash(data: &[u8], expected_hash: Hash, name: &str, secure_boot: bool) -> uefi::Result<()> {
-    let hash_correct = Sha256::digest(data) == expected_hash;
-    if !hash_correct {
+/// Failures are handled the same way as in [`crate::common::check_hash`].
+fn check_signature(
+    data: &[u8],
+    trusted_key: &[u8],
+    name: &str,
+    secure_boot: bool,
+) -> uefi::Result<()> {
+    if let Err(err) = verify_authenticode(data, trusted_key) {
         if secure_boot {
-            error!("{name} hash does not match!");
+            error!("{name} is not signed by the trusted key: {err}");
             return Err(Status::SECURITY_VIOLATION.into());
         } else {
-            warn!("{name} hash does not match! Continuing anyway.");
+            warn!("{name} is not signed by the trusted key: {err}. Continuing anyway.");
+            let _ = report_degraded_boot(
+                DegradedPath::FallbackSecureBootOff,
+                &format!("{name} is not signed by the trusted key"),
+            );
         }
     }
     Ok(())
 }
 
-pub fn boot_linux(handle: Handle, dynamic_initrds: Vec<Vec<u8>>) -> uefi::Result<()> {
+/// How much of a file is read at once while hashing it.
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Open a file of the generation on the ESP, along with its size.
+fn open_file(
+    file_system: &mut ScopedProtocol<SimpleFileSystem>,
+    path: &CStr16,
+) -> Result<(RegularFile, u64)> {
+    let mut file = file_system
+        .open_volume()?
+        .open(path, FileMode::Read, FileAttribute::empty())?
+        .into_regular_file()
+        .ok_or(Status::INVALID_PARAMETER)?;
+    let size = file.get_boxed_info::<FileInfo>()?.file_size();
+    Ok((file, size))
+}
+
+/// Fail early if a file on the ESP does not fit into memory, instead of running out of memory
+/// while reading it.
+fn check_memory_for_file(
+    file_system: &mut ScopedProtocol<SimpleFileSystem>,
+    path: &CStr16,
+    purpose: &str,
+) -> Result<()> {
+    match open_file(file_system, path) {
+        Ok((_, size)) => check_memory(usize::try_from(size).unwrap_or(usize::MAX), purpose),
+        // Reading the file reports the error.
+        Err(_) => Ok(()),
+    }
+}
+
+/// Read a file of the generation, e.g. the kernel, from the ESP and hash it on the way.
+///
+/// The file is read in chunks that are hashed while they are still in the cache, instead of
+/// hashing all of it in a second pass. The data is still returned in full, as it is loaded later.
+///
+/// Failures are logged with the path and the failing step, e.g. `Failed to open
+/// \EFI\nixos\kernel.efi: NOT_FOUND`, because they are typical of a broken ESP.
+fn read_and_hash(
+    file_system: &mut ScopedProtocol<SimpleFileSystem>,
+    path: &CStr16,
+) -> Result<(Vec<u8>, Hash)> {
+    let (mut file, size) = open_file(file_system, path)
+        .inspect_err(|err| error!("Failed to open {path}: {}", err.status()))?;
+    let size = usize::try_from(size).map_err(|_| {
+        error!("Failed to read {path}: it does not fit into memory");
+        Status::OUT_OF_RESOURCES
+    })?;
+
+    let mut data = vec![0; size];
+    let mut hasher = Sha256::new();
+    let mut read = 0;
+    while read < size {
+        let end = size.min(read + READ_CHUNK_SIZE);
+        match file.read(&mut data[read..end]) {
+            Ok(0) => {
+                error!("Failed to read {path}: it ended after {read} of {size} bytes");
+                return Err(Status::END_OF_FILE.into());
+            }
+            Ok(chunk) => {
+                hasher.update(&data[read..read + chunk]);
+                read += chunk;
+            }
+            Err(err) => {
+                error!("Failed to read {path}: {}", err.status());
+                return Err(err.status().into());
+            }
+        }
+    }
+
+    Ok((data, hasher.finalize()))
+}
+
+/// The device path of a file on the file system that this image was loaded from.
+///
+/// The firmware derives the device of an image that it loads from a buffer from this path, which
+/// the stub of the fallback image needs to find its own kernel and initrd.
+fn file_device_path(handle: Handle, filename: &CStr16) -> Result<Vec<u8>> {
+    let device = boot::open_protocol_exclusive::<LoadedImage>(handle)?
+        .device()
+        .ok_or(Status::NOT_FOUND)?;
+    let device_path = boot::open_protocol_exclusive::<DevicePath>(device)?;
+
+    let mut buffer = Vec::new();
+    let mut builder = build::DevicePathBuilder::with_vec(&mut buffer);
+    for node in device_path.node_iter() {
+        builder = builder.push(&node).map_err(|_| Status::INVALID_PARAMETER)?;
+    }
+    builder
+        .push(&build::media::FilePath {
+            path_name: filename,
+        })
+        .and_then(|builder| builder.finalize())
+        .map_err(|_| Status::INVALID_PARAMETER)?;
+    Ok(buffer)
+}
+
+/// Boot the known-good `fallback` image instead of this generation, after checking it against
+/// its hash.
+///
+/// The fallback image is measured into the same PCR as the command line, so that a fallback boot
+/// can be told apart from a normal one. If this measurement fails while measurements are required,
+/// the fallback image is not booted. This only returns if the fallback image cannot be booted.
+fn boot_fallback_image(
+    handle: Handle,
+    fallback: &FallbackImage,
+    measure_required: bool,
+) -> Result<()> {
+    let (image_data, image_hash) = {
+        let mut file_system = boot::get_image_file_system(handle)?;
+        read_and_hash(&mut file_system, &fallback.filename)?
+        // The stub of the fallback image opens the file system itself.
+    };
+    if !verify_hash(&image_hash, &fallback.hash) {
+        error!(
+            "The fallback image {} does not match its hash!",
+            fallback.filename
+        );
+        return Err(Status::SECURITY_VIOLATION.into());
+    }
+    let device_path = file_device_path(handle, &fallback.filename)?;
+    // SAFETY: The buffer was filled by the device path builder.
+    let device_path = unsafe { DevicePath::from_ffi_ptr(device_path.as_ptr().cast()) };
+
+    check_measurement(
+        measure_fallback_image(&image_data, "Fallback image"),
+        measure_required,
+    )?;
+    // The stub of the fallback image starts over with a fresh boot outcome, so reporting a
+    // degraded boot here would be lost.
+    warn!("Booting the fallback image {}.", fallback.filename);
+
+    // With Secure Boot, the firmware checks the signature of the fallback image as well.
+    let image = boot::load_image(
+        handle,
+        LoadImageSource::FromBuffer {
+            buffer: &image_data,
+            file_path: Some(device_path),
+        },
+    )?;
+    boot::start_image(image)
+}
+
+/// Read an initrd for this image from the ESP that is signed by the `recovery_key`, see
+/// [`is_signed_by_recovery_key`].
+///
+/// The initrd lives in `\loader\overrides\<image>.initrd`, see [`read_override`], and replaces the
+/// initrd of the generation, even if that one does not match its hash. It is measured into the same
+/// PCR as the credentials, so that a recovery boot can be told apart from a normal one. An error is
+/// only returned if this measurement fails while measurements are required.
+fn get_recovery_initrd(
+    handle: Handle,
+    recovery_key: &[u8],
+    measure_required: bool,
+) -> Result<Option<Vec<u8>>> {
+    let Some((override_path, initrd)) = read_override(handle, "initrd") else {
+        return Ok(None);
+    };
+
+    if !is_signed_by_recovery_key(handle, &override_path, &initrd, recovery_key) {
+        warn!("Ignoring initrd override {override_path}, it is not signed by the recovery key.");
+        return Ok(None);
+    }
+
+    check_measurement(
+        measure_recovery_override(&initrd, "Recovery initrd"),
+        measure_required,
+    )?;
+    warn!("Using the recovery initrd {override_path}.");
+    let _ = report_degraded_boot(DegradedPath::Recovery, &format!("initrd {override_path}"));
+
+    Ok(Some(initrd))
+}
+
+pub fn boot_linux(
+    handle: Handle,
+    stub_config: &StubConfig,
+    dynamic_initrds: Vec<Vec<u8>>,
+    cmdline_addons: Vec<CString16>,
+    mut boot_timer: BootTimer,
+) -> uefi::Result<()> {
     // SAFETY: We get a slice that represents our currently running
     // image and then parse the PE data structures from it. This is
     // safe, because we don't touch any data in the data sections that
     // might conceivably change while we look at the slice.
-    let config = unsafe {
-        EmbeddedConfiguration::new(booted_image_file().unwrap().as_slice())
-            .expect("Failed to extract configuration from binary. Did you run lzbt?")
-    };
+    let config =
+        unsafe { EmbeddedConfiguration::new(booted_image_file()?.as_slice()) }.map_err(|err| {
+            error!(
+                "Failed to extract the configuration from the image, was it built by lzbt? {err}"
+            );
+            err
+        })?;
+
+    if let Some(generation_directory) = &config.generation_directory {
+        check_generation_directory(&config.kernel_filename, generation_directory, "Kernel")?;
+        for part in &config.initrds {
+            if let Initrd::File(initrd_filename) = &part.initrd {
+                check_generation_directory(
+                    initrd_filename,
+                    generation_directory,
+                    &part.description(),
+                )?;
+            }
+        }
+    }
 
-    let secure_boot_enabled = get_secure_boot_status();
+    let secure_boot_enabled = secure_boot_state().is_enforcing();
+    let measure_required = stub_config.measure_required();
 
     let kernel_data;
-    let mut initrd_data;
+    let kernel_hash;
+    // The initrds along with their actual and expected hashes, in order.
+    let mut initrds = Vec::new();
 
     {
-        let file_system =
-            uefi::boot::get_image_file_system(handle).expect("Failed to get file system handle");
-        let mut file_system = FileSystem::new(file_system);
+        let mut file_system = uefi::boot::get_image_file_system(handle).map_err(|err| {
+            error!("Failed to open the file system of the image: {err}");
+            err
+        })?;
+
+        check_memory_for_file(&mut file_system, &config.kernel_filename, "the kernel")?;
+        for part in &config.initrds {
+            if let Initrd::File(initrd_filename) = &part.initrd {
+                check_memory_for_file(&mut file_system, initrd_filename, "the initrd")?;
+            }
+        }
 
-        kernel_data = file_system
-            .read(&*config.kernel_filename)
-            .expect("Failed to read kernel file into memory");
-        initrd_data = file_system
-            .read(&*config.initrd_filename)
-            .expect("Failed to read initrd file into memory");
+        (kernel_data, kernel_hash) = read_and_hash(&mut file_system, &config.kernel_filename)?;
+        boot_timer.end_phase("read-kernel");
+        for part in config.initrds {
+            let description = part.description();
+            let (initrd_data, initrd_hash) = match part.initrd {
+                Initrd::File(initrd_filename) => read_and_hash(&mut file_system, &initrd_filename)?,
+                // The hash covers the initrd as it is in the Nix store, i.e. the decompressed one.
+                Initrd::Embedded(initrd) => {
+                    let initrd_data = decompress(initrd)?;
+                    let initrd_hash = Sha256::digest(&initrd_data);
+                    (initrd_data, initrd_hash)
+                }
+            };
+            initrds.push((
+                part.section,
+                description,
+                initrd_data,
+                initrd_hash,
+                part.hash,
+            ));
+        }
     }
+    boot_timer.end_phase("read-initrd");
 
-    let cmdline = get_cmdline(&config.cmdline, secure_boot_enabled);
+    if stub_config.verify_only() {
+        let mut checks = vec![("kernel", verify_hash(&kernel_hash, &config.kernel_hash))];
+        for (section, _, _, initrd_hash, expected_hash) in &initrds {
+            checks.push((
+                section.trim_start_matches('.'),
+                verify_hash(initrd_hash, expected_hash),
+            ));
+        }
+        if let Some(kernel_signing_key) = &config.kernel_signing_key {
+            checks.push((
+                "kernel-signature",
+                decompress(kernel_data)
+                    .map(|kernel_data| {
+                        verify_authenticode(&kernel_data, kernel_signing_key).is_ok()
+                    })
+                    .unwrap_or(false),
+            ));
+        }
+        report_verification_and_reset(&checks);
+    }
 
-    check_hash(
-        &kernel_data,
-        config.kernel_hash,
-        "Kernel",
+    // A generation whose files got corrupted on the ESP would not boot with Secure Boot, and
+    // would boot unverified without it.
+    if let Some(fallback_image) = &config.fallback_image {
+        let intact = verify_hash(&kernel_hash, &config.kernel_hash)
+            && initrds.iter().all(|(_, _, _, initrd_hash, expected_hash)| {
+                verify_hash(initrd_hash, expected_hash)
+            });
+        if !intact {
+            warn!(
+                "This generation does not match its hashes, trying the fallback image {}.",
+                fallback_image.filename
+            );
+            if let Err(err) = boot_fallback_image(handle, fallback_image, measure_required) {
+                warn!("Failed to boot the fallback image: {err}");
+            }
+        }
+    }
+
+    // The base command line is chosen first, and the additions are appended to it in the order
+    // that `get_cmdline` describes.
+    let cmdline = get_cmdline(
+        handle,
+        config.cmdline,
+        &config.trusted_cmdline_overrides,
+        config.recovery_key.as_deref(),
         secure_boot_enabled,
+        measure_required,
     )?;
-    check_hash(
-        &initrd_data,
-        config.initrd_hash,
-        "Initrd",
+    let cmdline = with_cmdline_addons(cmdline, &cmdline_addons, measure_required)?;
+    let cmdline = with_cmdline_fallback(cmdline, &config.cmdline_fallback, measure_required)?;
+    let cmdline = with_smbios_cmdline_addon(cmdline, secure_boot_enabled, measure_required)?;
+    let cmdline = edit_cmdline(
+        cmdline,
+        stub_config.cmdline_edit_timeout(),
         secure_boot_enabled,
+        measure_required,
     )?;
+    // Time spent by the user editing the command line is not spent verifying.
+    boot_timer.end_phase("cmdline");
+
+    check_digest(
+        kernel_hash,
+        config.kernel_hash,
+        "Kernel",
+        secure_boot_enabled,
+    )?;
+    // The hash covers the kernel as it is stored on the ESP, the signature the kernel itself.
+    let kernel_data = decompress(kernel_data)?;
+    if let Some(kernel_signing_key) = &config.kernel_signing_key {
+        check_signature(
+            &kernel_data,
+            kernel_signing_key,
+            "Kernel",
+            secure_boot_enabled,
+        )?;
+    }
+    boot_timer.end_phase("verify-kernel");
+    let recovery_initrd = match config.recovery_key.as_deref() {
+        Some(recovery_key) => get_recovery_initrd(handle, recovery_key, measure_required)?,
+        None => None,
+    };
+    let initrd_alignment = stub_config.initrd_alignment();
+    let mut initrd_data = Vec::new();
+    if let Some(recovery_initrd) = recovery_initrd {
+        // The recovery initrd replaces all initrds of the generation.
+        check_initrd_compression(&recovery_initrd)?;
+        initrd_data = recovery_initrd;
+    } else {
+        // Every initrd is checked on its own, and the kernel gets their concatenation.
+        for (_, description, part_data, part_hash, expected_hash) in initrds {
+            check_digest(part_hash, expected_hash, &description, secure_boot_enabled)?;
+            check_initrd_compression(&part_data)?;
+            append_initrd(&mut initrd_data, part_data, initrd_alignment);
+        }
+    }
+    boot_timer.end_phase("verify-initrd");
 
     // Correctness: dynamic initrds are supposed to be validated by caller,
     // i.e. they are system extension images or credentials
     // that are supposedly measured in TPM2.
     // Therefore, it is normal to not verify their hashes against a configuration.
 
-    /// Compute the necessary padding based on the provided length
-    /// It returns None if no padding is necessary.
-    fn compute_pad4(len: usize) -> Vec<u8> {
-        vec![0u8; (4 - (len % 4)) % 4]
-    }
-
-    initrd_data.append(&mut compute_pad4(initrd_data.len()));
-    for mut extra_initrd in dynamic_initrds {
+    for extra_initrd in dynamic_initrds {
         // Uncomment for maximal debugging pleasure.
         // let debug_representation = extra_initrd.as_slice().escape_ascii().collect::<Vec<u8>>();
         // log::warn!("{:?}", String::from_utf8_lossy(&debug_representation));
-        initrd_data.append(&mut extra_initrd);
-        // Extra initrds ideally should be aligned, but just in case, let's verify this.
-        initrd_data.append(&mut compute_pad4(initrd_data.len()));
+        append_initrd(&mut initrd_data, extra_initrd, initrd_alignment);
+    }
+
+    if let Some(devicetree) = &config.devicetree {
+        install_devicetree(devicetree)?;
     }
 
-    boot_linux_unchecked(handle, kernel_data, &cmdline, initrd_data)
+    boot_linux_unchecked(
+        handle,
+        kernel_data,
+        &cmdline,
+        initrd_data,
+        stub_config.initrd_config_table(),
+        stub_config.pre_handoff_delay_ms(),
+        boot_timer,
+    )
 }